@@ -0,0 +1,119 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* one member talking on a channel and everyone else on it reading the
+ * line back, timed from the write until the last of them has it. this
+ * is the channel's fanout, with each [server] fanout setting, plus each
+ * member's write task, over real sockets on localhost */
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rusty_ircd::config::{Config, Fanout, ListenBlock};
+use rusty_ircd::{Server, ServerHandle};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::runtime::{Builder, Runtime};
+use tokio::sync::mpsc;
+
+/* kept under the usual 1024 open files, each member is two sockets */
+const MEMBERS: [usize; 3] = [10, 100, 400];
+
+async fn start(fanout: Fanout) -> ServerHandle {
+    let mut config = Config::default();
+    config.server.fanout = fanout;
+    config.services.database = ":memory:".to_string();
+    config.dns.rdns = false;
+    config.limits.connect_max = 0;
+    config.limits.unregistered_max = 0;
+    config.limits.clients_per_ip = 0;
+    config.limits.flood_burst = 1e9;
+    config.limits.flood_rate = 1e9;
+    Server::builder()
+        .config(config)
+        .name("irc.bench")
+        .listen(ListenBlock { address: IpAddr::V4(Ipv4Addr::LOCALHOST), port: 0, ..ListenBlock::default() })
+        .start()
+        .await
+        .expect("server should start")
+}
+
+/* everyone joins #bench, and every member but the first gets a task
+ * passing on each PRIVMSG it reads. hands back the first one's socket */
+async fn populate(handle: &ServerHandle, members: usize, heard: mpsc::UnboundedSender<()>) -> OwnedWriteHalf {
+    let mut talker = None;
+    for i in 0..members {
+        let (read, mut write) = TcpStream::connect(handle.local_addr()).await.unwrap().into_split();
+        let mut lines = BufReader::new(read).lines();
+        let hello = format!("NICK m{}\r\nUSER m{} 0 * :Bench\r\nJOIN #bench\r\n", i, i);
+        write.write_all(hello.as_bytes()).await.unwrap();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            if line.contains(" 366 ") {
+                break;
+            }
+        }
+        if i == 0 {
+            talker = Some(write);
+            continue;
+        }
+        let heard = heard.clone();
+        tokio::spawn(async move {
+            let _write = write;
+            while let Ok(Some(line)) = lines.next_line().await {
+                if line.contains(" PRIVMSG #bench ") && heard.send(()).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    talker.unwrap()
+}
+
+fn fanout(c: &mut Criterion) {
+    let rt: Runtime = Builder::new_multi_thread().enable_all().build().unwrap();
+    for (name, fanout) in [("outbox", Fanout::Outbox), ("broadcast", Fanout::Broadcast)].iter() {
+        bench_group(c, &rt, name, *fanout);
+    }
+}
+
+fn bench_group(c: &mut Criterion, rt: &Runtime, name: &str, fanout: Fanout) {
+    let mut group = c.benchmark_group(format!("fanout/{}", name));
+    group.measurement_time(Duration::from_secs(10));
+    for &members in MEMBERS.iter() {
+        let handle = rt.block_on(start(fanout));
+        let (tx, mut heard) = mpsc::unbounded_channel();
+        let mut talker = rt.block_on(populate(&handle, members, tx));
+        group.bench_with_input(BenchmarkId::from_parameter(members), &members, |b, &members| {
+            b.iter_custom(|iters| {
+                rt.block_on(async {
+                    let start = Instant::now();
+                    for _ in 0..iters {
+                        talker.write_all(b"PRIVMSG #bench :the quick brown fox jumps over the lazy dog\r\n").await.unwrap();
+                        for _ in 1..members {
+                            heard.recv().await.unwrap();
+                        }
+                    }
+                    start.elapsed()
+                })
+            })
+        });
+        rt.block_on(handle.shutdown("bench over"));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, fanout);
+criterion_main!(benches);
@@ -14,27 +14,38 @@
 *  You should have received a copy of the GNU Lesser General Public License
 *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
-extern crate log;
 extern crate tokio;
-extern crate tokio_native_tls;
-use crate::io::{ReadHalfWrap, WriteHalfWrap};
+use crate::config::{LimitsConfig, SlowPolicy};
+use crate::io::{ReadHalfWrap, TlsError, WriteHalfWrap};
 use crate::irc::error::Error as ircError;
 use crate::irc::reply::Reply as ircReply;
-use crate::irc::reply as reply;
+use crate::irc::cap::{self, CapState};
+use crate::irc::connlog::{self, Event};
+use crate::irc::dnsbl::{self, DnsblCheck};
+use crate::irc::accounts::AccountError;
+use crate::irc::sasl::SaslState;
+use crate::irc::link::{self, Link};
+use crate::irc::memory::Memory;
 use crate::irc::{self, Core, User, NamedEntity};
+use crate::linereader::{Line, LineReader};
 use crate::parser::{parse_message, ParseError};
+use crate::throttle::{PendingSlot, TokenBucket};
 use crate::irc::chan::ChanError;
+use std::collections::VecDeque;
 use std::error;
 use std::fmt;
 use std::io::Error as ioError;
 use std::net::IpAddr;
 use std::sync::{Arc, Weak, Mutex};
-use log::{debug, warn};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter, Lines};
-use tokio::sync::mpsc;
+use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use tracing::{debug, warn, Span};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::{mpsc, Notify};
 use tokio::sync::mpsc::error::SendError as mpscSendErr;
 use tokio::task::JoinError as tokJoinErr;
-use tokio_native_tls::native_tls::Error as tntTlsErr;
+use tokio::time;
+use tokio_tungstenite::tungstenite::Error as WsError;
 
 /* There are 3 main types of errors we can have here...
  * one is a parsing error, which should be covered by ParseError,
@@ -51,10 +62,17 @@ pub enum GenError {
     IRC(ircError),
     Mpsc(mpscSendErr<String>),
     Chan(ChanError),
+    Account(AccountError),
     DeadClient(Arc<User>),
     DeadUser(String),
-    TLS(tntTlsErr),
-    Tokio(tokJoinErr)
+    Detached(String),
+    TLS(TlsError),
+    WebSocket(Box<WsError>),
+    Tokio(tokJoinErr),
+    Disconnect(String),
+    SendQFull(u64),
+    #[cfg(feature = "lua")]
+    Lua(mlua::Error),
 }
 
 impl fmt::Display for GenError {
@@ -65,10 +83,17 @@ impl fmt::Display for GenError {
             GenError::IRC(ref err) => write!(f, "IRC Error: {}", err),
             GenError::Mpsc(ref err) => write!(f, "MPSC Send Error: {}", err),
             GenError::Chan(ref err) => write!(f, "Channel Error: {}", err),
+            GenError::Account(ref err) => write!(f, "Account Error: {}", err),
             GenError::DeadClient(user) => write!(f, "user {}, stale client", user.get_nick()),
             GenError::DeadUser(nick) => write!(f, "user {}, remant, scattered WeakRefs", nick),
+            GenError::Detached(nick) => write!(f, "user {} has no client attached", nick),
             GenError::TLS(ref err) => write!(f, "TLS Error: {}", err),
-            GenError::Tokio(ref err) => write!(f, "TLS Error: {}", err)
+            GenError::WebSocket(ref err) => write!(f, "WebSocket Error: {}", err),
+            GenError::Tokio(ref err) => write!(f, "TLS Error: {}", err),
+            GenError::Disconnect(reason) => write!(f, "disconnected: {}", reason),
+            GenError::SendQFull(id) => write!(f, "client {} is over its sendq", id),
+            #[cfg(feature = "lua")]
+            GenError::Lua(ref err) => write!(f, "Lua Error: {}", err),
         }
     }
 }
@@ -86,9 +111,16 @@ impl error::Error for GenError {
             GenError::Mpsc(ref err) => Some(err),
             GenError::DeadClient(_user) => None,
             GenError::DeadUser(_nick) => None,
+            GenError::Detached(_nick) => None,
             GenError::Chan(ref err) => Some(err),
+            GenError::Account(ref err) => Some(err),
             GenError::TLS(ref err) => Some(err),
-            GenError::Tokio(ref err) => Some(err)
+            GenError::WebSocket(ref err) => Some(err),
+            GenError::Tokio(ref err) => Some(err),
+            GenError::Disconnect(_reason) => None,
+            GenError::SendQFull(_id) => None,
+            #[cfg(feature = "lua")]
+            GenError::Lua(ref err) => Some(err),
         }
     }
 }
@@ -111,6 +143,12 @@ impl From<ChanError> for GenError {
     }
 }
 
+impl From<AccountError> for GenError {
+    fn from(err: AccountError) -> GenError {
+        GenError::Account(err)
+    }
+}
+
 impl From<ircError> for GenError {
     fn from(err: ircError) -> GenError {
         GenError::IRC(err)
@@ -123,18 +161,31 @@ impl From<mpscSendErr<String>> for GenError {
     }
 }
 
-impl From<tntTlsErr> for GenError {
-    fn from(err: tntTlsErr) -> GenError {
+impl From<TlsError> for GenError {
+    fn from(err: TlsError) -> GenError {
         GenError::TLS(err)
     }
 }
 
+impl From<WsError> for GenError {
+    fn from(err: WsError) -> GenError {
+        GenError::WebSocket(Box::new(err))
+    }
+}
+
 impl From<tokJoinErr> for GenError {
     fn from(err: tokJoinErr) -> GenError {
         GenError::Tokio(err)
     }
 }
 
+#[cfg(feature = "lua")]
+impl From<mlua::Error> for GenError {
+    fn from(err: mlua::Error) -> GenError {
+        GenError::Lua(err)
+    }
+}
+
 #[derive(Debug)]
 pub enum Host {
     Hostname(String),
@@ -150,12 +201,30 @@ impl Clone for Host {
     }
 }
 
+/* an IPv6 address like ::1 would start with a colon and wreck any
+ * line it ends up in the middle of, so those get a leading zero */
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Host::Hostname(host) => write!(f, "{}", host),
+            Host::HostAddr(ip) => {
+                let addr = ip.to_string();
+                if addr.starts_with(':') {
+                    write!(f, "0{}", addr)
+                } else {
+                    write!(f, "{}", addr)
+                }
+            },
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum ClientType {
     Dead,
     Unregistered,
     User(Arc<irc::User>),
-    //Server(Arc<Mutex<irc::Server>>), leave serv implmentation for much later
+    Server(Arc<Link>),
     ProtoUser(Arc<Mutex<irc::ProtoUser>>),
 }
 
@@ -165,6 +234,7 @@ impl Clone for ClientType {
             ClientType::Dead => ClientType::Dead,
             ClientType::Unregistered => ClientType::Unregistered,
             ClientType::User(user_ptr) => ClientType::User(Arc::clone(user_ptr)),
+            ClientType::Server(link) => ClientType::Server(Arc::clone(link)),
             ClientType::ProtoUser(proto_user_ptr) => {
                 ClientType::ProtoUser(Arc::clone(proto_user_ptr))
             }
@@ -172,31 +242,171 @@ impl Clone for ClientType {
     }
 }
 
-type MsgRecvr = mpsc::Receiver<String>;
 pub type ClientReply = Result<ircReply, ircError>;
 pub type ClientReplies = Vec<ClientReply>;
 
-pub async fn run_write_task(sock: WriteHalfWrap, mut rx: MsgRecvr) -> Result<(), ioError> {
+/* what goes down a client's outgoing queue, Close carries the
+ * final ERROR line and tells the write task to stop after it.
+ * Chatter is a channel message, the one thing a slow client can do
+ * without. lines come already CRLF terminated, and shared so a message
+ * going out to a whole channel only gets formatted and allocated once */
+#[derive(Debug)]
+pub enum Outgoing {
+    Line(Arc<str>),
+    Chatter(Arc<str>),
+    Close(String),
+}
+
+/* shared between a Client and its write task. bytes counts what's queued
+ * but not yet written, so a client that stops reading gets cut off at the
+ * sendq limit instead of piling up memory or stalling whoever's sending */
+#[derive(Debug)]
+struct QueueState {
+    bytes: AtomicUsize,
+    overflowed: AtomicBool,
+    /* when bytes last went over slow_bytes, None while it's under */
+    slow_since: Mutex<Option<Instant>>,
+    /* chatter isn't queued while this is set, and what was already
+     * queued gets skipped by the write task */
+    shedding: AtomicBool,
+    /* slow_bytes when shedding started, it stops once the write task has
+     * got bytes back under it */
+    shed_until: AtomicUsize,
+    /* the server wide total bytes counts towards */
+    memory: Arc<Memory>,
+}
+
+impl QueueState {
+    fn take(&self, bytes: usize) {
+        let left = self.bytes.fetch_sub(bytes, Ordering::SeqCst) - bytes;
+        self.memory.unqueue(bytes);
+        /* nothing more gets queued for a client that's stalled while it's
+         * shedding, so it's up to the write task to notice it's caught up */
+        if self.shedding.load(Ordering::SeqCst) && left <= self.shed_until.load(Ordering::SeqCst) {
+            *self.slow_since.lock().unwrap() = None;
+            self.shedding.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Drop for QueueState {
+    /* whatever never got written stops counting once the connection's gone */
+    fn drop(&mut self) {
+        self.memory.unqueue(*self.bytes.get_mut());
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SendQueue {
+    tx: mpsc::UnboundedSender<Outgoing>,
+    state: Arc<QueueState>,
+}
+
+#[derive(Debug)]
+pub struct SendQueueRecv {
+    rx: mpsc::UnboundedReceiver<Outgoing>,
+    state: Arc<QueueState>,
+}
+
+pub fn send_queue(memory: &Arc<Memory>) -> (SendQueue, SendQueueRecv) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let state = Arc::new(QueueState {
+        bytes: AtomicUsize::new(0),
+        overflowed: AtomicBool::new(false),
+        slow_since: Mutex::new(None),
+        shedding: AtomicBool::new(false),
+        shed_until: AtomicUsize::new(0),
+        memory: Arc::clone(memory),
+    });
+    (SendQueue { tx, state: Arc::clone(&state) }, SendQueueRecv { rx, state })
+}
+
+#[cfg(test)]
+impl SendQueueRecv {
+    /* everything queued so far without the CRLFs, for tests that have
+     * no write task */
+    pub fn drain(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Ok(out) = self.rx.try_recv() {
+            let line = match out {
+                Outgoing::Line(line) | Outgoing::Chatter(line) => line.to_string(),
+                Outgoing::Close(line) => line,
+            };
+            lines.push(line.trim_end().to_string());
+        }
+        lines
+    }
+}
+
+pub async fn run_write_task(sock: WriteHalfWrap, mut queue: SendQueueRecv) -> Result<(), ioError> {
     /* apparently we can't have ? after await on any of these
      * functions, because await returns (), but recv() and
      * write_all()/flush() shouldn't return (), should they? */
     let mut stream = BufWriter::new(sock);
-    while let Some(msg) = rx.recv().await {
-        stream.write(msg.as_bytes()).await?;
-        stream.flush().await?;
+    while let Some(msg) = queue.rx.recv().await {
+        let (line, chatter) = match msg {
+            Outgoing::Line(line) => (line, false),
+            Outgoing::Chatter(line) => (line, true),
+            Outgoing::Close(line) => {
+                /* and shut our side down properly, so TLS and WebSocket
+                 * clients see the ERROR and a clean close, not a reset */
+                stream.write_all(line.as_bytes()).await?;
+                stream.flush().await?;
+                stream.shutdown().await?;
+                break;
+            },
+        };
+        /* once over the sendq there's no point sending the backlog, and
+         * a slow client that's shedding doesn't get channel chatter */
+        let skip = queue.state.overflowed.load(Ordering::SeqCst)
+            || (chatter && queue.state.shedding.load(Ordering::SeqCst));
+        if !skip {
+            stream.write_all(line.as_bytes()).await?;
+            stream.flush().await?;
+        }
+        queue.state.take(line.len());
     }
     Ok(())
 }
 
+/* `pending` is the unregistered slot taken when the connection was
+ * accepted, and the registration deadline runs from `accepted`, so time
+ * spent on the TLS or WebSocket handshake counts against it too */
+#[allow(clippy::too_many_arguments)]
 pub async fn run_client_handler(
     id: u64,
     host: Host,
+    ip: IpAddr,
+    privacy: bool,
+    certfp: Option<String>,
     irc: Arc<Core>,
-    tx: MsgSendr,
+    tx: SendQueue,
     sock: ReadHalfWrap,
+    pending: Option<PendingSlot>,
+    accepted: time::Instant,
 ) {
-    let mut handler = ClientHandler::new(id, host, &irc, tx, sock);
+    let mut handler = ClientHandler::new(id, host, ip, privacy, certfp, &irc, tx, sock);
     irc.insert_client(handler.id, Arc::downgrade(&handler.client));
+    connlog::log(&handler.client, Event::Connect);
+    if let Some(slot) = pending {
+        handler.client.set_pending_slot(slot);
+    }
+    /* anyone still not registered when this goes off gets dropped */
+    let timeout = irc.get_config().limits.registration_timeout;
+    if timeout > 0 {
+        let client = Arc::downgrade(&handler.client);
+        tokio::spawn(async move {
+            time::sleep_until(accepted + Duration::from_secs(timeout)).await;
+            if let Some(client) = Weak::upgrade(&client) {
+                if !client.is_registered() {
+                    client.disconnect("Registration timed out");
+                }
+            }
+        });
+    }
+    if !privacy {
+        dnsbl::start_check(&handler.client);
+    }
     debug!("assigned client id {}", handler.id);
 
     /* would it be ridic to spawn a new process for every
@@ -216,11 +426,33 @@ pub async fn run_client_handler(
      * of this function, so it doesn't make sense to have any
      * return value, instead some diagnostics should be printed
      * here if there is any error */
+    let reason = match &res {
+        Err(GenError::Disconnect(reason)) => reason.clone(),
+        Err(err) => err.to_string(),
+        Ok(()) => String::from("Connection closed"),
+    };
     if let Err(err) = res {
         debug!("Client {} exited with error {}", handler.id, err);
+        /* a Disconnect has been sent its ERROR already, anything else
+         * still gets one if the socket will still take it */
+        if !matches!(err, GenError::Disconnect(_)) {
+            handler.client.close(&reason);
+        }
     } else {
         debug!("{}", "Unexpected EOF".to_string());
     }
+    connlog::log(&handler.client, Event::Quit(&reason));
+    match handler.client.get_client_type() {
+        /* the user only goes once its last connection has,
+         * and with [services] always_on not even then */
+        ClientType::User(user) if user.detach(handler.id) && !irc.hold_session(&user) => {
+            if let Some(link) = irc.get_link() {
+                link.quit(&user, &reason);
+            }
+        },
+        ClientType::Server(link) => link::closed(&irc, &link, &reason).await,
+        _ => (),
+    }
     /* All the cleanup stuff should just happen on Drop, so I've commented
      * a bunch out for now */
 
@@ -251,7 +483,7 @@ pub async fn run_client_handler(
         {
             let mut user_list: BTreeMap<u64, Arc<User>> = BTreeMap::new();
             for chan in witnesses.iter() {
-                let users = chan.gen_user_ptr_vec().clone();
+                let users = chan.gen_user_ptr_vec().await;
                 for user in users.iter() {
                     let id = user.get_id();
                     user_list.insert(id, Arc::clone(&user));
@@ -286,33 +518,163 @@ pub async fn run_client_handler(
     }*/
 }
 
-/* Receive and process IRC messages */
+/* Receive and process IRC messages. lines are read as soon as they
+ * arrive but only handled as fast as the client's flood bucket allows,
+ * anything beyond that waits in `pending` and if that backs up too far
+ * the client gets dropped for flooding */
 async fn process_lines(handler: &mut ClientHandler, irc: &Arc<Core>) -> Result<(), GenError> {
-    while let Some(line) = handler.stream.next_line().await? {
-        if line.is_empty() { continue }
-        match error_wrapper(&handler.client, irc, &line).await {
-            Err(GenError::IRC(err)) => handler.client.send_err(err).await?,
-            Err(GenError::Parse(err)) => handler.client.send_err(ircError::from(err)).await?,
-            Err(GenError::Chan(_err)) => (), /* non-fatal, will figure out how to handle later */
-            Err(GenError::Io(err)) => return Err(GenError::Io(err)),
-            Err(GenError::Mpsc(err)) => return Err(GenError::Mpsc(err)),
-            Err(GenError::DeadClient(user)) => attempt_cleanup(irc, user),
-            Err(GenError::DeadUser(nick)) => {
-                let _res = irc.search_user_chans_purge(&nick);
-                if let Err(err) = irc.remove_name(&nick) {
-                    warn!("received error {} trying to remove dead user {}", err, nick.to_string());
+    let limits = &irc.get_config().limits;
+    let mut pending: VecDeque<String> = VecDeque::new();
+    loop {
+        if pending.is_empty() {
+            match read_line(handler).await {
+                Ok(Some(line)) if line.is_empty() => continue,
+                Ok(Some(line)) => pending.push_back(line),
+                Ok(None) => break,
+                Err(err) => return handle_result(handler, irc, Err(err)).await,
+            }
+        }
+
+        let exempt = (limits.flood_exempt_opers && handler.client.is_oper()) || handler.client.is_server();
+        if let Err(delay) = handler.flood.try_take() {
+            if !exempt {
+                tokio::select! {
+                    _ = time::sleep(delay) => (),
+                    line = read_line(handler) => match line {
+                        Ok(Some(line)) if line.is_empty() => (),
+                        Ok(Some(line)) => pending.push_back(line),
+                        Ok(None) => break,
+                        Err(err) => return handle_result(handler, irc, Err(err)).await,
+                    },
                 }
-            },
-            Err(GenError::Tokio(err)) => return Err(GenError::Tokio(err)),
-            Err(GenError::TLS(err)) => return Err(GenError::TLS(err)),
-            Ok(replies) => {
-                for result_t in replies {
-                    match result_t {
-                        Ok(reply) => handler.client.send_rpl(reply).await?,
-                        Err(err) => handler.client.send_err(err).await?
-                    }
+                let recvq: usize = pending.iter().map(|line| line.len()).sum();
+                if limits.recvq > 0 && recvq > limits.recvq {
+                    debug!("client {} went over its recvq with {} bytes waiting", handler.id, recvq);
+                    return handle_result(handler, irc, Err(GenError::Disconnect("RecvQ exceeded".to_string()))).await;
+                }
+                if limits.flood_queue > 0 && pending.len() > limits.flood_queue {
+                    debug!("client {} flooded off with {} lines queued", handler.id, pending.len());
+                    return handle_result(handler, irc, Err(GenError::Disconnect("Excess Flood".to_string()))).await;
+                }
+                continue;
+            }
+        }
+
+        if let Some(line) = pending.pop_front() {
+            if let ClientType::User(user) = handler.client.get_client_type() {
+                if !is_keepalive(&line) {
+                    user.touch();
                 }
+            }
+            let result = error_wrapper(&handler.client, irc, &line).await;
+            handle_result(handler, irc, result).await?;
+        }
+    }
+    Ok(())
+}
+
+/* PING and PONG shouldn't stop a client from looking idle */
+fn is_keepalive(line: &str) -> bool {
+    let mut words = line.split(' ').filter(|word| !word.is_empty());
+    let mut command = words.next().unwrap_or("");
+    /* skip over tags and a prefix if there are any */
+    while command.starts_with('@') || command.starts_with(':') {
+        command = words.next().unwrap_or("");
+    }
+    command.eq_ignore_ascii_case("PING") || command.eq_ignore_ascii_case("PONG")
+}
+
+/* next line from the socket, unless something else has told us to drop
+ * the client (e.g. it went over its sendq while we weren't looking).
+ * lines over the length limit are dropped with ERR_INPUTTOOLONG, ones
+ * with a NUL in them are just dropped. a client that goes quiet for
+ * [limits] ping_interval gets PINGed, and dropped if it stays quiet.
+ * links have PINGs of their own */
+async fn read_line(handler: &mut ClientHandler) -> Result<Option<String>, GenError> {
+    let interval = handler.client.get_irc().get_config().limits.ping_interval;
+    loop {
+        let tags = handler.client.is_server() || handler.client.has_cap(cap::MESSAGE_TAGS);
+        let ping = interval > 0 && !handler.client.is_server();
+        let line = tokio::select! {
+            line = handler.stream.next_line(tags) => Some(line?),
+            reason = handler.client.killed() => return Err(GenError::Disconnect(reason)),
+            _ = time::sleep(Duration::from_secs(interval)), if ping => None,
+        };
+        let line = match line {
+            Some(line) => line,
+            None if handler.pinged => return Err(GenError::Disconnect(format!("Ping timeout: {} seconds", interval * 2))),
+            None => {
+                handler.pinged = true;
+                handler.client.send_line(&format!("PING :{}", handler.client.get_irc().get_host())).await?;
+                continue;
+            },
+        };
+        handler.pinged = false;
+        match line {
+            Some(Line::Line(line)) => return Ok(Some(line)),
+            Some(Line::TooLong) => {
+                debug!("client {} sent an overlong line", handler.id);
+                handler.client.send_err(ircError::InputTooLong).await?;
             },
+            Some(Line::Forbidden) => debug!("client {} sent a line with a NUL in it", handler.id),
+            None => return Ok(None),
+        }
+    }
+}
+
+async fn handle_result(handler: &ClientHandler, irc: &Arc<Core>, result: Result<ClientReplies, GenError>) -> Result<(), GenError> {
+    match relay_result(handler, irc, result).await {
+        /* whoever's over their sendq has already been told to disconnect,
+         * whether that's us or someone we were sending to, so carry on
+         * and let the read loop notice if it was us */
+        Err(GenError::SendQFull(id)) => {
+            debug!("client {} hit a full sendq on client {}", handler.id, id);
+            Ok(())
+        },
+        res => res,
+    }
+}
+
+/* relay replies and IRC errors back to the client, anything that
+ * means the connection is done for gets passed back up */
+async fn relay_result(handler: &ClientHandler, irc: &Arc<Core>, result: Result<ClientReplies, GenError>) -> Result<(), GenError> {
+    match result {
+        Err(GenError::IRC(err)) => flush(&handler.client, vec![Err(err)]).await?,
+        Err(GenError::Parse(err)) => flush(&handler.client, vec![Err(ircError::from(err))]).await?,
+        Err(GenError::Chan(_err)) => (), /* non-fatal, will figure out how to handle later */
+        Err(GenError::Account(err)) => warn!("client {}: account store error {}", handler.id, err),
+        Err(GenError::Io(err)) => return Err(GenError::Io(err)),
+        Err(GenError::Mpsc(err)) => return Err(GenError::Mpsc(err)),
+        Err(GenError::DeadClient(user)) => attempt_cleanup(irc, user).await,
+        Err(GenError::DeadUser(nick)) => {
+            let _res = irc.search_user_chans_purge(&nick).await;
+            if let Err(err) = irc.remove_name(&nick) {
+                warn!("received error {} trying to remove dead user {}", err, nick.to_string());
+            }
+        },
+        Err(GenError::Detached(_nick)) => (),
+        Err(GenError::Tokio(err)) => return Err(GenError::Tokio(err)),
+        Err(GenError::TLS(err)) => return Err(GenError::TLS(err)),
+        Err(GenError::WebSocket(err)) => return Err(GenError::WebSocket(err)),
+        Err(GenError::SendQFull(id)) => return Err(GenError::SendQFull(id)),
+        #[cfg(feature = "lua")]
+        Err(GenError::Lua(err)) => warn!("client {}: script error {}", handler.id, err),
+        Err(GenError::Disconnect(reason)) => {
+            handler.client.close(&reason);
+            return Err(GenError::Disconnect(reason));
+        },
+        Ok(replies) => flush(&handler.client, replies).await?,
+    }
+    Ok(())
+}
+
+/* everything a command has to say back to its client goes out through
+ * here, replies and IRC errors alike, in the order the handler gave them */
+async fn flush(client: &Client, replies: ClientReplies) -> Result<(), GenError> {
+    for result_t in replies {
+        match result_t {
+            Ok(reply) => client.send_rpl(reply).await?,
+            Err(err) => client.send_err(err).await?,
         }
     }
     Ok(())
@@ -322,12 +684,15 @@ async fn process_lines(handler: &mut ClientHandler, irc: &Arc<Core>) -> Result<(
  * and let's the caller process_lines() catch any errors, relaying parser or
  * IRC errors back to the client, or dropping the client on I/O error */
 async fn error_wrapper (client: &Arc<Client>, irc: &Arc<Core>, line: &str) -> Result<ClientReplies, GenError> {
+    if let ClientType::Server(link) = client.get_client_type() {
+        return link::command(irc, &link, line).await;
+    }
     let parsed = parse_message(line)?;
     irc::command(irc, client, parsed).await
 }
 
 /* found a stale user with no client */
-pub fn attempt_cleanup(irc: &Core, user: Arc<User>) {
+pub async fn attempt_cleanup(irc: &Core, user: Arc<User>) {
     let id = user.get_id();
     debug!("attempted cleanup of stale User, id {}", id);
 
@@ -353,7 +718,7 @@ pub fn attempt_cleanup(irc: &Core, user: Arc<User>) {
     }
 
     /* search for remaining references in channel lists */
-    let found = irc.search_user_chans_purge(&nick);
+    let found = irc.search_user_chans_purge(&nick).await;
     debug!("removed user {} from these channels: {}", nick, found.join(" "));
 
     /* also make sure the user's channel hashmap is also clear */
@@ -366,40 +731,91 @@ pub fn attempt_cleanup(irc: &Core, user: Arc<User>) {
 
 #[derive(Debug)]
 pub struct ClientHandler {
-    stream: Lines<BufReader<ReadHalfWrap>>,
+    stream: LineReader<ReadHalfWrap>,
     client: Arc<Client>,
+    flood: TokenBucket,
+    /* sent a PING for going quiet, and waiting to hear anything back */
+    pinged: bool,
     id: u64,
 }
 
 impl ClientHandler {
-    pub fn new(id: u64, host: Host, irc: &Arc<Core>, tx: MsgSendr, sock: ReadHalfWrap) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        id: u64,
+        host: Host,
+        ip: IpAddr,
+        privacy: bool,
+        certfp: Option<String>,
+        irc: &Arc<Core>,
+        tx: SendQueue,
+        sock: ReadHalfWrap,
+    ) -> Self {
+        let secure = sock.is_secure();
         ClientHandler {
-            stream: BufReader::new(sock).lines(),
-            client: Client::new(id, host, irc, tx),
+            stream: LineReader::new(sock),
+            client: Client::new(id, host, ip, secure, privacy, certfp, irc, tx),
+            flood: TokenBucket::new(irc.get_config().limits.flood_burst, irc.get_config().limits.flood_rate),
+            pinged: false,
             id,
         }
     }
 }
 
-type MsgSendr = mpsc::Sender<String>;
-
 #[derive(Debug)]
 pub struct Client {
     client_type: Mutex<ClientType>,
+    caps: Mutex<CapState>,
+    sasl: Mutex<SaslState>,
     id: u64,
-    host: Host,
+    host: Mutex<Host>,
+    ip: Mutex<IpAddr>,
+    gateway: Mutex<Option<String>>,
+    /* PASS <password> TS 6 :<sid> from a server, kept for SERVER */
+    link_pass: Mutex<Option<(String, String)>>,
+    /* connected to a TLS listener */
+    secure: bool,
+    /* connected to a privacy listener, where the IP is the same for
+     * everyone and the host is its cloak */
+    privacy: bool,
+    /* SHA-256 of their TLS client certificate */
+    certfp: Option<String>,
+    dnsbl: Mutex<Option<DnsblCheck>>,
+    /* our place in the unregistered connections limit, given up on registering */
+    pending: Mutex<Option<PendingSlot>>,
+    /* and in [limits] clients_per_ip once registered, held until we go */
+    ip_slot: Mutex<Option<PendingSlot>>,
     irc: Arc<Core>,
-    tx: MsgSendr,
+    tx: SendQueue,
+    kill: Notify,
+    kill_reason: Mutex<Option<String>>,
+    /* the connection's span from server.rs, for fields that are only
+     * known later and might be filled in from someone else's task */
+    span: Span,
 }
 
 impl Clone for Client {
     fn clone(&self) -> Self {
         Client {
             client_type: Mutex::new(self.client_type.lock().unwrap().clone()),
+            caps: Mutex::new(self.caps.lock().unwrap().clone()),
+            sasl: Mutex::new(self.sasl.lock().unwrap().clone()),
             id: self.id,
-            host: self.host.clone(),
+            host: Mutex::new(self.get_host()),
+            ip: Mutex::new(self.get_ip()),
+            gateway: Mutex::new(self.get_gateway()),
+            link_pass: Mutex::new(self.link_pass.lock().unwrap().clone()),
+            secure: self.secure,
+            privacy: self.privacy,
+            certfp: self.certfp.clone(),
+            dnsbl: Mutex::new(None), /* can't share a pending lookup */
+            pending: Mutex::new(None),
+            ip_slot: Mutex::new(None),
             irc: Arc::clone(&self.irc),
             tx: self.tx.clone(),
+            kill: Notify::new(),
+            kill_reason: Mutex::new(self.kill_reason.lock().unwrap().clone()),
+            span: self.span.clone(),
         }
     }
 }
@@ -412,42 +828,140 @@ impl Drop for Client {
 }
 
 impl Client {
-    pub fn new(id: u64, host: Host, irc: &Arc<Core>, tx: MsgSendr) -> Arc<Self> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        id: u64,
+        host: Host,
+        ip: IpAddr,
+        secure: bool,
+        privacy: bool,
+        certfp: Option<String>,
+        irc: &Arc<Core>,
+        tx: SendQueue,
+    ) -> Arc<Self> {
         Arc::new(Client {
             client_type: Mutex::new(ClientType::Unregistered),
+            caps: Mutex::new(CapState::default()),
+            sasl: Mutex::new(SaslState::default()),
             id,
-            host,
+            host: Mutex::new(host),
+            ip: Mutex::new(ip),
+            gateway: Mutex::new(None),
+            link_pass: Mutex::new(None),
+            secure,
+            privacy,
+            certfp,
+            dnsbl: Mutex::new(None),
+            pending: Mutex::new(None),
+            ip_slot: Mutex::new(None),
             irc: Arc::clone(irc),
             tx,
+            kill: Notify::new(),
+            kill_reason: Mutex::new(None),
+            /* we're made by the handler running inside it */
+            span: Span::current(),
         })
     }
 
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    pub fn is_privacy(&self) -> bool {
+        self.privacy
+    }
+
+    pub fn get_span(&self) -> &Span {
+        &self.span
+    }
+
+    pub fn get_certfp(&self) -> Option<String> {
+        self.certfp.clone()
+    }
+
+    pub fn get_link_pass(&self) -> Option<(String, String)> {
+        self.link_pass.lock().unwrap().clone()
+    }
+
+    pub fn set_link_pass(&self, password: String, sid: String) {
+        *self.link_pass.lock().unwrap() = Some((password, sid));
+    }
+
     // don't call this unless is_registered returns true
-    pub fn get_user(&self) -> Arc<User> {
+    /* None for anything that isn't a registered user, which includes
+     * one that's gone Dead since someone last checked */
+    pub fn get_user(&self) -> Option<Arc<User>> {
         match self.get_client_type() {
-            ClientType::User(u_ptr) => Arc::clone(&u_ptr),
-            _ => panic!("impossible"),
+            ClientType::User(u_ptr) => Some(u_ptr),
+            _ => None,
         }
     }
 
-    pub fn get_host(&self) -> &Host {
-        &self.host
+    pub fn get_host(&self) -> Host {
+        self.host.lock().unwrap().clone()
+    }
+
+    /* the address the connection came from, or the real user's
+     * address as passed on by a WEBIRC gateway */
+    pub fn get_ip(&self) -> IpAddr {
+        *self.ip.lock().unwrap()
+    }
+
+    pub fn get_gateway(&self) -> Option<String> {
+        self.gateway.lock().unwrap().clone()
+    }
+
+    /* called by WEBIRC, swaps the gateway's address for the user's */
+    pub fn set_gateway(&self, gateway: &str, host: Host, ip: IpAddr) {
+        *self.gateway.lock().unwrap() = Some(gateway.to_string());
+        *self.host.lock().unwrap() = host;
+        *self.ip.lock().unwrap() = ip;
+    }
+
+    pub fn is_server(&self) -> bool {
+        matches!(*self.client_type.lock().unwrap(), ClientType::Server(_))
+    }
+
+    pub fn is_oper(&self) -> bool {
+        match self.get_client_type() {
+            ClientType::User(user) => user.is_oper(),
+            _ => false,
+        }
     }
 
     pub fn is_registered(&self) -> bool {
         match self.get_client_type() {
             ClientType::Dead => false,
             ClientType::User(_p) => true,
+            ClientType::Server(_link) => true,
             ClientType::ProtoUser(_p) => false,
             ClientType::Unregistered => false,
         }
     }
 
     pub fn get_host_string(&self) -> String {
-        match &self.host {
-            Host::Hostname(name) => name.to_string(),
-            Host::HostAddr(ip_addr) => ip_addr.to_string(),
-        }
+        self.host.lock().unwrap().to_string()
+    }
+
+    /* replacing a pending lookup just means its answer gets ignored */
+    pub fn set_dnsbl_check(&self, check: DnsblCheck) {
+        *self.dnsbl.lock().unwrap() = Some(check);
+    }
+
+    pub fn take_dnsbl_check(&self) -> Option<DnsblCheck> {
+        self.dnsbl.lock().unwrap().take()
+    }
+
+    pub fn set_pending_slot(&self, slot: PendingSlot) {
+        *self.pending.lock().unwrap() = Some(slot);
+    }
+
+    pub fn release_pending_slot(&self) {
+        self.pending.lock().unwrap().take();
+    }
+
+    pub fn set_ip_slot(&self, slot: PendingSlot) {
+        *self.ip_slot.lock().unwrap() = Some(slot);
     }
 
     pub fn get_client_type(&self) -> ClientType {
@@ -463,12 +977,42 @@ impl Client {
         self.id
     }
 
+    pub fn get_caps(&self) -> CapState {
+        self.caps.lock().unwrap().clone()
+    }
+
+    pub fn update_caps<F: FnOnce(&mut CapState)>(&self, update: F) {
+        update(&mut self.caps.lock().unwrap());
+    }
+
+    pub fn get_sasl(&self) -> SaslState {
+        self.sasl.lock().unwrap().clone()
+    }
+
+    pub fn update_sasl<F: FnOnce(&mut SaslState)>(&self, update: F) {
+        update(&mut self.sasl.lock().unwrap());
+    }
+
+    pub fn has_cap(&self, cap: &str) -> bool {
+        self.caps.lock().unwrap().has(cap)
+    }
+
+    /* what to put in the target slot of a reply before we know who
+     * we're talking to, replies to unregistered clients use `*` */
+    pub fn get_nick_or_star(&self) -> String {
+        match self.get_client_type() {
+            ClientType::User(user) => user.get_nick(),
+            ClientType::ProtoUser(proto_user) => proto_user.lock().unwrap().get_nick().unwrap_or_else(|| "*".to_string()),
+            _ => "*".to_string(),
+        }
+    }
+
     pub fn get_irc(&self) -> &Arc<Core> {
         &self.irc
     }
 
     pub async fn send_err(&self, err: ircError) -> Result<(), GenError> {
-        let line = format!(":{} {}", self.irc.get_host(), err);
+        let line = err.format(&self.irc.get_host(), &self.get_nick_or_star(), &self.irc.get_templates());
         /* passing to an async fn and awaiting on it is gonna
          * cause lifetime problems with a &str... */
         self.send_line(&line).await?;
@@ -476,38 +1020,149 @@ impl Client {
     }
     
     pub async fn send_rpl(&self, reply: ircReply) -> Result<(), GenError> { /* GDB+ */
-        /* passing to an async fn and awaiting on it is gonna
-         * cause lifetime problems with a &str... */
-        let mut line = reply.format(&self.irc.get_host(), &self.get_user().get_nick());
-        /* break up long messages if neccessary,
-         * reply::split essentially returns line, None when
-         * line is not larger than MAX_MSG_SIZE */
-        loop {
-            let (trim, rest_opt) = reply::split(&line);
-            self.send_line(&trim).await?;
-            if let Some(rest) = rest_opt {
-                line = rest;
-            } else {
-                break;
-            }
+        for line in reply.lines(&self.irc.get_host(), &self.get_nick_or_star(), &self.irc.get_templates()) {
+            self.send_line(&line).await?;
         }
         Ok(())
     }
 
-    pub async fn send_line(&self, line: &str) -> Result<(), mpscSendErr<String>> {
-        let mut string = String::from(line);
-        string.push_str("\r\n");
-        /* thankfully mpsc::Sender has its own .clone()
-         * method, so we don't have to worry about our own
-         * Arc/Mutex wrapping, or the problems of holding
-         * a mutex across an await */
-        self.tx.clone().send(string).await
+    /* never blocks, if the client isn't keeping up and goes over its
+     * sendq it gets disconnected and further lines are dropped */
+    pub async fn send_line(&self, line: &str) -> Result<(), GenError> {
+        self.send_shared(&shared_line(line)).await
+    }
+
+    /* as send_line(), for a line from shared_line(). never waits on a
+     * slow reader, once they're over the sendq they get dropped and
+     * anything else sent their way is SendQFull. a server link only
+     * gets what the link code sends it with send_raw(), anything meant
+     * for one of its users is dropped here since it's in the wrong
+     * protocol, the link passes on what it needs to by itself */
+    pub async fn send_shared(&self, line: &Arc<str>) -> Result<(), GenError> {
+        self.queue_shared(line)
+    }
+
+    /* send_shared() for callers that mustn't yield, like a channel task
+     * going through its outbox */
+    pub fn queue_shared(&self, line: &Arc<str>) -> Result<(), GenError> {
+        if self.is_server() {
+            return Ok(());
+        }
+        self.send_raw(line)
+    }
+
+    /* a channel message, which a slow client might not get */
+    pub fn send_chatter(&self, line: &Arc<str>) -> Result<(), GenError> {
+        /* dropped here rather than by the write task, which is stuck
+         * behind the stalled socket and would let the queue fill anyway */
+        if self.is_server() || self.tx.state.shedding.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.enqueue(Outgoing::Chatter(Arc::clone(line)), line.len())
+    }
+
+    pub fn send_raw(&self, line: &Arc<str>) -> Result<(), GenError> {
+        self.enqueue(Outgoing::Line(Arc::clone(line)), line.len())
+    }
+
+    fn enqueue(&self, out: Outgoing, len: usize) -> Result<(), GenError> {
+        let state = &self.tx.state;
+        if state.overflowed.load(Ordering::SeqCst) {
+            return Err(GenError::SendQFull(self.id));
+        }
+        let limits = &self.irc.get_config().limits;
+        if matches!(out, Outgoing::Chatter(_)) && Memory::over(state.memory.queued(), len, limits.total_sendq) {
+            /* everyone's queues together are over total_sendq, channel
+             * messages wait until the backlog's gone down */
+            debug!("over total_sendq, channel message to client {} dropped", self.id);
+            return Ok(());
+        }
+        state.memory.queue(len);
+        let queued = state.bytes.fetch_add(len, Ordering::SeqCst) + len;
+        if limits.sendq > 0 && queued > limits.sendq {
+            state.take(len);
+            state.overflowed.store(true, Ordering::SeqCst);
+            debug!("client {} went over its sendq with {} bytes queued", self.id, queued);
+            self.disconnect("SendQ exceeded");
+            return Err(GenError::SendQFull(self.id));
+        }
+        if limits.slow_bytes > 0 {
+            self.check_slow(queued, limits)?;
+        }
+        self.tx.tx.send(out).map_err(|mpscSendErr(out)| match out {
+            Outgoing::Line(line) | Outgoing::Chatter(line) => GenError::Mpsc(mpscSendErr(line.to_string())),
+            Outgoing::Close(line) => GenError::Mpsc(mpscSendErr(line)),
+        })
+    }
+
+    /* [limits] slow_policy for a client that's been over slow_bytes for
+     * slow_after seconds. shedding stops once it's back under */
+    fn check_slow(&self, queued: usize, limits: &LimitsConfig) -> Result<(), GenError> {
+        let state = &self.tx.state;
+        let mut slow_since = state.slow_since.lock().unwrap();
+        if queued <= limits.slow_bytes {
+            *slow_since = None;
+            state.shedding.store(false, Ordering::SeqCst);
+            return Ok(());
+        }
+        let since = *slow_since.get_or_insert_with(Instant::now);
+        if since.elapsed() < Duration::from_secs(limits.slow_after) {
+            return Ok(());
+        }
+        match limits.slow_policy {
+            SlowPolicy::DropChatter => {
+                state.shed_until.store(limits.slow_bytes, Ordering::SeqCst);
+                if !state.shedding.swap(true, Ordering::SeqCst) {
+                    debug!("client {} is slow with {} bytes queued, dropping channel messages", self.id, queued);
+                }
+                Ok(())
+            },
+            SlowPolicy::Disconnect => {
+                state.overflowed.store(true, Ordering::SeqCst);
+                debug!("client {} is slow with {} bytes queued, disconnecting", self.id, queued);
+                self.disconnect("Slow client");
+                Err(GenError::SendQFull(self.id))
+            },
+        }
+    }
+
+    /* bytes queued and not yet written, for CHECK */
+    pub fn get_sendq(&self) -> usize {
+        self.tx.state.bytes.load(Ordering::SeqCst)
+    }
+
+    /* ask the client's read loop to drop it, safe to call from anywhere */
+    pub fn disconnect(&self, reason: &str) {
+        let mut kill_reason = self.kill_reason.lock().unwrap();
+        if kill_reason.is_none() {
+            *kill_reason = Some(reason.to_string());
+            self.kill.notify_one();
+        }
+    }
+
+    /* resolves once disconnect() has been called */
+    pub async fn killed(&self) -> String {
+        self.kill.notified().await;
+        self.kill_reason.lock().unwrap().clone().unwrap_or_default()
+    }
+
+    /* last line the client gets, bypasses the sendq limit */
+    pub fn close(&self, reason: &str) {
+        let line = format!("ERROR :Closing link: {}\r\n", reason);
+        if self.tx.tx.send(Outgoing::Close(line)).is_err() {
+            debug!("client {} write task already gone", self.id);
+        }
     }
 }
 
+/* terminates a line for the wire, ready for send_shared() */
+pub fn shared_line(line: &str) -> Arc<str> {
+    let mut string = String::with_capacity(line.len() + 2);
+    string.push_str(line);
+    string.push_str("\r\n");
+    Arc::from(string)
+}
+
 pub fn create_host_string(host_var: &Host) -> String {
-    match host_var {
-        Host::Hostname(hostname_str) => hostname_str.to_string(),
-        Host::HostAddr(ip_addr) => ip_addr.to_string(),
-    }
+    host_var.to_string()
 }
\ No newline at end of file
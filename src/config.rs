@@ -0,0 +1,961 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+extern crate serde;
+extern crate toml;
+use crate::irc::alias;
+use crate::irc::command::Command;
+use crate::irc::rfc_defs as rfc;
+use crate::irc::templates;
+use crate::password;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io::Error as ioError;
+use std::net::{IpAddr, Ipv6Addr};
+
+pub const DEFAULT_CONFIG_PATH: &str = "ircd.toml";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(ioError),
+    Toml(toml::de::Error),
+    OperHash(String),
+    WebircHash(String),
+    ServerName(String),
+    Cloak(String),
+    Spamfilter(String, regex::Error),
+    LinkHash(String),
+    Sid(String),
+    Allow(String),
+    Reply(String),
+    Alias(String),
+    OperCloak(String),
+    TrustedHost(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(ref err) => write!(f, "couldn't read config: {}", err),
+            ConfigError::Toml(ref err) => write!(f, "couldn't parse config: {}", err),
+            ConfigError::OperHash(name) => write!(f, "oper block {} needs an argon2id password_hash (see --mkpasswd)", name),
+            ConfigError::WebircHash(name) => write!(f, "webirc block {} needs an argon2id password_hash (see --mkpasswd)", name),
+            ConfigError::ServerName(name) => write!(f, "server name {} isn't a valid hostname", name),
+            ConfigError::Cloak(cloak) => write!(f, "listener cloak {} isn't a valid hostname", cloak),
+            ConfigError::Spamfilter(regex, err) => write!(f, "spamfilter {} isn't a valid regex: {}", regex, err),
+            ConfigError::LinkHash(name) => write!(f, "link block {} needs an argon2id password_hash (see --mkpasswd)", name),
+            ConfigError::Sid(sid) => write!(f, "sid {} should be a digit followed by two letters or digits", sid),
+            ConfigError::Allow(range) => write!(f, "listener allow {} should be an address or address/prefix", range),
+            ConfigError::Reply(key) => write!(f, "replies {} should be a three digit numeric with its text on one line", key),
+            ConfigError::OperCloak(cloak) => write!(f, "oper cloak {} isn't a valid hostname", cloak),
+            ConfigError::Alias(name) => write!(f, "alias {} should be a command line that isn't already a command", name),
+            ConfigError::TrustedHost(host) => write!(f, "webirc and link hosts {} should be an address or address/prefix, not a hostname", host),
+        }
+    }
+}
+
+impl error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ConfigError::Io(ref err) => Some(err),
+            ConfigError::Toml(ref err) => Some(err),
+            ConfigError::Spamfilter(_regex, ref err) => Some(err),
+            ConfigError::OperHash(_name) | ConfigError::WebircHash(_name) | ConfigError::ServerName(_name) | ConfigError::Cloak(_name)
+                | ConfigError::LinkHash(_name) | ConfigError::Sid(_name) | ConfigError::Allow(_name)
+                | ConfigError::Reply(_name) | ConfigError::Alias(_name) | ConfigError::OperCloak(_name)
+                | ConfigError::TrustedHost(_name) => None,
+        }
+    }
+}
+
+impl From<ioError> for ConfigError {
+    fn from(err: ioError) -> ConfigError {
+        ConfigError::Io(err)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(err: toml::de::Error) -> ConfigError {
+        ConfigError::Toml(err)
+    }
+}
+
+/* top level of the config file, every section is optional
+ * and falls back to the defaults given below */
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /* where load() read us from, so REHASH knows where to look */
+    #[serde(skip)]
+    pub path: Option<String>,
+    pub server: ServerConfig,
+    pub listen: Vec<ListenBlock>,
+    pub oper: Vec<OperBlock>,
+    pub webirc: Vec<WebircBlock>,
+    pub dnsbl: Vec<DnsblBlock>,
+    pub resv: Vec<ResvBlock>,
+    pub spamfilter: Vec<SpamfilterBlock>,
+    pub censor: CensorConfig,
+    pub audit: AuditConfig,
+    pub chanlog: ChanlogConfig,
+    pub connlog: ConnlogConfig,
+    pub admin: AdminConfig,
+    pub logging: LoggingConfig,
+    pub daemon: DaemonConfig,
+    pub runtime: RuntimeConfig,
+    pub dns: DnsConfig,
+    pub tls: TlsConfig,
+    pub limits: LimitsConfig,
+    pub services: ServicesConfig,
+    pub webhooks: WebhookConfig,
+    pub link: Option<LinkBlock>,
+    pub lua: LuaConfig,
+    /* [replies], numeric to the text it ends with, see irc::templates */
+    pub replies: BTreeMap<String, String>,
+    /* [aliases], a command of our own to the line it stands for, see
+     * irc::alias */
+    pub aliases: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /* the name we go by in replies, the machine's hostname if not set */
+    pub name: Option<String>,
+    /* reply to a CTCP VERSION sent to the server's name */
+    pub ctcp_version: bool,
+    /* what happens to our user when they lose a nick collision */
+    pub collision: CollisionAction,
+    /* give channels left without ops some back, see the example config */
+    pub op_restore: bool,
+    /* how channel messages get to the members, see chan.rs */
+    pub fanout: Fanout,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Fanout {
+    /* the channel's task queues each line for every member itself */
+    #[default]
+    Outbox,
+    /* PRIVMSG/NOTICE/TAGMSG to the whole channel go out on a
+     * tokio::sync::broadcast, and each member has a task of its own
+     * queueing them, in order with the rest, see chan.rs. only read
+     * when a channel is created */
+    Broadcast,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CollisionAction {
+    /* disconnect them */
+    Kill,
+    /* move them to a nick nobody can ask for */
+    #[default]
+    Rename,
+}
+
+/* [[listen]] blocks, one per address and port. with none at all we
+ * listen on 6667 and 6697 (TLS) on :: which takes IPv4 as well */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ListenBlock {
+    pub address: IpAddr,
+    pub port: u16,
+    pub tls: bool,
+    /* IRCv3 WebSocket (https://ircv3.net/specs/extensions/websocket)
+     * instead of plain lines, for browser clients */
+    pub websocket: bool,
+    /* websocket listeners: the Origins (like https://webchat.example.org)
+     * whose pages can connect here. empty means any, otherwise a client
+     * that doesn't send one of them, or no Origin at all, is turned away */
+    pub origins: Vec<String>,
+    /* for hidden services and the like: no reverse DNS or DNSBL lookups,
+     * everyone gets `cloak` for a host since the address is only ever
+     * the local tor daemon anyway */
+    pub privacy: bool,
+    pub cloak: String,
+    /* false to skip the reverse lookup here and just show the IP */
+    pub rdns: bool,
+    /* tls listeners: ask for a client certificate (but don't insist on
+     * one) so there's a certfp for SASL EXTERNAL and NickServ CERT */
+    pub client_certs: bool,
+    /* addresses or address/prefix ranges allowed to connect here, for a
+     * plaintext port only localhost or a gateway should use. empty means
+     * anyone */
+    pub allow: Vec<String>,
+    /* connections open here at once, from anywhere, 0 for no limit */
+    pub max_connections: usize,
+}
+
+impl Default for ListenBlock {
+    fn default() -> Self {
+        ListenBlock {
+            address: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            port: 6667,
+            tls: false,
+            websocket: false,
+            origins: Vec::new(),
+            privacy: false,
+            cloak: String::from("hidden.onion"),
+            rdns: true,
+            client_certs: false,
+            allow: Vec::new(),
+            max_connections: 1024,
+        }
+    }
+}
+
+impl ListenBlock {
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        self.allow.is_empty() || in_ranges(&self.allow, ip)
+    }
+}
+
+/* ip is in one of a list of ranges, see parse_range() */
+pub fn in_ranges(ranges: &[String], ip: IpAddr) -> bool {
+    ranges.iter().filter_map(|range| parse_range(range)).any(|range| in_range(range, ip))
+}
+
+/* 192.0.2.1, 2001:db8::/32 and so on, no prefix means just the one */
+pub fn parse_range(range: &str) -> Option<(IpAddr, u8)> {
+    let (addr, prefix) = match range.split_once('/') {
+        Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+        None => (range.parse::<IpAddr>().ok()?, None),
+    };
+    let bits = if addr.is_ipv4() { 32 } else { 128 };
+    match prefix {
+        Some(prefix) if prefix > bits => None,
+        Some(prefix) => Some((addr, prefix)),
+        None => Some((addr, bits)),
+    }
+}
+
+fn in_range((net, prefix): (IpAddr, u8), ip: IpAddr) -> bool {
+    match (net, ip) {
+        (IpAddr::V4(net), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            u32::from(net) & mask == u32::from(ip) & mask
+        },
+        (IpAddr::V6(net), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            u128::from(net) & mask == u128::from(ip) & mask
+        },
+        _ => false,
+    }
+}
+
+/* [[oper]] blocks, checked by the OPER command. only the argon2id
+ * hash of the password goes in the config, never the password itself.
+ * hosts are user@host masks the oper has to be connecting from, none
+ * at all means anywhere. cloak, if set, is the host they're shown with
+ * once they've opered up */
+#[derive(Debug, Clone, Deserialize)]
+pub struct OperBlock {
+    pub name: String,
+    pub password_hash: String,
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    #[serde(default)]
+    pub cloak: Option<String>,
+}
+
+/* [[webirc]] blocks for trusted web gateways, which are allowed to pass
+ * on the real user's address. hosts lists the gateway's own addresses or
+ * address/prefix ranges the WEBIRC command will be accepted from, never
+ * hostnames, since whoever has the IP decides what its PTR says */
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebircBlock {
+    pub name: String,
+    pub password_hash: String,
+    pub hosts: Vec<String>,
+}
+
+/* [[dnsbl]] blocks, new connections are looked up in each zone.
+ * actions are ordered from least to most severe, the worst hit wins */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DnsblAction {
+    Mark,
+    RequireSasl,
+    Reject,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DnsblBlock {
+    pub zone: String,
+    pub action: DnsblAction,
+    pub reason: Option<String>,
+}
+
+/* [[resv]] blocks, nick or channel masks (* and ? wildcards)
+ * only opers may use. more can be added at runtime with RESV */
+#[derive(Debug, Clone, Deserialize)]
+pub struct ResvBlock {
+    pub mask: String,
+    pub reason: Option<String>,
+}
+
+/* [[spamfilter]] blocks, a regex checked against what users send.
+ * the text checked is the message for privmsg/notice/part/quit and
+ * the new nick for nick. opers are never filtered */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SpamTarget {
+    Privmsg,
+    Notice,
+    Part,
+    Quit,
+    Nick,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SpamAction {
+    /* drop it and tell the sender */
+    Block,
+    /* disconnect them */
+    Kill,
+    /* disconnect them and keep their user@host out for `duration` */
+    Kline,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpamfilterBlock {
+    pub regex: String,
+    #[serde(default = "default_spam_targets")]
+    pub targets: Vec<SpamTarget>,
+    #[serde(default = "default_spam_action")]
+    pub action: SpamAction,
+    pub reason: Option<String>,
+    /* seconds, for kline */
+    #[serde(default = "default_spam_duration")]
+    pub duration: u64,
+}
+
+fn default_spam_targets() -> Vec<SpamTarget> {
+    vec![SpamTarget::Privmsg, SpamTarget::Notice]
+}
+
+fn default_spam_action() -> SpamAction {
+    SpamAction::Block
+}
+
+fn default_spam_duration() -> u64 {
+    3600
+}
+
+/* [censor], words channel mode +G won't let through. matched as whole
+ * words, ignoring case. picked up again on REHASH */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CensorAction {
+    /* star the words out and send the rest */
+    Replace,
+    /* refuse the whole message */
+    Block,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CensorConfig {
+    pub words: Vec<String>,
+    pub action: CensorAction,
+}
+
+impl Default for CensorConfig {
+    fn default() -> Self {
+        CensorConfig {
+            words: Vec::new(),
+            action: CensorAction::Replace,
+        }
+    }
+}
+
+/* [audit], the log of op actions each channel keeps for AUDIT. 0 entries
+ * turns it off, with a file everything is appended there as well */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AuditConfig {
+    pub entries: usize,
+    pub file: Option<String>,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            entries: 50,
+            file: None,
+        }
+    }
+}
+
+/* [chanlog], channels (masks) whose goings on get written to disk
+ * under directory. timestamp is a strftime format for each line */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ChanlogConfig {
+    pub channels: Vec<String>,
+    pub directory: String,
+    pub rotate: ChanlogRotate,
+    pub timestamp: String,
+}
+
+impl Default for ChanlogConfig {
+    fn default() -> Self {
+        ChanlogConfig {
+            channels: Vec::new(),
+            directory: String::from("chanlogs"),
+            rotate: ChanlogRotate::default(),
+            timestamp: String::from("%H:%M:%S"),
+        }
+    }
+}
+
+/* how often a channel starts on a new file */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChanlogRotate {
+    #[default]
+    Daily,
+    Monthly,
+    Never,
+}
+
+/* [connlog], a JSON line per connection event for abuse investigations */
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ConnlogConfig {
+    pub file: Option<String>,
+}
+
+/* what ADMIN tells people, leave it all out and they get ERR_NOADMININFO */
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AdminConfig {
+    pub location: Option<String>,
+    pub description: Option<String>,
+    pub email: Option<String>,
+}
+
+impl AdminConfig {
+    pub fn is_empty(&self) -> bool {
+        self.location.is_none() && self.description.is_none() && self.email.is_none()
+    }
+}
+
+/* which log lines get through is still down to RUST_LOG */
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/* when a log file gets moved aside for a fresh one */
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogRotate {
+    Never,
+    Size,
+    Daily,
+}
+
+/* file is written to as well as stderr, not instead of it */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    pub file: Option<String>,
+    pub rotate: LogRotate,
+    pub max_size_kb: u64,
+    /* how many old files hang around before the oldest is deleted */
+    pub keep: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            format: LogFormat::Text,
+            file: None,
+            rotate: LogRotate::Never,
+            max_size_kb: 10240,
+            keep: 7,
+        }
+    }
+}
+
+/* [daemon], for init systems that expect the server to go into the
+ * background by itself. once detached, log output goes to log_file
+ * or nowhere at all. the pidfile is written either way */
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct DaemonConfig {
+    pub background: bool,
+    pub pidfile: Option<String>,
+    pub log_file: Option<String>,
+}
+
+/* tokio's thread pools, 0 leaves it to tokio: a worker thread per CPU
+ * and up to 512 threads for blocking work (the account store).
+ * only read at startup, REHASH won't change them */
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RuntimeConfig {
+    pub worker_threads: usize,
+    pub max_blocking_threads: usize,
+}
+
+/* reverse lookups on connecting clients. no nameservers means use the
+ * ones in /etc/resolv.conf, rdns = false turns lookups off everywhere */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DnsConfig {
+    pub rdns: bool,
+    /* only use a PTR name if it resolves back to the client's address */
+    pub fcrdns: bool,
+    pub nameservers: Vec<IpAddr>,
+    pub timeout_ms: u64,
+    pub max_lookups: usize,
+    /* seconds an answer is remembered for and how many are kept, 0 for no cache */
+    pub cache_ttl: u64,
+    pub cache_size: usize,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        DnsConfig {
+            rdns: true,
+            fcrdns: true,
+            nameservers: Vec::new(),
+            timeout_ms: 3000,
+            max_lookups: 64,
+            cache_ttl: 300,
+            cache_size: 4096,
+        }
+    }
+}
+
+/* certificate for the TLS listeners, which files get used depends on
+ * the TLS backend the server was built with */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /* PEM certificate chain and unencrypted private key, as ACME clients
+     * write them out. native-tls wants the key in PKCS#8 form */
+    pub cert: String,
+    pub key: String,
+    /* native-tls only: a PKCS#12 identity and its password, used
+     * instead of cert and key if it's set */
+    pub identity: Option<String>,
+    pub password: String,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            cert: String::from("cert.pem"),
+            key: String::from("key.pem"),
+            identity: None,
+            password: String::new(),
+        }
+    }
+}
+
+/* flood and abuse limits, a max of 0 means unlimited */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /* connections allowed from one IP (or IPv6 /64) per connect_window seconds */
+    pub connect_max: usize,
+    pub connect_window: u64,
+    /* command flood control, a client can send flood_burst lines at once
+     * and then flood_rate lines per second, with up to flood_queue lines
+     * held back before they're disconnected for Excess Flood */
+    pub flood_burst: f64,
+    pub flood_rate: f64,
+    pub flood_queue: usize,
+    pub flood_exempt_opers: bool,
+    /* bytes queued for a client before it's dropped with SendQ exceeded,
+     * and bytes of unprocessed input before RecvQ exceeded */
+    pub sendq: usize,
+    pub recvq: usize,
+    /* a client with more than slow_bytes queued for slow_after seconds
+     * is slow, and slow_policy says what happens. 0 bytes for never */
+    pub slow_bytes: usize,
+    pub slow_after: u64,
+    pub slow_policy: SlowPolicy,
+    /* bytes waiting in every client's send queue put together, past
+     * which channel messages aren't queued. 0 for no limit */
+    pub total_sendq: usize,
+    /* bytes kept for all the detached always_on sessions put together,
+     * past which each session's oldest lines make room. 0 for no limit */
+    pub total_backlog: usize,
+    /* JOINs and nick changes allowed per user in their window (seconds),
+     * flood_exempt_opers covers these too */
+    pub join_max: usize,
+    pub join_window: u64,
+    pub nick_max: usize,
+    pub nick_window: u64,
+    /* CTCPs (other than ACTION) a user can send per ctcp_window seconds */
+    pub ctcp_max: usize,
+    pub ctcp_window: u64,
+    /* +typing notifications a user can send per typing_window seconds,
+     * any more have the tag dropped rather than the message */
+    pub typing_max: usize,
+    pub typing_window: u64,
+    /* channels a user can be in at once, advertised as CHANLIMIT */
+    pub max_channels: usize,
+    /* seconds to send NICK and USER in, and how many connections from one
+     * IP (or /64) can be sat there without having done so, 0 for no limit */
+    pub registration_timeout: u64,
+    pub unregistered_max: usize,
+    /* seconds of silence before a client gets a PING, and as long again
+     * to say anything before it's dropped. 0 for never */
+    pub ping_interval: u64,
+    /* registered clients from one IP (or /64) at once, 0 for no limit.
+     * addresses matching a mask in the exempt list aren't counted */
+    pub clients_per_ip: usize,
+    pub clients_per_ip_exempt: Vec<String>,
+    /* seconds given to get the goodbye out to everyone on shutdown */
+    pub shutdown_grace: u64,
+    /* comma separated targets allowed per command, advertised as TARGMAX.
+     * commands not listed (or 0) have no limit */
+    pub targmax: BTreeMap<String, usize>,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            connect_max: 10,
+            connect_window: 60,
+            flood_burst: 10.0,
+            flood_rate: 1.0,
+            flood_queue: 20,
+            flood_exempt_opers: true,
+            sendq: 1048576,
+            recvq: 8192,
+            slow_bytes: 0,
+            slow_after: 30,
+            slow_policy: SlowPolicy::default(),
+            total_sendq: 0,
+            total_backlog: 0,
+            join_max: 5,
+            join_window: 30,
+            nick_max: 3,
+            nick_window: 30,
+            ctcp_max: 5,
+            ctcp_window: 10,
+            typing_max: 2,
+            typing_window: 6,
+            max_channels: 20,
+            registration_timeout: 60,
+            ping_interval: 120,
+            unregistered_max: 5,
+            clients_per_ip: 10,
+            clients_per_ip_exempt: Vec::new(),
+            shutdown_grace: 5,
+            targmax: [("JOIN", 10), ("NAMES", 10), ("NOTICE", 4), ("PART", 10), ("PRIVMSG", 4), ("TAGMSG", 4), ("WHOIS", 4)]
+                .iter()
+                .map(|(cmd, max)| (cmd.to_string(), *max))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SlowPolicy {
+    /* skip the channel messages waiting in the queue, oldest first,
+     * until it's back under slow_bytes. numerics, private messages and
+     * everything else still get through */
+    #[default]
+    DropChatter,
+    /* disconnect them as though they'd gone over the sendq */
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServicesConfig {
+    /* account database used by NickServ, SASL and HostServ,
+     * ignored when built without the sqlite feature */
+    pub database: String,
+    /* offer SASL, REHASH can turn it on and off */
+    pub sasl: bool,
+    /* nicks that are account names are only for their account,
+     * and logging in puts you on your account's nick */
+    pub enforce_nick: bool,
+    /* a SASL login to an account that's already on attaches to that
+     * session instead of being a user of its own, like a bouncer */
+    pub multiclient: bool,
+    /* with multiclient, a logged in user stays on (nick, channels and
+     * all) when its last connection goes, and what's sent to it in the
+     * meantime is kept for the next client to attach */
+    pub always_on: bool,
+    /* lines kept for a detached session, the oldest go first */
+    pub always_on_backlog: usize,
+}
+
+impl Default for ServicesConfig {
+    fn default() -> Self {
+        ServicesConfig {
+            database: String::from("accounts.db"),
+            sasl: true,
+            enforce_nick: false,
+            multiclient: false,
+            always_on: false,
+            always_on_backlog: 500,
+        }
+    }
+}
+
+/* [webhooks], an HTTP POST to an account's webhook (set with NickServ
+ * WEBHOOK) when it's PM'd or mentioned with nobody connected */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WebhookConfig {
+    pub enabled: bool,
+    /* at most max pushes per account every window seconds */
+    pub max: usize,
+    pub window: u64,
+    /* failed pushes are tried again retries times, retry_delay seconds
+     * apart the first time and a bit longer every time after */
+    pub retries: u32,
+    pub retry_delay: u64,
+    /* seconds to connect and get an answer */
+    pub timeout: u64,
+    /* https:// only unless this is on */
+    pub allow_http: bool,
+    /* hostname masks webhooks may go to, any public address if empty.
+     * hosts listed here are trusted even if they resolve to loopback or
+     * private addresses, which are refused otherwise */
+    pub hosts: Vec<String>,
+    /* PEM roots for checking https:// servers, only read by the rustls
+     * backend, native-tls goes by the platform's */
+    pub ca_file: String,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            enabled: false,
+            max: 10,
+            window: 60,
+            retries: 3,
+            retry_delay: 30,
+            timeout: 10,
+            allow_http: false,
+            hosts: Vec::new(),
+            ca_file: String::from("/etc/ssl/certs/ca-certificates.crt"),
+        }
+    }
+}
+
+/* [link], an external services package (Atheme and the like) connecting
+ * in over TS6 from one of hosts (addresses or address/prefix ranges, like
+ * webirc). password_hash checks the PASS they send, send_password is
+ * what we send back. sid is our own server ID on the link. with a link
+ * block the built-in NickServ and HostServ are turned off. connect, a
+ * host:port, has us dial out to services as well, again and again
+ * whenever the link drops, waiting reconnect_min seconds at first and
+ * twice as long each failure after up to reconnect_max */
+#[derive(Debug, Clone, Deserialize)]
+pub struct LinkBlock {
+    pub name: String,
+    pub password_hash: String,
+    pub send_password: String,
+    #[serde(default = "default_link_hosts")]
+    pub hosts: Vec<String>,
+    #[serde(default = "default_sid")]
+    pub sid: String,
+    #[serde(default)]
+    pub connect: Option<String>,
+    #[serde(default = "default_reconnect_min")]
+    pub reconnect_min: u64,
+    #[serde(default = "default_reconnect_max")]
+    pub reconnect_max: u64,
+}
+
+fn default_link_hosts() -> Vec<String> {
+    vec![String::from("127.0.0.1"), String::from("::1")]
+}
+
+fn default_sid() -> String {
+    String::from("0RS")
+}
+
+fn default_reconnect_min() -> u64 {
+    5
+}
+
+fn default_reconnect_max() -> u64 {
+    300
+}
+
+/* [lua], scripts are loaded at startup, each in its own sandbox.
+ * ignored when built without the lua feature */
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LuaConfig {
+    pub scripts: Vec<String>,
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path)?;
+        let mut config = Config::parse(&text)?;
+        config.path = Some(path.to_string());
+        Ok(config)
+    }
+
+    pub fn parse(text: &str) -> Result<Config, ConfigError> {
+        let config: Config = toml::from_str(text)?;
+        /* catch plaintext passwords left over in the config at startup
+         * rather than having every OPER attempt silently fail */
+        if let Some(block) = config.oper.iter().find(|block| !password::is_hash(&block.password_hash)) {
+            return Err(ConfigError::OperHash(block.name.clone()));
+        }
+        if let Some(cloak) = config.oper.iter().filter_map(|block| block.cloak.as_ref()).find(|cloak| !rfc::valid_hostname(cloak)) {
+            return Err(ConfigError::OperCloak(cloak.clone()));
+        }
+        if let Some(block) = config.webirc.iter().find(|block| !password::is_hash(&block.password_hash)) {
+            return Err(ConfigError::WebircHash(block.name.clone()));
+        }
+        if let Some(name) = config.server.name.as_ref().filter(|name| !rfc::valid_hostname(name)) {
+            return Err(ConfigError::ServerName(name.clone()));
+        }
+        if let Some(block) = config.listen.iter().find(|block| block.privacy && !rfc::valid_hostname(&block.cloak)) {
+            return Err(ConfigError::Cloak(block.cloak.clone()));
+        }
+        if let Some(range) = config.listen.iter().flat_map(|block| block.allow.iter()).find(|range| parse_range(range).is_none()) {
+            return Err(ConfigError::Allow(range.clone()));
+        }
+        /* these get trusted with more than a listener's allow list does */
+        let mut trusted_hosts = config.webirc.iter().flat_map(|block| block.hosts.iter()).chain(config.link.iter().flat_map(|block| block.hosts.iter()));
+        if let Some(host) = trusted_hosts.find(|host| parse_range(host).is_none()) {
+            return Err(ConfigError::TrustedHost(host.clone()));
+        }
+        if let Some(block) = &config.link {
+            if !password::is_hash(&block.password_hash) {
+                return Err(ConfigError::LinkHash(block.name.clone()));
+            }
+            if !rfc::valid_sid(&block.sid) {
+                return Err(ConfigError::Sid(block.sid.clone()));
+            }
+        }
+        if let Some((key, _text)) = config.replies.iter().find(|(key, text)| templates::numeric(key).is_none() || !templates::valid(text)) {
+            return Err(ConfigError::Reply(key.clone()));
+        }
+        if let Some((name, _line)) = config.aliases.iter().find(|(name, line)| name.parse::<Command>().is_ok() || !alias::valid(line)) {
+            return Err(ConfigError::Alias(name.clone()));
+        }
+        for block in config.spamfilter.iter() {
+            if let Err(err) = regex::Regex::new(&block.regex) {
+                return Err(ConfigError::Spamfilter(block.regex.clone(), err));
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn listeners(&self) -> Vec<ListenBlock> {
+        if !self.listen.is_empty() {
+            return self.listen.clone();
+        }
+        vec![
+            ListenBlock::default(),
+            ListenBlock { port: 6697, tls: true, ..ListenBlock::default() },
+        ]
+    }
+
+    pub fn find_oper(&self, name: &str) -> Option<&OperBlock> {
+        self.oper.iter().find(|block| block.name == name)
+    }
+
+    /* gateways whose hosts include the connecting address */
+    pub fn find_webirc(&self, ip: IpAddr) -> Vec<&WebircBlock> {
+        self.webirc.iter().filter(|block| in_ranges(&block.hosts, ip)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_config_parses() {
+        let config = Config::parse(include_str!("../ircd.example.toml"))
+            .expect("ircd.example.toml should always be a valid config");
+        assert!(config.find_oper("aoei").is_some());
+        assert!(config.find_oper("nobody").is_none());
+        assert_eq!(config.find_webirc("127.0.0.1".parse().unwrap()).len(), 1);
+        assert_eq!(config.find_webirc("::1".parse().unwrap()).len(), 1);
+        assert!(config.find_webirc("10.0.0.1".parse().unwrap()).is_empty());
+        assert!(config.dnsbl.iter().any(|block| block.action == DnsblAction::RequireSasl));
+        assert_eq!(config.resv.len(), 2);
+        assert!(!config.admin.is_empty());
+        assert_eq!(config.logging.format, LogFormat::Text);
+        assert_eq!(config.logging.rotate, LogRotate::Daily);
+    }
+
+    #[test]
+    fn plaintext_oper_password_rejected() {
+        let text = "[[oper]]\nname = \"aoei\"\npassword_hash = \"changeme\"\n";
+        assert!(matches!(Config::parse(text), Err(ConfigError::OperHash(_))));
+    }
+
+    #[test]
+    fn listener_allow_ranges() {
+        let block = ListenBlock { allow: vec!["127.0.0.0/8".to_string(), "::1".to_string(), "10.1.2.3".to_string()], ..ListenBlock::default() };
+        assert!(block.allows("127.0.0.1".parse().unwrap()));
+        assert!(block.allows("::1".parse().unwrap()));
+        assert!(block.allows("10.1.2.3".parse().unwrap()));
+        assert!(!block.allows("10.1.2.4".parse().unwrap()));
+        assert!(!block.allows("2001:db8::1".parse().unwrap()));
+        assert!(ListenBlock::default().allows("192.0.2.1".parse().unwrap()));
+        assert_eq!(parse_range("0.0.0.0/0"), Some(("0.0.0.0".parse().unwrap(), 0)));
+        assert!(parse_range("10.0.0.0/33").is_none());
+        let text = "[[listen]]\nport = 6667\nallow = [\"localhost\"]\n";
+        assert!(matches!(Config::parse(text), Err(ConfigError::Allow(_))));
+    }
+
+    #[test]
+    fn trusted_hosts_are_addresses() {
+        let hash = "$argon2id$v=19$m=4096,t=3,p=1$t9KVHXQ+BZiDMBpyg43sTA$9H+Md5V5vYVXps4ia/J6JpmKB6BbSevoTyGIsMs8tyM";
+        let webirc = format!("[[webirc]]\nname = \"gw\"\npassword_hash = \"{}\"\nhosts = [\"localhost\"]\n", hash);
+        assert!(matches!(Config::parse(&webirc), Err(ConfigError::TrustedHost(_))));
+        let link = format!("[link]\nname = \"services.test\"\npassword_hash = \"{}\"\nsend_password = \"x\"\nhosts = [\"services.test\"]\n", hash);
+        assert!(matches!(Config::parse(&link), Err(ConfigError::TrustedHost(_))));
+        let webirc = format!("[[webirc]]\nname = \"gw\"\npassword_hash = \"{}\"\nhosts = [\"192.0.2.0/24\"]\n", hash);
+        let config = Config::parse(&webirc).unwrap();
+        assert_eq!(config.find_webirc("192.0.2.9".parse().unwrap()).len(), 1);
+        assert!(config.find_webirc("192.0.3.9".parse().unwrap()).is_empty());
+    }
+
+    #[test]
+    fn empty_config_gives_defaults() {
+        let config = Config::parse("").unwrap();
+        assert!(config.oper.is_empty());
+        assert!(config.webirc.is_empty());
+        assert!(config.dnsbl.is_empty());
+        assert!(config.resv.is_empty());
+        assert_eq!(config.services.database, "accounts.db");
+        assert_eq!(config.limits.connect_max, 10);
+    }
+}
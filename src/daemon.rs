@@ -0,0 +1,73 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* detaching from the terminal for [daemon] background = true, and the
+ * pidfile. this has to happen before the tokio runtime starts, forking
+ * only brings the calling thread along */
+use crate::config::DaemonConfig;
+use std::fs::{self, File, OpenOptions};
+use std::io::Error as ioError;
+
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+
+/* the usual double fork: the first child starts a new session so it's
+ * got no controlling terminal, the second isn't a session leader so it
+ * can never get one back. stdin then reads nothing and stdout and
+ * stderr go to log_file */
+#[cfg(unix)]
+pub fn detach(config: &DaemonConfig) -> Result<(), ioError> {
+    /* opened up front so a bad path is reported on the terminal */
+    let out = match &config.log_file {
+        Some(path) => OpenOptions::new().create(true).append(true).open(path)?,
+        None => OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    let null = File::open("/dev/null")?;
+    fork_and_exit()?;
+    if unsafe { libc::setsid() } == -1 {
+        return Err(ioError::last_os_error());
+    }
+    fork_and_exit()?;
+    for (from, to) in [(null.as_raw_fd(), 0), (out.as_raw_fd(), 1), (out.as_raw_fd(), 2)] {
+        if unsafe { libc::dup2(from, to) } == -1 {
+            return Err(ioError::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/* the parent leaves straight away, only the child comes back */
+#[cfg(unix)]
+fn fork_and_exit() -> Result<(), ioError> {
+    match unsafe { libc::fork() } {
+        -1 => Err(ioError::last_os_error()),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+#[cfg(not(unix))]
+pub fn detach(_config: &DaemonConfig) -> Result<(), ioError> {
+    Err(ioError::new(std::io::ErrorKind::Unsupported, "can't go into the background on this platform"))
+}
+
+pub fn write_pidfile(path: &str) -> Result<(), ioError> {
+    fs::write(path, format!("{}\n", std::process::id()))
+}
+
+pub fn remove_pidfile(path: &str) {
+    let _ = fs::remove_file(path);
+}
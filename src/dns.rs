@@ -0,0 +1,293 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* reverse DNS for new connections, and the DNSBL lookups. the talking to
+ * nameservers is hickory-resolver's, which reads /etc/resolv.conf (search,
+ * ndots and options included) unless [dns] names the servers, and falls
+ * back to TCP when a UDP answer comes back truncated. /etc/hosts is
+ * checked first for reverse lookups. lookups are capped at max_lookups at
+ * once and the whole thing, waiting for a slot included, gives up after
+ * the timeout and leaves the client with their IP.
+ * answers (including "no PTR") are cached for cache_ttl seconds so people
+ * reconnecting, or a whole NAT'd network, only cost one lookup.
+ * with [dns] fcrdns on, a PTR name only counts if looking it up again
+ * gives back the client's address, anyone can put whatever they like
+ * in the PTR records for their own IPs */
+use crate::config::DnsConfig;
+use crate::irc::rfc_defs as rfc;
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+use hickory_resolver::system_conf;
+use hickory_resolver::TokioAsyncResolver;
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::time;
+use tracing::debug;
+
+const HOSTS: &str = "/etc/hosts";
+
+#[derive(Debug)]
+pub struct Resolver {
+    hosts: HashMap<IpAddr, String>,
+    resolver: TokioAsyncResolver,
+    timeout: Duration,
+    fcrdns: bool,
+    lookups: Semaphore,
+    cache: Mutex<DnsCache>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+/* ip -> answer and when it expires. once it's full, expired entries get
+ * cleared out and if that doesn't help new answers just aren't kept */
+#[derive(Debug)]
+struct DnsCache {
+    entries: HashMap<IpAddr, (Option<String>, Instant)>,
+    ttl: Duration,
+    max_entries: usize,
+}
+
+impl DnsCache {
+    fn new(ttl: Duration, max_entries: usize) -> Self {
+        DnsCache { entries: HashMap::new(), ttl, max_entries }
+    }
+
+    /* outer None for a miss, Some(None) for a cached "no name" */
+    fn get(&mut self, ip: &IpAddr, now: Instant) -> Option<Option<String>> {
+        match self.entries.get(ip) {
+            Some((name, expires)) if *expires > now => Some(name.clone()),
+            Some(_) => {
+                self.entries.remove(ip);
+                None
+            },
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, ip: IpAddr, name: Option<String>, now: Instant) {
+        if self.ttl.as_secs() == 0 || self.max_entries == 0 {
+            return;
+        }
+        if self.entries.len() >= self.max_entries {
+            self.entries.retain(|_ip, (_name, expires)| *expires > now);
+        }
+        if self.entries.len() < self.max_entries {
+            self.entries.insert(ip, (name, now + self.ttl));
+        }
+    }
+}
+
+/* the nameservers from [dns] if there are any, else the system's, else
+ * whatever is listening on localhost */
+fn resolver_config(config: &DnsConfig) -> (ResolverConfig, ResolverOpts) {
+    if !config.nameservers.is_empty() {
+        let servers = NameServerConfigGroup::from_ips_clear(&config.nameservers, 53, true);
+        return (ResolverConfig::from_parts(None, Vec::new(), servers), ResolverOpts::default());
+    }
+    system_conf::read_system_conf().unwrap_or_else(|err| {
+        debug!("couldn't read the system resolver config: {}", err);
+        let servers = NameServerConfigGroup::from_ips_clear(&[IpAddr::V4(Ipv4Addr::LOCALHOST)], 53, true);
+        (ResolverConfig::from_parts(None, Vec::new(), servers), ResolverOpts::default())
+    })
+}
+
+/* Some(None) for a real "nothing there", which is worth remembering,
+ * None for a lookup that failed and might go better next time */
+fn no_answer(err: &ResolveError) -> Option<Option<String>> {
+    match err.kind() {
+        ResolveErrorKind::NoRecordsFound { .. } => Some(None),
+        _ => None,
+    }
+}
+
+impl Resolver {
+    pub fn new(config: &DnsConfig) -> Self {
+        let (resolver_config, mut opts) = resolver_config(config);
+        opts.timeout = Duration::from_millis(config.timeout_ms);
+        /* we keep our own cache of reverse answers */
+        opts.cache_size = 0;
+        Resolver {
+            hosts: fs::read_to_string(HOSTS).map(|text| parse_hosts(&text)).unwrap_or_default(),
+            resolver: TokioAsyncResolver::tokio(resolver_config, opts),
+            timeout: Duration::from_millis(config.timeout_ms),
+            fcrdns: config.fcrdns,
+            lookups: Semaphore::new(config.max_lookups.max(1)),
+            cache: Mutex::new(DnsCache::new(Duration::from_secs(config.cache_ttl), config.cache_size)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /* the PTR name for ip, if there is one and it's usable as a hostname
+     * (and resolves back to ip, if we're checking) */
+    pub async fn reverse(&self, ip: IpAddr) -> Option<String> {
+        if let Some(name) = self.hosts.get(&ip) {
+            return Some(name.clone());
+        }
+        if let Some(name) = self.cache.lock().unwrap().get(&ip, Instant::now()) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return name;
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        /* only real answers get cached, a timeout might go better next time */
+        match time::timeout(self.timeout, self.lookup_host(ip)).await {
+            Ok(Some(name)) => {
+                self.cache.lock().unwrap().insert(ip, name.clone(), Instant::now());
+                name
+            },
+            Ok(None) => None,
+            Err(_) => {
+                debug!("reverse lookup for {} timed out", ip);
+                None
+            }
+        }
+    }
+
+    /* A records for name, uncached since it's only DNSBL zones asking.
+     * None if nobody answered before the timeout */
+    pub async fn forward(&self, name: &str) -> Option<Vec<IpAddr>> {
+        let _permit = self.lookups.acquire().await;
+        /* a DNSBL zone is never relative to our search domains */
+        let name = format!("{}.", name.trim_end_matches('.'));
+        match time::timeout(self.timeout, self.resolver.ipv4_lookup(name.as_str())).await {
+            Ok(Ok(lookup)) => Some(lookup.iter().map(|a| IpAddr::V4(a.0)).collect()),
+            Ok(Err(err)) => {
+                debug!("lookup for {} failed: {}", name, err);
+                no_answer(&err).map(|_| Vec::new())
+            },
+            Err(_) => {
+                debug!("lookup for {} timed out", name);
+                None
+            }
+        }
+    }
+
+    /* for STATS d */
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.cache.lock().unwrap().entries.len(),
+        }
+    }
+
+    /* None if none of the nameservers got back to us */
+    async fn lookup_host(&self, ip: IpAddr) -> Option<Option<String>> {
+        let _permit = self.lookups.acquire().await;
+        let name = match self.resolver.reverse_lookup(ip).await {
+            Ok(lookup) => lookup.iter().next().map(|ptr| ptr.0.to_utf8().trim_end_matches('.').to_string()),
+            Err(err) => return no_answer(&err),
+        };
+        let name = match name {
+            Some(name) if rfc::valid_hostname(&name) => name,
+            _ => return Some(None),
+        };
+        if !self.fcrdns {
+            return Some(Some(name));
+        }
+        let fqdn = format!("{}.", name);
+        let addresses: Vec<IpAddr> = match ip {
+            IpAddr::V4(_) => match self.resolver.ipv4_lookup(fqdn.as_str()).await {
+                Ok(lookup) => lookup.iter().map(|a| IpAddr::V4(a.0)).collect(),
+                Err(err) => return no_answer(&err),
+            },
+            IpAddr::V6(_) => match self.resolver.ipv6_lookup(fqdn.as_str()).await {
+                Ok(lookup) => lookup.iter().map(|aaaa| IpAddr::V6(aaaa.0)).collect(),
+                Err(err) => return no_answer(&err),
+            },
+        };
+        if addresses.contains(&ip) {
+            Some(Some(name))
+        } else {
+            debug!("{} has a PTR for {} but doesn't resolve back to it", ip, name);
+            Some(None)
+        }
+    }
+}
+
+/* first name listed for each address wins, like gethostbyaddr */
+fn parse_hosts(text: &str) -> HashMap<IpAddr, String> {
+    let mut hosts = HashMap::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut words = line.split_whitespace();
+        let ip = match words.next().and_then(|word| word.parse::<IpAddr>().ok()) {
+            Some(ip) => ip,
+            None => continue,
+        };
+        if let Some(name) = words.next().filter(|name| rfc::valid_hostname(name)) {
+            hosts.entry(ip).or_insert_with(|| name.to_string());
+        }
+    }
+    hosts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_expiry() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let now = Instant::now();
+        let mut cache = DnsCache::new(Duration::from_secs(60), 1);
+        assert_eq!(cache.get(&ip, now), None);
+        cache.insert(ip, Some("host.example.org".to_string()), now);
+        assert_eq!(cache.get(&ip, now), Some(Some("host.example.org".to_string())));
+        /* full, so this one doesn't get in until the first has expired */
+        cache.insert(other, None, now);
+        assert_eq!(cache.get(&other, now), None);
+        let later = now + Duration::from_secs(61);
+        cache.insert(other, None, later);
+        assert_eq!(cache.get(&ip, later), None);
+        assert_eq!(cache.get(&other, later), Some(None));
+    }
+
+    #[test]
+    fn hosts_file() {
+        let hosts = parse_hosts("127.0.0.1 localhost\n::1 ip6-localhost ip6-loopback\n127.0.0.1 other # dup\n");
+        assert_eq!(hosts.get(&"127.0.0.1".parse().unwrap()).map(String::as_str), Some("localhost"));
+        assert_eq!(hosts.get(&"::1".parse().unwrap()).map(String::as_str), Some("ip6-localhost"));
+    }
+
+    #[tokio::test]
+    async fn cache_counters() {
+        /* nothing answers there, so a lookup that isn't cached fails */
+        let config = DnsConfig { nameservers: vec!["127.0.0.1".parse().unwrap()], timeout_ms: 100, ..DnsConfig::default() };
+        let resolver = Resolver::new(&config);
+        let cached: IpAddr = "192.0.2.1".parse().unwrap();
+        resolver.cache.lock().unwrap().insert(cached, Some("host.example.org".to_string()), Instant::now());
+        assert_eq!(resolver.reverse(cached).await.as_deref(), Some("host.example.org"));
+        assert_eq!(resolver.reverse(cached).await.as_deref(), Some("host.example.org"));
+        assert_eq!(resolver.reverse("192.0.2.2".parse().unwrap()).await, None);
+        /* a failed lookup isn't cached, so this is another miss */
+        assert_eq!(resolver.reverse("192.0.2.2".parse().unwrap()).await, None);
+        assert_eq!(resolver.cache_stats(), CacheStats { hits: 2, misses: 2, entries: 1 });
+    }
+}
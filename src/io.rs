@@ -15,14 +15,220 @@
 *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 extern crate tokio;
-extern crate tokio_native_tls;
+extern crate tokio_tungstenite;
+use crate::client::GenError;
+use crate::config::TlsConfig;
+use core::future::Future;
 use core::pin::Pin;
 use core::result::Result;
-use core::task::{Context, Poll};
+use core::task::{ready, Context, Poll};
+use futures_util::sink::Sink;
+use futures_util::stream::{SplitSink, SplitStream, Stream, StreamExt};
+use sha2::{Digest, Sha256};
 use tokio::io::Error as tioError;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
-use tokio_native_tls::TlsStream;
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("a TLS backend is needed, enable either the native-tls or the rustls feature");
+
+/* the TLS library is picked at build time, native-tls (the default) uses
+ * the platform's own TLS library and rustls avoids linking one at all.
+ * if both features are on rustls wins */
+pub trait TlsAccept: Send + Sync + Sized {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send;
+    type Error;
+    /* whether client_certs does anything, and so whether there'll ever
+     * be a certfp for SASL EXTERNAL */
+    const CLIENT_CERTS: bool;
+
+    /* client_certs asks the client for a certificate without requiring one */
+    fn from_config(config: &TlsConfig, client_certs: bool) -> Result<Self, Self::Error>;
+    fn accept(&self, sock: TcpStream) -> impl Future<Output = Result<Self::Stream, GenError>> + Send;
+    /* DER of the certificate the client sent, if they sent one */
+    fn peer_cert(stream: &Self::Stream) -> Option<Vec<u8>>;
+}
+
+/* certfp as most ircds show it, lowercase hex SHA-256 of the DER */
+pub fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+pub use self::native::{Acceptor, Connector, TlsError};
+#[cfg(feature = "rustls")]
+pub use self::rustls::{Acceptor, Connector, TlsError};
+
+pub type TlsStream = <Acceptor as TlsAccept>::Stream;
+
+/* reads the PEM cert chain and PKCS#8 key, or the PKCS#12 identity */
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+mod native {
+    extern crate tokio_native_tls;
+    use super::TlsAccept;
+    use crate::client::GenError;
+    use crate::config::TlsConfig;
+    use std::fs;
+    use tokio::net::TcpStream;
+    use tokio_native_tls::native_tls::{Identity, TlsAcceptor as NativeTlsAcc, TlsConnector as NativeTlsConn};
+    use tokio_native_tls::{TlsAcceptor, TlsConnector, TlsStream};
+    use tracing::warn;
+
+    pub use tokio_native_tls::native_tls::Error as TlsError;
+
+    pub struct Acceptor(TlsAcceptor);
+
+    impl TlsAccept for Acceptor {
+        type Stream = TlsStream<TcpStream>;
+        type Error = GenError;
+        const CLIENT_CERTS: bool = false;
+
+        fn from_config(config: &TlsConfig, client_certs: bool) -> Result<Self, GenError> {
+            if client_certs {
+                warn!("native-tls can't ask clients for certificates, client_certs needs the rustls backend");
+            }
+            let identity = match &config.identity {
+                Some(identity) => Identity::from_pkcs12(&fs::read(identity)?, &config.password)?,
+                None => Identity::from_pkcs8(&fs::read(&config.cert)?, &fs::read(&config.key)?)?,
+            };
+            Ok(Acceptor(TlsAcceptor::from(NativeTlsAcc::new(identity)?)))
+        }
+
+        async fn accept(&self, sock: TcpStream) -> Result<Self::Stream, GenError> {
+            Ok(self.0.accept(sock).await?)
+        }
+
+        fn peer_cert(stream: &Self::Stream) -> Option<Vec<u8>> {
+            stream.get_ref().peer_certificate().ok()??.to_der().ok()
+        }
+    }
+
+    /* our end of outgoing connections, checked against the platform's
+     * own roots so there's no ca_file to read */
+    pub struct Connector(TlsConnector);
+
+    impl Connector {
+        pub fn new(_ca_file: &str) -> Result<Self, GenError> {
+            Ok(Connector(TlsConnector::from(NativeTlsConn::new()?)))
+        }
+
+        pub async fn connect(&self, host: &str, sock: TcpStream) -> Result<TlsStream<TcpStream>, GenError> {
+            Ok(self.0.connect(host, sock).await?)
+        }
+    }
+}
+
+/* reads the PEM cert chain and key, the key can be PKCS#8 or RSA */
+#[cfg(feature = "rustls")]
+mod rustls {
+    extern crate tokio_rustls;
+    use super::TlsAccept;
+    use crate::client::GenError;
+    use crate::config::TlsConfig;
+    use std::fs::File;
+    use std::io::{BufReader, Error as ioError, ErrorKind};
+    use std::sync::Arc;
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+    use tokio_rustls::rustls::{
+        Certificate, ClientCertVerified, ClientCertVerifier, ClientConfig, DistinguishedNames, NoClientAuth, PrivateKey, ServerConfig,
+        Session,
+    };
+    use tokio_rustls::client::TlsStream as ClientTlsStream;
+    use tokio_rustls::server::TlsStream;
+    use tokio_rustls::webpki::{DNSName, DNSNameRef};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    pub use tokio_rustls::rustls::TLSError as TlsError;
+
+    pub struct Acceptor(TlsAcceptor);
+
+    fn bad_pem(path: &str, what: &str) -> GenError {
+        GenError::Io(ioError::new(ErrorKind::InvalidData, format!("no {} found in {}", what, path)))
+    }
+
+    fn load_key(path: &str) -> Result<PrivateKey, GenError> {
+        let pkcs8 = pkcs8_private_keys(&mut BufReader::new(File::open(path)?)).map_err(|_| bad_pem(path, "key"))?;
+        let rsa = rsa_private_keys(&mut BufReader::new(File::open(path)?)).map_err(|_| bad_pem(path, "key"))?;
+        pkcs8.into_iter().chain(rsa).next().ok_or_else(|| bad_pem(path, "key"))
+    }
+
+    /* takes whatever certificate the client offers, or none at all. it's
+     * only ever used for its fingerprint, which the account has to have
+     * been told about, so there's nothing to check it against */
+    struct AnyClientCert;
+
+    impl ClientCertVerifier for AnyClientCert {
+        fn client_auth_root_subjects(&self, _sni: Option<&DNSName>) -> Option<DistinguishedNames> {
+            Some(DistinguishedNames::new())
+        }
+
+        fn client_auth_mandatory(&self, _sni: Option<&DNSName>) -> Option<bool> {
+            Some(false)
+        }
+
+        fn verify_client_cert(&self, _presented: &[Certificate], _sni: Option<&DNSName>) -> Result<ClientCertVerified, TlsError> {
+            Ok(ClientCertVerified::assertion())
+        }
+    }
+
+    impl TlsAccept for Acceptor {
+        type Stream = TlsStream<TcpStream>;
+        type Error = GenError;
+        const CLIENT_CERTS: bool = true;
+
+        fn from_config(config: &TlsConfig, client_certs: bool) -> Result<Self, GenError> {
+            let chain = certs(&mut BufReader::new(File::open(&config.cert)?)).map_err(|_| bad_pem(&config.cert, "certificate"))?;
+            if chain.is_empty() {
+                return Err(bad_pem(&config.cert, "certificate"));
+            }
+            let mut server_config = if client_certs {
+                ServerConfig::new(Arc::new(AnyClientCert))
+            } else {
+                ServerConfig::new(NoClientAuth::new())
+            };
+            server_config.set_single_cert(chain, load_key(&config.key)?)?;
+            Ok(Acceptor(TlsAcceptor::from(Arc::new(server_config))))
+        }
+
+        async fn accept(&self, sock: TcpStream) -> Result<Self::Stream, GenError> {
+            Ok(self.0.accept(sock).await?)
+        }
+
+        fn peer_cert(stream: &Self::Stream) -> Option<Vec<u8>> {
+            let (_sock, session) = stream.get_ref();
+            session.get_peer_certificates()?.into_iter().next().map(|cert| cert.0)
+        }
+    }
+
+    /* our end of outgoing connections. rustls has no idea where the
+     * platform keeps its roots, so they come from the PEM bundle ca_file */
+    pub struct Connector(TlsConnector);
+
+    impl Connector {
+        pub fn new(ca_file: &str) -> Result<Self, GenError> {
+            let mut config = ClientConfig::new();
+            let (added, _skipped) = config.root_store
+                .add_pem_file(&mut BufReader::new(File::open(ca_file)?))
+                .map_err(|_| bad_pem(ca_file, "certificate"))?;
+            if added == 0 {
+                return Err(bad_pem(ca_file, "certificate"));
+            }
+            Ok(Connector(TlsConnector::from(Arc::new(config))))
+        }
+
+        pub async fn connect(&self, host: &str, sock: TcpStream) -> Result<ClientTlsStream<TcpStream>, GenError> {
+            let name = DNSNameRef::try_from_ascii_str(host)
+                .map_err(|_| GenError::Io(ioError::new(ErrorKind::InvalidInput, format!("{} isn't a hostname", host))))?;
+            Ok(self.0.connect(name, sock).await?)
+        }
+    }
+}
 
 /* implement AsyncRead/Write and AsyncRead/WriteExt on wrappers so that the
  * rest of our code need not care whether we're dealing with ClearText or
@@ -30,13 +236,161 @@ use tokio_native_tls::TlsStream;
 #[derive(Debug)]
 pub enum ReadHalfWrap {
     ClearText(ReadHalf<TcpStream>),
-    Encrypted(ReadHalf<TlsStream<TcpStream>>)
+    Encrypted(ReadHalf<TlsStream>),
+    WebSocket(WsRead<TcpStream>),
+    SecureWebSocket(WsRead<TlsStream>),
 }
 
 #[derive(Debug)]
 pub enum WriteHalfWrap {
     ClearText(WriteHalf<TcpStream>),
-    Encrypted(WriteHalf<TlsStream<TcpStream>>)
+    Encrypted(WriteHalf<TlsStream>),
+    WebSocket(WsWrite<TcpStream>),
+    SecureWebSocket(WsWrite<TlsStream>),
+}
+
+/* IRCv3 over WebSocket, see https://ircv3.net/specs/extensions/websocket
+ * each frame is one IRC line without the CRLF. reading puts the CRLF back
+ * so the line reader can't tell the difference, writing holds on to bytes
+ * until there's a whole line and sends that as a frame. the binary
+ * subprotocol gets binary frames, text (or nothing negotiated) gets text */
+pub const WS_TEXT_PROTOCOL: &str = "text.ircv3.net";
+pub const WS_BINARY_PROTOCOL: &str = "binary.ircv3.net";
+
+#[derive(Debug)]
+pub struct WsRead<S> {
+    inner: SplitStream<WebSocketStream<S>>,
+    pending: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct WsWrite<S> {
+    inner: SplitSink<WebSocketStream<S>, Message>,
+    binary: bool,
+    pending: Vec<u8>,
+}
+
+fn ws_error(err: WsError) -> tioError {
+    tioError::other(err)
+}
+
+/* strips whatever line ending the client left on, if any */
+fn trim_line_ending(mut line: Vec<u8>) -> Vec<u8> {
+    while line.last().is_some_and(|c| *c == b'\n' || *c == b'\r') {
+        line.pop();
+    }
+    line
+}
+
+/* does the WebSocket handshake on an already accepted (and maybe TLS wrapped)
+ * connection, picking the binary subprotocol only if the client asks for it
+ * and text doesn't come first. with any origins given, the browser has to
+ * say it's on one of them or it gets a 403 */
+pub async fn accept_websocket<S>(stream: S, origins: &[String]) -> Result<(WsRead<S>, WsWrite<S>), WsError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut binary = false;
+    /* the signature is tungstenite's Callback, not ours to slim down */
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, mut response: Response| -> Result<Response, ErrorResponse> {
+        if !origin_allowed(request, origins) {
+            let mut forbidden = ErrorResponse::new(Some("Origin not allowed".to_string()));
+            *forbidden.status_mut() = StatusCode::FORBIDDEN;
+            return Err(forbidden);
+        }
+        let offered = request.headers()
+            .get_all("Sec-WebSocket-Protocol")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|protocol| protocol.trim())
+            .find(|protocol| *protocol == WS_TEXT_PROTOCOL || *protocol == WS_BINARY_PROTOCOL);
+        if let Some(protocol) = offered {
+            binary = protocol == WS_BINARY_PROTOCOL;
+            response.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(protocol_str(binary)));
+        }
+        Ok(response)
+    };
+    let (sink, stream) = tokio_tungstenite::accept_hdr_async(stream, callback).await?.split();
+    Ok((
+        WsRead { inner: stream, pending: Vec::new() },
+        WsWrite { inner: sink, binary, pending: Vec::new() },
+    ))
+}
+
+fn origin_allowed(request: &Request, origins: &[String]) -> bool {
+    if origins.is_empty() {
+        return true;
+    }
+    let origin = match request.headers().get("Origin").and_then(|value| value.to_str().ok()) {
+        Some(origin) => origin.trim_end_matches('/'),
+        None => return false,
+    };
+    origins.iter().any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+}
+
+fn protocol_str(binary: bool) -> &'static str {
+    if binary { WS_BINARY_PROTOCOL } else { WS_TEXT_PROTOCOL }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsRead<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<Result<(), tioError>> {
+        let this = Pin::into_inner(self);
+        while this.pending.is_empty() {
+            let line = match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                /* nothing written to buf means EOF */
+                None | Some(Ok(Message::Close(_))) => return Poll::Ready(Ok(())),
+                Some(Ok(Message::Text(text))) => trim_line_ending(text.into_bytes()),
+                Some(Ok(Message::Binary(data))) => trim_line_ending(data),
+                /* tungstenite answers pings by itself */
+                Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => continue,
+                Some(Err(err)) => return Poll::Ready(Err(ws_error(err))),
+            };
+            if !line.is_empty() {
+                this.pending = line;
+                this.pending.extend_from_slice(b"\r\n");
+            }
+        }
+        let n = buf.remaining().min(this.pending.len());
+        buf.put_slice(&this.pending[..n]);
+        this.pending.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsWrite<S> {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<Result<usize, tioError>> {
+        Pin::into_inner(self).pending.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), tioError>> {
+        let this = Pin::into_inner(self);
+        while let Some(end) = this.pending.iter().position(|c| *c == b'\n') {
+            ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(ws_error)?;
+            let line = trim_line_ending(this.pending.drain(..=end).collect());
+            let msg = if this.binary {
+                Message::Binary(line)
+            } else {
+                Message::Text(String::from_utf8_lossy(&line).into_owned())
+            };
+            Pin::new(&mut this.inner).start_send(msg).map_err(ws_error)?;
+        }
+        Pin::new(&mut this.inner).poll_flush(cx).map_err(ws_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), tioError>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut Pin::into_inner(self).inner).poll_close(cx).map_err(ws_error)
+    }
+}
+
+impl ReadHalfWrap {
+    /* came in over TLS, websocket or not */
+    pub fn is_secure(&self) -> bool {
+        matches!(self, ReadHalfWrap::Encrypted(_) | ReadHalfWrap::SecureWebSocket(_))
+    }
 }
 
 impl AsyncRead for ReadHalfWrap {
@@ -44,7 +398,9 @@ impl AsyncRead for ReadHalfWrap {
         let wrapper = Pin::into_inner(self);
         match wrapper {
             ReadHalfWrap::ClearText(inner) => AsyncRead::poll_read(Pin::new(inner), cx, buf),
-            ReadHalfWrap::Encrypted(inner) => AsyncRead::poll_read(Pin::new(inner), cx, buf)
+            ReadHalfWrap::Encrypted(inner) => AsyncRead::poll_read(Pin::new(inner), cx, buf),
+            ReadHalfWrap::WebSocket(inner) => AsyncRead::poll_read(Pin::new(inner), cx, buf),
+            ReadHalfWrap::SecureWebSocket(inner) => AsyncRead::poll_read(Pin::new(inner), cx, buf),
         }
     }
 }
@@ -58,7 +414,9 @@ impl AsyncWrite for WriteHalfWrap {
         let wrapper = Pin::into_inner(self);
         match wrapper {
             WriteHalfWrap::ClearText(inner) => AsyncWrite::poll_write(Pin::new(inner), cx, buf),
-            WriteHalfWrap::Encrypted(inner) => AsyncWrite::poll_write(Pin::new(inner), cx, buf)
+            WriteHalfWrap::Encrypted(inner) => AsyncWrite::poll_write(Pin::new(inner), cx, buf),
+            WriteHalfWrap::WebSocket(inner) => AsyncWrite::poll_write(Pin::new(inner), cx, buf),
+            WriteHalfWrap::SecureWebSocket(inner) => AsyncWrite::poll_write(Pin::new(inner), cx, buf),
         }
     }
 
@@ -66,7 +424,9 @@ impl AsyncWrite for WriteHalfWrap {
         let wrapper = Pin::into_inner(self);
         match wrapper {
             WriteHalfWrap::ClearText(inner) => AsyncWrite::poll_flush(Pin::new(inner), cx),
-            WriteHalfWrap::Encrypted(inner) => AsyncWrite::poll_flush(Pin::new(inner), cx)
+            WriteHalfWrap::Encrypted(inner) => AsyncWrite::poll_flush(Pin::new(inner), cx),
+            WriteHalfWrap::WebSocket(inner) => AsyncWrite::poll_flush(Pin::new(inner), cx),
+            WriteHalfWrap::SecureWebSocket(inner) => AsyncWrite::poll_flush(Pin::new(inner), cx),
         }
     }
 
@@ -74,7 +434,9 @@ impl AsyncWrite for WriteHalfWrap {
         let wrapper = Pin::into_inner(self);
         match wrapper {
             WriteHalfWrap::ClearText(inner) => AsyncWrite::poll_shutdown(Pin::new(inner), cx),
-            WriteHalfWrap::Encrypted(inner) => AsyncWrite::poll_shutdown(Pin::new(inner), cx)
+            WriteHalfWrap::Encrypted(inner) => AsyncWrite::poll_shutdown(Pin::new(inner), cx),
+            WriteHalfWrap::WebSocket(inner) => AsyncWrite::poll_shutdown(Pin::new(inner), cx),
+            WriteHalfWrap::SecureWebSocket(inner) => AsyncWrite::poll_shutdown(Pin::new(inner), cx),
         }
     }
 }
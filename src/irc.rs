@@ -14,25 +14,82 @@
 *  You should have received a copy of the GNU Lesser General Public License
 *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
+pub mod accounts;
+pub mod alias;
+pub mod audit;
+pub mod broadcast;
+pub mod cap;
+pub mod censor;
 pub mod chan;
+pub mod chanlog;
+pub mod check;
+pub mod collision;
+pub mod command;
+pub mod connlog;
+pub mod ctcp;
+pub mod dnsbl;
 pub mod error;
+pub mod event;
+pub mod info;
+pub mod isupport;
+pub mod kline;
+pub mod link;
+#[cfg(feature = "lua")]
+pub mod lua;
+pub mod mask;
+pub mod memory;
+pub mod mode;
+pub mod module;
+pub mod readmarker;
 pub mod reply;
+pub mod resv;
 pub mod rfc_defs;
-use crate::{USER_MODES, CHAN_MODES};
+pub mod sasl;
+pub mod trace;
+pub mod services;
+pub mod spamfilter;
+pub mod stats;
+pub mod tags;
+pub mod templates;
+pub mod webhook;
+pub mod webirc;
+pub mod whois;
+use crate::USER_MODES;
 use crate::client;
+use crate::dns::Resolver;
 use crate::client::{Client, ClientType, ClientReply, ClientReplies, GenError, Host};
-use crate::irc::chan::{ChanFlags, Channel, ChanTopic};
+use crate::config::{Config, OperBlock, SpamTarget};
+use crate::irc::alias::Aliases;
+use crate::irc::censor::Censor;
+use crate::irc::chan::{ChanFlags, Channel};
+use crate::irc::command::Command;
 use crate::irc::error::Error as ircError;
+use crate::irc::event::{Event, Wire};
 use crate::irc::reply::Reply as ircReply;
 use crate::irc::rfc_defs as rfc;
+use crate::irc::accounts::AccountStore;
+use crate::irc::module::{ChanEvent, Module};
+use crate::irc::kline::Kline;
+use crate::irc::link::Link;
+use crate::irc::memory::Memory;
+use crate::irc::resv::Resv;
+use crate::irc::spamfilter::Spamfilter;
+use crate::irc::stats::CommandStats;
+use crate::irc::templates::Templates;
+use crate::irc::webhook::Webhooks;
 use crate::parser::ParsedMsg;
-extern crate log;
+use crate::password;
+use crate::throttle::{ConnThrottle, PendingLimit, PendingSlot, RateWindow};
 extern crate chrono;
 use chrono::Utc;
-use log::{debug, warn, trace};
+use tracing::{debug, warn, trace};
 use std::clone::Clone;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex, Weak};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, Instant};
+use tokio::task;
 
 
 macro_rules! gef {
@@ -48,15 +105,27 @@ pub enum NamedEntity {
 impl Clone for NamedEntity {
     fn clone(&self) -> Self {
         match self {
-            NamedEntity::User(ptr) => NamedEntity::User(Weak::clone(&ptr)),
-            NamedEntity::Chan(ptr) => NamedEntity::Chan(Arc::clone(&ptr)),
+            NamedEntity::User(ptr) => NamedEntity::User(Weak::clone(ptr)),
+            NamedEntity::Chan(ptr) => NamedEntity::Chan(Arc::clone(ptr)),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct UserFlags {
-    registered: bool
+    oper: bool,
+    /* +B, the user says they're a bot */
+    bot: bool,
+    /* +i, hidden from WHO and NAMES unless you share a channel */
+    invisible: bool,
+    /* +R, only logged in users can PRIVMSG or NOTICE them */
+    registered_only: bool,
+    /* +S, only users on TLS can PRIVMSG or NOTICE them */
+    secure_only: bool,
+    /* showing the cloak from their oper block, which goes with the +o */
+    oper_cloak: bool,
+    /* DNSBL zones the user was listed in but let through anyway */
+    dnsbl: Option<String>,
 }
 
 #[derive(Debug)]
@@ -66,11 +135,30 @@ pub struct User {
     username: String,
     real_name: Mutex<String>,
     host: Host,
+    vhost: Mutex<Option<String>>,
+    account: Mutex<Option<String>>,
     server: String,
     channel_list: Mutex<HashMap<String, Weak<Channel>>>,
     flags: Mutex<UserFlags>,
+    joins: Mutex<RateWindow>,
+    nick_changes: Mutex<RateWindow>,
+    ctcps: Mutex<RateWindow>,
+    typing: Mutex<RateWindow>,
+    /* unix times for WHOIS, last_active doesn't count PINGs */
+    signon: i64,
+    last_active: Mutex<i64>,
+    /* when the current nick was taken, settles nick collisions */
+    nick_ts: Mutex<i64>,
+    /* TS6 UID for users on the other side of a server link */
+    uid: Option<String>,
     irc: Arc<Core>,
-    client: Weak<Client>,
+    /* with [services] multiclient there can be more than one connection
+     * attached to the same user, the first one still around counts as
+     * the user's own for things like the IP and certificate */
+    clients: Mutex<Vec<Weak<Client>>>,
+    /* Some while an always_on session has no connections, holding
+     * what's been sent to it since, see Core::hold_session() */
+    backlog: Mutex<Option<VecDeque<Arc<str>>>>,
 }
 
 impl Clone for User {
@@ -81,11 +169,22 @@ impl Clone for User {
             username: self.username.clone(),
             real_name: Mutex::new(self.real_name.lock().unwrap().clone()),
             host: self.host.clone(),
+            vhost: Mutex::new(self.vhost.lock().unwrap().clone()),
+            account: Mutex::new(self.account.lock().unwrap().clone()),
             server: self.server.clone(),
             channel_list: Mutex::new(self.channel_list.lock().unwrap().clone()),
             flags: Mutex::new(self.flags.lock().unwrap().clone()),
+            joins: Mutex::new(self.joins.lock().unwrap().clone()),
+            nick_changes: Mutex::new(self.nick_changes.lock().unwrap().clone()),
+            ctcps: Mutex::new(self.ctcps.lock().unwrap().clone()),
+            typing: Mutex::new(self.typing.lock().unwrap().clone()),
+            signon: self.signon,
+            last_active: Mutex::new(*self.last_active.lock().unwrap()),
+            nick_ts: Mutex::new(*self.nick_ts.lock().unwrap()),
+            uid: self.uid.clone(),
             irc: Arc::clone(&self.irc),
-            client: Weak::clone(&self.client)
+            clients: Mutex::new(self.clients.lock().unwrap().clone()),
+            backlog: Mutex::new(self.backlog.lock().unwrap().clone()),
         }
     }
 }
@@ -93,11 +192,15 @@ impl Clone for User {
 impl Drop for User {
     fn drop (&mut self) {
         debug!("drop called on user {}, clear channel list", self.get_nick());
+        if let Some(backlog) = self.backlog.get_mut().unwrap().take() {
+            self.irc.memory.release(backlog.iter().map(|line| line.len()).sum());
+        }
         self.clear_up();
     }
 }
 
 impl User {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: u64,
         irc: &Arc<Core>,
@@ -107,40 +210,126 @@ impl User {
         host: client::Host,
         server: String,
         client: &Arc<Client>,
+        uid: Option<String>,
     ) -> Arc<Self> {
+        let now = Utc::now().timestamp();
         Arc::new(User {
             id,
-            irc: Arc::clone(&irc),
+            irc: Arc::clone(irc),
             nick: Mutex::new(nick),
             username,
             real_name: Mutex::new(real_name),
             host,
+            vhost: Mutex::new(None),
+            account: Mutex::new(None),
             server,
             channel_list: Mutex::new(HashMap::new()),
-            client: Arc::downgrade(client),
-            flags: Mutex::new(UserFlags { registered: true }), /*channel_list: Mutex::new(Vec::new())*/
+            clients: Mutex::new(vec![Arc::downgrade(client)]),
+            backlog: Mutex::new(None),
+            flags: Mutex::new(UserFlags::default()), /*channel_list: Mutex::new(Vec::new())*/
+            joins: Mutex::new(RateWindow::default()),
+            nick_changes: Mutex::new(RateWindow::default()),
+            ctcps: Mutex::new(RateWindow::default()),
+            typing: Mutex::new(RateWindow::default()),
+            signon: now,
+            last_active: Mutex::new(now),
+            nick_ts: Mutex::new(now),
+            uid,
         })
     }
 
     /* since this is basically the drop() code,
      * have drop just call this */
     pub fn clear_up(&self) {
-        self.channel_list.lock()
+        /* the channels drop themselves from the namespace
+         * once their last user is gone */
+        let chans: Vec<Weak<Channel>> = self.channel_list.lock()
             .unwrap()
             .drain()
-            .filter_map(|(_name, chan_ptr)|{
-                Weak::upgrade(&chan_ptr)
-                /* but is it bad to silently ignore the refs that won't upgrade... */
-            }).for_each(|chan|{
-                chan.rm_key(&self.get_nick());
-                if chan.is_empty() {
-                    if let Err(err) = self.irc.remove_name(&chan.get_name()) {
-                        warn!("error {} removing non-existant channel {}", err, &chan.get_name());
-                    }
-                }
-            });
-        if let Err(err) = self.irc.remove_name(&self.get_nick()) {
-            warn!("error {} removing non-existant nick {}", err, &self.get_nick());
+            .map(|(_name, chan_ptr)| chan_ptr)
+            .collect();
+        let nick = self.get_nick();
+        /* but is it bad to silently ignore the refs that won't upgrade... */
+        for chan in chans.iter().filter_map(Weak::upgrade) {
+            chan.rm_key(&nick);
+        }
+        if let Err(err) = self.irc.remove_name(&nick) {
+            warn!("error {} removing non-existant nick {}", err, &nick);
+        }
+    }
+
+    /* every connection attached to us that's still alive */
+    pub fn get_clients(&self) -> Vec<Arc<Client>> {
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|client| client.strong_count() > 0);
+        clients.iter().filter_map(Weak::upgrade).collect()
+    }
+
+    fn first_client(&self) -> Option<Arc<Client>> {
+        self.get_clients().into_iter().next()
+    }
+
+    /* another connection logged in to our account joins the session */
+    pub fn attach(&self, client: &Arc<Client>) {
+        self.clients.lock().unwrap().push(Arc::downgrade(client));
+    }
+
+    /* a connection going away, true if it was the last one */
+    pub fn detach(&self, id: u64) -> bool {
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|client| Weak::upgrade(client).is_some_and(|client| client.get_id() != id));
+        clients.is_empty()
+    }
+
+    pub fn is_detached(&self) -> bool {
+        self.backlog.lock().unwrap().is_some()
+    }
+
+    fn detach_session(&self) {
+        *self.backlog.lock().unwrap() = Some(VecDeque::new());
+    }
+
+    /* back from being detached, with everything missed in the meantime */
+    fn resume_session(&self) -> Vec<Arc<str>> {
+        let backlog = self.backlog.lock().unwrap().take().map(Vec::from).unwrap_or_default();
+        self.irc.memory.release(backlog.iter().map(|line| line.len()).sum());
+        backlog
+    }
+
+    /* keep line for whoever attaches next, false if we're not detached */
+    fn buffer(&self, line: &Arc<str>) -> bool {
+        let mut backlog = self.backlog.lock().unwrap();
+        let backlog = match backlog.as_mut() {
+            Some(backlog) => backlog,
+            None => return false,
+        };
+        if self.irc.config.services.always_on_backlog == 0 {
+            return true;
+        }
+        let memory = &self.irc.memory;
+        let total_backlog = self.irc.config.limits.total_backlog;
+        /* make room from our own oldest, for the line count and for
+         * total_backlog. past that the line just isn't kept */
+        while backlog.len() >= self.irc.config.services.always_on_backlog
+            || (!backlog.is_empty() && Memory::over(memory.backlog(), line.len(), total_backlog))
+        {
+            if let Some(old) = backlog.pop_front() {
+                memory.release(old.len());
+            }
+        }
+        if Memory::over(memory.backlog(), line.len(), total_backlog) {
+            return true;
+        }
+        memory.keep(line.len());
+        backlog.push_back(Arc::clone(line));
+        true
+    }
+
+    /* a detached session being KILLed, there's no connection to close
+     * so it just goes */
+    pub fn end_session(&self) {
+        if self.irc.release_session(self.id).is_some() {
+            self.clear_up();
         }
     }
 
@@ -148,12 +337,15 @@ impl User {
      * if that fails, so some cleanup and return an error indicating
      * dead client or similar */
     pub fn fetch_client(self: &Arc<Self>) -> Result<Arc<Client>, GenError> { /* GDB++ */
-        Weak::upgrade(&self.client).ok_or_else(|| {
+        if self.is_detached() {
+            return Err(GenError::Detached(self.get_nick()));
+        }
+        self.first_client().ok_or_else(|| {
             self.clear_up();
             debug!("fetch_client(): got a dead client @ user {}", self.get_nick());
             /* can't iterate here as chan.notify_quit() will call
              * user.send_line() and make this fn recursive */
-            GenError::DeadClient(Arc::clone(&self))
+            GenError::DeadClient(Arc::clone(self))
         })
     }
 
@@ -161,17 +353,51 @@ impl User {
      * lead to race conditions and mess with book-keeping (unless I stop
      * relying on purely text based keys for some User/Channel management) */
     pub fn change_nick(self: &Arc<Self>, name: &str) -> Result<ircReply, GenError> {
-        self.irc.try_nick_change(self, name)
+        let old_nick = self.get_nick();
+        let reply = self.irc.try_nick_change(self, name)?;
+        if let Some(link) = self.irc.get_link() {
+            link.nick_change(self);
+        }
+        if let (false, Some(client)) = (self.is_remote(), self.first_client()) {
+            connlog::log(&client, connlog::Event::Nick(&old_nick));
+        }
+        /* SANICK, services and collisions change someone else's nick,
+         * so it's their connections' spans that get it, not ours */
+        for client in self.get_clients() {
+            client.get_span().record("nick", name);
+        }
+        Ok(reply)
     }
 
     pub fn get_id(&self) -> u64 {
         self.id
     }
 
+    pub fn get_n_channels(&self) -> usize {
+        self.channel_list.lock().unwrap().len()
+    }
+
+    /* only one channel_list lock held at a time, so two users
+     * asking about each other can't deadlock */
+    pub fn shares_channel(&self, other: &User) -> bool {
+        let ours: Vec<String> = self.channel_list.lock().unwrap().keys().cloned().collect();
+        let theirs = other.channel_list.lock().unwrap();
+        ours.iter().any(|name| theirs.contains_key(name))
+    }
+
+    pub fn is_on(&self, chan: &str) -> bool {
+        self.channel_list.lock().unwrap().contains_key(chan)
+    }
+
+    /* whether we get to see an invisible user in WHO and NAMES */
+    pub fn can_see(&self, other: &User) -> bool {
+        !other.is_invisible() || self.id == other.id || self.is_oper() || self.shares_channel(other)
+    }
+
     pub fn get_channel_list(&self) -> Vec<Weak<Channel>> {
         let mut values = Vec::new();
         for val in self.channel_list.lock().unwrap().values() {
-            values.push(Weak::clone(&val));
+            values.push(Weak::clone(val));
         }
         values
     }
@@ -192,9 +418,14 @@ impl User {
     }
 
     pub fn get_host_string(&self) -> String {
-        match &self.host {
-            Host::Hostname(name) => name.to_string(),
-            Host::HostAddr(ip_addr) => ip_addr.to_string(),
+        self.host.to_string()
+    }
+
+    /* the host other users get to see, which is the vhost if one is active */
+    pub fn get_visible_host(&self) -> String {
+        match &*self.vhost.lock().unwrap() {
+            Some(vhost) => vhost.clone(),
+            None => self.get_host_string(),
         }
     }
 
@@ -202,15 +433,236 @@ impl User {
         self.real_name.lock().unwrap().clone()
     }
 
+    pub fn get_account(&self) -> Option<String> {
+        self.account.lock().unwrap().clone()
+    }
+
+    pub fn set_account(&self, account: Option<String>) {
+        *self.account.lock().unwrap() = account;
+    }
+
+    pub fn is_oper(&self) -> bool {
+        self.flags.lock().unwrap().oper
+    }
+
+    pub fn set_oper(&self, oper: bool) {
+        self.flags.lock().unwrap().oper = oper;
+    }
+
+    pub fn get_signon(&self) -> i64 {
+        self.signon
+    }
+
+    pub fn get_idle(&self) -> i64 {
+        Utc::now().timestamp() - *self.last_active.lock().unwrap()
+    }
+
+    pub fn get_nick_ts(&self) -> i64 {
+        *self.nick_ts.lock().unwrap()
+    }
+
+    pub fn set_nick_ts(&self, ts: i64) {
+        *self.nick_ts.lock().unwrap() = ts;
+    }
+
+    /* only set for users on the far end of a link */
+    pub fn get_remote_uid(&self) -> Option<String> {
+        self.uid.clone()
+    }
+
+    pub fn is_remote(&self) -> bool {
+        self.uid.is_some()
+    }
+
+    pub fn touch(&self) {
+        *self.last_active.lock().unwrap() = Utc::now().timestamp();
+    }
+
+    pub fn is_bot(&self) -> bool {
+        self.flags.lock().unwrap().bot
+    }
+
+    pub fn set_bot(&self, bot: bool) {
+        self.flags.lock().unwrap().bot = bot;
+    }
+
+    pub fn is_registered_only(&self) -> bool {
+        self.flags.lock().unwrap().registered_only
+    }
+
+    pub fn set_registered_only(&self, registered_only: bool) {
+        self.flags.lock().unwrap().registered_only = registered_only;
+    }
+
+    pub fn is_secure_only(&self) -> bool {
+        self.flags.lock().unwrap().secure_only
+    }
+
+    pub fn set_secure_only(&self, secure_only: bool) {
+        self.flags.lock().unwrap().secure_only = secure_only;
+    }
+
+    /* whether the user was wearing an oper cloak, which they aren't
+     * any more once this has been asked */
+    pub fn take_oper_cloak(&self) -> bool {
+        std::mem::take(&mut self.flags.lock().unwrap().oper_cloak)
+    }
+
+    /* +R and +S, why src can't PRIVMSG or NOTICE us. opers
+     * get through anyway */
+    pub fn refuses_msg_from(&self, src: &User) -> Option<ircError> {
+        if src.is_oper() {
+            None
+        } else if self.is_registered_only() && src.get_account().is_none() {
+            Some(ircError::NoNonReg(self.get_nick()))
+        } else if self.is_secure_only() && !src.is_secure() {
+            Some(ircError::CantSendToUser(self.get_nick(), "You must be connected with TLS to message this user (+S)".to_string()))
+        } else {
+            None
+        }
+    }
+
+    pub fn is_invisible(&self) -> bool {
+        self.flags.lock().unwrap().invisible
+    }
+
+    pub fn set_invisible(&self, invisible: bool) {
+        self.flags.lock().unwrap().invisible = invisible;
+    }
+
+    /* user modes for RPL_UMODEIS, in USER_MODES order */
+    pub fn get_modes(&self) -> String {
+        let secure = !self.is_remote() && self.is_secure();
+        let flags = self.flags.lock().unwrap();
+        let mut modes = String::from("+");
+        if flags.bot {
+            modes.push('B');
+        }
+        if flags.registered_only {
+            modes.push('R');
+        }
+        if flags.secure_only {
+            modes.push('S');
+        }
+        /* +Z can't be set or unset, it's just whether they're on TLS */
+        if secure {
+            modes.push('Z');
+        }
+        if flags.invisible {
+            modes.push('i');
+        }
+        if flags.oper {
+            modes.push('o');
+        }
+        modes
+    }
+
+    /* JOIN and NICK limits, Err says how long they need to wait */
+    pub fn throttle_join(&self) -> Result<(), Duration> {
+        let limits = &self.irc.get_config().limits;
+        if limits.flood_exempt_opers && self.is_oper() {
+            return Ok(());
+        }
+        self.joins.lock().unwrap().check(limits.join_max, Duration::from_secs(limits.join_window))
+    }
+
+    pub fn throttle_nick(&self) -> Result<(), Duration> {
+        let limits = &self.irc.get_config().limits;
+        if limits.flood_exempt_opers && self.is_oper() {
+            return Ok(());
+        }
+        self.nick_changes.lock().unwrap().check(limits.nick_max, Duration::from_secs(limits.nick_window))
+    }
+
+    pub fn throttle_ctcp(&self) -> Result<(), Duration> {
+        let limits = &self.irc.get_config().limits;
+        if limits.flood_exempt_opers && self.is_oper() {
+            return Ok(());
+        }
+        self.ctcps.lock().unwrap().check(limits.ctcp_max, Duration::from_secs(limits.ctcp_window))
+    }
+
+    /* no exemption for opers, nobody needs to type that fast */
+    pub fn throttle_typing(&self) -> Result<(), Duration> {
+        let limits = &self.irc.get_config().limits;
+        self.typing.lock().unwrap().check(limits.typing_max, Duration::from_secs(limits.typing_window))
+    }
+
+    /* for WHOIS to show opers */
+    pub fn get_dnsbl_mark(&self) -> Option<String> {
+        self.flags.lock().unwrap().dnsbl.clone()
+    }
+
+    pub fn set_dnsbl_mark(&self, zones: Option<String>) {
+        self.flags.lock().unwrap().dnsbl = zones;
+    }
+
+    /* whether any of our connections has it, see send_cap_line() for
+     * sending something only the ones that asked for it understand */
+    pub fn has_cap(&self, cap: &str) -> bool {
+        self.get_clients().iter().any(|client| client.has_cap(cap))
+    }
+
+    /* only if every connection attached is, +S can't be got round
+     * by attaching a plaintext one */
+    pub fn is_secure(&self) -> bool {
+        let clients = self.get_clients();
+        !clients.is_empty() && clients.iter().all(|client| client.is_secure())
+    }
+
+    /* services' users would get the link's own certificate otherwise */
+    pub fn get_certfp(&self) -> Option<String> {
+        if self.is_remote() {
+            return None;
+        }
+        self.first_client().and_then(|client| client.get_certfp())
+    }
+
     pub fn get_prefix(&self) -> String {
         format!(
             "{}!{}@{}",
             self.get_nick(),
             self.username,
-            self.get_host_string()
+            self.get_visible_host()
         )
     }
 
+    /* everyone sharing at least one channel with us, ourselves included */
+    pub async fn get_peers(&self) -> Vec<Arc<User>> {
+        let mut peers: BTreeMap<u64, Arc<User>> = BTreeMap::new();
+        for chan in self.get_channel_list().iter().filter_map(Weak::upgrade) {
+            for user in chan.gen_user_ptr_vec().await {
+                peers.insert(user.get_id(), user);
+            }
+        }
+        peers.into_values().collect()
+    }
+
+    /* swap the displayed host, clients that negotiated chghost get told
+     * about it straight away, the rest will see the new host on the next
+     * line we relay from this user. RPL_HOSTHIDDEN only goes out for a
+     * vhost, going back to the real host isn't hiding anything */
+    pub async fn set_vhost(self: &Arc<Self>, vhost: Option<String>) -> Result<(), GenError> {
+        let old_prefix = self.get_prefix();
+        let hidden = vhost.is_some();
+        *self.vhost.lock().unwrap() = vhost;
+        let new_host = self.get_visible_host();
+        let line = client::shared_line(&format!(":{} CHGHOST {} {}", old_prefix, self.username, new_host));
+        let mut peers = self.get_peers().await;
+        if peers.is_empty() {
+            peers.push(Arc::clone(self));
+        }
+        for peer in peers.iter() {
+            if let Err(err) = peer.send_cap_line(cap::CHGHOST, &line).await {
+                debug!("couldn't send CHGHOST to {}: {}", peer.get_nick(), err);
+            }
+        }
+        if hidden {
+            self.send_rpl(ircReply::HostHidden(new_host)).await?;
+        }
+        Ok(())
+    }
+
     pub fn get_server(&self) -> String {
         self.server.clone()
     }
@@ -220,59 +672,126 @@ impl User {
         src: &User,
         command_str: &str,
         target: &str,
-        msg: &str
+        msg: &str,
+        tags: &str
     ) -> Result<ClientReply, GenError> { /* GDB+ */
-        let prefix = src.get_prefix();
-        let line = format!(":{} {} {} :{}", &prefix, command_str, target, msg);
-        /* instead of unwrap(), fetch_client() tries to upgrade the pointer,
-         * if that fails it does some cleaning up and returns a GenError::Io(unexpected Eof)
-         */
-        let my_client = self.fetch_client()?;
-        /* passing to an async fn and awaiting on it is gonna
-         * cause lifetime problems with a &str... */
-        my_client.send_line(&line).await?;
+        let tagmsg = command_str == "TAGMSG";
+        /* services' users get theirs over the link, which has no tags */
+        if let Some(uid) = &self.uid {
+            if let (Some(link), false) = (self.irc.get_link(), tagmsg) {
+                link.message(src, command_str, uid, msg);
+            }
+            return Ok(Ok(ircReply::None));
+        }
+        let event = event::Shared::new(Event::Message {
+            tags: tags.to_string(),
+            prefix: src.get_prefix(),
+            command: command_str.to_string(),
+            target: target.to_string(),
+            text: msg.to_string(),
+        });
+        self.send_event(&event).await?;
         Ok(Ok(ircReply::None))
     }
 
-    pub async fn send_err(self: &Arc<Self>, err: ircError) -> Result<ircReply, GenError> { /* GDB+ */
-        let line = format!(":{} {}", self.irc.get_host(), err);
-        let my_client = self.fetch_client()?;
-        /* passing to an async fn and awaiting on it is gonna
-         * cause lifetime problems with a &str... */
-        my_client.send_line(&line).await?;
+    /* each connection gets the event the way it asked for it. a detached
+     * session keeps the plain line, a TAGMSG has nothing worth keeping */
+    pub async fn send_event(self: &Arc<Self>, event: &event::Shared) -> Result<ircReply, GenError> {
+        self.queue_event(event)
+    }
+
+    /* send_event() without the await, nothing in it waits on the client */
+    pub fn queue_event(self: &Arc<Self>, event: &event::Shared) -> Result<ircReply, GenError> {
+        if self.is_detached() {
+            if let Some(line) = event.line(Wire::default()) {
+                self.buffer(&line);
+            }
+            return Ok(ircReply::None);
+        }
+        self.fetch_client()?;
+        for client in self.get_clients() {
+            match event.line(Wire::of(&client)) {
+                Some(line) if event.is_chatter() => client.send_chatter(&line)?,
+                Some(line) => client.queue_shared(&line)?,
+                None => (),
+            }
+        }
         Ok(ircReply::None)
     }
 
+    /* only to the connections that negotiated cap */
+    pub async fn send_cap_line(self: &Arc<Self>, cap: &str, line: &Arc<str>) -> Result<ircReply, GenError> {
+        if self.is_detached() {
+            return Ok(ircReply::None);
+        }
+        self.fetch_client()?;
+        for client in self.get_clients().iter().filter(|client| client.has_cap(cap)) {
+            client.send_shared(line).await?;
+        }
+        Ok(ircReply::None)
+    }
+
+    /* our own PRIVMSG or NOTICE going back to the rest of our connections,
+     * so they all see both sides of the conversation */
+    pub async fn echo(self: &Arc<Self>, from: u64, command_str: &str, target: &str, msg: &str) -> Result<(), GenError> {
+        let clients: Vec<Arc<Client>> = self.get_clients().into_iter().filter(|client| client.get_id() != from).collect();
+        if clients.is_empty() {
+            return Ok(());
+        }
+        let line = client::shared_line(&format!(":{} {} {} :{}", self.get_prefix(), command_str, target, msg));
+        for client in clients {
+            client.send_shared(&line).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn send_err(self: &Arc<Self>, err: ircError) -> Result<ircReply, GenError> { /* GDB+ */
+        let line = err.format(&self.irc.get_host(), &self.get_nick(), &self.irc.get_templates());
+        self.send_line(&line).await
+    }
+
     pub async fn send_rpl(self: &Arc<Self>, reply: ircReply) -> Result<ircReply, GenError> { /* GDB+ */
-        /* passing to an async fn and awaiting on it is gonna
-         * cause lifetime problems with a &str... */
-        let mut line = reply.format(&self.get_server(), &self.get_nick());
-        let my_client = self.fetch_client()?;
-        /* break up long messages if neccessary,
-         * reply::split essentially returns line, None when
-         * line is not larger than MAX_MSG_SIZE */
-        loop {
-            let (trim, rest_opt) = reply::split(&line);
-            my_client.send_line(&trim).await?;
-            if let Some(rest) = rest_opt {
-                line = rest;
-            } else {
-                break;
-            }
+        for line in reply.lines(&self.get_server(), &self.get_nick(), &self.irc.get_templates()) {
+            self.send_line(&line).await?;
         }
         Ok(ircReply::None)
     }
 
+    /* for replies to something done to this user rather than by them, e.g. SAJOIN */
+    pub async fn send_replies(self: &Arc<Self>, replies: ClientReplies) -> Result<(), GenError> {
+        for reply in replies {
+            match reply {
+                Ok(ircReply::None) => (),
+                Ok(reply) => { self.send_rpl(reply).await?; },
+                Err(err) => { self.send_err(err).await?; },
+            }
+        }
+        Ok(())
+    }
+
     pub async fn send_line(self: &Arc<Self>, line: &str) -> Result<ircReply, GenError> { /* GDB++ */
-        let my_client = self.fetch_client()?;
-        /* passing to an async fn and awaiting on it is gonna
-         * cause lifetime problems with a &str... */
-        my_client.send_line(line).await?;
+        self.send_shared(&client::shared_line(line)).await
+    }
+
+    /* for fanning the same line out to lots of users, see client::shared_line().
+     * goes to every connection attached to the user */
+    pub async fn send_shared(self: &Arc<Self>, line: &Arc<str>) -> Result<ircReply, GenError> {
+        self.queue_shared(line)
+    }
+
+    pub fn queue_shared(self: &Arc<Self>, line: &Arc<str>) -> Result<ircReply, GenError> {
+        if self.buffer(line) {
+            return Ok(ircReply::None);
+        }
+        self.fetch_client()?;
+        for client in self.get_clients() {
+            client.queue_shared(line)?;
+        }
         Ok(ircReply::None)
     }
 
     pub fn upgrade(weak_ptr: &Weak<Self>, nick: &str) -> Result<Arc<Self>, GenError> { /* GDB+++ */
-        if let Some(good_ptr) = Weak::upgrade(&weak_ptr) {
+        if let Some(good_ptr) = Weak::upgrade(weak_ptr) {
             Ok(good_ptr)
         } else {
             Err(GenError::DeadUser(nick.to_string()))
@@ -287,11 +806,56 @@ pub struct ProtoUser {
     real_name: Option<String>,
 }
 
+impl ProtoUser {
+    pub fn get_nick(&self) -> Option<String> {
+        self.nick.clone()
+    }
+
+    pub fn get_username(&self) -> Option<String> {
+        self.username.clone()
+    }
+}
+
+/* all the shared state lives behind plain std mutexes, which is fine
+ * as long as they're only held for a quick look or update and never
+ * across an .await (the guards aren't Send, so spawn() won't let that
+ * compile anyway). where more than one is needed at once they're always
+ * taken in this order, so nick changes can't deadlock against joins,
+ * parts or cleanup running on another task:
+ *   Core::namespace -> User::channel_list
+ * everything else (nick, flags, clients...) is a leaf and must not have
+ * another lock taken while it's held. channels don't lock at all, each
+ * one is a task that owns its state (see chan.rs), and its jobs only
+ * ever take User::channel_list on their own */
 #[derive(Debug)]
 pub struct Core {
+    config: Config,
+    resolver: Resolver,
+    pub accounts: AccountStore,
+    /* None unless [webhooks] enabled */
+    pub webhooks: Option<Webhooks>,
     namespace: Mutex<HashMap<String, NamedEntity>>,
     clients: Mutex<HashMap<u64, Weak<Client>>>,
+    /* always_on users with no connection, nothing else keeps them alive */
+    sessions: Mutex<HashMap<u64, Arc<User>>>,
     id_counter: Mutex<u64>, //servers: Mutex<HashMap<u64, Arc<Server>>>,
+    conn_throttle: Mutex<ConnThrottle>,
+    pending: PendingLimit,
+    per_ip: PendingLimit,
+    resvs: Mutex<Vec<Resv>>,
+    klines: Mutex<Vec<Kline>>,
+    spamfilter: Spamfilter,
+    censor: RwLock<Arc<Censor>>,
+    templates: RwLock<Arc<Templates>>,
+    aliases: RwLock<Arc<Aliases>>,
+    /* [services] sasl, can be flipped by REHASH */
+    sasl: AtomicBool,
+    modules: RwLock<Vec<Arc<dyn Module>>>,
+    command_stats: Mutex<BTreeMap<String, CommandStats>>,
+    memory: Arc<Memory>,
+    link: RwLock<Option<Arc<Link>>>,
+    /* the connection we last dialed out to services on, [link] connect */
+    link_dialed: Mutex<Option<u64>>,
     hostname: String,
     version: String,
     date: String,
@@ -301,20 +865,54 @@ pub struct Core {
 
 impl Core {
     // init hash tables
-    pub fn new(hostname: String, version: String) -> Arc<Self> {
+    pub fn new(hostname: String, version: String, config: Config, accounts: AccountStore) -> Arc<Self> {
         let clients = Mutex::new(HashMap::new());
         //let servers  = Mutex::new(HashMap::new());
         let namespace = Mutex::new(HashMap::new());
         let id_counter = Mutex::new(0);
+        let conn_throttle = Mutex::new(ConnThrottle::new(
+            config.limits.connect_max,
+            Duration::from_secs(config.limits.connect_window),
+        ));
+        let resvs = Mutex::new(config.resv.iter().map(Resv::from).collect());
+        let spamfilter = Spamfilter::new(&config.spamfilter);
+        let censor = RwLock::new(Arc::new(Censor::new(&config.censor)));
+        let templates = RwLock::new(Arc::new(Templates::new(&config.replies)));
+        let aliases = RwLock::new(Arc::new(Aliases::new(&config.aliases)));
+        let resolver = Resolver::new(&config.dns);
+        let pending = PendingLimit::new(config.limits.unregistered_max);
+        let per_ip = PendingLimit::new(config.limits.clients_per_ip);
+        let sasl = AtomicBool::new(config.services.sasl);
+        let webhooks = Webhooks::start(&config.webhooks, &accounts);
         Arc::new(Core {
+            config,
+            resolver,
+            accounts,
+            webhooks,
             clients,
+            sessions: Mutex::new(HashMap::new()),
             namespace, // combined nick and channel HashMap
             id_counter, //servers
+            conn_throttle,
+            pending,
+            per_ip,
+            resvs,
+            klines: Mutex::new(Vec::new()),
+            spamfilter,
+            censor,
+            templates,
+            aliases,
+            sasl,
+            modules: RwLock::new(Vec::new()),
+            command_stats: Mutex::new(BTreeMap::new()),
+            memory: Arc::new(Memory::default()),
+            link: RwLock::new(None),
+            link_dialed: Mutex::new(None),
             hostname,
             version,
             date: Utc::now().to_rfc2822(),
             user_modes: String::from(USER_MODES),
-            chan_modes: String::from(CHAN_MODES)
+            chan_modes: chan::all_modes()
         })
     }
 
@@ -350,16 +948,407 @@ impl Core {
         ret
     }
 
+    /* registered channels come back at startup, empty but for their
+     * modes, topic, bans and access list */
+    pub async fn restore_channels(self: &Arc<Self>) -> Result<(), GenError> {
+        for reg in self.accounts.list_channels().await? {
+            let chan = Arc::new(Channel::restore(self, &reg));
+            self.insert_name(&reg.name, NamedEntity::Chan(chan))?;
+            debug!("restored registered channel {}", reg.name);
+        }
+        Ok(())
+    }
+
+    /* called by a channel's task once it's empty, the id check stops it
+     * taking out a newer channel that's since been made with the same name */
+    pub fn remove_chan(&self, name: &str, id: u64) -> bool {
+        let mut hashmap = self.namespace.lock().unwrap();
+        if !matches!(hashmap.get(name), Some(NamedEntity::Chan(chan)) if chan.get_id() == id) {
+            return false;
+        }
+        hashmap.remove(name);
+        debug!("removed empty channel {} from hashmap, size = {}", name, hashmap.len());
+        true
+    }
+
     pub fn get_host(&self) -> String {
         self.hostname.clone()
     }
 
+    pub fn get_config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn get_resolver(&self) -> &Resolver {
+        &self.resolver
+    }
+
+    pub fn add_module(&self, module: Arc<dyn Module>) {
+        debug!("loaded module {}", module.name());
+        self.modules.write().unwrap().push(module);
+    }
+
+    /* a copy, so nothing's held while the hooks run */
+    pub fn get_modules(&self) -> Vec<Arc<dyn Module>> {
+        self.modules.read().unwrap().clone()
+    }
+
+    /* on_chan_event() for every module, event is only put together if
+     * there's a module to give it to */
+    pub fn chan_event(self: &Arc<Self>, chan: &str, event: impl FnOnce() -> ChanEvent) {
+        let modules = self.get_modules();
+        if modules.is_empty() {
+            return;
+        }
+        let event = event();
+        for module in modules {
+            module.on_chan_event(self, chan, &event);
+        }
+    }
+
+    pub fn count_command(&self, cmd: &str, time: Duration) {
+        let mut command_stats = self.command_stats.lock().unwrap();
+        let stats = command_stats.entry(cmd.to_string()).or_default();
+        stats.count += 1;
+        stats.time += time;
+    }
+
+    /* in alphabetical order */
+    pub fn get_command_stats(&self) -> Vec<(String, CommandStats)> {
+        self.command_stats.lock().unwrap().iter().map(|(cmd, stats)| (cmd.clone(), stats.clone())).collect()
+    }
+
+    pub fn get_memory(&self) -> &Arc<Memory> {
+        &self.memory
+    }
+
+    /* the services link, if it's up */
+    pub fn get_link(&self) -> Option<Arc<Link>> {
+        self.link.read().unwrap().clone()
+    }
+
+    pub fn set_link(&self, link: Option<Arc<Link>>) {
+        *self.link.write().unwrap() = link;
+    }
+
+    /* connection id `id` is one we dialed, it's had our PASS and SERVER */
+    pub fn set_link_dialed(&self, id: u64) {
+        *self.link_dialed.lock().unwrap() = Some(id);
+    }
+
+    pub fn is_link_dialed(&self, id: u64) -> bool {
+        *self.link_dialed.lock().unwrap() == Some(id)
+    }
+
+    pub fn get_spamfilter(&self) -> &Spamfilter {
+        &self.spamfilter
+    }
+
+    pub fn get_censor(&self) -> Arc<Censor> {
+        Arc::clone(&self.censor.read().unwrap())
+    }
+
+    pub fn set_censor(&self, censor: Censor) {
+        *self.censor.write().unwrap() = Arc::new(censor);
+    }
+
+    pub fn get_templates(&self) -> Arc<Templates> {
+        Arc::clone(&self.templates.read().unwrap())
+    }
+
+    pub fn set_templates(&self, templates: Templates) {
+        *self.templates.write().unwrap() = Arc::new(templates);
+    }
+
+    pub fn get_aliases(&self) -> Arc<Aliases> {
+        Arc::clone(&self.aliases.read().unwrap())
+    }
+
+    pub fn set_aliases(&self, aliases: Aliases) {
+        *self.aliases.write().unwrap() = Arc::new(aliases);
+    }
+
+    /* [limits] targmax, Err if targets is a longer list than cmd allows */
+    pub fn check_targets(&self, cmd: &str, targets: &str) -> Result<(), ircError> {
+        match self.config.limits.targmax.get(cmd) {
+            Some(&max) if max > 0 && targets.split(',').count() > max => Err(ircError::TooManyTargets(cmd.to_string(), max)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn sasl_enabled(&self) -> bool {
+        self.sasl.load(Ordering::Relaxed)
+    }
+
+    /* true if that was a change */
+    pub fn set_sasl(&self, enabled: bool) -> bool {
+        self.sasl.swap(enabled, Ordering::Relaxed) != enabled
+    }
+
+    /* the ones still in force, expired ones get dropped on the way */
+    pub fn get_klines(&self) -> Vec<Kline> {
+        let mut klines = self.klines.lock().unwrap();
+        klines.retain(|kline| !kline.expired());
+        klines.clone()
+    }
+
+    pub fn find_kline(&self, username: &str, host: &str, ip: &IpAddr) -> Option<Kline> {
+        self.get_klines().into_iter().find(|kline| kline.matches(username, host, ip))
+    }
+
+    /* a new K-line for a mask we already have replaces the old one */
+    pub fn add_kline(&self, kline: Kline) {
+        let mut klines = self.klines.lock().unwrap();
+        klines.retain(|old| !old.mask.eq_ignore_ascii_case(&kline.mask));
+        klines.push(kline);
+    }
+
+    pub fn get_resvs(&self) -> Vec<Resv> {
+        self.resvs.lock().unwrap().clone()
+    }
+
+    pub fn find_resv(&self, name: &str) -> Option<Resv> {
+        self.resvs.lock().unwrap().iter().find(|resv| resv.matches(name)).cloned()
+    }
+
+    /* false if the exact mask is already there */
+    pub fn add_resv(&self, resv: Resv) -> bool {
+        let mut resvs = self.resvs.lock().unwrap();
+        if resvs.iter().any(|old| old.mask.eq_ignore_ascii_case(&resv.mask)) {
+            return false;
+        }
+        resvs.push(resv);
+        true
+    }
+
+    pub fn remove_resv(&self, mask: &str) -> bool {
+        let mut resvs = self.resvs.lock().unwrap();
+        let before = resvs.len();
+        resvs.retain(|resv| !resv.mask.eq_ignore_ascii_case(mask));
+        resvs.len() != before
+    }
+
+    /* called from the accept loops for every new connection */
+    pub fn allow_connection(&self, ip: IpAddr) -> bool {
+        self.conn_throttle.lock().unwrap().check(ip)
+    }
+
+    /* None if ip already has too many connections still registering */
+    pub fn reserve_unregistered(&self, ip: IpAddr) -> Option<PendingSlot> {
+        self.pending.reserve(ip)
+    }
+
+    /* None if ip already has [limits] clients_per_ip registered clients */
+    pub fn reserve_client(&self, ip: IpAddr) -> Option<PendingSlot> {
+        self.per_ip.reserve(ip)
+    }
+
+    /* matches something in [limits] clients_per_ip_exempt */
+    pub fn ip_exempt(&self, ip: IpAddr) -> bool {
+        let addr = ip.to_string();
+        self.config.limits.clients_per_ip_exempt.iter().any(|mask| mask::matches(mask, &addr))
+    }
+
+    /* every live user, the namespace lock is dropped before upgrading */
+    pub fn get_users(&self) -> Vec<Arc<User>> {
+        let users: Vec<Weak<User>> = self.namespace
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|ent| match ent {
+                NamedEntity::User(user) => Some(Weak::clone(user)),
+                _ => None,
+            }).collect();
+        users.iter().filter_map(Weak::upgrade).collect()
+    }
+
+    /* with [services] multiclient, the local user already logged in to
+     * the account client authenticated to with SASL, for it to attach to */
+    pub fn find_session(&self, client: &Client) -> Option<Arc<User>> {
+        if !self.config.services.multiclient {
+            return None;
+        }
+        let account = client.get_sasl().get_account()?;
+        self.find_users_by_account(&account).into_iter().find(|user| !user.is_remote())
+    }
+
+    /* user's last connection has gone, with [services] always_on it stays
+     * on detached if it's logged in. true if it's being kept */
+    pub fn hold_session(&self, user: &Arc<User>) -> bool {
+        let services = &self.config.services;
+        if !services.multiclient || !services.always_on || user.is_remote() || user.get_account().is_none() {
+            return false;
+        }
+        debug!("holding on to {} with no client attached", user.get_nick());
+        user.detach_session();
+        self.sessions.lock().unwrap().insert(user.get_id(), Arc::clone(user));
+        true
+    }
+
+    fn release_session(&self, id: u64) -> Option<Arc<User>> {
+        self.sessions.lock().unwrap().remove(&id)
+    }
+
+    pub fn find_users_by_account(&self, account: &str) -> Vec<Arc<User>> {
+        self.get_users()
+            .into_iter()
+            .filter(|user| match user.get_account() {
+                Some(acc) => acc.eq_ignore_ascii_case(account),
+                None => false,
+            }).collect()
+    }
+
+    /* server notices for opers, e.g. DNSBL hits */
+    pub async fn notice_opers(&self, text: &str) {
+        for oper in self.get_users().iter().filter(|user| user.is_oper()) {
+            self.server_notice(oper, &format!("*** Notice -- {}", text)).await;
+        }
+    }
+
+    /* tell everyone we're going down and drop them all, the ERROR line
+     * goes out from each client's own read loop as it exits */
+    pub async fn shutdown(&self, reason: &str) {
+        for client in self.get_clients() {
+            let line = format!(":{} NOTICE {} :*** Server shutting down: {}", self.hostname, client.get_nick_or_star(), reason);
+            if let Err(err) = client.send_line(&line).await {
+                debug!("failed to send shutdown notice to client {}: {}", client.get_id(), err);
+            }
+            client.disconnect(reason);
+        }
+    }
+
+    /* GLOBOPS from here or OPERWALL from the link, opers only */
+    pub async fn notice_globops(&self, from: &str, text: &str) {
+        for oper in self.get_users().iter().filter(|user| user.is_oper() && !user.is_remote()) {
+            self.server_notice(oper, &format!("*** Global -- from {}: {}", from, text)).await;
+        }
+    }
+
+    /* a NICK to the user and once to everyone sharing a channel with them */
+    pub async fn notify_nick(&self, user: &Arc<User>, old_prefix: &str) {
+        let event = event::Shared::new(Event::Nick { prefix: old_prefix.to_string(), nick: user.get_nick() });
+        let mut seen = HashSet::new();
+        seen.insert(user.get_id());
+        if let Err(err) = user.send_event(&event).await {
+            debug!("couldn't tell {} about their new nick: {}", user.get_nick(), err);
+        }
+        for chan in user.get_channel_list().iter().filter_map(Weak::upgrade) {
+            for member in chan.gen_user_ptr_vec().await {
+                if !seen.insert(member.get_id()) || member.is_remote() {
+                    continue;
+                }
+                if let Err(err) = member.send_event(&event).await {
+                    debug!("couldn't send NICK to {}: {}", member.get_nick(), err);
+                }
+            }
+        }
+    }
+
+    pub async fn server_notice(&self, user: &Arc<User>, text: &str) {
+        let line = format!(":{} NOTICE {} :{}", self.hostname, user.get_nick(), text);
+        if let Err(err) = user.send_line(&line).await {
+            debug!("failed to send server notice to {}: {}", user.get_nick(), err);
+        }
+    }
+
+    /* NickServ IDENTIFY/REGISTER and SASL on an already registered
+     * client all end up here */
+    pub async fn login(&self, user: &Arc<User>, account: &str) -> Result<(), GenError> {
+        debug!("user {} logged in as {}", user.get_nick(), account);
+        user.set_account(Some(account.to_string()));
+        user.send_rpl(ircReply::LoggedIn(user.get_prefix(), account.to_string())).await?;
+        self.apply_vhost(user).await?;
+        if self.enforcing_nicks() && self.account_nick_free(user, account) {
+            let old_prefix = user.get_prefix();
+            user.change_nick(account)?;
+            self.notify_nick(user, &old_prefix).await;
+        }
+        Ok(())
+    }
+
+    /* [services] enforce_nick, there's nothing to enforce with a link
+     * since its services have the accounts */
+    pub fn enforcing_nicks(&self) -> bool {
+        self.config.services.enforce_nick && self.config.link.is_none()
+    }
+
+    /* whether the account name can be given to user as a nick,
+     * false if they're on it already */
+    fn account_nick_free(&self, user: &User, account: &str) -> bool {
+        !user.get_nick().eq_ignore_ascii_case(account) && rfc::valid_nick(account) && self.get_name(account).is_none()
+    }
+
+    /* Some(error) if enforce_nick is on and nick is someone else's account */
+    pub async fn check_protected_nick(&self, nick: &str, account: Option<&str>) -> Result<Option<ircError>, GenError> {
+        if !self.enforcing_nicks() {
+            return Ok(None);
+        }
+        match self.accounts.get(nick).await? {
+            Some(owner) if !account.is_some_and(|acc| acc.eq_ignore_ascii_case(&owner.name)) => {
+                Ok(Some(ircError::UnavailResource(nick.to_string(), "registered nick, log in to use it".to_string())))
+            },
+            _ => Ok(None),
+        }
+    }
+
+    pub async fn logout(&self, user: &Arc<User>) -> Result<(), GenError> {
+        user.set_account(None);
+        if user.vhost.lock().unwrap().is_some() {
+            user.set_vhost(None).await?;
+        }
+        user.send_rpl(ircReply::LoggedOut(user.get_prefix())).await?;
+        Ok(())
+    }
+
+    /* back to the account's vhost, or the real host, for an oper who
+     * dropped their +o while showing the oper block's cloak */
+    pub async fn uncloak_oper(&self, user: &Arc<User>) -> Result<(), GenError> {
+        if user.is_oper() || !user.take_oper_cloak() {
+            return Ok(());
+        }
+        if !self.apply_vhost(user).await? {
+            user.set_vhost(None).await?;
+        }
+        if let Some(link) = self.get_link() {
+            link.chghost(user);
+        }
+        Ok(())
+    }
+
+    /* look up the vhost assigned to the user's account and switch to it,
+     * returns false if there was nothing to apply */
+    pub async fn apply_vhost(&self, user: &Arc<User>) -> Result<bool, GenError> {
+        let vhost = match user.get_account() {
+            Some(account) => self.accounts.get(&account).await?.and_then(|acc| acc.vhost),
+            None => None,
+        };
+        match vhost {
+            Some(vhost) => {
+                user.set_vhost(Some(vhost)).await?;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
     pub fn get_client(&self, id: &u64) -> Option<Weak<Client>> {
         self.clients
             .lock()
             .unwrap()
             .get(id)
-            .map(|cli| Weak::clone(cli))
+            .map(Weak::clone)
+    }
+
+    /* every live connection, registered or not, in connection order */
+    pub fn get_clients(&self) -> Vec<Arc<Client>> {
+        let mut clients: Vec<Arc<Client>> = self.clients
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        clients.sort_by_key(|client| client.get_id());
+        clients
     }
 
     pub fn remove_client(&self, id: &u64) -> Option<Weak<Client>> {
@@ -399,7 +1388,7 @@ impl Core {
         let mut ret = Vec::new();
         for ent in mutex_lock.values() {
             if let NamedEntity::Chan(chan) = ent {
-                ret.push(Arc::clone(&chan));
+                ret.push(Arc::clone(chan));
             }
         }
         ret
@@ -413,13 +1402,6 @@ impl Core {
         }; ret
     }
 
-    pub fn get_list_reply(&self) -> Vec<(Arc<Channel>, Option<ChanTopic>)> {
-        let vector = self.list_chans_ptr();
-        let mut out_vect = Vec::new();
-        for item in vector {
-            out_vect.push((Arc::clone(&item), item.get_topic()));
-        } out_vect
-    }
 
     pub fn get_umodes(&self) -> String {
         self.user_modes.clone()
@@ -439,6 +1421,9 @@ impl Core {
         chan.rm_user(user, part_msg).await.map_err(|_e|{
                 ircError::NotOnChannel(chanmask.to_string())
             })?;
+        if let Some(link) = self.get_link() {
+            link.part(user, &chan.get_name(), part_msg);
+        }
         Ok(ircReply::None)
     }
 
@@ -449,23 +1434,71 @@ impl Core {
             return Ok(replies);
         }
         let nick = user.get_nick();
-        match self.get_chan(chanmask) {
-            Ok(chan) => {
-                /* need to check if user is already in chan */
-                if chan.is_joined(&nick) {
-                    return Ok(replies);
+        if !user.is_oper() {
+            if let Some(resv) = self.find_resv(chanmask) {
+                replies.push(Err(ircError::UnavailResource(chanmask.to_string(), resv.reason)));
+                return Ok(replies);
+            }
+        }
+        /* MAXCHANNELS, only counts against joining somewhere new */
+        let max_channels = self.config.limits.max_channels;
+        let joined = match self.get_chan(chanmask) {
+            Ok(chan) => chan.is_joined(&nick).await,
+            Err(_) => false,
+        };
+        if max_channels > 0 && user.get_n_channels() >= max_channels && !joined {
+            replies.push(Err(ircError::TooManyChannels(chanmask.to_string())));
+            return Ok(replies);
+        }
+        if let Ok(chan) = self.get_chan(chanmask) {
+            if !joined && chan.is_banned(user).await {
+                replies.push(Err(ircError::BannedFromChan(chanmask.to_string())));
+                return Ok(replies);
+            }
+            if !joined && chan.get_modes().await.secure_only && !user.is_secure() {
+                replies.push(Err(ircError::SecureOnlyChan(chanmask.to_string())));
+                return Ok(replies);
+            }
+        }
+        for module in self.get_modules() {
+            if let Err(err) = module.on_join(self, user, chanmask) {
+                replies.push(Err(err));
+                return Ok(replies);
+            }
+        }
+        let replies = self.force_join(chanmask, user).await?;
+        if !joined {
+            readmarker::on_join(self, user, chanmask).await?;
+        }
+        Ok(replies)
+    }
+
+    /* the join itself with none of the checks, SAJOIN comes straight here */
+    pub async fn force_join(self: &Arc<Core>, chanmask: &str, user: &Arc<User>) -> Result<ClientReplies, GenError> {
+        /* a channel whose last user just left is already out of the
+         * namespace by the time add_user() says so, so go round again */
+        loop {
+            let (chan, created) = match self.get_chan(chanmask) {
+                Ok(chan) => (chan, false),
+                Err(_) => {
+                    let chan = Arc::new(Channel::new(self, chanmask));
+                    self.insert_name(chanmask, NamedEntity::Chan(Arc::clone(&chan)))?; // what happens if this error does occur?
+                    (chan, true)
                 }
-                chan.add_user(user, ChanFlags::None).await
-            },
-            Err(_) => {
-                let chan = Arc::new(Channel::new(&self, chanmask));
-                self.insert_name(chanmask, NamedEntity::Chan(Arc::clone(&chan)))?; // what happens if this error does occur?
-                chan.add_user(user, ChanFlags::Op).await
+            };
+            let flags = if created { ChanFlags::Op } else { ChanFlags::None };
+            if let Some(replies) = chan.add_user(user, flags).await? {
+                if let Some(link) = self.get_link() {
+                    link.join(user, &chan, created);
+                }
+                return Ok(replies);
             }
         }
     }
 
-    /* don't want anyone to take our nick while we're in the middle of faffing around... */
+    /* don't want anyone to take our nick while we're in the middle of faffing around...
+     * holds namespace and channel_list, the channels get told about the new
+     * nick while they're held so it's in their queue ahead of anything else */
     pub fn try_nick_change(&self, user: &User, new_nick: &str) -> Result<ircReply, GenError> {
         let mut big_fat_mutex_lock = self.namespace.lock().unwrap();
         let mut chanlist_mutex_lock = user.channel_list.lock().unwrap();
@@ -480,13 +1513,12 @@ impl Core {
 
                 /* update User struct */
                 *user.nick.lock().unwrap() = nick;
+                *user.nick_ts.lock().unwrap() = Utc::now().timestamp();
 
                 /* update channels list */
                 for (chan_name, chan_wptr) in chanlist_mutex_lock.clone().iter() {
-                    if let Some(chan) = Weak::upgrade(&chan_wptr) {
-                        if let Err(err) = chan.update_nick(&old_nick, &new_nick) {
-                            warn!("try to update nick {} in chan {} despite not being in chan, error: {}", &chan_name, &old_nick, err);
-                        }
+                    if let Some(chan) = Weak::upgrade(chan_wptr) {
+                        chan.update_nick(&old_nick, new_nick);
                     } else {
                         debug!("try_nick_change(): can't upgrade pointer to {}, deleting key", chan_name);
                         chanlist_mutex_lock.remove(chan_name);
@@ -519,32 +1551,30 @@ impl Core {
             nick.to_string(),
             username,
             real_name,
-            host.clone(),
+            host,
             server,
             client,
+            None,
         );
         self.insert_name(&nick, NamedEntity::User(Arc::downgrade(&user)))?;
         Ok(user)
     }
 
     /* think a bit more about what this method is doing and what it's for */
-    fn _search_user_chans(&self, nick: &str, purge: bool) -> Vec<String> {
+    async fn _search_user_chans(&self, nick: &str, purge: bool) -> Vec<String> {
         let mut channels = Vec::new();
         let mut chan_strings = Vec::new();
         for value in self.namespace.lock().unwrap().values() {
             if let NamedEntity::Chan(chan_ptr) = value {
-                channels.push(Arc::clone(&chan_ptr));
+                channels.push(Arc::clone(chan_ptr));
             }
         }
 
         for channel in channels.iter() {
-            if channel.is_joined(nick) {
+            if channel.is_joined(nick).await {
                 chan_strings.push(channel.get_name());
                 if purge {
-                    channel.rm_key(&nick);
-                    if channel.is_empty() && self.remove_name(&channel.get_name()).is_ok() {
-                        debug!("_search_user_chans(): remove channel {} from IRC HashMap", &channel.get_name());
-                    }
+                    channel.rm_key(nick);
                 }
             }
         }
@@ -552,12 +1582,12 @@ impl Core {
         chan_strings
     }
 
-    pub fn search_user_chans(&self, nick: &str) -> Vec<String> {
-        self._search_user_chans(nick, false)
+    pub async fn search_user_chans(&self, nick: &str) -> Vec<String> {
+        self._search_user_chans(nick, false).await
     }
 
-    pub fn search_user_chans_purge(&self, nick: &str) -> Vec<String> {
-        self._search_user_chans(nick, true)
+    pub async fn search_user_chans_purge(&self, nick: &str) -> Vec<String> {
+        self._search_user_chans(nick, true).await
     }
 }
 
@@ -569,50 +1599,232 @@ pub enum MsgType {
 
 pub async fn command(irc: &Arc<Core>, client: &Arc<Client>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
     let registered = client.is_registered();
-    let cmd = params.command.to_ascii_uppercase();
+    let params = irc.get_aliases().expand(params);
+
+    for module in irc.get_modules() {
+        if let Some(handled) = module.handle_command(irc, client, &params) {
+            return handled.await;
+        }
+    }
+
+    let command = match params.command.parse::<Command>() {
+        Ok(command) => command,
+        /* anything unknown is left out of the stats, or the table could be filled with junk */
+        Err(err) => return gef!(err),
+    };
+    let start = Instant::now();
+    let is_oper = || client.get_user().is_some_and(|user| user.is_oper());
+    let result = match command.check(registered, is_oper, params.opt_params.len()) {
+        Err(err) => gef!(err),
+        Ok(()) => dispatch(irc, client, command, params).await,
+    };
+    irc.count_command(command.name(), start.elapsed());
+    result
+}
+
+/* command.check() saw a registered user, but the client type can change
+ * under us before the handler gets it (a KILL leaving it Dead, say), so
+ * this is looked up again and the command refused if it's gone */
+fn registered_user(client: &Client) -> Result<Arc<User>, GenError> {
+    client.get_user().ok_or_else(|| GenError::from(ircError::NotRegistered))
+}
 
-    match &cmd[..] {
-        "NICK" => nick(irc, client, params).await,
-        "USER" => user(irc, client, params).await,
-        "PRIVMSG" if registered => msg(irc, &client.get_user(), params, false).await,
-        "NOTICE" if registered => msg(irc, &client.get_user(), params, true).await,
-        "JOIN" if registered => join(irc, &client.get_user(), params).await,
-        "PART" if registered => part(irc, &client.get_user(), params).await,
-        "TOPIC" if registered => topic(irc, &client.get_user(), params).await,
-        "LIST" if registered => list(irc).await,
-        "PART" | "JOIN" | "PRIVMSG" | "NOTICE" | "TOPIC" | "LIST" if !registered => gef!(ircError::NotRegistered),
-        _ => gef!(ircError::UnknownCommand(params.command.to_string())),
+/* by the time we get here command.check() has passed */
+async fn dispatch(irc: &Arc<Core>, client: &Arc<Client>, command: Command, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    match command {
+        Command::Cap => cap::command(irc, client, params).await,
+        Command::Authenticate => sasl::authenticate(irc, client, params).await,
+        Command::Webirc => webirc::command(irc, client, params).await,
+        Command::Pass => link::pass(client, params),
+        Command::Server => link::accept(irc, client, params).await,
+        Command::Capab => Ok(Vec::new()),
+        Command::Nick => nick(irc, client, params).await,
+        Command::User => user(irc, client, params).await,
+        Command::Quit => quit(irc, client, params).await,
+        Command::Privmsg => msg(irc, client, params, false).await,
+        Command::Notice => msg(irc, client, params, true).await,
+        Command::Oper => oper(irc, &registered_user(client)?, params).await,
+        Command::Resv => resv::resv(irc, &registered_user(client)?, params).await,
+        Command::Unresv => resv::unresv(irc, &registered_user(client)?, params).await,
+        Command::Tagmsg => tags::tagmsg(irc, &registered_user(client)?, params).await,
+        Command::Join => join(irc, &registered_user(client)?, params).await,
+        Command::Part => part(irc, &registered_user(client)?, params).await,
+        Command::Topic => topic(irc, &registered_user(client)?, params).await,
+        Command::List => list(irc, &registered_user(client)?).await,
+        Command::Names => names(irc, &registered_user(client)?, params).await,
+        Command::Audit => audit::command(irc, &registered_user(client)?, params).await,
+        Command::Check => check::command(irc, &registered_user(client)?, params).await,
+        Command::Mode => mode::command(irc, &registered_user(client)?, params).await,
+        Command::Sajoin => sajoin(irc, &registered_user(client)?, params).await,
+        Command::Sapart => sapart(irc, &registered_user(client)?, params).await,
+        Command::Samode => mode::samode(irc, &registered_user(client)?, params).await,
+        Command::Sanick => sanick(irc, &registered_user(client)?, params).await,
+        Command::Globops => globops(irc, &registered_user(client)?, params).await,
+        Command::Admin => info::admin(irc, params).await,
+        Command::Info => info::info(irc, params).await,
+        Command::Version => info::version(irc, params).await,
+        Command::Time => info::time(irc, params).await,
+        Command::Trace => trace::command(irc, &registered_user(client)?, params).await,
+        Command::Stats => stats::command(irc, &registered_user(client)?, params).await,
+        Command::Rehash => rehash(irc, &registered_user(client)?).await,
+        Command::Whois => whois::whois(irc, &registered_user(client)?, params).await,
+        Command::Who => whois::who(irc, &registered_user(client)?, params).await,
+        Command::Markread => readmarker::command(irc, &registered_user(client)?, params).await,
     }
 }
 
-pub async fn list(irc: &Core) -> Result<ClientReplies, GenError> {
-    let tuple_vector = irc.get_list_reply();
+/* REHASH, opers only. rereads the config file, but so far only the
+ * [censor] word list, [replies] and [services] sasl are taken from it,
+ * everything else needs a restart */
+pub async fn rehash(irc: &Core, user: &Arc<User>) -> Result<ClientReplies, GenError> {
     let mut replies = Vec::new();
-    for (chan, topic) in tuple_vector.iter() {
-        replies.push(Ok(ircReply::ListReply(chan.get_name(), chan.get_n_users(), topic.clone())));
+    let path = match &irc.get_config().path {
+        Some(path) => path.clone(),
+        None => {
+            irc.server_notice(user, "No config file to reload").await;
+            return Ok(replies);
+        }
+    };
+    match Config::load(&path) {
+        Ok(config) => {
+            replies.push(Ok(ircReply::Rehashing(path.clone())));
+            irc.set_censor(Censor::new(&config.censor));
+            irc.set_templates(Templates::new(&config.replies));
+            irc.set_aliases(Aliases::new(&config.aliases));
+            if irc.set_sasl(config.services.sasl) {
+                cap::notify(irc, cap::SASL, config.services.sasl).await;
+            }
+            irc.notice_opers(&format!("{} is rehashing {}", user.get_nick(), path)).await;
+        },
+        Err(err) => {
+            warn!("REHASH of {} failed: {}", path, err);
+            irc.server_notice(user, &format!("REHASH failed: {}", err)).await;
+        },
     }
-    replies.push(Ok(ircReply::EndofList));
     Ok(replies)
 }
 
-pub async fn topic(irc: &Core, user: &User, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+/* QUIT [:<reason>], the ERROR goes out as the read loop winds up */
+pub async fn quit(irc: &Arc<Core>, client: &Arc<Client>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut reason = params.opt_params.first().cloned().unwrap_or_default();
+    if !reason.is_empty() && spamfilter::enforce(irc, client, SpamTarget::Quit, "*", &reason).await {
+        reason.clear();
+    }
+    client.disconnect(&if reason.is_empty() { "Quit".to_string() } else { format!("Quit: {}", reason) });
+    Ok(Vec::new())
+}
+
+pub async fn oper(irc: &Core, user: &Arc<User>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
     let mut replies = Vec::new();
-    if params.opt_params.is_empty() {
-        replies.push(Err(ircError::NeedMoreParams("TOPIC".to_string())));
+    let (name, password) = (&params.opt_params[0], params.opt_params[1].clone());
+    let (hash, cloak) = match irc.get_config().find_oper(name) {
+        Some(block) if oper_host_allowed(block, user) => (block.password_hash.clone(), block.cloak.clone()),
+        _ => {
+            replies.push(Err(ircError::NoOperHost));
+            return Ok(replies);
+        }
+    };
+    if task::spawn_blocking(move || password::verify(&password, &hash)).await? {
+        debug!("user {} is now an oper (block {})", user.get_nick(), name);
+        user.set_oper(true);
+        if let Some(link) = irc.get_link() {
+            link.user_mode(user, "+o");
+        }
+        if let Some(cloak) = cloak {
+            user.set_vhost(Some(cloak)).await?;
+            user.flags.lock().unwrap().oper_cloak = true;
+            if let Some(link) = irc.get_link() {
+                link.chghost(user);
+            }
+        }
+        replies.push(Ok(ircReply::YoureOper));
+    } else {
+        replies.push(Err(ircError::PasswdMismatch));
+    }
+    Ok(replies)
+}
+
+fn oper_host_allowed(block: &OperBlock, user: &User) -> bool {
+    if block.hosts.is_empty() {
+        return true;
+    }
+    let (username, host) = (user.get_username(), user.get_host_string());
+    let ip = match user.first_client() {
+        Some(client) => client.get_ip(),
+        None => return false,
+    };
+    block.hosts.iter().any(|mask| mask::matches_userhost(mask, &username, &host, &ip))
+}
+
+/* channels to a LIST batch */
+const LIST_BATCH: usize = 50;
+
+/* +s channels are left out unless you're on them (or an oper). the
+ * namespace is only held long enough to copy out the channel pointers,
+ * then the replies go out LIST_BATCH channels at a time with a yield in
+ * between, so a big LIST doesn't hold anyone else up (SAFELIST) */
+pub async fn list(irc: &Core, user: &Arc<User>) -> Result<ClientReplies, GenError> {
+    let chans = irc.list_chans_ptr();
+    for batch in chans.chunks(LIST_BATCH) {
+        for chan in batch {
+            let name = chan.get_name();
+            if !user.is_oper() && !user.is_on(&name) && chan.get_modes().await.secret {
+                continue;
+            }
+            let topic = chan.get_topic().await;
+            user.send_rpl(ircReply::ListReply(name, chan.get_n_users().await, topic)).await?;
+        }
+        task::yield_now().await;
+    }
+    Ok(vec![Ok(ircReply::EndofList)])
+}
+
+/* NAMES <channel>{,<channel>}, anyone not on the channel
+ * doesn't get to see its invisible users */
+pub async fn names(irc: &Core, user: &Arc<User>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let chanmasks = match params.opt_params.first() {
+        Some(chanmasks) => chanmasks,
+        None => {
+            replies.push(Ok(ircReply::EndofNames("*".to_string())));
+            return Ok(replies);
+        }
+    };
+    if let Err(err) = irc.check_targets("NAMES", chanmasks) {
+        replies.push(Err(err));
         return Ok(replies);
     }
+    for chanmask in chanmasks.split(',') {
+        if let Ok(chan) = irc.get_chan(chanmask) {
+            let nicks: Vec<String> = chan.get_members()
+                .await
+                .iter()
+                .filter(|(member, _flags)| user.can_see(member))
+                .map(|(member, flags)| format!("{}{}", flags.prefix(), member.get_nick()))
+                .collect();
+            if !nicks.is_empty() {
+                replies.push(Ok(ircReply::NameReply(chan.get_name(), nicks)));
+            }
+        }
+        replies.push(Ok(ircReply::EndofNames(chanmask.to_string())));
+    }
+    Ok(replies)
+}
+
+pub async fn topic(irc: &Core, user: &Arc<User>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
 
     /* are ya in the chan? */
     let chanmask = params.opt_params.remove(0);
     let chan = irc.get_chan(&chanmask)?;
-    if !chan.is_joined(&user.get_nick()) {
+    if !chan.is_joined(&user.get_nick()).await {
         replies.push(Err(ircError::NotOnChannel(chanmask)));
         return Ok(replies);
     }
 
     /* just want to receive topic? */
     if params.opt_params.is_empty() {
-        if let Some(topic) = chan.get_topic() {
+        if let Some(topic) = chan.get_topic().await {
             replies.push(Ok(ircReply::Topic(chanmask.clone(), topic.text)));
             replies.push(Ok(ircReply::TopicSetBy(chanmask, topic.usermask, topic.timestamp)));
         } else {
@@ -622,8 +1834,12 @@ pub async fn topic(irc: &Core, user: &User, mut params: ParsedMsg) -> Result<Cli
     };
     
     /* set topic IF permissions allow */
-    if chan.is_op(user) {
-        chan.set_topic(&params.opt_params.remove(0), &user);
+    if !chan.get_modes().await.topic_lock || chan.is_op(user).await {
+        let text = params.opt_params.remove(0);
+        chan.set_topic(&text, user).await;
+        if let Some(link) = irc.get_link() {
+            link.topic(user, &chanmask, &text);
+        }
     } else {
         replies.push(Err(ircError::ChanOPrivsNeeded(chanmask)));
     }
@@ -632,46 +1848,144 @@ pub async fn topic(irc: &Core, user: &User, mut params: ParsedMsg) -> Result<Cli
 
 pub async fn join(irc: &Arc<Core>, user: &Arc<User>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
     let mut replies = Vec::new();
-    if params.opt_params.is_empty() {
-        replies.push(Err(ircError::NeedMoreParams("JOIN".to_string())));
-        return Ok(replies);
-    }
 
     /* JOIN can take a second argument. The format is:
      * JOIN comma,sep.,chan,list comma,sep.,key,list
      * but I'll leave key implementation til later */
     let targets = params.opt_params.remove(0);
+    if let Err(err) = irc.check_targets("JOIN", &targets) {
+        replies.push(Err(err));
+        return Ok(replies);
+    }
     for target in targets.split(',') {
-        replies.append(&mut irc.join_chan(&target, user).await?);
+        if let Err(wait) = user.throttle_join() {
+            let text = format!("*** You are joining channels too fast, please wait {} seconds before joining {}",
+                               wait.as_secs() + 1, target);
+            irc.server_notice(user, &text).await;
+            break;
+        }
+        replies.append(&mut irc.join_chan(target, user).await?);
     }
     Ok(replies)
 }
 
-pub async fn part(irc: &Arc<Core>, user: &Arc<User>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
-    let mut replies: ClientReplies = Vec::new();
-    if params.opt_params.is_empty() {
-        replies.push(Err(ircError::NeedMoreParams("PART".to_string())));
+/* SAJOIN <nick> <chan>{,<chan>}, skips bans, limits and throttles */
+pub async fn sajoin(irc: &Arc<Core>, user: &Arc<User>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let (nick, targets) = (&params.opt_params[0], &params.opt_params[1]);
+    let target_user = match irc.get_nick(nick).and_then(|weak| Weak::upgrade(&weak)) {
+        Some(target_user) => target_user,
+        None => {
+            replies.push(Err(ircError::NoSuchNick(nick.to_string())));
+            return Ok(replies);
+        }
+    };
+    for target in targets.split(',') {
+        if !rfc::valid_channel(target) {
+            replies.push(Err(ircError::NoSuchChannel(target.to_string())));
+            continue;
+        }
+        let join_replies = irc.force_join(target, &target_user).await?;
+        recipient_result(irc, target_user.send_replies(join_replies).await).await?;
+        irc.notice_opers(&format!("{} used SAJOIN to make {} join {}", user.get_nick(), target_user.get_nick(), target)).await;
+    }
+    Ok(replies)
+}
+
+/* SAPART <nick> <chan>{,<chan>} [:<reason>] */
+pub async fn sapart(irc: &Arc<Core>, user: &Arc<User>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let (nick, targets) = (&params.opt_params[0], &params.opt_params[1]);
+    let part_msg = params.opt_params.get(2).cloned().unwrap_or_default();
+    let target_user = match irc.get_nick(nick).and_then(|weak| Weak::upgrade(&weak)) {
+        Some(target_user) => target_user,
+        None => {
+            replies.push(Err(ircError::NoSuchNick(nick.to_string())));
+            return Ok(replies);
+        }
+    };
+    for target in targets.split(',') {
+        if irc.part_chan(target, &target_user, &part_msg).await.is_err() {
+            replies.push(Err(ircError::UserNotInChannel(target_user.get_nick(), target.to_string())));
+            continue;
+        }
+        irc.notice_opers(&format!("{} used SAPART to make {} part {}", user.get_nick(), target_user.get_nick(), target)).await;
+    }
+    Ok(replies)
+}
+
+/* GLOBOPS :<text>, a server notice to every oper here and OPERWALL
+ * over the link, for opers to talk among themselves */
+pub async fn globops(irc: &Arc<Core>, user: &Arc<User>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let text = &params.opt_params[0];
+    irc.notice_globops(&user.get_nick(), text).await;
+    if let Some(link) = irc.get_link() {
+        link.operwall(user, text);
+    }
+    Ok(Vec::new())
+}
+
+/* SANICK <nick> <new nick>, only for our own users. skips the nick
+ * throttle, RESVs and account protection, everyone sharing a channel
+ * with them sees the NICK as well as the user themselves */
+pub async fn sanick(irc: &Arc<Core>, user: &Arc<User>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let (nick, new_nick) = (&params.opt_params[0], &params.opt_params[1]);
+    let target_user = match irc.get_nick(nick).and_then(|weak| Weak::upgrade(&weak)) {
+        Some(target_user) if !target_user.is_remote() => target_user,
+        _ => {
+            replies.push(Err(ircError::NoSuchNick(nick.to_string())));
+            return Ok(replies);
+        }
+    };
+    if !rfc::valid_nick(new_nick) {
+        replies.push(Err(ircError::ErroneusNickname(new_nick.to_string())));
         return Ok(replies);
     }
+    if services::is_service(new_nick) && irc.get_config().link.is_none() {
+        replies.push(Err(ircError::NicknameInUse(new_nick.to_string())));
+        return Ok(replies);
+    }
+    let (old_nick, old_prefix) = (target_user.get_nick(), target_user.get_prefix());
+    if let Err(GenError::IRC(err)) = target_user.change_nick(new_nick) {
+        replies.push(Err(err));
+        return Ok(replies);
+    }
+    irc.notify_nick(&target_user, &old_prefix).await;
+    irc.server_notice(&target_user, &format!("*** Your nick was changed to {} by an operator", new_nick)).await;
+    irc.notice_opers(&format!("{} used SANICK to change {} to {}", user.get_nick(), old_nick, new_nick)).await;
+    Ok(replies)
+}
+
+pub async fn part(irc: &Arc<Core>, user: &Arc<User>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies: ClientReplies = Vec::new();
 
     let targets = params.opt_params.remove(0);
-    let part_msg = if params.opt_params.is_empty() {
+    if let Err(err) = irc.check_targets("PART", &targets) {
+        replies.push(Err(err));
+        return Ok(replies);
+    }
+    let mut part_msg = if params.opt_params.is_empty() {
         String::from("")
     } else {
         params.opt_params.remove(0)
     };
+    if !part_msg.is_empty() && spamfilter::enforce(irc, &user.fetch_client()?, SpamTarget::Part, &targets, &part_msg).await {
+        part_msg.clear();
+    }
     for target in targets.split(',') {
-        replies.push(irc.part_chan(&target, user, &part_msg).await);
+        replies.push(irc.part_chan(target, user, &part_msg).await);
     }
     Ok(replies)
 }
 
 pub async fn msg(
-    irc: &Core,
-    send_u: &Arc<User>,
+    irc: &Arc<Core>,
+    client: &Arc<Client>,
     mut params: ParsedMsg,
     notice: bool,
 ) -> Result<ClientReplies, GenError> {
+    let send_u = &registered_user(client)?;
     let mut replies = Vec::new();
     if params.opt_params.is_empty() {
         if !notice {
@@ -696,17 +2010,81 @@ pub async fn msg(
     // concatenate the remainder to one string
     let message = params.opt_params.join(" ");
     trace!("{} from user {} to {}, content: {}", cmd, send_u.get_nick(), targets, message);
+    if let Err(err) = irc.check_targets(cmd, &targets) {
+        if !notice {
+            replies.push(Err(err));
+        }
+        return Ok(replies);
+    }
+    let filter_target = if notice { SpamTarget::Notice } else { SpamTarget::Privmsg };
+    if spamfilter::enforce(irc, &send_u.fetch_client()?, filter_target, &targets, &message).await {
+        return Ok(replies);
+    }
+    let tags = tags::relay(send_u, &params.tags);
+    let ctcp = ctcp::parse(&message);
+    if let Some(ctcp) = ctcp.as_ref().filter(|ctcp| !ctcp.is_action()) {
+        if let Err(wait) = send_u.throttle_ctcp() {
+            let text = format!("*** You are sending CTCPs too fast, please wait {} seconds before sending {}",
+                               wait.as_secs() + 1, ctcp.command);
+            irc.server_notice(send_u, &text).await;
+            return Ok(replies);
+        }
+    }
 
-    // loop over targets
-    for target in targets.split(',') {
-        match irc.get_name(target) {
+    // loop over targets, the ones it went out to get echoed to any
+    // other connections attached to send_u
+    let mut delivered = Vec::new();
+    'targets: for target in targets.split(',') {
+        for module in irc.get_modules() {
+            if let Err(err) = module.on_privmsg(irc, send_u, target, &message, notice) {
+                if !notice {
+                    replies.push(Err(err));
+                }
+                continue 'targets;
+            }
+        }
+        if target.eq_ignore_ascii_case(&irc.get_host()) && !notice && irc.get_config().server.ctcp_version
+            && ctcp.as_ref().is_some_and(|ctcp| ctcp.command == "VERSION") {
+            let line = format!(":{} NOTICE {} :{}", irc.get_host(), send_u.get_nick(), ctcp::quote("VERSION", &irc.get_version()));
+            send_u.send_line(&line).await?;
+            continue;
+        }
+        if broadcast::is_broadcast(target) {
+            if let Err(err) = broadcast::send(irc, send_u, cmd, target, &message).await? {
+                if !notice {
+                    replies.push(Err(err));
+                }
+            }
+            continue;
+        }
+        if let Some(service) = services::lookup(target).filter(|_| irc.get_config().link.is_none()) {
+            /* services never answer a NOTICE, that way two bots can't loop */
+            if !notice {
+                replies.append(&mut services::privmsg(irc, send_u, service, &message).await?);
+            }
+            continue;
+        }
+        match irc.get_name(chan::split_status(target).1) {
             Some(NamedEntity::User(user_weak)) => {
                 match User::upgrade(&user_weak, target) {
                     Ok(recv_u) => {
-                        replies.push(recv_u.send_msg(&send_u, &cmd, &target, &message).await?);
+                        if let Some(err) = recv_u.refuses_msg_from(send_u) {
+                            if !notice {
+                                replies.push(Err(err));
+                            }
+                            continue;
+                        }
+                        let sent = recipient_result(irc, recv_u.send_msg(send_u, cmd, target, &message, &tags).await).await?;
+                        if let Some(reply) = sent {
+                            replies.push(reply);
+                        }
+                        if let Some(webhooks) = irc.webhooks.as_ref().filter(|_| !notice) {
+                            webhooks.private(&recv_u, send_u, &message);
+                        }
+                        delivered.push(target);
                     },
                     Err(GenError::DeadUser(nick)) => {
-                        let _res = irc.search_user_chans_purge(&nick);
+                        let _res = irc.search_user_chans_purge(&nick).await;
                         if let Err(err) = irc.remove_name(&nick) {
                             warn!("error {} removing nick {} from hash, but it doesn't exist", err, &nick)
                         }
@@ -715,15 +2093,58 @@ pub async fn msg(
                     Err(e) => return Err(e),
                 }
             },
-            Some(NamedEntity::Chan(chan))
-                => replies.push(chan.send_msg(&send_u, &cmd, &target, &message).await?),
+            Some(NamedEntity::Chan(chan)) => {
+                let reply = chan.send_msg(send_u, cmd, target, &message, &tags).await?;
+                if let (Ok(_), Some(link)) = (&reply, irc.get_link()) {
+                    if chan.get_members().await.iter().any(|(member, _flags)| member.is_remote()) {
+                        link.message(send_u, cmd, target, &message);
+                    }
+                }
+                if reply.is_ok() {
+                    if let Some(webhooks) = irc.webhooks.as_ref().filter(|_| !notice) {
+                        webhooks.channel(&chan, send_u, &message).await;
+                    }
+                    delivered.push(target);
+                }
+                replies.push(reply);
+            },
             None => replies.push(Err(ircError::NoSuchNick(target.to_string())))
         }
     }
+    for target in delivered {
+        send_u.echo(client.get_id(), cmd, target, &message).await?;
+    }
     Ok(replies)
 }
 
-pub async fn user(irc: &Core, client: &Arc<Client>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+/* what came of sending something to another user. their sendq filling
+ * up has already seen them off and a user gone stale just needs clearing
+ * away, neither is the sender's problem so they get None and carry on
+ * with the rest of their command. anything else is passed back */
+pub async fn recipient_result<T>(irc: &Core, res: Result<T, GenError>) -> Result<Option<T>, GenError> {
+    match res {
+        Ok(val) => Ok(Some(val)),
+        Err(GenError::SendQFull(id)) => {
+            debug!("client {} is over its sendq, skipped", id);
+            Ok(None)
+        },
+        Err(GenError::Detached(_nick)) => Ok(None),
+        Err(GenError::DeadClient(user)) => {
+            client::attempt_cleanup(irc, user).await;
+            Ok(None)
+        },
+        Err(GenError::DeadUser(nick)) => {
+            let _res = irc.search_user_chans_purge(&nick).await;
+            if let Err(err) = irc.remove_name(&nick) {
+                warn!("error {} removing nick {} from hash, but it doesn't exist", err, &nick)
+            }
+            Ok(None)
+        },
+        Err(err) => Err(err),
+    }
+}
+
+pub async fn user(irc: &Arc<Core>, client: &Arc<Client>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
     // a USER command should have exactly four parameters
     // <username> <hostname> <servername> <realname>,
     // though we ignore the middle two unless a server is
@@ -733,58 +2154,48 @@ pub async fn user(irc: &Core, client: &Arc<Client>, params: ParsedMsg) -> Result
     if args.len() != 4 {
         return gef!(ircError::NeedMoreParams("USER".to_string()));
     }
-    let username = args[0].clone();
+    /* there's no ident (RFC 1413) lookup, so the username is only ever
+     * what the client told us and gets a ~ to say so, like everywhere
+     * else. K-lines and bans on ~* and the like rely on that */
+    let username = args[0].trim_start_matches('~');
+    if !rfc::valid_user(username) {
+        replies.push(Err(ircError::InvalidUsername));
+        return Ok(replies);
+    }
+    let username = format!("~{}", username);
     let real_name = args[3].clone();
 
-    let result = match client.get_client_type() {
-        ClientType::Dead => None,
+    match client.get_client_type() {
+        ClientType::Dead => (),
         ClientType::Unregistered => {
             // initiate handshake
-            Some(ClientType::ProtoUser(Arc::new(Mutex::new(ProtoUser {
+            client.set_client_type(ClientType::ProtoUser(Arc::new(Mutex::new(ProtoUser {
                 nick: None,
                 username: Some(username),
                 real_name: Some(real_name),
-            }))))
+            }))));
         }
-        ClientType::User(_user_ref) => {
+        ClientType::User(_) | ClientType::Server(_) => {
             // already registered! can't change username
             replies.push(Err(ircError::AlreadyRegistred));
             return Ok(replies);
         }
         ClientType::ProtoUser(proto_user_ref) => {
-            // got nick already? if so, complete registration
-            let proto_user = proto_user_ref.lock().unwrap();
-            if let Some(nick) = &proto_user.nick {
-                // had nick already, complete registration
-                let ret = Some(ClientType::User(
-                    irc.register(client, nick.clone(), username.clone(), real_name)?, // propagate the error if it goes wrong
-                ));
-                replies.push(Ok(ircReply::Welcome(nick.clone(), username.clone(), client.get_host_string())));
-                replies.push(Ok(ircReply::YourHost(irc.get_host(), irc.get_version())));
-                replies.push(Ok(ircReply::Created(irc.get_date())));
-                replies.push(Ok(ircReply::MyInfo(irc.get_host(), irc.get_version(), irc.get_umodes(), irc.get_chanmodes())));
-                ret
-            } else {
-                // don't see an error in the irc file,
-                // except the one if you're already reg'd
-                // NOTICE_BLOCKY
-                proto_user_ref.lock().unwrap().username = Some(username);
-                proto_user_ref.lock().unwrap().real_name = Some(real_name);
-                None
-            }
-        } //ClientType::Server(_server_ref) => (None, None, false)
-    };
-
-    if let Some(new_client_type) = result {
-        client.set_client_type(new_client_type);
+            let mut proto_user = proto_user_ref.lock().unwrap();
+            proto_user.username = Some(username);
+            proto_user.real_name = Some(real_name);
+        }
     }
+
+    // got nick already? if so, complete registration
+    replies.append(&mut register_if_ready(irc, client).await?);
     Ok(replies)
 }
 
-pub async fn nick(irc: &Core, client: &Arc<Client>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+pub async fn nick(irc: &Arc<Core>, client: &Arc<Client>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
     let mut replies = Vec::new();
     let nick;
-    if let Some(n) = params.opt_params.iter().next() {
+    if let Some(n) = params.opt_params.first() {
         nick = n.to_string();
     } else {
         replies.push(Err(ircError::NeedMoreParams("NICK".to_string())));
@@ -797,61 +2208,207 @@ pub async fn nick(irc: &Core, client: &Arc<Client>, params: ParsedMsg) -> Result
         return Ok(replies);
     }
 
-    // is this nick already taken?
-    if let Some(_hit) = irc.get_name(&nick) {
+    // reserved nicks are off limits to all but opers
+    if !client.is_oper() {
+        if let Some(resv) = irc.find_resv(&nick) {
+            replies.push(Err(ircError::UnavailResource(nick, resv.reason)));
+            return Ok(replies);
+        }
+    }
+
+    if spamfilter::enforce(irc, client, SpamTarget::Nick, &nick, &nick).await {
+        return Ok(replies);
+    }
+
+    // is this nick already taken? services count as taken, too
+    if irc.get_name(&nick).is_some() || (services::is_service(&nick) && irc.get_config().link.is_none()) {
         replies.push(Err(ircError::NicknameInUse(nick)));
         return Ok(replies);
     }
 
-    // we can return a tuple and send messages after the match
-    // to avoid borrowing mutably inside the immutable borrow
-    // (Some(&str), Some(ClientType), bool died)
-    let result = match client.get_client_type() {
-        ClientType::Dead => None,
+    match client.get_client_type() {
+        ClientType::Dead | ClientType::Server(_) => (),
         ClientType::Unregistered => {
             // in this case we need to create a "proto user"
-            Some(ClientType::ProtoUser(Arc::new(Mutex::new(ProtoUser {
+            client.set_client_type(ClientType::ProtoUser(Arc::new(Mutex::new(ProtoUser {
                 nick: Some(nick),
                 username: None,
                 real_name: None,
-            }))))
+            }))));
         }
         ClientType::User(user_ref) => {
-            // just a nick change
+            // account nicks are off limits with enforce_nick, unregistered
+            // clients are checked once SASL has had its chance
+            if let Some(err) = irc.check_protected_nick(&nick, user_ref.get_account().as_deref()).await? {
+                replies.push(Err(err));
+                return Ok(replies);
+            }
+            // just a nick change, if they're not doing it too often
+            if let Err(wait) = user_ref.throttle_nick() {
+                replies.push(Err(ircError::NickTooFast(nick, wait.as_secs() + 1)));
+                return Ok(replies);
+            }
             user_ref.change_nick(&nick)?;
-            None
+            return Ok(replies);
         }
         ClientType::ProtoUser(proto_user_ref) => {
-            // in this case we already got USER
-            let mut proto_user = proto_user_ref.lock().unwrap();
             // need to account for the case where NICK is sent
             // twice without any user command
-            if proto_user.nick.is_some() {
-                proto_user.nick = Some(nick);
-                None
-            } else {
-                // full registration! wooo
-                let username = proto_user.username.as_ref();
-                let real_name = proto_user.real_name.as_ref();
-                let ret = Some(ClientType::User(
-                    irc.register(
-                        client,
-                        nick.clone(),
-                        username.unwrap().to_string(),
-                        real_name.unwrap().to_string(),
-                    )?, // error propagation if registration fails
-                ));
-                replies.push(Ok(ircReply::Welcome(nick.clone(), username.unwrap().clone(), client.get_host_string())));
-                replies.push(Ok(ircReply::YourHost(irc.get_host(), irc.get_version())));
-                replies.push(Ok(ircReply::Created(irc.get_date())));
-                replies.push(Ok(ircReply::MyInfo(irc.get_host(), irc.get_version(), irc.get_umodes(), irc.get_chanmodes())));
-                ret
+            proto_user_ref.lock().unwrap().nick = Some(nick);
+        }
+    }
+
+    // full registration if USER was already seen! wooo
+    replies.append(&mut register_if_ready(irc, client).await?);
+    Ok(replies)
+}
+
+/* NICK, USER and CAP END can all be the last piece of the handshake,
+ * registration happens once we have a nick and a username and the
+ * client isn't in the middle of capability negotiation */
+/* 001 to 005 */
+fn welcome(irc: &Core, nick: String, username: String, host: String) -> Vec<ircReply> {
+    let mut replies = vec![
+        ircReply::Welcome(nick, username, host),
+        ircReply::YourHost(irc.get_host(), irc.get_version()),
+        ircReply::Created(irc.get_date()),
+        ircReply::MyInfo(irc.get_host(), irc.get_version(), irc.get_umodes(), irc.get_chanmodes()),
+    ];
+    replies.extend(isupport::replies(irc));
+    replies
+}
+
+/* a new connection joining a session that's already on. the welcome
+ * and a JOIN with the topic and names for each channel all go straight
+ * out so they arrive in that order, followed by anything a detached
+ * always_on session was sent while nobody was there */
+async fn attach(irc: &Arc<Core>, client: &Arc<Client>, user: &Arc<User>, ip_slot: Option<PendingSlot>) -> Result<(), GenError> {
+    debug!("client {} attached to {}", client.get_id(), user.get_nick());
+    user.attach(client);
+    client.get_span().record("nick", user.get_nick().as_str());
+    let backlog = user.resume_session();
+    irc.release_session(user.get_id());
+    client.set_client_type(ClientType::User(Arc::clone(user)));
+    client.release_pending_slot();
+    if let Some(slot) = ip_slot {
+        client.set_ip_slot(slot);
+    }
+    connlog::log(client, connlog::Event::Register);
+
+    for reply in welcome(irc, user.get_nick(), user.get_username(), client.get_host_string()) {
+        client.send_rpl(reply).await?;
+    }
+    if user.get_visible_host() != user.get_host_string() {
+        client.send_rpl(ircReply::HostHidden(user.get_visible_host())).await?;
+    }
+    client.send_rpl(ircReply::UModeIs(user.get_modes())).await?;
+    for chan in user.get_channel_list().iter().filter_map(Weak::upgrade) {
+        let name = chan.get_name();
+        let join = Event::Join { prefix: user.get_prefix(), chan: name.clone(), account: user.get_account(), realname: user.get_realname() };
+        if let Some(line) = join.render(Wire::of(client)) {
+            client.send_line(&line).await?;
+        }
+        if let Some(topic) = chan.get_topic().await {
+            client.send_rpl(ircReply::Topic(name.clone(), topic.text)).await?;
+            client.send_rpl(ircReply::TopicSetBy(name.clone(), topic.usermask, topic.timestamp)).await?;
+        }
+        client.send_rpl(ircReply::NameReply(name.clone(), chan.get_nick_list().await)).await?;
+        client.send_rpl(ircReply::EndofNames(name)).await?;
+    }
+    for line in backlog {
+        client.send_shared(&line).await?;
+    }
+    Ok(())
+}
+
+pub async fn register_if_ready(irc: &Arc<Core>, client: &Arc<Client>) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if client.get_caps().is_negotiating() {
+        return Ok(replies);
+    }
+    let proto_user_ref = match client.get_client_type() {
+        ClientType::ProtoUser(proto_user_ref) => proto_user_ref,
+        _ => return Ok(replies),
+    };
+    let (mut nick, username, real_name) = {
+        let proto_user = proto_user_ref.lock().unwrap();
+        match (&proto_user.nick, &proto_user.username, &proto_user.real_name) {
+            (Some(nick), Some(username), Some(real_name)) => (nick.clone(), username.clone(), real_name.clone()),
+            _ => return Ok(replies),
+        }
+    };
+
+    /* with enforce_nick a SASL login decides the nick, and nobody
+     * else gets to register with an account's nick */
+    if irc.enforcing_nicks() {
+        let account = client.get_sasl().get_account();
+        match account.as_deref() {
+            Some(account) if !nick.eq_ignore_ascii_case(account) && rfc::valid_nick(account) && irc.get_name(account).is_none() => {
+                nick = account.to_string();
+            },
+            _ => if let Some(err) = irc.check_protected_nick(&nick, account.as_deref()).await? {
+                proto_user_ref.lock().unwrap().nick = None;
+                replies.push(Err(err));
+                return Ok(replies);
+            },
+        }
+    }
+
+    if let Some(kline) = irc.find_kline(&username, &client.get_host_string(), &client.get_ip()) {
+        debug!("client {} matches K-line {}", client.get_id(), kline.mask);
+        connlog::log(client, connlog::Event::Kline(&kline.mask));
+        return Err(GenError::Disconnect(format!("K-lined ({})", kline.reason)));
+    }
+
+    let ip_slot = if client.is_privacy() || irc.ip_exempt(client.get_ip()) {
+        None
+    } else {
+        match irc.reserve_client(client.get_ip()) {
+            Some(slot) => Some(slot),
+            None => {
+                debug!("client {} is over the clients_per_ip limit", client.get_id());
+                return Err(GenError::Disconnect("Too many connections from your host".to_string()));
             }
         }
     };
 
-    if let Some(new_client_type) = result {
-        client.set_client_type(new_client_type);
+    /* the DNSBL lookup was started when the client connected,
+     * this is the last chance to turn them away */
+    let dnsbl_mark = dnsbl::enforce(irc, client).await?;
+
+    if let Some(user) = irc.find_session(client) {
+        attach(irc, client, &user, ip_slot).await?;
+        return Ok(replies);
+    }
+
+    let user = irc.register(client, nick.clone(), username.clone(), real_name)?; // propagate the error if it goes wrong
+    user.set_dnsbl_mark(dnsbl_mark);
+    client.get_span().record("nick", nick.as_str());
+    client.set_client_type(ClientType::User(Arc::clone(&user)));
+    client.release_pending_slot();
+    if let Some(slot) = ip_slot {
+        client.set_ip_slot(slot);
+    }
+
+    /* a SASL login during the handshake carries over to the new user,
+     * nobody can see us yet so the vhost can be swapped in silently */
+    let mut vhost = None;
+    if let Some(account) = client.get_sasl().get_account() {
+        vhost = irc.accounts.get(&account).await?.and_then(|acc| acc.vhost);
+        user.set_account(Some(account));
+        *user.vhost.lock().unwrap() = vhost.clone();
+    }
+    connlog::log(client, connlog::Event::Register);
+
+    replies.extend(welcome(irc, nick, username, client.get_host_string()).into_iter().map(Ok));
+    if let Some(vhost) = vhost {
+        replies.push(Ok(ircReply::HostHidden(vhost)));
+    }
+    if let Some(link) = irc.get_link() {
+        link.introduce(&user);
+    }
+    for module in irc.get_modules() {
+        module.on_register(irc, &user);
     }
     Ok(replies)
 }
@@ -0,0 +1,655 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* persistent account storage for NickServ, SASL and HostServ,
+ * ChanServ's registered channels and each account's read markers.
+ * with the `sqlite` feature (on by default) accounts live in an SQLite
+ * database, without it they're only kept in memory until the server exits.
+ * either way the backend is synchronous, so every call is pushed off the
+ * async runtime with spawn_blocking. passwords are only ever stored as
+ * argon2id hashes, see password.rs */
+use crate::irc::chan::{BanMask, ChanTopic};
+use crate::password;
+use crate::password::HashError;
+use chrono::Utc;
+use std::error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::task;
+use tokio::task::JoinError as tokJoinErr;
+
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub name: String,
+    pub password_hash: String,
+    pub email: Option<String>,
+    pub vhost: Option<String>,
+    pub registered_at: i64,
+}
+
+/* a channel registered with ChanServ. the channel's own task has the live
+ * copy and writes the whole thing back whenever it changes */
+#[derive(Debug, Clone)]
+pub struct ChannelReg {
+    pub name: String,
+    pub founder: String,
+    pub registered_at: i64,
+    /* the channel TS, kept so it doesn't reset on a restart */
+    pub created: i64,
+    pub modes: String,
+    pub topic: Option<ChanTopic>,
+    pub bans: Vec<BanMask>,
+    /* accounts that get ops on joining, besides the founder */
+    pub access: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum AccountError {
+    Exists(String),
+    NoSuchAccount(String),
+    /* the fingerprint's already on another account */
+    CertfpTaken(String),
+    #[cfg(feature = "sqlite")]
+    Db(rusqlite::Error),
+    Hash(HashError),
+    Tokio(tokJoinErr),
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AccountError::Exists(name) => write!(f, "account {} already exists", name),
+            AccountError::NoSuchAccount(name) => write!(f, "no such account {}", name),
+            AccountError::CertfpTaken(certfp) => write!(f, "certificate fingerprint {} belongs to another account", certfp),
+            #[cfg(feature = "sqlite")]
+            AccountError::Db(ref err) => write!(f, "account database error: {}", err),
+            AccountError::Hash(ref err) => write!(f, "password hashing failed: {}", err),
+            AccountError::Tokio(ref err) => write!(f, "account task failed: {}", err),
+        }
+    }
+}
+
+impl error::Error for AccountError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "sqlite")]
+            AccountError::Db(ref err) => Some(err),
+            AccountError::Hash(ref err) => Some(err),
+            AccountError::Tokio(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for AccountError {
+    fn from(err: rusqlite::Error) -> AccountError {
+        AccountError::Db(err)
+    }
+}
+
+impl From<HashError> for AccountError {
+    fn from(err: HashError) -> AccountError {
+        AccountError::Hash(err)
+    }
+}
+
+impl From<tokJoinErr> for AccountError {
+    fn from(err: tokJoinErr) -> AccountError {
+        AccountError::Tokio(err)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod backend {
+    use super::{Account, AccountError, ChannelReg};
+    use crate::irc::chan::{BanMask, ChanTopic};
+    use rusqlite::{params, Connection, OptionalExtension, Row};
+
+    const SCHEMA: &str = "
+        CREATE TABLE IF NOT EXISTS accounts (
+            name TEXT PRIMARY KEY COLLATE NOCASE,
+            password_hash TEXT NOT NULL,
+            email TEXT,
+            vhost TEXT,
+            registered_at INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS certfps (
+            fingerprint TEXT PRIMARY KEY COLLATE NOCASE,
+            account TEXT NOT NULL REFERENCES accounts(name) ON DELETE CASCADE
+        );
+        CREATE TABLE IF NOT EXISTS channels (
+            name TEXT PRIMARY KEY COLLATE NOCASE,
+            founder TEXT NOT NULL,
+            registered_at INTEGER NOT NULL,
+            created INTEGER NOT NULL,
+            modes TEXT NOT NULL,
+            topic TEXT,
+            topic_setter TEXT,
+            topic_ts INTEGER
+        );
+        CREATE TABLE IF NOT EXISTS channel_bans (
+            channel TEXT NOT NULL REFERENCES channels(name) ON DELETE CASCADE,
+            mask TEXT NOT NULL,
+            setter TEXT NOT NULL,
+            set_at INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS channel_access (
+            channel TEXT NOT NULL REFERENCES channels(name) ON DELETE CASCADE,
+            account TEXT NOT NULL COLLATE NOCASE,
+            PRIMARY KEY (channel, account)
+        );
+        CREATE TABLE IF NOT EXISTS read_markers (
+            account TEXT NOT NULL COLLATE NOCASE,
+            target TEXT NOT NULL COLLATE NOCASE,
+            timestamp INTEGER NOT NULL,
+            PRIMARY KEY (account, target)
+        );
+        CREATE TABLE IF NOT EXISTS webhooks (
+            account TEXT PRIMARY KEY COLLATE NOCASE REFERENCES accounts(name) ON DELETE CASCADE,
+            url TEXT NOT NULL
+        );";
+
+    #[derive(Debug)]
+    pub struct Backend {
+        conn: Connection,
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Account> {
+        Ok(Account {
+            name: row.get(0)?,
+            password_hash: row.get(1)?,
+            email: row.get(2)?,
+            vhost: row.get(3)?,
+            registered_at: row.get(4)?,
+        })
+    }
+
+    impl Backend {
+        pub fn open(path: &str) -> Result<Self, AccountError> {
+            let conn = Connection::open(path)?;
+            conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+            conn.execute_batch(SCHEMA)?;
+            Ok(Backend { conn })
+        }
+
+        pub fn get(&self, name: &str) -> Result<Option<Account>, AccountError> {
+            Ok(self.conn.query_row(
+                "SELECT name, password_hash, email, vhost, registered_at FROM accounts WHERE name = ?1",
+                params![name],
+                from_row,
+            ).optional()?)
+        }
+
+        pub fn insert(&self, account: &Account) -> Result<(), AccountError> {
+            if self.get(&account.name)?.is_some() {
+                return Err(AccountError::Exists(account.name.clone()));
+            }
+            self.conn.execute(
+                "INSERT INTO accounts (name, password_hash, email, vhost, registered_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![account.name, account.password_hash, account.email, account.vhost, account.registered_at],
+            )?;
+            Ok(())
+        }
+
+        pub fn set_vhost(&self, name: &str, vhost: Option<&str>) -> Result<(), AccountError> {
+            let n = self.conn.execute("UPDATE accounts SET vhost = ?1 WHERE name = ?2", params![vhost, name])?;
+            if n == 0 {
+                return Err(AccountError::NoSuchAccount(name.to_string()));
+            }
+            Ok(())
+        }
+
+        pub fn list_vhosts(&self) -> Result<Vec<(String, String)>, AccountError> {
+            let mut stmt = self.conn.prepare("SELECT name, vhost FROM accounts WHERE vhost IS NOT NULL ORDER BY name")?;
+            let rows = stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+        }
+
+        pub fn add_certfp(&self, name: &str, certfp: &str) -> Result<(), AccountError> {
+            if self.get(name)?.is_none() {
+                return Err(AccountError::NoSuchAccount(name.to_string()));
+            }
+            let certfp = certfp.to_ascii_lowercase();
+            let owner: Option<String> = self.conn.query_row(
+                "SELECT account FROM certfps WHERE fingerprint = ?1",
+                params![certfp],
+                |row| row.get(0),
+            ).optional()?;
+            match owner {
+                Some(owner) if owner.eq_ignore_ascii_case(name) => Ok(()),
+                Some(_owner) => Err(AccountError::CertfpTaken(certfp)),
+                None => {
+                    self.conn.execute("INSERT INTO certfps (fingerprint, account) VALUES (?1, ?2)", params![certfp, name])?;
+                    Ok(())
+                },
+            }
+        }
+
+        pub fn find_certfp(&self, certfp: &str) -> Result<Option<Account>, AccountError> {
+            Ok(self.conn.query_row(
+                "SELECT a.name, a.password_hash, a.email, a.vhost, a.registered_at FROM accounts a
+                 JOIN certfps c ON c.account = a.name WHERE c.fingerprint = ?1",
+                params![certfp.to_ascii_lowercase()],
+                from_row,
+            ).optional()?)
+        }
+
+        pub fn list_channels(&self) -> Result<Vec<ChannelReg>, AccountError> {
+            let mut stmt = self.conn.prepare(
+                "SELECT name, founder, registered_at, created, modes, topic, topic_setter, topic_ts FROM channels ORDER BY name",
+            )?;
+            let rows = stmt.query_map(params![], |row| {
+                let topic = match (row.get(5)?, row.get(6)?, row.get(7)?) {
+                    (Some(text), Some(usermask), Some(timestamp)) => Some(ChanTopic { text, usermask, timestamp }),
+                    _ => None,
+                };
+                Ok(ChannelReg {
+                    name: row.get(0)?,
+                    founder: row.get(1)?,
+                    registered_at: row.get(2)?,
+                    created: row.get(3)?,
+                    modes: row.get(4)?,
+                    topic,
+                    bans: Vec::new(),
+                    access: Vec::new(),
+                })
+            })?;
+            let mut channels = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+            for reg in channels.iter_mut() {
+                let mut stmt = self.conn.prepare("SELECT mask, setter, set_at FROM channel_bans WHERE channel = ?1 ORDER BY rowid")?;
+                let bans = stmt.query_map(params![reg.name], |row| {
+                    Ok(BanMask { mask: row.get(0)?, setter: row.get(1)?, timestamp: row.get(2)? })
+                })?;
+                reg.bans = bans.collect::<rusqlite::Result<Vec<_>>>()?;
+                let mut stmt = self.conn.prepare("SELECT account FROM channel_access WHERE channel = ?1 ORDER BY account")?;
+                let access = stmt.query_map(params![reg.name], |row| row.get(0))?;
+                reg.access = access.collect::<rusqlite::Result<Vec<_>>>()?;
+            }
+            Ok(channels)
+        }
+
+        /* replaces whatever was there, bans and access list included */
+        pub fn save_channel(&mut self, reg: &ChannelReg) -> Result<(), AccountError> {
+            let tx = self.conn.transaction()?;
+            tx.execute("DELETE FROM channels WHERE name = ?1", params![reg.name])?;
+            let (topic, setter, ts) = match &reg.topic {
+                Some(topic) => (Some(&topic.text), Some(&topic.usermask), Some(topic.timestamp)),
+                None => (None, None, None),
+            };
+            tx.execute(
+                "INSERT INTO channels (name, founder, registered_at, created, modes, topic, topic_setter, topic_ts)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![reg.name, reg.founder, reg.registered_at, reg.created, reg.modes, topic, setter, ts],
+            )?;
+            for ban in reg.bans.iter() {
+                tx.execute(
+                    "INSERT INTO channel_bans (channel, mask, setter, set_at) VALUES (?1, ?2, ?3, ?4)",
+                    params![reg.name, ban.mask, ban.setter, ban.timestamp],
+                )?;
+            }
+            for account in reg.access.iter() {
+                tx.execute(
+                    "INSERT OR IGNORE INTO channel_access (channel, account) VALUES (?1, ?2)",
+                    params![reg.name, account],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        }
+
+        pub fn drop_channel(&self, name: &str) -> Result<(), AccountError> {
+            self.conn.execute("DELETE FROM channels WHERE name = ?1", params![name])?;
+            Ok(())
+        }
+
+        pub fn get_read_marker(&self, account: &str, target: &str) -> Result<Option<i64>, AccountError> {
+            Ok(self.conn.query_row(
+                "SELECT timestamp FROM read_markers WHERE account = ?1 AND target = ?2",
+                params![account, target],
+                |row| row.get(0),
+            ).optional()?)
+        }
+
+        pub fn set_read_marker(&self, account: &str, target: &str, timestamp: i64) -> Result<i64, AccountError> {
+            self.conn.execute(
+                "INSERT INTO read_markers (account, target, timestamp) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (account, target) DO UPDATE SET timestamp = MAX(timestamp, excluded.timestamp)",
+                params![account, target, timestamp],
+            )?;
+            Ok(self.get_read_marker(account, target)?.unwrap_or(timestamp))
+        }
+
+        pub fn get_webhook(&self, account: &str) -> Result<Option<String>, AccountError> {
+            Ok(self.conn.query_row(
+                "SELECT url FROM webhooks WHERE account = ?1",
+                params![account],
+                |row| row.get(0),
+            ).optional()?)
+        }
+
+        pub fn set_webhook(&self, account: &str, url: Option<&str>) -> Result<(), AccountError> {
+            match url {
+                Some(url) => self.conn.execute(
+                    "INSERT OR REPLACE INTO webhooks (account, url) VALUES (?1, ?2)",
+                    params![account, url],
+                )?,
+                None => self.conn.execute("DELETE FROM webhooks WHERE account = ?1", params![account])?,
+            };
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+mod backend {
+    use super::{Account, AccountError, ChannelReg};
+    use std::collections::{BTreeMap, HashMap};
+
+    #[derive(Debug)]
+    pub struct Backend {
+        accounts: HashMap<String, Account>,
+        certfps: HashMap<String, String>,
+        channels: BTreeMap<String, ChannelReg>,
+        read_markers: HashMap<(String, String), i64>,
+        webhooks: HashMap<String, String>,
+    }
+
+    impl Backend {
+        pub fn open(_path: &str) -> Result<Self, AccountError> {
+            Ok(Backend {
+                accounts: HashMap::new(),
+                certfps: HashMap::new(),
+                channels: BTreeMap::new(),
+                read_markers: HashMap::new(),
+                webhooks: HashMap::new(),
+            })
+        }
+
+        pub fn get(&self, name: &str) -> Result<Option<Account>, AccountError> {
+            Ok(self.accounts.get(&name.to_ascii_lowercase()).cloned())
+        }
+
+        pub fn insert(&mut self, account: &Account) -> Result<(), AccountError> {
+            let key = account.name.to_ascii_lowercase();
+            if self.accounts.contains_key(&key) {
+                return Err(AccountError::Exists(account.name.clone()));
+            }
+            self.accounts.insert(key, account.clone());
+            Ok(())
+        }
+
+        pub fn set_vhost(&mut self, name: &str, vhost: Option<&str>) -> Result<(), AccountError> {
+            match self.accounts.get_mut(&name.to_ascii_lowercase()) {
+                Some(account) => {
+                    account.vhost = vhost.map(|v| v.to_string());
+                    Ok(())
+                },
+                None => Err(AccountError::NoSuchAccount(name.to_string())),
+            }
+        }
+
+        pub fn list_vhosts(&self) -> Result<Vec<(String, String)>, AccountError> {
+            let mut list: Vec<(String, String)> = self.accounts
+                .values()
+                .filter_map(|acc| acc.vhost.clone().map(|vhost| (acc.name.clone(), vhost)))
+                .collect();
+            list.sort();
+            Ok(list)
+        }
+
+        pub fn add_certfp(&mut self, name: &str, certfp: &str) -> Result<(), AccountError> {
+            if !self.accounts.contains_key(&name.to_ascii_lowercase()) {
+                return Err(AccountError::NoSuchAccount(name.to_string()));
+            }
+            let (certfp, name) = (certfp.to_ascii_lowercase(), name.to_ascii_lowercase());
+            match self.certfps.get(&certfp) {
+                Some(owner) if *owner != name => Err(AccountError::CertfpTaken(certfp)),
+                Some(_owner) => Ok(()),
+                None => {
+                    self.certfps.insert(certfp, name);
+                    Ok(())
+                },
+            }
+        }
+
+        pub fn find_certfp(&self, certfp: &str) -> Result<Option<Account>, AccountError> {
+            match self.certfps.get(&certfp.to_ascii_lowercase()) {
+                Some(name) => self.get(name),
+                None => Ok(None),
+            }
+        }
+
+        pub fn list_channels(&self) -> Result<Vec<ChannelReg>, AccountError> {
+            Ok(self.channels.values().cloned().collect())
+        }
+
+        pub fn save_channel(&mut self, reg: &ChannelReg) -> Result<(), AccountError> {
+            self.channels.insert(reg.name.to_ascii_lowercase(), reg.clone());
+            Ok(())
+        }
+
+        pub fn drop_channel(&mut self, name: &str) -> Result<(), AccountError> {
+            self.channels.remove(&name.to_ascii_lowercase());
+            Ok(())
+        }
+
+        pub fn get_read_marker(&self, account: &str, target: &str) -> Result<Option<i64>, AccountError> {
+            let key = (account.to_ascii_lowercase(), target.to_ascii_lowercase());
+            Ok(self.read_markers.get(&key).copied())
+        }
+
+        pub fn set_read_marker(&mut self, account: &str, target: &str, timestamp: i64) -> Result<i64, AccountError> {
+            let key = (account.to_ascii_lowercase(), target.to_ascii_lowercase());
+            let marker = self.read_markers.entry(key).or_insert(timestamp);
+            *marker = (*marker).max(timestamp);
+            Ok(*marker)
+        }
+
+        pub fn get_webhook(&self, account: &str) -> Result<Option<String>, AccountError> {
+            Ok(self.webhooks.get(&account.to_ascii_lowercase()).cloned())
+        }
+
+        pub fn set_webhook(&mut self, account: &str, url: Option<&str>) -> Result<(), AccountError> {
+            let key = account.to_ascii_lowercase();
+            match url {
+                Some(url) => self.webhooks.insert(key, url.to_string()),
+                None => self.webhooks.remove(&key),
+            };
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AccountStore {
+    backend: Arc<Mutex<backend::Backend>>,
+    /* what authenticate() checks against for an account that doesn't exist */
+    dummy_hash: Arc<str>,
+}
+
+impl AccountStore {
+    pub fn open(path: &str) -> Result<Self, AccountError> {
+        Ok(AccountStore {
+            backend: Arc::new(Mutex::new(backend::Backend::open(path)?)),
+            dummy_hash: password::hash("")?.into(),
+        })
+    }
+
+    /* run a closure against the backend on the blocking thread pool,
+     * the mutex is only ever taken there, never across an await */
+    async fn with_backend<T, F>(&self, f: F) -> Result<T, AccountError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut backend::Backend) -> Result<T, AccountError> + Send + 'static,
+    {
+        let backend = Arc::clone(&self.backend);
+        task::spawn_blocking(move || f(&mut backend.lock().unwrap())).await?
+    }
+
+    pub async fn get(&self, name: &str) -> Result<Option<Account>, AccountError> {
+        let name = name.to_string();
+        self.with_backend(move |db| db.get(&name)).await
+    }
+
+    /* the hash is worked out before touching the backend so the
+     * mutex isn't held for the duration of argon2 */
+    pub async fn register(&self, name: &str, password: &str, email: Option<String>) -> Result<(), AccountError> {
+        let password = password.to_string();
+        let account = Account {
+            name: name.to_string(),
+            password_hash: task::spawn_blocking(move || password::hash(&password)).await??,
+            email,
+            vhost: None,
+            registered_at: Utc::now().timestamp(),
+        };
+        self.with_backend(move |db| db.insert(&account)).await
+    }
+
+    pub async fn set_vhost(&self, name: &str, vhost: Option<String>) -> Result<(), AccountError> {
+        let name = name.to_string();
+        self.with_backend(move |db| db.set_vhost(&name, vhost.as_deref())).await
+    }
+
+    pub async fn list_vhosts(&self) -> Result<Vec<(String, String)>, AccountError> {
+        self.with_backend(|db| db.list_vhosts()).await
+    }
+
+    pub async fn add_certfp(&self, name: &str, certfp: &str) -> Result<(), AccountError> {
+        let (name, certfp) = (name.to_string(), certfp.to_string());
+        self.with_backend(move |db| db.add_certfp(&name, &certfp)).await
+    }
+
+    /* the account a certificate fingerprint belongs to, for SASL EXTERNAL */
+    pub async fn find_certfp(&self, certfp: &str) -> Result<Option<Account>, AccountError> {
+        let certfp = certfp.to_string();
+        self.with_backend(move |db| db.find_certfp(&certfp)).await
+    }
+
+    /* every registered channel, for recreating them at startup */
+    pub async fn list_channels(&self) -> Result<Vec<ChannelReg>, AccountError> {
+        self.with_backend(|db| db.list_channels()).await
+    }
+
+    pub async fn save_channel(&self, reg: ChannelReg) -> Result<(), AccountError> {
+        self.with_backend(move |db| db.save_channel(&reg)).await
+    }
+
+    pub async fn drop_channel(&self, name: &str) -> Result<(), AccountError> {
+        let name = name.to_string();
+        self.with_backend(move |db| db.drop_channel(&name)).await
+    }
+
+    /* when the account last read target, in milliseconds */
+    pub async fn get_read_marker(&self, account: &str, target: &str) -> Result<Option<i64>, AccountError> {
+        let (account, target) = (account.to_string(), target.to_string());
+        self.with_backend(move |db| db.get_read_marker(&account, &target)).await
+    }
+
+    /* markers only ever move forward, this hands back whichever is newer
+     * out of timestamp and what was already stored */
+    pub async fn set_read_marker(&self, account: &str, target: &str, timestamp: i64) -> Result<i64, AccountError> {
+        let (account, target) = (account.to_string(), target.to_string());
+        self.with_backend(move |db| db.set_read_marker(&account, &target, timestamp)).await
+    }
+
+    /* where to POST notifications for the account, see webhook.rs */
+    pub async fn get_webhook(&self, account: &str) -> Result<Option<String>, AccountError> {
+        let account = account.to_string();
+        self.with_backend(move |db| db.get_webhook(&account)).await
+    }
+
+    pub async fn set_webhook(&self, account: &str, url: Option<String>) -> Result<(), AccountError> {
+        let account = account.to_string();
+        self.with_backend(move |db| db.set_webhook(&account, url.as_deref())).await
+    }
+
+    /* checks the password and hands back the account on success. an
+     * account that doesn't exist still gets a hash checked, so it takes
+     * as long as a wrong password and doesn't give away who's registered */
+    pub async fn authenticate(&self, name: &str, password: &str) -> Result<Option<Account>, AccountError> {
+        let account = self.get(name).await?;
+        let hash = match &account {
+            Some(account) => account.password_hash.clone(),
+            None => self.dummy_hash.to_string(),
+        };
+        let password = password.to_string();
+        let verified = task::spawn_blocking(move || password::verify(&password, &hash)).await?;
+        Ok(account.filter(|_account| verified))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn store() -> AccountStore {
+        let store = AccountStore::open(":memory:").unwrap();
+        store.register("alice", "hunter22", None).await.unwrap();
+        store.register("bob", "correct horse", Some("bob@example.org".to_string())).await.unwrap();
+        store
+    }
+
+    #[tokio::test]
+    async fn register_and_verify() {
+        let store = store().await;
+        let alice = store.get("ALICE").await.unwrap().unwrap();
+        assert_eq!(alice.name, "alice");
+        assert!(alice.password_hash.starts_with("$argon2id$"));
+        assert!(store.authenticate("alice", "hunter22").await.unwrap().is_some());
+        assert!(store.authenticate("Alice", "hunter22").await.unwrap().is_some());
+        assert!(store.authenticate("alice", "hunter23").await.unwrap().is_none());
+        assert!(store.authenticate("bob", "hunter22").await.unwrap().is_none());
+        assert_eq!(store.get("bob").await.unwrap().unwrap().email.as_deref(), Some("bob@example.org"));
+    }
+
+    #[tokio::test]
+    async fn duplicate_account() {
+        let store = store().await;
+        match store.register("Alice", "other", None).await {
+            Err(AccountError::Exists(name)) => assert_eq!(name, "Alice"),
+            other => panic!("registered a second alice: {:?}", other),
+        }
+        assert!(store.authenticate("alice", "hunter22").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn certfp_ownership() {
+        let store = store().await;
+        store.add_certfp("alice", "AB12CD").await.unwrap();
+        /* adding it again to the same account is fine */
+        store.add_certfp("Alice", "ab12cd").await.unwrap();
+        assert_eq!(store.find_certfp("ab12cd").await.unwrap().unwrap().name, "alice");
+        match store.add_certfp("bob", "ab12cd").await {
+            Err(AccountError::CertfpTaken(_)) => (),
+            other => panic!("moved alice's certfp to bob: {:?}", other),
+        }
+        assert_eq!(store.find_certfp("AB12CD").await.unwrap().unwrap().name, "alice");
+        match store.add_certfp("nobody", "ef34").await {
+            Err(AccountError::NoSuchAccount(_)) => (),
+            other => panic!("added a certfp to no account: {:?}", other),
+        }
+        assert!(store.find_certfp("ef34").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn unknown_account() {
+        let store = store().await;
+        /* the dummy hash is of "", which mustn't log anyone in */
+        assert!(store.authenticate("nobody", "").await.unwrap().is_none());
+        assert!(store.authenticate("nobody", "hunter22").await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,124 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* [aliases], shortcuts like NS for PRIVMSG NickServ. an alias is a
+ * command line of its own, where $1, $2.. stand for the parameters the
+ * client sent and $* for all of them joined up with spaces, so
+ * `NS = "PRIVMSG NickServ :$*"`. one without any $ just gets whatever
+ * the client sent tacked on the end, `J = "JOIN"`. irc::command()
+ * swaps them in before anything else looks at the command, modules
+ * included. aliases can't stand in for real commands or each other */
+use crate::parser::{self, ParsedMsg};
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Clone, PartialEq)]
+struct Alias {
+    command: String,
+    params: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Aliases {
+    aliases: HashMap<String, Alias>,
+}
+
+impl Aliases {
+    /* ones that don't parse are left out, Config::parse() won't let
+     * them through anyway */
+    pub fn new(config: &BTreeMap<String, String>) -> Self {
+        let aliases = config
+            .iter()
+            .filter_map(|(name, line)| parse(line).map(|alias| (name.to_ascii_uppercase(), alias)))
+            .collect();
+        Aliases { aliases }
+    }
+
+    /* msg as the command it's short for, or as it was */
+    pub fn expand(&self, mut msg: ParsedMsg) -> ParsedMsg {
+        let alias = match self.aliases.get(&msg.command.to_ascii_uppercase()) {
+            Some(alias) => alias,
+            None => return msg,
+        };
+        let args = std::mem::take(&mut msg.opt_params);
+        msg.command = alias.command.clone();
+        if !alias.params.iter().any(|param| param.contains('$')) {
+            msg.opt_params = alias.params.iter().cloned().chain(args).collect();
+        } else {
+            msg.opt_params = alias.params.iter().map(|param| fill(param, &args)).collect();
+        }
+        msg
+    }
+}
+
+/* the command and its parameters, None if it isn't a command line */
+fn parse(line: &str) -> Option<Alias> {
+    let parsed = parser::parse_message(line).ok()?;
+    if !parsed.tags.is_empty() || parsed.opt_prefix.is_some() {
+        return None;
+    }
+    Some(Alias { command: parsed.command.to_ascii_uppercase(), params: parsed.opt_params })
+}
+
+/* fit to be an alias, for Config::parse() */
+pub fn valid(line: &str) -> bool {
+    parse(line).is_some()
+}
+
+/* $* and $1..$9 from args, ones they didn't send come out empty */
+fn fill(param: &str, args: &[String]) -> String {
+    let mut out = String::with_capacity(param.len());
+    let mut chars = param.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek().copied()) {
+            ('$', Some('*')) => {
+                chars.next();
+                out.push_str(&args.join(" "));
+            },
+            ('$', Some(digit @ '1'..='9')) => {
+                chars.next();
+                let n = digit as usize - '1' as usize;
+                out.push_str(args.get(n).map_or("", String::as_str));
+            },
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expansion() {
+        let mut config = BTreeMap::new();
+        config.insert("ns".to_string(), "PRIVMSG NickServ :$*".to_string());
+        config.insert("J".to_string(), "JOIN".to_string());
+        config.insert("topicof".to_string(), "TOPIC $1".to_string());
+        let aliases = Aliases::new(&config);
+
+        let msg = aliases.expand(parser::parse_message("NS IDENTIFY hunter2").unwrap());
+        assert_eq!(msg.command, "PRIVMSG");
+        assert_eq!(msg.opt_params, vec!["NickServ", "IDENTIFY hunter2"]);
+        let msg = aliases.expand(parser::parse_message("j #rust,#help").unwrap());
+        assert_eq!((msg.command.as_str(), msg.opt_params), ("JOIN", vec!["#rust,#help".to_string()]));
+        let msg = aliases.expand(parser::parse_message("TOPICOF").unwrap());
+        assert_eq!(msg.opt_params, vec![""]);
+        let msg = aliases.expand(parser::parse_message("PRIVMSG #chan :$*").unwrap());
+        assert_eq!(msg.opt_params, vec!["#chan", "$*"]);
+        assert!(!valid(""));
+    }
+}
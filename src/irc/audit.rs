@@ -0,0 +1,98 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* AUDIT <channel> [<count>], so chanops can settle "who banned X" without
+ * asking an oper. every channel keeps its own last few kicks, bans, topic
+ * and mode changes, see [audit] in the config */
+use crate::client::{ClientReplies, GenError};
+use crate::irc::error::Error as ircError;
+use crate::irc::{Core, User};
+use crate::parser::ParsedMsg;
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Arc;
+use tokio::task;
+use tracing::warn;
+
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub time: DateTime<Utc>,
+    pub by: String,
+    pub action: String,
+}
+
+impl AuditEntry {
+    pub fn new(by: &str, action: String) -> Self {
+        AuditEntry {
+            time: Utc::now(),
+            by: by.to_string(),
+            action,
+        }
+    }
+}
+
+/* "2020-10-16 12:00:00 nick!user@host MODE +b *!*@spam" */
+impl fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.time.format("%Y-%m-%d %H:%M:%S"), self.by, self.action)
+    }
+}
+
+/* one line per entry in the [audit] file, the write happens off on a
+ * blocking thread so the channel task doesn't wait on the disk */
+pub fn append(path: &str, chan: &str, entry: &AuditEntry) {
+    let line = format!("{} {}\n", chan, entry);
+    let path = path.to_string();
+    task::spawn_blocking(move || {
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+        if let Err(err) = written {
+            warn!("couldn't write to audit log {}: {}", path, err);
+        }
+    });
+}
+
+/* the answer comes as server notices, there's no numeric for this */
+pub async fn command(irc: &Core, user: &Arc<User>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let chanmask = &params.opt_params[0];
+    let chan = match irc.get_chan(chanmask) {
+        Ok(chan) => chan,
+        Err(err) => {
+            replies.push(Err(err));
+            return Ok(replies);
+        }
+    };
+    if !user.is_oper() && !chan.is_op(user).await {
+        replies.push(Err(ircError::ChanOPrivsNeeded(chan.get_name())));
+        return Ok(replies);
+    }
+    let entries = chan.get_audit().await;
+    let count = params.opt_params.get(1)
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(entries.len())
+        .min(entries.len());
+    for entry in entries[entries.len() - count..].iter() {
+        irc.server_notice(user, &format!("{} {}", chan.get_name(), entry)).await;
+    }
+    irc.server_notice(user, &format!("End of audit log for {}", chan.get_name())).await;
+    Ok(replies)
+}
@@ -0,0 +1,79 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* oper mass messages, PRIVMSG/NOTICE to $$<servermask> for everyone on
+ * a matching server or $#<hostmask> for everyone whose host matches.
+ * like RFC 1459 says the mask needs a top level domain with no wildcards
+ * in it, so $$* can't be sent by accident. there's only us to deliver
+ * to, users on the services link never see these */
+use crate::client::GenError;
+use crate::irc::error::Error as ircError;
+use crate::irc::mask;
+use crate::irc::{Core, User};
+
+pub fn is_broadcast(target: &str) -> bool {
+    target.starts_with("$$") || target.starts_with("$#")
+}
+
+/* the part after the last dot has to be there and can't be wild */
+fn check_toplevel(mask: &str) -> Result<(), ircError> {
+    match mask.rsplit_once('.') {
+        None => Err(ircError::NoTopLevel(mask.to_string())),
+        Some((_rest, top)) if top.is_empty() || mask::is_wild(top) => Err(ircError::WildTopLevel(mask.to_string())),
+        Some(_) => Ok(()),
+    }
+}
+
+/* send to everyone target covers, Err for the sender if it can't go */
+pub async fn send(irc: &Core, source: &User, cmd: &str, target: &str, msg: &str) -> Result<Result<(), ircError>, GenError> {
+    if !source.is_oper() {
+        return Ok(Err(ircError::NoPrivileges));
+    }
+    let hostmask = &target[2..];
+    if let Err(err) = check_toplevel(hostmask) {
+        return Ok(Err(err));
+    }
+    let by_server = target.starts_with("$$");
+    if by_server && !mask::matches(hostmask, &irc.get_host()) {
+        return Ok(Err(ircError::NoSuchServer(hostmask.to_string())));
+    }
+    let line = format!(":{} {} {} :{}", source.get_prefix(), cmd, target, msg);
+    for user in irc.get_users().iter().filter(|user| !user.is_remote()) {
+        /* vhosts don't hide anyone from an oper */
+        let matched = by_server
+            || mask::matches(hostmask, &user.get_host_string())
+            || mask::matches(hostmask, &user.get_visible_host());
+        if matched {
+            user.send_line(&line).await?;
+        }
+    }
+    Ok(Ok(()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toplevel() {
+        assert!(check_toplevel("*.example.net").is_ok());
+        assert!(check_toplevel("irc.*.net").is_ok());
+        assert!(matches!(check_toplevel("*"), Err(ircError::NoTopLevel(_))));
+        assert!(matches!(check_toplevel("*.*"), Err(ircError::WildTopLevel(_))));
+        assert!(matches!(check_toplevel("example.n?t"), Err(ircError::WildTopLevel(_))));
+        assert!(matches!(check_toplevel("example."), Err(ircError::WildTopLevel(_))));
+    }
+}
@@ -0,0 +1,239 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* IRCv3 capability negotiation, see https://ircv3.net/specs/extensions/capability-negotiation
+ * CAP LS 302 gets values (sasl=PLAIN,EXTERNAL) and LS/LIST replies split
+ * over several lines when they're long. 302 clients, and anyone who asks
+ * for cap-notify, are sent CAP NEW and DEL when what's on offer changes,
+ * which for now is just sasl coming and going with REHASH */
+use crate::client::{Client, ClientReplies, GenError};
+use crate::irc::error::Error as ircError;
+use crate::irc::sasl;
+use crate::irc::{self, Core};
+use crate::parser::ParsedMsg;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+pub const CAP_NOTIFY: &str = "cap-notify";
+pub const CHGHOST: &str = "chghost";
+pub const EXTENDED_JOIN: &str = "extended-join";
+pub const MESSAGE_TAGS: &str = "message-tags";
+/* nobody has more than one of @ and + at a time, so the NAMES and WHO
+ * everyone gets already has every prefix a member has */
+pub const MULTI_PREFIX: &str = "multi-prefix";
+pub const READ_MARKER: &str = "draft/read-marker";
+pub const SASL: &str = "sasl";
+
+/* everything we'd ever be willing to ACK, see available() for right now */
+pub const SUPPORTED: &[&str] = &[CAP_NOTIFY, CHGHOST, EXTENDED_JOIN, MESSAGE_TAGS, MULTI_PREFIX, READ_MARKER, SASL];
+
+/* keeps `:server CAP nick LS * :` plus the caps under 512 bytes with
+ * room to spare for a long server name and nick */
+const MAX_CAPS_LEN: usize = 400;
+
+/* per-client negotiation state, lives on the Client since caps
+ * are a property of the connection rather than the user */
+#[derive(Debug, Clone, Default)]
+pub struct CapState {
+    negotiating: bool,
+    /* the highest CAP LS version the client has sent, 0 for plain LS */
+    version: u32,
+    enabled: HashSet<String>,
+}
+
+impl CapState {
+    pub fn is_negotiating(&self) -> bool {
+        self.negotiating
+    }
+
+    pub fn has(&self, cap: &str) -> bool {
+        self.enabled.contains(cap)
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        let mut caps: Vec<String> = self.enabled.iter().cloned().collect();
+        caps.sort();
+        caps
+    }
+
+    /* LS 302 turns cap-notify on without asking, and it can't be turned off */
+    pub fn wants_notify(&self) -> bool {
+        self.version >= 302 || self.has(CAP_NOTIFY)
+    }
+}
+
+/* the caps on offer at the moment and their 302 values */
+pub fn available(irc: &Core) -> Vec<(&'static str, Option<&'static str>)> {
+    SUPPORTED
+        .iter()
+        .filter(|cap| **cap != SASL || irc.sasl_enabled())
+        .map(|cap| (*cap, if *cap == SASL { Some(sasl::mechanisms(irc)) } else { None }))
+        .collect()
+}
+
+fn is_available(irc: &Core, cap: &str) -> bool {
+    available(irc).iter().any(|(name, _value)| *name == cap)
+}
+
+fn token(version: u32, cap: &str, value: Option<&str>) -> String {
+    match value {
+        Some(value) if version >= 302 => format!("{}={}", cap, value),
+        _ => cap.to_string(),
+    }
+}
+
+/* caps packed into as few lines as fit, all but the last marked with *.
+ * before 302 there's no way to continue a reply so it's all one line */
+fn cap_lines(prefix: &str, subcmd: &str, caps: &[String], multiline: bool) -> Vec<String> {
+    let mut chunks: Vec<String> = Vec::new();
+    for cap in caps {
+        match chunks.last_mut() {
+            Some(chunk) if !multiline || chunk.len() + 1 + cap.len() <= MAX_CAPS_LEN => {
+                chunk.push(' ');
+                chunk.push_str(cap);
+            },
+            _ => chunks.push(cap.clone()),
+        }
+    }
+    if chunks.is_empty() {
+        chunks.push(String::new());
+    }
+    let last = chunks.len() - 1;
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let more = if i < last { " *" } else { "" };
+            format!("{} {}{} :{}", prefix, subcmd, more, chunk)
+        })
+        .collect()
+}
+
+/* CAP NEW or DEL to everyone who wants to hear about it. a cap that's
+ * gone is dropped from everyone who had it enabled as well */
+pub async fn notify(irc: &Core, cap: &str, added: bool) {
+    let value = if cap == SASL { Some(sasl::mechanisms(irc)) } else { None };
+    for client in irc.get_clients() {
+        let caps = client.get_caps();
+        if !added {
+            client.update_caps(|caps| {
+                caps.enabled.remove(cap);
+            });
+        }
+        if !caps.wants_notify() {
+            continue;
+        }
+        let line = if added {
+            format!(":{} CAP {} NEW :{}", irc.get_host(), client.get_nick_or_star(), token(caps.version, cap, value))
+        } else {
+            format!(":{} CAP {} DEL :{}", irc.get_host(), client.get_nick_or_star(), cap)
+        };
+        /* a client that's going away anyway doesn't matter here */
+        let _ = client.send_line(&line).await;
+    }
+}
+
+pub async fn command(irc: &Arc<Core>, client: &Arc<Client>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let subcmd = params.opt_params.remove(0).to_ascii_uppercase();
+    let target = client.get_nick_or_star();
+    let prefix = format!(":{} CAP {}", irc.get_host(), target);
+
+    match &subcmd[..] {
+        "LS" => {
+            let version = params.opt_params.first().and_then(|v| v.parse::<u32>().ok()).unwrap_or(0);
+            /* CAP LS before registration holds the handshake open until CAP END */
+            let registered = client.is_registered();
+            client.update_caps(|caps| {
+                caps.negotiating |= !registered;
+                caps.version = caps.version.max(version);
+            });
+            let version = client.get_caps().version;
+            let caps: Vec<String> = available(irc)
+                .into_iter()
+                .map(|(cap, value)| token(version, cap, value))
+                .collect();
+            for line in cap_lines(&prefix, "LS", &caps, version >= 302) {
+                client.send_line(&line).await?;
+            }
+        },
+        "LIST" => {
+            let enabled = client.get_caps().list();
+            for line in cap_lines(&prefix, "LIST", &enabled, client.get_caps().version >= 302) {
+                client.send_line(&line).await?;
+            }
+        },
+        "REQ" => {
+            if !client.is_registered() {
+                client.update_caps(|caps| caps.negotiating = true);
+            }
+            let requested = params.opt_params.join(" ");
+            /* the request is atomic, either everything is ACKed or nothing is */
+            let all_ok = requested
+                .split_whitespace()
+                .all(|cap| is_available(irc, cap.trim_start_matches('-')));
+            if all_ok {
+                client.update_caps(|caps| {
+                    for cap in requested.split_whitespace() {
+                        if let Some(removed) = cap.strip_prefix('-') {
+                            caps.enabled.remove(removed);
+                        } else {
+                            caps.enabled.insert(cap.to_string());
+                        }
+                    }
+                });
+                client.send_line(&format!("{} ACK :{}", prefix, requested)).await?;
+            } else {
+                client.send_line(&format!("{} NAK :{}", prefix, requested)).await?;
+            }
+        },
+        "END" => {
+            client.update_caps(|caps| caps.negotiating = false);
+            if !client.is_registered() {
+                replies.append(&mut irc::register_if_ready(irc, client).await?);
+            }
+        },
+        _ => replies.push(Err(ircError::InvalidCapCmd(subcmd))),
+    }
+    Ok(replies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ls_lines() {
+        let caps: Vec<String> = (0..100).map(|i| format!("vendor.example/cap-{}", i)).collect();
+        let lines = cap_lines(":irc.example.org CAP *", "LS", &caps, true);
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| line.len() <= 510));
+        assert!(lines[..lines.len() - 1].iter().all(|line| line.starts_with(":irc.example.org CAP * LS * :")));
+        assert!(lines.last().unwrap().starts_with(":irc.example.org CAP * LS :"));
+        let rejoined: Vec<String> = lines.iter().flat_map(|line| line.split_once(" :").unwrap().1.split(' ').map(String::from).collect::<Vec<_>>()).collect();
+        assert_eq!(rejoined, caps);
+
+        assert_eq!(cap_lines(":s CAP *", "LS", &caps, false).len(), 1);
+        assert_eq!(cap_lines(":s CAP nick", "LIST", &[], true), vec![":s CAP nick LIST :".to_string()]);
+    }
+
+    #[test]
+    fn values_only_for_302() {
+        assert_eq!(token(302, SASL, Some("PLAIN")), "sasl=PLAIN");
+        assert_eq!(token(0, SASL, Some("PLAIN")), "sasl");
+        assert_eq!(token(302, CHGHOST, None), "chghost");
+    }
+}
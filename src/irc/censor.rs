@@ -0,0 +1,82 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* the word list behind channel mode +G, built from [censor] into a
+ * single regex. Core keeps the current one and REHASH swaps in a new one */
+use crate::config::{CensorAction, CensorConfig};
+use regex::{Regex, RegexBuilder};
+use std::borrow::Cow;
+
+#[derive(Debug)]
+pub struct Censor {
+    pattern: Option<Regex>,
+    action: CensorAction,
+}
+
+impl Censor {
+    pub fn new(config: &CensorConfig) -> Censor {
+        let words: Vec<String> = config.words.iter()
+            .filter(|word| !word.is_empty())
+            .map(|word| regex::escape(word))
+            .collect();
+        /* escaped words can't make a bad regex, only an oversized one */
+        let pattern = if words.is_empty() {
+            None
+        } else {
+            RegexBuilder::new(&format!(r"\b(?:{})\b", words.join("|")))
+                .case_insensitive(true)
+                .build()
+                .ok()
+        };
+        Censor { pattern, action: config.action }
+    }
+
+    /* None if the message can't go out at all, otherwise the message
+     * with anything on the list starred out */
+    pub fn apply<'a>(&self, msg: &'a str) -> Option<Cow<'a, str>> {
+        let pattern = match &self.pattern {
+            Some(pattern) => pattern,
+            None => return Some(Cow::Borrowed(msg)),
+        };
+        match self.action {
+            CensorAction::Block if pattern.is_match(msg) => None,
+            CensorAction::Block => Some(Cow::Borrowed(msg)),
+            CensorAction::Replace => Some(pattern.replace_all(msg, |caps: &regex::Captures| {
+                "*".repeat(caps[0].chars().count())
+            })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn censor(words: &[&str], action: CensorAction) -> Censor {
+        Censor::new(&CensorConfig { words: words.iter().map(|word| word.to_string()).collect(), action })
+    }
+
+    #[test]
+    fn whole_words_only() {
+        let replace = censor(&["smeg", "a.b"], CensorAction::Replace);
+        assert_eq!(replace.apply("SMEG off, smeghead").unwrap(), "**** off, smeghead");
+        assert_eq!(replace.apply("a.b but not axb").unwrap(), "*** but not axb");
+        let block = censor(&["smeg"], CensorAction::Block);
+        assert!(block.apply("oh Smeg").is_none());
+        assert_eq!(block.apply("smeghead").unwrap(), "smeghead");
+        assert_eq!(censor(&[], CensorAction::Block).apply("smeg").unwrap(), "smeg");
+    }
+}
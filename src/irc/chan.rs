@@ -1,22 +1,38 @@
-extern crate log;
 extern crate chrono;
-use crate::client::{ClientReply, ClientReplies, GenError};
+use crate::client::{shared_line, ClientReply, ClientReplies, GenError};
+use crate::config::{Fanout, SlowPolicy};
+use crate::irc::accounts::ChannelReg;
+use crate::irc::audit::{self, AuditEntry};
+use crate::irc::ctcp;
 use crate::irc::error::Error as ircError;
+use crate::irc::event::{self, Event};
+use crate::irc::isupport::CHANTYPES;
+use crate::irc::mask;
+use crate::irc::module::ChanEvent;
 use crate::irc::reply::Reply as ircReply;
-use crate::irc::{Core, User};
+use crate::irc::{Core, NamedEntity, User};
 
 use chrono::Utc;
+use std::borrow::Cow;
 use std::clone::Clone;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
+use std::collections::btree_map::Entry;
 use std::{error, fmt};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::broadcast::{self, error::TryRecvError};
+use tokio::sync::{mpsc, oneshot, Notify};
 
-use log::{debug,warn};
+use tracing::{debug, warn};
 
 #[derive(Debug)]
 pub enum ChanError {
     LinkFailed(String, String),
     UnlinkFailed(String, String),
+    /* the job panicked and took the channel down with it, or the
+     * channel had already gone */
+    JobFailed(String),
 }
 
 impl error::Error for ChanError {}
@@ -25,28 +41,70 @@ impl fmt::Display for ChanError {
         match self {
             ChanError::LinkFailed(nick, chan) => write!(f, "couldn't add {} to {} channel list", nick, chan),
             ChanError::UnlinkFailed(nick, chan) => write!(f, "couldn't remove {} from {} channel list", nick, chan),
+            ChanError::JobFailed(chan) => write!(f, "channel task for {} couldn't answer", chan),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+/* in order, so a status can be compared with >= */
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum ChanFlags {
     None,
     Voice,
     Op,
 }
 
-#[derive(Debug, Clone)]
+impl ChanFlags {
+    /* '+' tag for voice or '@' for chanop */
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            ChanFlags::None => "",
+            ChanFlags::Voice => "+",
+            ChanFlags::Op => "@",
+        }
+    }
+}
+
+/* STATUSMSG, @#chan is for ops only and +#chan for voices and ops.
+ * anything else is a plain target, everyone on it gets the message */
+pub fn split_status(target: &str) -> (ChanFlags, &str) {
+    let status = match target.chars().next() {
+        Some('@') => ChanFlags::Op,
+        Some('+') => ChanFlags::Voice,
+        _ => return (ChanFlags::None, target),
+    };
+    let chan = &target[1..];
+    if chan.starts_with(|c| CHANTYPES.contains(c)) {
+        (status, chan)
+    } else {
+        (ChanFlags::None, target)
+    }
+}
+
+#[derive(Debug)]
 pub struct ChanUser {
     user_ptr: Weak<User>,
     chan_flags: ChanFlags,
+    /* their end of the stream, with fanout = "broadcast" */
+    tail: Option<Arc<Tail>>,
 }
 
 impl ChanUser {
     pub fn new(user: &Arc<User>, flags: ChanFlags) -> ChanUser {
         ChanUser {
-            user_ptr: Arc::downgrade(&user),
+            user_ptr: Arc::downgrade(user),
             chan_flags: flags,
+            tail: None,
+        }
+    }
+}
+
+/* leaving, however it happens, gets them everything from the stream
+ * they were due first, so it all comes before their PART or KICK */
+impl Drop for ChanUser {
+    fn drop(&mut self) {
+        if let Some(tail) = &self.tail {
+            tail.close();
         }
     }
 }
@@ -68,254 +126,1195 @@ impl Clone for ChanTopic {
     }
 }
 
+/* the simple on/off channel modes, new channels start out +nt */
+pub const FLAG_MODES: &str = "CGPmnstz";
+/* modes taking a mask to add to or take off a list */
+pub const LIST_MODES: &str = "b";
+/* modes giving a member a status, highest first */
+pub const STATUS_MODES: &str = "ov";
+
+/* every channel mode, in order, for RPL_MYINFO */
+pub fn all_modes() -> String {
+    let mut modes: Vec<char> = FLAG_MODES.chars().chain(LIST_MODES.chars()).chain(STATUS_MODES.chars()).collect();
+    modes.sort_unstable();
+    modes.into_iter().collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct ChanModes {
+    pub no_ctcp: bool,
+    pub censor: bool,
+    /* +P, kept around with its modes and topic once everyone's gone,
+     * only opers can set it */
+    pub permanent: bool,
+    pub moderated: bool,
+    pub no_external: bool,
+    /* +s, hidden from LIST and WHOIS for anyone not on it */
+    pub secret: bool,
+    pub topic_lock: bool,
+    /* +z, only TLS users can join */
+    pub secure_only: bool,
+}
+
+impl Default for ChanModes {
+    fn default() -> Self {
+        ChanModes {
+            no_ctcp: false,
+            censor: false,
+            permanent: false,
+            moderated: false,
+            no_external: true,
+            secret: false,
+            topic_lock: true,
+            secure_only: false,
+        }
+    }
+}
+
+impl ChanModes {
+    /* flip a mode, false if the letter isn't one of ours */
+    pub fn set(&mut self, mode: char, on: bool) -> bool {
+        match mode {
+            'C' => self.no_ctcp = on,
+            'G' => self.censor = on,
+            'P' => self.permanent = on,
+            'm' => self.moderated = on,
+            'n' => self.no_external = on,
+            's' => self.secret = on,
+            't' => self.topic_lock = on,
+            'z' => self.secure_only = on,
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn is_set(&self, mode: char) -> bool {
+        match mode {
+            'C' => self.no_ctcp,
+            'G' => self.censor,
+            'P' => self.permanent,
+            'm' => self.moderated,
+            'n' => self.no_external,
+            's' => self.secret,
+            't' => self.topic_lock,
+            'z' => self.secure_only,
+            _ => false,
+        }
+    }
+}
+
+impl ChanModes {
+    /* back from the "+nt" form Display gives, for registered channels */
+    pub fn parse(modes: &str) -> Self {
+        let mut parsed = ChanModes::default();
+        for mode in FLAG_MODES.chars() {
+            parsed.set(mode, modes.contains(mode));
+        }
+        parsed
+    }
+}
+
+impl fmt::Display for ChanModes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags: String = FLAG_MODES.chars().filter(|mode| self.is_set(*mode)).collect();
+        write!(f, "+{}", flags)
+    }
+}
+
+/* the ChanServ side of a registered channel */
+#[derive(Debug, Clone)]
+pub struct ChanReg {
+    pub founder: String,
+    pub registered_at: i64,
+    pub access: Vec<String>,
+}
+
+impl ChanReg {
+    /* the founder and the access list get ops on joining */
+    pub fn has_access(&self, account: &str) -> bool {
+        self.founder.eq_ignore_ascii_case(account) || self.access.iter().any(|acc| acc.eq_ignore_ascii_case(account))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BanMask {
+    pub mask: String,
+    pub setter: String,
+    pub timestamp: i64,
+}
+
+/* a channel is just a handle on a task that owns all of the channel's
+ * state. anything that wants to look at or change the channel sends it a
+ * job over an mpsc channel, and the task runs the jobs one at a time
+ * against ChanState, so there's nothing to lock. lines a job wants to go
+ * out get queued in the outbox and delivered by the task once the job is
+ * done, so fanout for a channel is serialized too.
+ *
+ * with [server] fanout = "broadcast" the channel's messages (not STATUSMSG,
+ * which only some members get) skip the outbox and go on a broadcast
+ * stream instead, with a tail() task per member queueing them, so the
+ * channel's task only sends each message the once. before it delivers
+ * anything from the outbox, and when someone leaves, the channel's task
+ * catches their stream up itself, which keeps each member's lines in
+ * the order the channel sent them. a member who still falls more than
+ * STREAM_CAPACITY behind is a slow client, see Tail::lagged() */
+type ChanJob = Box<dyn FnOnce(&mut ChanState) + Send>;
+
+static NEXT_CHAN_ID: AtomicU64 = AtomicU64::new(1);
+
+/* messages a channel's stream holds for members' tail() tasks to catch up on */
+const STREAM_CAPACITY: usize = 1024;
+
 #[derive(Debug)]
 pub struct Channel {
+    id: u64,
     name: String,
-    topic: Mutex<Option<ChanTopic>>,
-    users: Mutex<BTreeMap<String, ChanUser>>,
-    banmasks: Mutex<Vec<String>>,
-    irc: Arc<Core>,
+    /* creation time, the channel TS on a server link */
+    created: i64,
+    jobs: mpsc::UnboundedSender<ChanJob>,
 }
 
-impl Channel {
-    pub fn new(irc: &Arc<Core>, chanmask: &str) -> Channel {
-        let name = chanmask.to_string();
-        let topic = Mutex::new(None);
-        let users = Mutex::new(BTreeMap::new());
-        let banmasks = Mutex::new(Vec::new());
-        Channel {
-            name,
-            topic,
-            users,
-            banmasks,
-            irc: Arc::clone(&irc)
+/* the same line for everyone, or an event each connection gets
+ * its own form of */
+enum Outgoing {
+    Line(Arc<str>),
+    Event(Arc<event::Shared>),
+}
+
+/* a message on the broadcast stream, for everyone but its sender */
+#[derive(Debug)]
+struct Posting {
+    skip: Weak<User>,
+    event: Arc<event::Shared>,
+}
+
+/* fanout = "broadcast", what the channel's task posts on. `wake` gets
+ * every tail() task looking for what's new */
+struct Stream {
+    postings: broadcast::Sender<Arc<Posting>>,
+    wake: Arc<Notify>,
+}
+
+struct ChanState {
+    id: u64,
+    name: String,
+    created: i64,
+    topic: Option<ChanTopic>,
+    users: BTreeMap<String, ChanUser>,
+    modes: ChanModes,
+    banmasks: Vec<BanMask>,
+    audit: VecDeque<AuditEntry>,
+    irc: Weak<Core>,
+    /* registered with ChanServ, these stay around when empty */
+    reg: Option<ChanReg>,
+    /* something the store keeps has changed since it was last saved */
+    dirty: bool,
+    /* set once someone has joined, so a brand new channel isn't
+     * mistaken for an abandoned one */
+    populated: bool,
+    /* the last user left and we're out of the namespace, any JOIN
+     * that still got here has to go and make a new channel */
+    closed: bool,
+    /* each line once, with everyone it's going to */
+    outbox: Vec<(Vec<Weak<User>>, Outgoing)>,
+    /* run after the outbox is flushed, this is how call() answers so
+     * the caller can't get ahead of the lines its job sent out */
+    done: Vec<Box<dyn FnOnce() + Send>>,
+    /* fanout = "broadcast", when the channel was made */
+    stream: Option<Stream>,
+}
+
+impl ChanState {
+    /* what goes in the store, if we're registered */
+    fn snapshot(&self) -> Option<ChannelReg> {
+        let reg = self.reg.as_ref()?;
+        Some(ChannelReg {
+            name: self.name.clone(),
+            founder: reg.founder.clone(),
+            registered_at: reg.registered_at,
+            created: self.created,
+            modes: self.modes.to_string(),
+            topic: self.topic.clone(),
+            bans: self.banmasks.clone(),
+            access: reg.access.clone(),
+        })
+    }
+
+    /* keep the last [audit] entries, and append to the file if there is one */
+    fn record(&mut self, by: &str, action: String) {
+        let irc = match Weak::upgrade(&self.irc) {
+            Some(irc) => irc,
+            None => return,
+        };
+        let config = &irc.get_config().audit;
+        if config.entries == 0 {
+            return;
+        }
+        let entry = AuditEntry::new(by, action);
+        if let Some(path) = &config.file {
+            audit::append(path, &self.name, &entry);
+        }
+        self.audit.push_back(entry);
+        while self.audit.len() > config.entries {
+            self.audit.pop_front();
         }
     }
 
-    /* spit out a vector of (key, value) tuples */
-    fn _get_user_list(&self) -> Vec<(String, ChanUser)> {
-        self.users
-            .lock()
-            .unwrap()
-            .clone()
-            .into_iter()
-            .collect::<Vec<_>>()
+    /* queue a line for everyone on the channel, minus one nick maybe */
+    fn broadcast(&mut self, line: &Arc<str>, skip: Option<&str>) {
+        self.broadcast_status(line, skip, &ChanFlags::None);
+    }
+
+    /* only to members with at least status */
+    fn broadcast_status(&mut self, line: &Arc<str>, skip: Option<&str>, status: &ChanFlags) {
+        let to = self.recipients(skip, status);
+        self.outbox.push((to, Outgoing::Line(Arc::clone(line))));
+    }
+
+    fn broadcast_event(&mut self, event: &Arc<event::Shared>, skip: Option<&str>, status: &ChanFlags) {
+        let to = self.recipients(skip, status);
+        self.outbox.push((to, Outgoing::Event(Arc::clone(event))));
     }
 
-    /* generate a vector of Arc pointers to users on this channel,
-     * remove any nicks from the tree if upgrade on the weak pointer
-     * fails */
-    pub fn gen_user_ptr_vec(&self) -> Vec<Arc<User>> {
-        let mut bad_keys = Vec::new();
+    /* a message from a member (or someone outside a -n channel) for
+     * everyone else, on the stream if we've got one */
+    fn broadcast_chatter(&mut self, event: &Arc<event::Shared>, from: &str) {
+        let skip = self.users.get(from).map_or_else(Weak::new, |chan_user| Weak::clone(&chan_user.user_ptr));
+        match &self.stream {
+            Some(stream) => {
+                /* only fails with nobody subscribed, so nobody to tell */
+                let _ = stream.postings.send(Arc::new(Posting { skip, event: Arc::clone(event) }));
+                stream.wake.notify_waiters();
+            },
+            None => self.broadcast_event(event, Some(from), &ChanFlags::None),
+        }
+    }
+
+    fn recipients(&self, skip: Option<&str>, status: &ChanFlags) -> Vec<Weak<User>> {
+        let mut to = Vec::with_capacity(self.users.len());
+        for (nick, chan_user) in self.users.iter() {
+            if Some(nick.as_str()) != skip && chan_user.chan_flags >= *status {
+                to.push(Weak::clone(&chan_user.user_ptr));
+            }
+        }
+        to
+    }
+
+    /* Arc pointers to users on this channel, any nicks whose
+     * weak pointer won't upgrade get dropped from the tree */
+    fn live_users(&mut self) -> Vec<Arc<User>> {
         let mut ret = Vec::new();
-        for (key, val) in self._get_user_list().iter() {
-            if let Some(ptr) = Weak::upgrade(&val.user_ptr) {
+        self.users.retain(|_nick, chan_user| match Weak::upgrade(&chan_user.user_ptr) {
+            Some(ptr) => {
                 ret.push(ptr);
-            } else {
-                bad_keys.push(key.clone());
+                true
+            },
+            None => false,
+        });
+        ret
+    }
+
+    /* nicks with their prefix, for NAMES */
+    fn nick_list(&self) -> Vec<String> {
+        self.users
+            .iter()
+            .map(|(key, val)| format!("{}{}", val.chan_flags.prefix(), key))
+            .collect::<Vec<_>>()
+    }
+
+    fn get_flags(&self, nick: &str) -> Option<ChanFlags> {
+        self.users.get(nick).map(|chan_user| chan_user.chan_flags.clone())
+    }
+
+    /* tell the modules, see Core::chan_event() */
+    fn notify(&self, event: impl FnOnce() -> ChanEvent) {
+        if let Some(irc) = Weak::upgrade(&self.irc) {
+            irc.chan_event(&self.name, event);
+        }
+    }
+
+    fn has_ops(&self) -> bool {
+        self.users.values().any(|chan_user| chan_user.chan_flags == ChanFlags::Op)
+    }
+
+    fn op_restore(&self) -> bool {
+        Weak::upgrade(&self.irc).is_some_and(|irc| irc.get_config().server.op_restore)
+    }
+
+    /* [server] op_restore, after someone's left. a registered channel
+     * with nobody opped any more gets ops back for everyone on it with
+     * access, an unregistered one has to wait for the next JOIN */
+    fn restore_ops(&mut self) {
+        if self.users.is_empty() || self.has_ops() || !self.op_restore() {
+            return;
+        }
+        let (irc, reg) = match (Weak::upgrade(&self.irc), &self.reg) {
+            (Some(irc), Some(reg)) => (irc, reg.clone()),
+            _ => return,
+        };
+        let mut opped = Vec::new();
+        for (nick, chan_user) in self.users.iter_mut() {
+            let account = Weak::upgrade(&chan_user.user_ptr).and_then(|user| user.get_account());
+            if account.is_some_and(|account| reg.has_access(&account)) {
+                chan_user.chan_flags = ChanFlags::Op;
+                opped.push(nick.clone());
             }
         }
-        for key in bad_keys.iter() {
-            self.users.lock().unwrap().remove(key);
+        for nick in opped {
+            let op = shared_line(&format!(":{} MODE {} +o {}", irc.get_host(), self.name, nick));
+            self.broadcast(&op, None);
         }
-        ret
     }
 
-    /* this one just gives the actual nicks themselves,
-     * without chan privilege signifiers */
-    fn _get_nick_list_wo_badges(&self) -> Vec<String> {
-        self._get_user_list()
-            .iter()
-            .map(|(key, _val)|{
-                key.to_string()
-            }).collect::<Vec<_>>()
+    /* bans can match either the shown host or the real one,
+     * otherwise a vhost would be a free pass */
+    fn is_banned(&self, masks: &UserMasks) -> bool {
+        self.banmasks.iter().any(|ban| {
+            mask::matches(&ban.mask, &masks.shown) || mask::matches(&ban.mask, &masks.real)
+        })
     }
 
-    /* this time give the nicks processed with added '+'
-     * tag for voice or '@' for chanop */
-    pub fn get_nick_list(&self) -> Vec<String> {
-        self._get_user_list()
-            .iter()
-            .map(|(key, val)| {
-                match val.chan_flags {
-                    ChanFlags::None => key.to_string(),
-                    ChanFlags::Voice => format!("+{}", key).to_string(),
-                    ChanFlags::Op => format!("@{}", key).to_string(),
+    /* PRIVMSG/NOTICE permission: ops and voices always get through,
+     * otherwise +m, +n and bans all apply */
+    fn can_send(&self, masks: &UserMasks) -> bool {
+        match self.get_flags(&masks.nick) {
+            Some(ChanFlags::Op) | Some(ChanFlags::Voice) => true,
+            Some(ChanFlags::None) => !self.modes.moderated && !self.is_banned(masks),
+            None => !self.modes.no_external && !self.modes.moderated && !self.is_banned(masks),
+        }
+    }
+}
+
+/* everything about a user the channel task needs to judge them, taken
+ * up front since jobs can't borrow the User */
+struct UserMasks {
+    nick: String,
+    shown: String,
+    real: String,
+}
+
+impl UserMasks {
+    fn new(user: &User) -> Self {
+        let nick = user.get_nick();
+        let real = format!("{}!{}@{}", nick, user.get_username(), user.get_host_string());
+        UserMasks { nick, shown: user.get_prefix(), real }
+    }
+}
+
+/* a member's end of the channel's stream. their tail() task works
+ * through it as messages come in, but the channel's task can catch it
+ * up too, see run() */
+#[derive(Debug)]
+struct Tail {
+    member: Weak<User>,
+    chan: String,
+    irc: Weak<Core>,
+    /* None once they've left */
+    stream: Mutex<Option<broadcast::Receiver<Arc<Posting>>>>,
+    wake: Arc<Notify>,
+}
+
+impl Tail {
+    /* queue everything posted for them so far. false once there's
+     * nothing more to come, and their tail() task can stop */
+    fn catch_up(&self) -> bool {
+        let mut stream = self.stream.lock().unwrap();
+        let (postings, user) = match (stream.as_mut(), Weak::upgrade(&self.member)) {
+            (Some(postings), Some(user)) => (postings, user),
+            _ => {
+                *stream = None;
+                return false;
+            }
+        };
+        loop {
+            match postings.try_recv() {
+                Ok(posting) if Weak::ptr_eq(&posting.skip, &self.member) => (),
+                Ok(posting) => {
+                    if let Err(err) = user.queue_event(&posting.event) {
+                        debug!("another tasks's client died: {}, note dead key {}", err, &user.get_nick());
+                    }
+                },
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Lagged(missed)) => {
+                    if !self.lagged(&user, missed) {
+                        *stream = None;
+                        return false;
+                    }
+                },
+                Err(TryRecvError::Closed) => {
+                    *stream = None;
+                    return false;
+                }
+            }
+        }
+    }
+
+    /* more than STREAM_CAPACITY behind, so `missed` messages are gone.
+     * that's a slow client, [limits] slow_policy says whether they get
+     * told what they missed or disconnected. false if they're going */
+    fn lagged(&self, user: &Arc<User>, missed: u64) -> bool {
+        let irc = match Weak::upgrade(&self.irc) {
+            Some(irc) => irc,
+            None => return false,
+        };
+        debug!("{} fell behind on {} and missed {} messages", user.get_nick(), self.chan, missed);
+        match irc.get_config().limits.slow_policy {
+            SlowPolicy::DropChatter => {
+                let notice = format!(":{} NOTICE {} :*** You missed {} messages on {}", irc.get_host(), user.get_nick(), missed, self.chan);
+                let _ = user.queue_shared(&shared_line(&notice));
+                true
+            },
+            SlowPolicy::Disconnect => {
+                for client in user.get_clients() {
+                    client.disconnect("Slow client");
                 }
-            }).collect::<Vec<_>>()
+                false
+            },
+        }
     }
 
-    pub fn get_n_users(&self) -> usize {
-        self.users.lock().unwrap().len()
+    /* the last of what they were due, and then no more */
+    fn close(&self) {
+        self.catch_up();
+        self.stream.lock().unwrap().take();
+        self.wake.notify_waiters();
     }
+}
 
-    pub fn get_topic(&self) -> Option<ChanTopic> {
-        match self.topic.lock().unwrap().clone() {
-            Some(topic) => Some(topic.clone()),
-            None => None
+/* works through a member's stream until they leave or the channel closes */
+fn tail(stream: &Stream, user: &Arc<User>, chan: &str, irc: &Weak<Core>) -> Arc<Tail> {
+    let tail = Arc::new(Tail {
+        member: Arc::downgrade(user),
+        chan: chan.to_string(),
+        irc: Weak::clone(irc),
+        stream: Mutex::new(Some(stream.postings.subscribe())),
+        wake: Arc::clone(&stream.wake),
+    });
+    let ours = Arc::clone(&tail);
+    tokio::spawn(async move {
+        loop {
+            /* enabled before looking, so a posting in between still wakes us */
+            let woken = ours.wake.notified();
+            tokio::pin!(woken);
+            woken.as_mut().enable();
+            if !ours.catch_up() {
+                break;
+            }
+            woken.await;
         }
+    });
+    tail
+}
+
+fn format_msg(prefix: &str, command_str: &str, target: &str, msg: &str) -> Arc<str> {
+    shared_line(&if msg.is_empty() {
+        format!(":{} {} {}", prefix, command_str, target)
+    } else {
+        format!(":{} {} {} :{}", prefix, command_str, target, msg)
+    })
+}
+
+/* a job panicked part way through, so there's no knowing what shape
+ * the state's in and nothing more gets run against it. everyone is
+ * kicked and the channel goes, a registered one comes straight back
+ * the way the store last had it */
+async fn shut_down(mut state: ChanState) {
+    state.closed = true;
+    state.outbox.clear();
+    state.done.clear();
+    let irc = match Weak::upgrade(&state.irc) {
+        Some(irc) => irc,
+        None => return,
+    };
+    let reason = "Channel closed after an internal error";
+    for (nick, chan_user) in std::mem::take(&mut state.users) {
+        if let Some(user) = Weak::upgrade(&chan_user.user_ptr) {
+            /* after their ChanUser goes, so the KICK comes after
+             * anything from the stream */
+            drop(chan_user);
+            user.channel_list.lock().unwrap().remove(&state.name);
+            let kick = format_msg(&irc.get_host(), "KICK", &format!("{} {}", state.name, nick), reason);
+            let _ = user.queue_shared(&kick);
+            state.notify(|| ChanEvent::Kick { by: irc.get_host(), nick, reason: reason.to_string() });
+        }
+    }
+    if !irc.remove_chan(&state.name, state.id) || state.reg.is_none() {
+        return;
+    }
+    match irc.accounts.list_channels().await {
+        Ok(regs) => {
+            if let Some(reg) = regs.iter().find(|reg| reg.name == state.name) {
+                let chan = Arc::new(Channel::restore(&irc, reg));
+                if let Err(err) = irc.insert_name(&reg.name, NamedEntity::Chan(chan)) {
+                    warn!("couldn't bring back registered channel {}: {}", reg.name, err);
+                }
+            }
+        },
+        Err(err) => warn!("couldn't reload registered channel {}: {}", state.name, err),
+    }
+}
+
+async fn run(mut state: ChanState, mut jobs: mpsc::UnboundedReceiver<ChanJob>) {
+    while let Some(job) = jobs.recv().await {
+        /* a bug in one job costs the channel, not the whole server.
+         * anything still queued gets dropped with `jobs`, so their
+         * call()s come back JobFailed */
+        if panic::catch_unwind(AssertUnwindSafe(|| job(&mut state))).is_err() {
+            warn!("a job on {} panicked, closing the channel", state.name);
+            shut_down(state).await;
+            return;
+        }
+        /* everyone gets what's on the stream for them before anything
+         * after it from the outbox, their tail() tasks may not have got
+         * there yet */
+        if state.stream.is_some() && !state.outbox.is_empty() {
+            for tail in state.users.values().filter_map(|chan_user| chan_user.tail.as_ref()) {
+                tail.catch_up();
+            }
+        }
+        /* none of the sends wait on the recipient, they only queue the
+         * line up for its write task or drop it if that's over its sendq,
+         * so the whole outbox goes out without yielding once */
+        for (to, line) in std::mem::take(&mut state.outbox) {
+            for user in to.iter().filter_map(Weak::upgrade) {
+                let sent = match &line {
+                    Outgoing::Line(line) => user.queue_shared(line),
+                    Outgoing::Event(event) => user.queue_event(event),
+                };
+                if let Err(err) = sent {
+                    debug!("another tasks's client died: {}, note dead key {}", err, &user.get_nick());
+                }
+            }
+        }
+        for done in std::mem::take(&mut state.done) {
+            done();
+        }
+        /* saved here rather than from the job so saves can't overtake each other */
+        if state.dirty {
+            state.dirty = false;
+            if let (Some(reg), Some(irc)) = (state.snapshot(), Weak::upgrade(&state.irc)) {
+                if let Err(err) = irc.accounts.save_channel(reg).await {
+                    warn!("couldn't save registered channel {}: {}", state.name, err);
+                }
+            }
+        }
+        /* last one out takes the channel out of the namespace,
+         * unless it's registered or +P. taking +P off an empty
+         * channel closes it here too */
+        if state.populated && state.users.is_empty() && !state.closed && state.reg.is_none() && !state.modes.permanent {
+            state.closed = true;
+            if let Some(irc) = Weak::upgrade(&state.irc) {
+                irc.remove_chan(&state.name, state.id);
+            }
+        }
+    }
+    debug!("channel task for {} finished", state.name);
+}
+
+impl Channel {
+    pub fn new(irc: &Arc<Core>, chanmask: &str) -> Channel {
+        Channel::start(irc, chanmask, Utc::now().timestamp(), |_state| ())
+    }
+
+    /* a registered channel coming back at startup, it counts as populated
+     * so that dropping it while it's still empty closes it */
+    pub fn restore(irc: &Arc<Core>, reg: &ChannelReg) -> Channel {
+        Channel::start(irc, &reg.name, reg.created, |state| {
+            state.topic = reg.topic.clone();
+            state.modes = ChanModes::parse(&reg.modes);
+            state.banmasks = reg.bans.clone();
+            state.reg = Some(ChanReg {
+                founder: reg.founder.clone(),
+                registered_at: reg.registered_at,
+                access: reg.access.clone(),
+            });
+            state.populated = true;
+        })
+    }
+
+    fn start(irc: &Arc<Core>, chanmask: &str, created: i64, setup: impl FnOnce(&mut ChanState)) -> Channel {
+        let id = NEXT_CHAN_ID.fetch_add(1, Ordering::Relaxed);
+        let name = chanmask.to_string();
+        let (jobs, rx) = mpsc::unbounded_channel();
+        let mut state = ChanState {
+            id,
+            name: name.clone(),
+            created,
+            topic: None,
+            users: BTreeMap::new(),
+            modes: ChanModes::default(),
+            banmasks: Vec::new(),
+            audit: VecDeque::new(),
+            irc: Arc::downgrade(irc),
+            reg: None,
+            dirty: false,
+            populated: false,
+            closed: false,
+            outbox: Vec::new(),
+            done: Vec::new(),
+            stream: match irc.get_config().server.fanout {
+                Fanout::Outbox => None,
+                Fanout::Broadcast => Some(Stream {
+                    postings: broadcast::channel(STREAM_CAPACITY).0,
+                    wake: Arc::new(Notify::new()),
+                }),
+            },
+        };
+        setup(&mut state);
+        tokio::spawn(run(state, rx));
+        Channel { id, name, created, jobs }
+    }
+
+    /* fire and forget, the task only stops once every handle is
+     * gone so the send can't fail while we've got &self */
+    fn job(&self, job: impl FnOnce(&mut ChanState) + Send + 'static) {
+        let _ = self.jobs.send(Box::new(job));
+    }
+
+    /* run a job and wait for its answer, which only arrives once
+     * anything it broadcast has been handed to the clients. Err if the
+     * job panicked or the channel's gone, see shut_down(). the getters
+     * below answer as though there was nothing there and the setters as
+     * though nothing changed */
+    async fn call<T: Send + 'static>(&self, f: impl FnOnce(&mut ChanState) -> T + Send + 'static) -> Result<T, ChanError> {
+        let (tx, rx) = oneshot::channel();
+        self.job(move |state| {
+            let ret = f(state);
+            state.done.push(Box::new(move || {
+                let _ = tx.send(ret);
+            }));
+        });
+        rx.await.map_err(|_| ChanError::JobFailed(self.name.clone()))
+    }
+
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn get_ts(&self) -> i64 {
+        self.created
+    }
+
+    pub async fn gen_user_ptr_vec(&self) -> Vec<Arc<User>> {
+        self.call(|state| state.live_users()).await.unwrap_or_default()
+    }
+
+    /* users along with their @/+ status, for WHO */
+    pub async fn get_members(&self) -> Vec<(Arc<User>, ChanFlags)> {
+        self.call(|state| {
+            state.users.values()
+                .filter_map(|chan_user| Weak::upgrade(&chan_user.user_ptr).map(|user| (user, chan_user.chan_flags.clone())))
+                .collect()
+        }).await.unwrap_or_default()
     }
 
-    pub fn set_topic(&self, topic_text: &str, user: &User) {
+    pub async fn get_nick_list(&self) -> Vec<String> {
+        self.call(|state| state.nick_list()).await.unwrap_or_default()
+    }
+
+    pub async fn get_n_users(&self) -> usize {
+        self.call(|state| state.users.len()).await.unwrap_or_default()
+    }
+
+    pub async fn get_topic(&self) -> Option<ChanTopic> {
+        self.call(|state| state.topic.clone()).await.unwrap_or_default()
+    }
+
+    pub async fn set_topic(&self, topic_text: &str, user: &User) {
+        self.set_topic_as(topic_text, &user.get_prefix()).await
+    }
+
+    /* for topics from somewhere other than one of our users, e.g. services */
+    pub async fn set_topic_as(&self, topic_text: &str, usermask: &str) {
         let topic = ChanTopic {
             text: topic_text.to_string(),
-            usermask: user.get_prefix(),
+            usermask: usermask.to_string(),
             timestamp: Utc::now().timestamp()
         };
-        *self.topic.lock().unwrap() = Some(topic);
+        self.call(move |state| {
+            state.record(&topic.usermask, format!("TOPIC :{}", topic.text));
+            state.notify(|| ChanEvent::Topic { by: topic.usermask.clone(), text: topic.text.clone() });
+            state.topic = Some(topic);
+            state.dirty = true;
+        }).await.unwrap_or_default()
     }
 
-    pub fn get_name(&self) -> String {
-        self.name.clone()
+    pub async fn is_op(&self, user: &User) -> bool {
+        self.get_flags(&user.get_nick()).await == Some(ChanFlags::Op)
+    }
+
+    pub async fn get_flags(&self, nick: &str) -> Option<ChanFlags> {
+        let nick = nick.to_string();
+        self.call(move |state| state.get_flags(&nick)).await.unwrap_or_default()
     }
 
-    pub fn get_names_list(&self) -> Vec<String> {
-        self.get_nick_list()
+    /* false if they aren't on the channel */
+    pub async fn set_flags(&self, nick: &str, flags: ChanFlags) -> bool {
+        let nick = nick.to_string();
+        self.call(move |state| match state.users.get_mut(&nick) {
+            Some(chan_user) => {
+                chan_user.chan_flags = flags;
+                true
+            },
+            None => false,
+        }).await.unwrap_or_default()
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.users.lock().unwrap().is_empty()
+    /* oldest first */
+    pub async fn get_audit(&self) -> Vec<AuditEntry> {
+        self.call(|state| state.audit.iter().cloned().collect()).await.unwrap_or_default()
     }
 
-    pub fn is_op(&self, user: &User) -> bool {
-        let op = format!("@{}", &user.nick.lock().unwrap());
-        self.users.lock().unwrap().contains_key(&op)
+    /* for the audit log, queued like update_nick() */
+    pub fn audit(&self, by: &str, action: String) {
+        let by = by.to_string();
+        self.job(move |state| state.record(&by, action));
     }
 
-    pub fn is_joined(&self, nick: &str) -> bool {
-        self.users.lock().unwrap().contains_key(nick)
+    pub async fn get_modes(&self) -> ChanModes {
+        self.call(|state| state.modes.clone()).await.unwrap_or_default()
     }
 
-    /* put add_ and rm_user() here together and have all the code to handle
-     * that in one place, both for User and Chan side - plus, mutex lock
-     * everything for the entire fn call */
-    pub async fn add_user(self: &Arc<Self>, new_user: &Arc<User>, flags: ChanFlags) -> Result<ClientReplies, GenError> {
-        let chan = self.get_name();
-        let mut replies = Vec::new();
-        {
-            let mut chan_mutex_lock = self.users.lock().unwrap();
-            let mut user_mutex_lock = new_user.channel_list.lock().unwrap();
-            let nick = new_user.get_nick();
-            let chan = self.get_name();
-            let chan_ptr = Arc::downgrade(&self);
+    /* true if it actually changed anything */
+    pub async fn set_mode(&self, mode: char, on: bool) -> bool {
+        self.call(move |state| {
+            let changed = state.modes.is_set(mode) != on && state.modes.set(mode, on);
+            state.dirty |= changed;
+            changed
+        }).await.unwrap_or_default()
+    }
 
-            if !chan_mutex_lock.contains_key(&nick) {
-                chan_mutex_lock.insert(nick, ChanUser::new(new_user, flags));
-                user_mutex_lock.insert(chan, chan_ptr);
+    pub async fn get_bans(&self) -> Vec<BanMask> {
+        self.call(|state| state.banmasks.clone()).await.unwrap_or_default()
+    }
 
-                
-            } else {
-                return Ok(replies) /* already on chan */
+    pub async fn add_ban(&self, mask: &str, setter: &str) -> bool {
+        let ban = BanMask {
+            mask: mask.to_string(),
+            setter: setter.to_string(),
+            timestamp: Utc::now().timestamp(),
+        };
+        self.call(move |state| {
+            if state.banmasks.iter().any(|old| old.mask.eq_ignore_ascii_case(&ban.mask)) {
+                return false;
             }
-        } /* de-scope mutex locks */
+            state.banmasks.push(ban);
+            state.dirty = true;
+            true
+        }).await.unwrap_or_default()
+    }
 
-        /* also self.notify_join() */
-        replies.push(self.notify_join(new_user, &chan).await?);
-        if let Some(topic) = self.get_topic() {
-            replies.push(Ok(ircReply::Topic(chan.to_string(), topic.text)));
-            replies.push(Ok(ircReply::TopicSetBy(chan.to_string(), topic.usermask, topic.timestamp)))
-        }
-        replies.push(Ok(ircReply::NameReply(chan.to_string(), self.get_nick_list())));
-        replies.push(Ok(ircReply::EndofNames(chan.to_string())));
-        Ok(replies)
+    pub async fn remove_ban(&self, mask: &str) -> bool {
+        let mask = mask.to_string();
+        self.call(move |state| {
+            let before = state.banmasks.len();
+            state.banmasks.retain(|ban| !ban.mask.eq_ignore_ascii_case(&mask));
+            state.dirty |= state.banmasks.len() != before;
+            state.banmasks.len() != before
+        }).await.unwrap_or_default()
     }
 
-    /* still need this for User::drop() */
-    pub fn rm_key(&self, key: &str) -> Option<ChanUser> {
-        self.users.lock().unwrap().remove(key)
+    pub async fn get_reg(&self) -> Option<ChanReg> {
+        self.call(|state| state.reg.clone()).await.unwrap_or_default()
     }
 
-    /* put add_ and rm_user() here together and have all the code to handle
-     * that in one place, both for User and Chan side - plus, mutex lock
-     * everything for the entire fn call */
-    pub async fn rm_user(&self, user: &User, msg: &str) -> Result<(), ChanError> {
-        /* Notify part msg */
-        if !self.is_empty() {
-            let _res = self.notify_part(user, &self.get_name(), msg).await;
-        }
-
-        let retval = {
-            let mut chan_mutex_lock = self.users.lock().unwrap();
-            let mut user_mutex_lock = user.channel_list.lock().unwrap();
-
-            let key = user.get_nick().to_string();
-            let chan = self.get_name();
-            if let Some(_val) = chan_mutex_lock.remove(&key) {
-                user_mutex_lock.remove(&chan);
-                if chan_mutex_lock.is_empty() {
-                    if let Err(err) = self.irc.remove_name(&chan) {
-                        warn!("error {} removing chan {} from hash - it doesn't exist", err, &chan);
-                    }
+    /* false if it's already registered */
+    pub async fn register(&self, founder: &str) -> bool {
+        let reg = ChanReg {
+            founder: founder.to_string(),
+            registered_at: Utc::now().timestamp(),
+            access: Vec::new(),
+        };
+        self.call(move |state| {
+            if state.reg.is_some() {
+                return false;
+            }
+            state.reg = Some(reg);
+            state.dirty = true;
+            true
+        }).await.unwrap_or_default()
+    }
+
+    /* the store has to be told separately, see ChanServ DROP */
+    pub async fn unregister(&self) -> bool {
+        self.call(|state| state.reg.take().is_some()).await.unwrap_or_default()
+    }
+
+    /* false if there was nothing to change */
+    pub async fn set_access(&self, account: &str, add: bool) -> bool {
+        let account = account.to_string();
+        self.call(move |state| {
+            let reg = match &mut state.reg {
+                Some(reg) => reg,
+                None => return false,
+            };
+            let listed = reg.access.iter().any(|acc| acc.eq_ignore_ascii_case(&account));
+            match (add, listed) {
+                (true, false) => reg.access.push(account),
+                (false, true) => reg.access.retain(|acc| !acc.eq_ignore_ascii_case(&account)),
+                _ => return false,
+            }
+            state.dirty = true;
+            true
+        }).await.unwrap_or_default()
+    }
+
+    pub async fn is_banned(&self, user: &User) -> bool {
+        let masks = UserMasks::new(user);
+        self.call(move |state| state.is_banned(&masks)).await.unwrap_or_default()
+    }
+
+    /* send a line as-is to everyone on the channel, e.g. MODE changes */
+    pub async fn send_line(&self, line: &str) {
+        let line = shared_line(line);
+        self.call(move |state| state.broadcast(&line, None)).await.unwrap_or_default()
+    }
+
+    pub async fn is_joined(&self, nick: &str) -> bool {
+        let nick = nick.to_string();
+        self.call(move |state| state.users.contains_key(&nick)).await.unwrap_or_default()
+    }
+
+    /* both the Chan and User side of the link get done in the one job,
+     * so nobody sees one without the other. None means the channel
+     * closed up before we got there and the caller should make a new one */
+    pub async fn add_user(self: &Arc<Self>, new_user: &Arc<User>, flags: ChanFlags) -> Result<Option<ClientReplies>, ChanError> {
+        let chan_ptr = Arc::downgrade(self);
+        let user = Arc::downgrade(new_user);
+        let nick = new_user.get_nick();
+        let account = new_user.get_account();
+        let join = event::Shared::new(Event::Join {
+            prefix: new_user.get_prefix(),
+            chan: self.name.clone(),
+            account: account.clone(),
+            realname: new_user.get_realname(),
+        });
+        self.call(move |state| {
+            if state.closed {
+                return None;
+            }
+            let auto_op = match (&state.reg, &account) {
+                (Some(reg), Some(account)) => flags == ChanFlags::None && reg.has_access(account),
+                /* op_restore, first in to an unregistered channel
+                 * that's been left without ops */
+                (None, _) => flags == ChanFlags::None && !state.users.is_empty() && !state.has_ops() && state.op_restore(),
+                _ => false,
+            };
+            let flags = if auto_op { ChanFlags::Op } else { flags };
+            let mut replies = Vec::new();
+            let new_user = match Weak::upgrade(&user) {
+                Some(new_user) => new_user,
+                None => return Some(replies),
+            };
+            if let Entry::Vacant(entry) = state.users.entry(nick) {
+                let mut chan_user = ChanUser::new(&new_user, flags);
+                if let Some(stream) = &state.stream {
+                    chan_user.tail = Some(tail(stream, &new_user, &state.name, &state.irc));
                 }
-                Ok(())
+                entry.insert(chan_user);
+                new_user.channel_list.lock().unwrap().insert(state.name.clone(), chan_ptr);
+                state.populated = true;
             } else {
-                Err(ChanError::UnlinkFailed(key, chan))
+                return Some(replies) /* already on chan */
+            }
+
+            /* your own JOIN echoed back confirms success */
+            state.broadcast_event(&join, None, &ChanFlags::None);
+            state.notify(|| ChanEvent::Join { prefix: new_user.get_prefix() });
+            let chan = state.name.clone();
+            if auto_op {
+                if let Some(irc) = Weak::upgrade(&state.irc) {
+                    let op = shared_line(&format!(":{} MODE {} +o {}", irc.get_host(), chan, new_user.get_nick()));
+                    state.broadcast(&op, None);
+                }
             }
-        }; /* de-scope Mutex */
+            if let Some(topic) = &state.topic {
+                replies.push(Ok(ircReply::Topic(chan.clone(), topic.text.clone())));
+                replies.push(Ok(ircReply::TopicSetBy(chan.clone(), topic.usermask.clone(), topic.timestamp)))
+            }
+            replies.push(Ok(ircReply::NameReply(chan.clone(), state.nick_list())));
+            replies.push(Ok(ircReply::EndofNames(chan)));
+            Some(replies)
+        }).await
+    }
 
-        retval
+    /* for User::drop() and cleaning up dead keys, the user side of
+     * the link is already gone so there's nobody to tell */
+    pub fn rm_key(&self, key: &str) {
+        let key = key.to_string();
+        self.job(move |state| {
+            state.users.remove(&key);
+            state.restore_ops();
+        });
     }
 
-    /* similar rationale to the above about linking and unlinking users to chans */
-    pub fn update_nick(&self, old_nick: &str, new_nick: &str) -> Result<(), ircError> {
-        let mut mutex_lock = self.users.lock().unwrap();
-        let key = old_nick.to_string();
-        if let Some(val) = mutex_lock.remove(&key) {
-            mutex_lock.insert(new_nick.to_string(), val);
+    pub async fn rm_user(&self, user: &User, msg: &str) -> Result<(), ChanError> {
+        let line = format_msg(&user.get_prefix(), "PART", &self.name, msg);
+        let event = ChanEvent::Part { prefix: user.get_prefix(), reason: msg.to_string() };
+        self.unlink(user, line, event).await
+    }
+
+    /* there's no KICK command yet, this is for the server (scripts and
+     * the like) to throw someone out, `by` is whoever it comes from */
+    pub async fn kick(&self, by: &str, user: &User, reason: &str) -> Result<(), ChanError> {
+        let target = format!("{} {}", self.name, user.get_nick());
+        let line = format_msg(by, "KICK", &target, reason);
+        let nick = user.get_nick();
+        let event = ChanEvent::Kick { by: by.to_string(), nick: nick.clone(), reason: reason.to_string() };
+        self.unlink(user, line, event).await?;
+        self.audit(by, format!("KICK {} :{}", nick, reason));
+        Ok(())
+    }
+
+    /* take a user off the channel, they get `line` too */
+    async fn unlink(&self, user: &User, line: Arc<str>, event: ChanEvent) -> Result<(), ChanError> {
+        let key = user.get_nick();
+        let outcome = self.call(move |state| {
+            if !state.users.contains_key(&key) {
+                return Err(ChanError::UnlinkFailed(key, state.name.clone()));
+            }
+            state.broadcast(&line, None);
+            state.notify(|| event);
+            state.users.remove(&key);
+            state.restore_ops();
             Ok(())
-        } else {
-            Err(ircError::NotOnChannel(self.name.clone()))
+        }).await.and_then(|outcome| outcome);
+        if outcome.is_ok() {
+            user.channel_list.lock().unwrap().remove(&self.name);
         }
+        outcome
     }
 
-    async fn _send_msg(
-        &self,
-        source: &User,
-        command_str: &str,
-        target: &str,
-        msg: &str
-    ) -> Result<ClientReply, GenError> {
-        // checks for banmasks should be done-
-        // also whether the sending user is in the channel or not
-        let prefix = source.get_prefix();
-        let line = if msg.is_empty() {
-            format!(":{} {} {}", prefix, command_str, target)
-        } else {
-            format!(":{} {} {} :{}", prefix, command_str, target, msg)
-        };
+    /* queued rather than awaited, this gets called with the namespace
+     * locked so that a NICK and a JOIN can't cross over */
+    pub fn update_nick(&self, old_nick: &str, new_nick: &str) {
+        let (old_nick, new_nick) = (old_nick.to_string(), new_nick.to_string());
+        self.job(move |state| {
+            if let Some(val) = state.users.remove(&old_nick) {
+                state.users.insert(new_nick, val);
+            } else {
+                warn!("{} changed nick to {} but wasn't on {}", old_nick, new_nick, state.name);
+            }
+        });
+    }
 
-        if self.is_joined(&source.get_nick()) {
-            // if we clone the list, the true list could change while
-            // we're forwarding messages, but this keeps us thread safe
-            let users = self.gen_user_ptr_vec();
-            for user in users.iter() {
-                // if you're parting or joining, your own echoed message confirms success
-                if user.id != source.id || command_str == "JOIN" || command_str == "PART" {
-                    if let Err(err) = user.send_line(&line).await {
-                        debug!("another tasks's client died: {}, note dead key {}", err, &user.get_nick());
-                        //user.clear_chans_and_exit();
-                    }
+    /* can_send(), +C and +G get checked in the same job as the fanout
+     * so a mode change can't land in between */
+    pub async fn send_msg(&self, source: &User, cmd: &str, target: &str, msg: &str, tags: &str) -> Result<ClientReply, GenError> {
+        let masks = UserMasks::new(source);
+        let censor = source.irc.get_censor();
+        let ctcp = ctcp::parse(msg).is_some_and(|ctcp| !ctcp.is_action());
+        let (cmd, target, msg, tags) = (cmd.to_string(), target.to_string(), msg.to_string(), tags.to_string());
+        Ok(self.call(move |state| {
+            if !state.can_send(&masks) || (ctcp && state.modes.no_ctcp) {
+                return Err(ircError::CannotSendToChan(target));
+            }
+            let msg = if state.modes.censor {
+                match censor.apply(&msg) {
+                    Some(msg) => msg,
+                    None => return Err(ircError::CannotSendToChan(target)),
                 }
+            } else {
+                Cow::Borrowed(msg.as_str())
+            };
+            let status = split_status(&target).0;
+            /* messages only some of the channel can see aren't anyone's business */
+            if cmd != "TAGMSG" && status == ChanFlags::None {
+                state.notify(|| ChanEvent::Message { prefix: masks.shown.clone(), command: cmd.clone(), text: msg.to_string() });
+            }
+            let event = event::Shared::new(Event::Message {
+                tags: tags.clone(),
+                prefix: masks.shown.clone(),
+                command: cmd.clone(),
+                target: target.clone(),
+                text: msg.into_owned(),
+            });
+            if status == ChanFlags::None {
+                state.broadcast_chatter(&event, &masks.nick);
+            } else {
+                state.broadcast_event(&event, Some(&masks.nick), &status);
             }
-            Ok(Ok(ircReply::None))
-        } else {
-            Ok(Err(ircError::CannotSendToChan(target.to_string())))
+            Ok(ircReply::None)
+        }).await?)
+    }
+
+    /* QUIT only makes sense from members, and they don't get
+     * it back since they're gone */
+    pub async fn notify_quit(&self, source: &User, chan: &str, msg: &str) -> Result<ClientReply, GenError> {
+        let nick = source.get_nick();
+        let prefix = source.get_prefix();
+        let line = format_msg(&prefix, "QUIT", chan, msg);
+        let (chan, reason) = (chan.to_string(), msg.to_string());
+        Ok(self.call(move |state| {
+            if state.users.contains_key(&nick) {
+                state.broadcast(&line, Some(&nick));
+                state.notify(|| ChanEvent::Quit { prefix, reason });
+                Ok(ircReply::None)
+            } else {
+                Err(ircError::CannotSendToChan(chan))
+            }
+        }).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{send_queue, Client, Host, SendQueueRecv};
+    use crate::config::Config;
+    use crate::irc::accounts::AccountStore;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+    use tokio::time;
+
+    fn core(fanout: Fanout) -> Arc<Core> {
+        let mut config = Config::default();
+        config.server.fanout = fanout;
+        let accounts = AccountStore::open(":memory:").unwrap();
+        Core::new("irc.test".to_string(), "test".to_string(), config, accounts)
+    }
+
+    /* a user with no socket, whatever's sent to them stays in the queue */
+    fn member(irc: &Arc<Core>, nick: &str) -> (Arc<User>, Arc<Client>, SendQueueRecv) {
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let (tx, rx) = send_queue(irc.get_memory());
+        let client = Client::new(irc.assign_id(), Host::HostAddr(ip), ip, false, false, None, irc, tx);
+        let user = User::new(irc.assign_id(), irc, nick.to_string(), nick.to_string(), nick.to_string(),
+                             Host::HostAddr(ip), irc.get_host(), &client, None);
+        (user, client, rx)
+    }
+
+    #[test]
+    fn every_mode_listed() {
+        assert_eq!(all_modes(), "CGPbmnostvz");
+    }
+
+    fn privmsg(from: &User, text: &str) -> Arc<event::Shared> {
+        event::Shared::new(Event::Message {
+            tags: String::new(),
+            prefix: from.get_prefix(),
+            command: "PRIVMSG".to_string(),
+            target: "#test".to_string(),
+            text: text.to_string(),
+        })
+    }
+
+    /* the messages come off the stream, the MODE and PART out of the
+     * outbox, and bob still gets them in the order they were sent. the
+     * jobs are queued without waiting in between so bob's tail() gets
+     * no chance to run before the outbox goes out */
+    #[tokio::test]
+    async fn stream_keeps_order() {
+        let irc = core(Fanout::Broadcast);
+        let (alice, _alice_client, _alice_rx) = member(&irc, "alice");
+        let (bob, _bob_client, mut bob_rx) = member(&irc, "bob");
+        let chan = Arc::new(Channel::new(&irc, "#test"));
+        chan.add_user(&alice, ChanFlags::Op).await.unwrap();
+        chan.add_user(&bob, ChanFlags::None).await.unwrap();
+        for i in 0..100 {
+            let event = privmsg(&alice, &i.to_string());
+            chan.job(move |state| state.broadcast_chatter(&event, "alice"));
         }
+        chan.job(|state| state.broadcast(&shared_line(":alice MODE #test +m"), None));
+        for i in 100..110 {
+            let event = privmsg(&alice, &i.to_string());
+            chan.job(move |state| state.broadcast_chatter(&event, "alice"));
+        }
+        chan.rm_user(&bob, "bye").await.unwrap();
+        let lines = bob_rx.drain();
+        let said = |i| format!(":{} PRIVMSG #test :{}", alice.get_prefix(), i);
+        let mut want: Vec<String> = (0..100).map(said).collect();
+        want.push(":alice MODE #test +m".to_string());
+        want.extend((100..110).map(said));
+        want.push(format!(":{} PART #test :bye", bob.get_prefix()));
+        assert_eq!(lines[lines.len() - want.len()..], want[..]);
     }
 
-    pub async fn send_msg(&self, source: &User, cmd: &str, target: &str, msg: &str) -> Result<ClientReply, GenError> {
-        self._send_msg(source, cmd, target, msg).await
+    /* more than STREAM_CAPACITY behind, and bob gets told what he missed */
+    #[tokio::test]
+    async fn lagging_member_told() {
+        let irc = core(Fanout::Broadcast);
+        let (alice, _alice_client, _alice_rx) = member(&irc, "alice");
+        let (bob, _bob_client, mut bob_rx) = member(&irc, "bob");
+        let chan = Arc::new(Channel::new(&irc, "#test"));
+        chan.add_user(&alice, ChanFlags::Op).await.unwrap();
+        chan.add_user(&bob, ChanFlags::None).await.unwrap();
+        let event = privmsg(&alice, "flood");
+        /* all in the one job, so bob's tail() can't get a look in */
+        chan.job(move |state| {
+            for _ in 0..STREAM_CAPACITY + 10 {
+                state.broadcast_chatter(&event, "alice");
+            }
+        });
+        chan.rm_user(&bob, "bye").await.unwrap();
+        let lines = bob_rx.drain();
+        assert!(lines.contains(&":irc.test NOTICE bob :*** You missed 10 messages on #test".to_string()), "got {:?}", lines);
+        assert_eq!(lines.iter().filter(|line| line.ends_with(":flood")).count(), STREAM_CAPACITY);
     }
 
-    pub async fn notify_join(&self, source: &User, chan: &str) -> Result<ClientReply, GenError> {
-        self._send_msg(source, "JOIN", chan, "").await
+    /* fire and forget jobs run in the order they're sent, and a call()
+     * only answers once what it broadcast is queued for everyone */
+    #[tokio::test]
+    async fn jobs_in_order() {
+        let irc = core(Fanout::Outbox);
+        let (alice, _alice_client, mut alice_rx) = member(&irc, "alice");
+        let chan = Arc::new(Channel::new(&irc, "#test"));
+        chan.add_user(&alice, ChanFlags::Op).await.unwrap();
+        for i in 0..10 {
+            chan.audit("alice", i.to_string());
+        }
+        let actions: Vec<String> = chan.get_audit().await.into_iter().map(|entry| entry.action).collect();
+        assert_eq!(actions, (0..10).map(|i| i.to_string()).collect::<Vec<_>>());
+        alice_rx.drain();
+        chan.send_line(":alice MODE #test +m").await;
+        assert_eq!(alice_rx.drain(), vec![":alice MODE #test +m"]);
     }
 
-    pub async fn notify_part(&self, source: &User, chan: &str, msg: &str) -> Result<ClientReply, GenError> {
-        self._send_msg(source, "PART", chan, msg).await
+    /* a panicking job closes the channel: everyone's kicked, it's gone
+     * from the namespace, and calls from then on come back JobFailed */
+    #[tokio::test]
+    async fn panic_closes_channel() {
+        let irc = core(Fanout::Broadcast);
+        let (alice, _alice_client, mut alice_rx) = member(&irc, "alice");
+        let chan = Arc::new(Channel::new(&irc, "#test"));
+        irc.insert_name("#test", NamedEntity::Chan(Arc::clone(&chan))).unwrap();
+        chan.add_user(&alice, ChanFlags::Op).await.unwrap();
+        alice_rx.drain();
+        let failed = chan.call(|_state| -> bool { panic!("job went wrong") }).await;
+        assert!(matches!(failed, Err(ChanError::JobFailed(_))));
+        assert!(matches!(chan.call(|state| state.users.len()).await, Err(ChanError::JobFailed(_))));
+        assert!(matches!(chan.add_user(&alice, ChanFlags::None).await, Err(ChanError::JobFailed(_))));
+        assert!(irc.get_chan("#test").is_err());
+        assert!(alice.channel_list.lock().unwrap().is_empty());
+        assert_eq!(alice_rx.drain(), vec![":irc.test KICK #test alice :Channel closed after an internal error"]);
     }
 
-    pub async fn notify_quit(&self, source: &User, chan: &str, msg: &str) -> Result<ClientReply, GenError> {
-        self._send_msg(source, "QUIT", chan, msg).await
+    /* and a registered one comes back from the store */
+    #[tokio::test]
+    async fn panic_restores_registered() {
+        let irc = core(Fanout::Outbox);
+        let (alice, _alice_client, _alice_rx) = member(&irc, "alice");
+        let chan = Arc::new(Channel::new(&irc, "#test"));
+        irc.insert_name("#test", NamedEntity::Chan(Arc::clone(&chan))).unwrap();
+        chan.add_user(&alice, ChanFlags::Op).await.unwrap();
+        assert!(chan.register("alice").await);
+        chan.set_topic_as("saved", "alice").await;
+        let _ = chan.call(|_state| -> bool { panic!("job went wrong") }).await;
+        let back = time::timeout(Duration::from_secs(5), async {
+            loop {
+                match irc.get_chan("#test") {
+                    Ok(back) => return back,
+                    Err(_) => time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        }).await.expect("registered channel should come back");
+        assert_ne!(back.get_id(), chan.get_id());
+        assert_eq!(back.get_reg().await.map(|reg| reg.founder), Some("alice".to_string()));
+        assert_eq!(back.get_topic().await.map(|topic| topic.text), Some("saved".to_string()));
+        assert_eq!(back.get_n_users().await, 0);
     }
 }
@@ -0,0 +1,152 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* [chanlog], what goes on in the channels listed there written out to
+ * disk as it happens, a file per channel per day (or month, or just the
+ * one) under the chanlog directory. it's a module like any other, fed
+ * by on_chan_event(), and hands its lines to a task of its own which
+ * writes them in order on a blocking thread so no channel waits on the
+ * disk. the files are opened for each line, so moving or deleting them
+ * underneath us is fine */
+use crate::config::{ChanlogConfig, ChanlogRotate};
+use crate::irc::ctcp;
+use crate::irc::mask;
+use crate::irc::module::{ChanEvent, Module};
+use crate::irc::Core;
+use chrono::{DateTime, Local};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task;
+use tracing::warn;
+
+#[derive(Debug)]
+pub struct ChanLog {
+    config: ChanlogConfig,
+    lines: mpsc::UnboundedSender<(PathBuf, String)>,
+}
+
+impl ChanLog {
+    /* None with no channels to log */
+    pub fn start(config: &ChanlogConfig) -> Option<Self> {
+        if config.channels.is_empty() {
+            return None;
+        }
+        let (lines, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run(rx));
+        Some(ChanLog { config: config.clone(), lines })
+    }
+
+    fn logs(&self, chan: &str) -> bool {
+        self.config.channels.iter().any(|pattern| mask::matches(pattern, chan))
+    }
+
+    /* <directory>/<channel>/<date>.log, or <directory>/<channel>.log
+     * when it never rotates */
+    fn path(&self, chan: &str, now: &DateTime<Local>) -> PathBuf {
+        let mut path = PathBuf::from(&self.config.directory);
+        let name = file_name(chan);
+        match self.config.rotate {
+            ChanlogRotate::Daily => path.extend([name, now.format("%Y-%m-%d.log").to_string()]),
+            ChanlogRotate::Monthly => path.extend([name, now.format("%Y-%m.log").to_string()]),
+            ChanlogRotate::Never => path.push(format!("{}.log", name)),
+        }
+        path
+    }
+}
+
+impl Module for ChanLog {
+    fn name(&self) -> &str {
+        "chanlog"
+    }
+
+    fn on_chan_event(&self, _irc: &Arc<Core>, chan: &str, event: &ChanEvent) {
+        if !self.logs(chan) {
+            return;
+        }
+        let now = Local::now();
+        let line = format!("[{}] {}\n", now.format(&self.config.timestamp), describe(chan, event));
+        let _res = self.lines.send((self.path(chan, &now), line));
+    }
+}
+
+/* the channel as something safe to put in a path, lowercase so the
+ * one channel doesn't end up split over differently cased files. it
+ * starts with a channel prefix so it can't be . or .. */
+fn file_name(chan: &str) -> String {
+    chan.to_lowercase()
+        .chars()
+        .map(|c| if c == '/' || c == '\\' || c.is_control() { '_' } else { c })
+        .collect()
+}
+
+fn nick(prefix: &str) -> &str {
+    prefix.split('!').next().unwrap_or(prefix)
+}
+
+/* each event as a line of the log, without the time */
+fn describe(chan: &str, event: &ChanEvent) -> String {
+    match event {
+        ChanEvent::Join { prefix } => format!("--> {} has joined {}", prefix, chan),
+        ChanEvent::Part { prefix, reason } => format!("<-- {} has left {} ({})", prefix, chan, reason),
+        ChanEvent::Kick { by, nick: kicked, reason } => format!("<-- {} was kicked by {} ({})", kicked, nick(by), reason),
+        ChanEvent::Quit { prefix, reason } => format!("<-- {} has quit ({})", prefix, reason),
+        ChanEvent::Topic { by, text } => format!("-!- {} changed the topic to: {}", nick(by), text),
+        ChanEvent::Message { prefix, command, text } => match ctcp::parse(text) {
+            Some(action) if action.is_action() => format!(" * {} {}", nick(prefix), action.params),
+            _ if command == "NOTICE" => format!("-{}- {}", nick(prefix), text),
+            _ => format!("<{}> {}", nick(prefix), text),
+        },
+    }
+}
+
+async fn run(mut lines: mpsc::UnboundedReceiver<(PathBuf, String)>) {
+    while let Some((path, line)) = lines.recv().await {
+        let written = task::spawn_blocking(move || {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            OpenOptions::new().create(true).append(true).open(&path)?.write_all(line.as_bytes())
+        }).await;
+        match written {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => warn!("couldn't write to channel log: {}", err),
+            Err(err) => warn!("channel log writer failed: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines() {
+        let prefix = "nick!user@host".to_string();
+        assert_eq!(describe("#chan", &ChanEvent::Join { prefix: prefix.clone() }), "--> nick!user@host has joined #chan");
+        let message = |command: &str, text: &str| ChanEvent::Message {
+            prefix: prefix.clone(),
+            command: command.to_string(),
+            text: text.to_string(),
+        };
+        assert_eq!(describe("#chan", &message("PRIVMSG", "hi")), "<nick> hi");
+        assert_eq!(describe("#chan", &message("PRIVMSG", "\x01ACTION waves\x01")), " * nick waves");
+        assert_eq!(describe("#chan", &message("NOTICE", "hi")), "-nick- hi");
+        assert_eq!(file_name("#Rust/Help"), "#rust_help");
+    }
+}
@@ -0,0 +1,120 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* CHECK <nick>|<channel>, opers only. dumps what we've got on a user or
+ * channel, including things no other command shows like the sendq and
+ * the negotiated caps, for working out what's going on in a live server.
+ * like AUDIT it all comes back as server notices */
+use crate::client::{ClientReplies, GenError};
+use crate::irc::chan::Channel;
+use crate::irc::error::Error as ircError;
+use crate::irc::isupport::CHANTYPES;
+use crate::irc::{Core, User};
+use crate::parser::ParsedMsg;
+use chrono::{TimeZone, Utc};
+use std::sync::{Arc, Weak};
+
+fn timestamp(ts: i64) -> String {
+    format!("{} ({})", Utc.timestamp(ts, 0).format("%Y-%m-%d %H:%M:%S"), ts)
+}
+
+fn check_user(user: &Arc<User>) -> Vec<(&'static str, String)> {
+    let mut lines = vec![
+        ("id", user.get_id().to_string()),
+        ("mask", user.get_prefix()),
+        ("realname", user.get_realname()),
+        ("host", user.get_host_string()),
+        ("server", user.get_server()),
+        ("modes", user.get_modes()),
+        ("signon", timestamp(user.get_signon())),
+        ("nick ts", timestamp(user.get_nick_ts())),
+        ("idle", format!("{}s", user.get_idle())),
+    ];
+    if let Some(account) = user.get_account() {
+        lines.push(("account", account));
+    }
+    if let Some(zones) = user.get_dnsbl_mark() {
+        lines.push(("dnsbl", zones));
+    }
+    if let Some(uid) = user.get_remote_uid() {
+        lines.push(("uid", uid));
+    } else if let Ok(client) = user.fetch_client() {
+        lines.push(("ip", client.get_ip().to_string()));
+        if let Some(gateway) = client.get_gateway() {
+            lines.push(("gateway", gateway));
+        }
+        lines.push(("secure", client.is_secure().to_string()));
+        if let Some(certfp) = client.get_certfp() {
+            lines.push(("certfp", certfp));
+        }
+        lines.push(("sendq", format!("{} bytes", client.get_sendq())));
+        let caps = client.get_caps().list();
+        lines.push(("caps", if caps.is_empty() { "none".to_string() } else { caps.join(" ") }));
+    }
+    let mut chans: Vec<String> = user.get_channel_list().iter().filter_map(Weak::upgrade).map(|chan| chan.get_name()).collect();
+    chans.sort();
+    lines.push(("channels", if chans.is_empty() { "none".to_string() } else { chans.join(" ") }));
+    lines
+}
+
+async fn check_chan(chan: &Channel) -> Vec<(&'static str, String)> {
+    let mut lines = vec![
+        ("id", chan.get_id().to_string()),
+        ("created", timestamp(chan.get_ts())),
+        ("modes", chan.get_modes().await.to_string()),
+    ];
+    if let Some(topic) = chan.get_topic().await {
+        lines.push(("topic", topic.text));
+        lines.push(("topic set", format!("by {} at {}", topic.usermask, timestamp(topic.timestamp))));
+    }
+    let members = chan.get_members().await;
+    lines.push(("members", members.len().to_string()));
+    for (user, flags) in members.iter() {
+        lines.push(("member", format!("{}{} (id {})", flags.prefix(), user.get_prefix(), user.get_id())));
+    }
+    for ban in chan.get_bans().await {
+        lines.push(("ban", format!("{} by {} at {}", ban.mask, ban.setter, timestamp(ban.timestamp))));
+    }
+    lines.push(("audit entries", chan.get_audit().await.len().to_string()));
+    lines
+}
+
+pub async fn command(irc: &Core, user: &Arc<User>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let target = &params.opt_params[0];
+    let lines = if target.starts_with(|c| CHANTYPES.contains(c)) {
+        match irc.get_chan(target) {
+            Ok(chan) => check_chan(&chan).await,
+            Err(err) => {
+                replies.push(Err(err));
+                return Ok(replies);
+            }
+        }
+    } else {
+        match irc.get_nick(target).and_then(|found| Weak::upgrade(&found)) {
+            Some(found) => check_user(&found),
+            None => {
+                replies.push(Err(ircError::NoSuchNick(target.to_string())));
+                return Ok(replies);
+            }
+        }
+    };
+    for (key, value) in lines {
+        irc.server_notice(user, &format!("CHECK {} {}: {}", target, key, value)).await;
+    }
+    irc.server_notice(user, &format!("End of CHECK for {}", target)).await;
+    Ok(replies)
+}
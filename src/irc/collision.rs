@@ -0,0 +1,121 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* nick collisions, for when a linked server brings in a nick we've already
+ * got. same rules as TS6: each side's nick has a timestamp, for two
+ * different people the older nick keeps it, but for the same user@host
+ * it's most likely them reconnecting so the newer one does. a tie and
+ * both lose. the services link calls this when a UID or NICK from
+ * services lands on a nick one of our users has */
+use crate::config::CollisionAction;
+use crate::irc::connlog::{self, Event};
+use crate::irc::{Core, User};
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+/* one side's claim on the nick */
+pub struct NickClaim<'a> {
+    pub ts: i64,
+    pub username: &'a str,
+    pub host: &'a str,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Resolution {
+    KeepLocal,
+    KeepRemote,
+    DropBoth,
+}
+
+pub fn resolve(local: &NickClaim, remote: &NickClaim) -> Resolution {
+    if local.ts == remote.ts {
+        return Resolution::DropBoth;
+    }
+    let same_user = local.username == remote.username && local.host.eq_ignore_ascii_case(remote.host);
+    if (local.ts < remote.ts) != same_user {
+        Resolution::KeepLocal
+    } else {
+        Resolution::KeepRemote
+    }
+}
+
+/* where the loser goes when we rename rather than kill, starting with a
+ * digit means no client can ask for it with NICK. fits in NICKLEN */
+pub fn uid_nick(id: u64) -> String {
+    format!("0{:08X}", id & 0xffff_ffff)
+}
+
+/* settle a collision between our user and the remote claim. if ours
+ * loses they get renamed or killed as [server] collision says, the
+ * caller deals with the remote side going by what comes back */
+pub async fn handle(irc: &Core, user: &Arc<User>, remote: &NickClaim<'_>) -> Resolution {
+    let nick = user.get_nick();
+    let username = user.get_username();
+    let host = user.get_host_string();
+    let local = NickClaim { ts: user.get_nick_ts(), username: &username, host: &host };
+    let resolution = resolve(&local, remote);
+    debug!("nick collision on {}: {:?}", nick, resolution);
+    if resolution == Resolution::KeepLocal {
+        return resolution;
+    }
+    irc.notice_opers(&format!("Nick collision on {} ({}@{} vs {}@{})", nick, username, host, remote.username, remote.host)).await;
+    match irc.get_config().server.collision {
+        CollisionAction::Rename => {
+            let new_nick = uid_nick(user.get_id());
+            match user.change_nick(&new_nick) {
+                Ok(_) => {
+                    let line = format!(":{}!{}@{} NICK :{}", nick, username, user.get_visible_host(), new_nick);
+                    if let Err(err) = user.send_line(&line).await {
+                        debug!("couldn't tell {} about their new nick: {}", new_nick, err);
+                    }
+                },
+                Err(err) => {
+                    warn!("couldn't rename {} after a nick collision: {}", nick, err);
+                    kill(user);
+                },
+            }
+        },
+        CollisionAction::Kill => kill(user),
+    }
+    resolution
+}
+
+fn kill(user: &Arc<User>) {
+    match user.fetch_client() {
+        Ok(client) => {
+            connlog::log(&client, Event::Kill("Nick collision"));
+            client.disconnect("Nick collision");
+        },
+        Err(_) => user.end_session(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn older_or_same_user() {
+        let claim = |ts, username, host| NickClaim { ts, username, host };
+        assert_eq!(resolve(&claim(100, "a", "one.example"), &claim(200, "b", "two.example")), Resolution::KeepLocal);
+        assert_eq!(resolve(&claim(200, "a", "one.example"), &claim(100, "b", "two.example")), Resolution::KeepRemote);
+        /* same user@host, the newer connection wins */
+        assert_eq!(resolve(&claim(100, "a", "one.example"), &claim(200, "a", "ONE.example")), Resolution::KeepRemote);
+        assert_eq!(resolve(&claim(200, "a", "one.example"), &claim(100, "a", "one.example")), Resolution::KeepLocal);
+        assert_eq!(resolve(&claim(100, "a", "one.example"), &claim(100, "b", "two.example")), Resolution::DropBoth);
+        assert_eq!(uid_nick(42), "00000002A");
+    }
+}
@@ -0,0 +1,197 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* every command we know about, and what it takes to use it. irc::command()
+ * checks a command against its Spec before its handler gets called, so the
+ * handlers can take it as read that the client's registered (or not, as
+ * the case may be), is an oper where it needs to be, and sent at least
+ * min_params parameters. anything they want beyond that is up to them */
+use crate::irc::error::Error as ircError;
+use std::str::FromStr;
+use Command::*;
+use Registration::{After, Any, Before};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Command {
+    Cap,
+    Authenticate,
+    Webirc,
+    Pass,
+    Server,
+    Capab,
+    Nick,
+    User,
+    Oper,
+    Resv,
+    Unresv,
+    Privmsg,
+    Notice,
+    Tagmsg,
+    Join,
+    Part,
+    Topic,
+    List,
+    Names,
+    Audit,
+    Check,
+    Mode,
+    Sajoin,
+    Sapart,
+    Samode,
+    Sanick,
+    Admin,
+    Info,
+    Version,
+    Time,
+    Trace,
+    Stats,
+    Rehash,
+    Globops,
+    Whois,
+    Who,
+    Markread,
+    Quit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Registration {
+    /* before or after, CAP and the like */
+    Any,
+    /* only while registering, ERR_ALREADYREGISTRED after */
+    Before,
+    /* only once registered, ERR_NOTREGISTERED before */
+    After,
+}
+
+#[derive(Debug)]
+pub struct Spec {
+    pub command: Command,
+    pub name: &'static str,
+    pub registration: Registration,
+    pub oper: bool,
+    pub min_params: usize,
+}
+
+const fn spec(command: Command, name: &'static str, registration: Registration, oper: bool, min_params: usize) -> Spec {
+    Spec { command, name, registration, oper, min_params }
+}
+
+pub const COMMANDS: &[Spec] = &[
+    spec(Cap, "CAP", Any, false, 1),
+    spec(Authenticate, "AUTHENTICATE", Any, false, 1),
+    spec(Webirc, "WEBIRC", Before, false, 4),
+    spec(Pass, "PASS", Before, false, 1),
+    spec(Server, "SERVER", Before, false, 0),
+    spec(Capab, "CAPAB", Before, false, 0),
+    spec(Nick, "NICK", Any, false, 1),
+    spec(User, "USER", Any, false, 4),
+    spec(Oper, "OPER", After, false, 2),
+    spec(Resv, "RESV", After, true, 0),
+    spec(Unresv, "UNRESV", After, true, 1),
+    spec(Privmsg, "PRIVMSG", After, false, 0),
+    spec(Notice, "NOTICE", After, false, 0),
+    spec(Tagmsg, "TAGMSG", After, false, 0),
+    spec(Join, "JOIN", After, false, 1),
+    spec(Part, "PART", After, false, 1),
+    spec(Topic, "TOPIC", After, false, 1),
+    spec(List, "LIST", After, false, 0),
+    spec(Names, "NAMES", After, false, 0),
+    spec(Audit, "AUDIT", After, false, 1),
+    spec(Check, "CHECK", After, true, 1),
+    spec(Mode, "MODE", After, false, 1),
+    spec(Sajoin, "SAJOIN", After, true, 2),
+    spec(Sapart, "SAPART", After, true, 2),
+    spec(Samode, "SAMODE", After, true, 2),
+    spec(Sanick, "SANICK", After, true, 2),
+    spec(Admin, "ADMIN", After, false, 0),
+    spec(Info, "INFO", After, false, 0),
+    spec(Version, "VERSION", After, false, 0),
+    spec(Time, "TIME", After, false, 0),
+    spec(Trace, "TRACE", After, true, 0),
+    spec(Stats, "STATS", After, true, 1),
+    spec(Rehash, "REHASH", After, true, 0),
+    spec(Globops, "GLOBOPS", After, true, 1),
+    spec(Whois, "WHOIS", After, false, 0),
+    spec(Who, "WHO", After, false, 0),
+    spec(Markread, "MARKREAD", After, false, 1),
+    spec(Quit, "QUIT", Any, false, 0),
+];
+
+impl Command {
+    pub fn spec(self) -> &'static Spec {
+        /* every variant has exactly one entry, see the test below */
+        COMMANDS.iter().find(|spec| spec.command == self).unwrap()
+    }
+
+    pub fn name(self) -> &'static str {
+        self.spec().name
+    }
+
+    /* whether a client in this state can use the command at all. is_oper
+     * is only asked once we know there's a registered user to ask about */
+    pub fn check(self, registered: bool, is_oper: impl FnOnce() -> bool, n_params: usize) -> Result<(), ircError> {
+        let spec = self.spec();
+        match spec.registration {
+            Before if registered => return Err(ircError::AlreadyRegistred),
+            After if !registered => return Err(ircError::NotRegistered),
+            _ => (),
+        }
+        if spec.oper && !is_oper() {
+            return Err(ircError::NoPrivileges);
+        }
+        if n_params < spec.min_params {
+            return Err(ircError::NeedMoreParams(spec.name.to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Command {
+    type Err = ircError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        COMMANDS
+            .iter()
+            .find(|spec| spec.name.eq_ignore_ascii_case(name))
+            .map(|spec| spec.command)
+            .ok_or_else(|| ircError::UnknownCommand(name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table() {
+        for spec in COMMANDS {
+            assert_eq!(COMMANDS.iter().filter(|other| other.command == spec.command).count(), 1);
+            assert_eq!(spec.name.parse::<Command>().ok(), Some(spec.command));
+        }
+        assert_eq!("privmsg".parse::<Command>().ok(), Some(Privmsg));
+        assert!("BOGUS".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn checks() {
+        assert!(Join.check(false, || false, 1).is_err());
+        assert!(Webirc.check(true, || false, 4).is_err());
+        assert!(Sajoin.check(true, || false, 2).is_err());
+        assert!(Sajoin.check(true, || true, 1).is_err());
+        assert!(Sajoin.check(true, || true, 2).is_ok());
+        assert!(Quit.check(false, || unreachable!(), 0).is_ok());
+    }
+}
@@ -0,0 +1,121 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* the [connlog] file, one JSON object per line for every local connection
+ * coming, registering, changing nick and going, for digging through after
+ * abuse. unlike the tracing output it's there whatever RUST_LOG says */
+use crate::client::{Client, ClientType};
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::Write;
+use tokio::task;
+use tracing::warn;
+
+pub enum Event<'a> {
+    Connect,
+    Register,
+    /* the old nick, the client already has the new one */
+    Nick(&'a str),
+    Quit(&'a str),
+    Kill(&'a str),
+    /* the mask that matched */
+    Kline(&'a str),
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time: String,
+    event: &'static str,
+    id: u64,
+    ip: String,
+    host: String,
+    ident: Option<String>,
+    nick: Option<String>,
+    account: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old_nick: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mask: Option<&'a str>,
+}
+
+/* does nothing without a file. the write goes off on a blocking
+ * thread, the same as the channel audit log */
+pub fn log(client: &Client, event: Event) {
+    let path = match &client.get_irc().get_config().connlog.file {
+        Some(path) => path.clone(),
+        None => return,
+    };
+    let (ident, account) = match client.get_client_type() {
+        ClientType::User(user) => (Some(user.get_username()), user.get_account()),
+        ClientType::ProtoUser(proto_user) => (proto_user.lock().unwrap().get_username(), client.get_sasl().get_account()),
+        _ => (None, None),
+    };
+    let nick = client.get_nick_or_star();
+    let mut record = Record {
+        time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        event: "",
+        id: client.get_id(),
+        ip: client.get_ip().to_string(),
+        host: client.get_host_string(),
+        ident,
+        nick: if nick == "*" { None } else { Some(nick) },
+        account,
+        old_nick: None,
+        reason: None,
+        mask: None,
+    };
+    record.event = match event {
+        Event::Connect => "connect",
+        Event::Register => "register",
+        Event::Nick(old) => {
+            record.old_nick = Some(old);
+            "nick"
+        },
+        Event::Quit(reason) => {
+            record.reason = Some(reason);
+            "quit"
+        },
+        Event::Kill(reason) => {
+            record.reason = Some(reason);
+            "kill"
+        },
+        Event::Kline(mask) => {
+            record.mask = Some(mask);
+            "kline"
+        },
+    };
+    let mut line = match serde_json::to_string(&record) {
+        Ok(line) => line,
+        Err(err) => {
+            warn!("couldn't format a connlog record: {}", err);
+            return;
+        }
+    };
+    line.push('\n');
+    task::spawn_blocking(move || {
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+        if let Err(err) = written {
+            warn!("couldn't write to connlog {}: {}", path, err);
+        }
+    });
+}
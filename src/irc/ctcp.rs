@@ -0,0 +1,74 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* CTCP (https://modern.ircdocs.horse/ctcp) is a PRIVMSG or NOTICE whose
+ * text is wrapped in \x01, e.g. "\x01VERSION\x01" or "\x01ACTION waves\x01".
+ * we relay them like any other message, this is just enough to tell
+ * them apart for +C, flood limits and answering VERSION ourselves */
+pub const DELIM: char = '\x01';
+
+#[derive(Debug, PartialEq)]
+pub struct Ctcp<'a> {
+    pub command: String,
+    pub params: &'a str,
+}
+
+impl Ctcp<'_> {
+    /* ACTION is /me, which is really just chat */
+    pub fn is_action(&self) -> bool {
+        self.command == "ACTION"
+    }
+}
+
+/* None if it's an ordinary message. the closing \x01 is optional,
+ * plenty of clients leave it off */
+pub fn parse(msg: &str) -> Option<Ctcp<'_>> {
+    let body = msg.strip_prefix(DELIM)?;
+    let body = body.strip_suffix(DELIM).unwrap_or(body);
+    let (command, params) = match body.find(' ') {
+        Some(space) => (&body[..space], &body[space + 1..]),
+        None => (body, ""),
+    };
+    if command.is_empty() {
+        return None;
+    }
+    Some(Ctcp { command: command.to_ascii_uppercase(), params })
+}
+
+pub fn quote(command: &str, params: &str) -> String {
+    if params.is_empty() {
+        format!("{}{}{}", DELIM, command, DELIM)
+    } else {
+        format!("{}{} {}{}", DELIM, command, params, DELIM)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_quote() {
+        assert_eq!(parse("\x01ACTION waves\x01"), Some(Ctcp { command: "ACTION".to_string(), params: "waves" }));
+        assert!(parse("\x01action waves").unwrap().is_action());
+        assert_eq!(parse("\x01version\x01"), Some(Ctcp { command: "VERSION".to_string(), params: "" }));
+        assert_eq!(parse("\x01DCC SEND file 1 2 3\x01").unwrap().params, "SEND file 1 2 3");
+        assert_eq!(parse("hello \x01VERSION\x01"), None);
+        assert_eq!(parse("\x01\x01"), None);
+        assert_eq!(quote("VERSION", "rusty-ircd"), "\x01VERSION rusty-ircd\x01");
+        assert_eq!(quote("PING", ""), "\x01PING\x01");
+    }
+}
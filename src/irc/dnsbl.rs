@@ -0,0 +1,138 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* DNS blacklist checks. the lookups are kicked off as soon as a client
+ * connects (and again after WEBIRC) and go through our own resolver, all
+ * the zones at once, while the client gets on with NICK/USER/CAP.
+ * registration then waits for the answer, which is never more than
+ * [dns] timeout_ms away, and applies the worst action out of all the
+ * zones that hit */
+use crate::client::{Client, GenError};
+use crate::config::{DnsblAction, DnsblBlock};
+use crate::irc::Core;
+use tracing::debug;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::task::{self, JoinHandle};
+
+#[derive(Debug, Clone)]
+pub struct DnsblHit {
+    pub zone: String,
+    pub action: DnsblAction,
+    pub reason: String,
+}
+
+pub type DnsblCheck = JoinHandle<Vec<DnsblHit>>;
+
+/* 192.0.2.1 in zone -> 1.2.0.192.zone, IPv6 goes nibble by nibble */
+pub fn query_name(ip: &IpAddr, zone: &str) -> String {
+    let reversed: Vec<String> = match ip {
+        IpAddr::V4(v4) => v4.octets().iter().rev().map(|octet| octet.to_string()).collect(),
+        IpAddr::V6(v6) => v6.octets()
+            .iter()
+            .rev()
+            .flat_map(|byte| vec![byte & 0xf, byte >> 4])
+            .map(|nibble| format!("{:x}", nibble))
+            .collect(),
+    };
+    format!("{}.{}", reversed.join("."), zone)
+}
+
+/* a listing answers with an A record in 127.0.0.0/8, NXDOMAIN or
+ * anything else (including lookup failures and timeouts) counts as not listed */
+async fn lookup(irc: Arc<Core>, ip: IpAddr, blocks: Vec<DnsblBlock>) -> Vec<DnsblHit> {
+    let queries: Vec<JoinHandle<Option<DnsblHit>>> = blocks.into_iter()
+        .map(|block| {
+            let irc = Arc::clone(&irc);
+            task::spawn(async move {
+                let addrs = irc.get_resolver().forward(&query_name(&ip, &block.zone)).await.unwrap_or_default();
+                if !addrs.iter().any(|addr| matches!(addr, IpAddr::V4(v4) if v4.octets()[0] == 127)) {
+                    return None;
+                }
+                Some(DnsblHit {
+                    reason: block.reason.clone().unwrap_or_else(|| format!("Listed in {}", block.zone)),
+                    zone: block.zone,
+                    action: block.action,
+                })
+            })
+        })
+        .collect();
+    let mut hits = Vec::new();
+    for query in queries {
+        if let Ok(Some(hit)) = query.await {
+            hits.push(hit);
+        }
+    }
+    hits
+}
+
+pub fn start_check(client: &Client) {
+    let blocks = client.get_irc().get_config().dnsbl.clone();
+    let ip = client.get_ip();
+    /* nothing to ask about for local connections */
+    if blocks.is_empty() || ip.is_loopback() {
+        return;
+    }
+    client.set_dnsbl_check(task::spawn(lookup(Arc::clone(client.get_irc()), ip, blocks)));
+}
+
+/* waits for the lookup and tells opers about any hits. an Err means the
+ * client should be dropped, otherwise we hand back the zones to mark the
+ * user with, if any */
+pub async fn enforce(irc: &Core, client: &Client) -> Result<Option<String>, GenError> {
+    let hits = match client.take_dnsbl_check() {
+        Some(check) => check.await?,
+        None => return Ok(None),
+    };
+    let worst = match hits.iter().max_by_key(|hit| hit.action) {
+        Some(worst) => worst,
+        None => return Ok(None),
+    };
+    let zones = hits.iter().map(|hit| hit.zone.as_str()).collect::<Vec<&str>>().join(", ");
+    let rejected = match worst.action {
+        DnsblAction::Reject => true,
+        DnsblAction::RequireSasl => client.get_sasl().get_account().is_none(),
+        DnsblAction::Mark => false,
+    };
+    let outcome = if rejected { "rejected" } else { "allowed" };
+    debug!("client {} ({}) listed in {}, {}", client.get_id(), client.get_ip(), zones, outcome);
+    irc.notice_opers(&format!(
+        "DNSBL: {} ({}) listed in {}, {}",
+        client.get_nick_or_star(), client.get_ip(), zones, outcome
+    )).await;
+
+    if rejected {
+        Err(GenError::Disconnect(worst.reason.clone()))
+    } else {
+        Ok(Some(zones))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reversed_query_names() {
+        let v4: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(query_name(&v4, "dnsbl.example"), "1.2.0.192.dnsbl.example");
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(
+            query_name(&v6, "dnsbl.example"),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.dnsbl.example"
+        );
+    }
+}
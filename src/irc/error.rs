@@ -15,6 +15,7 @@
 *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 use std::{error, fmt};
+use crate::irc::templates::Templates;
 use crate::parser::ParseError;
 
 impl From<ParseError> for Error {
@@ -34,59 +35,212 @@ impl From<ParseError> for Error {
     }
 }
 
+impl Error {
+    /* the numeric the error goes out as */
+    pub fn numeric(&self) -> u16 {
+        match self {
+            Error::NoSuchNick(_nick) => 401,
+            Error::NoSuchServer(_server) => 402,
+            Error::NoSuchChannel(_chan) => 403,
+            Error::CannotSendToChan(_chan) => 404,
+            Error::TooManyChannels(_chan) => 405,
+            Error::TooManyTargets(_cmd, _max) => 407,
+            Error::NoRecipient(_cmd) => 411,
+            Error::NoTextToSend => 412,
+            Error::NoTopLevel(_mask) => 413,
+            Error::WildTopLevel(_mask) => 414,
+            Error::InputTooLong => 417,
+            Error::UnknownCommand(_cmd) => 421,
+            Error::NoAdminInfo(_server) => 423,
+            Error::NoNicknameGiven => 431,
+            Error::ErroneusNickname(_nick) => 432,
+            Error::NicknameInUse(_nick) => 433,
+            Error::UnavailResource(_name, _reason) => 437,
+            Error::NickTooFast(_nick, _secs) => 438,
+            Error::UserNotInChannel(_nick, _chan) => 441,
+            Error::NotOnChannel(_chan) => 442,
+            Error::NotRegistered => 451,
+            Error::NeedMoreParams(_cmd) => 461,
+            Error::AlreadyRegistred => 462,
+            Error::InvalidUsername => 468,
+            Error::NoPermForHost => 463,
+            Error::PasswdMismatch => 464,
+            Error::UnknownMode(_mode) => 472,
+            Error::BannedFromChan(_chan) => 474,
+            Error::SecureOnlyChan(_chan) => 489,
+            Error::NoPrivileges => 481,
+            Error::ChanOPrivsNeeded(_chan) => 482,
+            Error::NoNonReg(_nick) => 486,
+            Error::NoOperHost => 491,
+            Error::UModeUnknownFlag => 501,
+            Error::CantSendToUser(_nick, _reason) => 531,
+            Error::UsersDontMatch => 502,
+            Error::InvalidCapCmd(_cmd) => 410,
+            Error::SaslFail => 904,
+            Error::SaslTooLong => 905,
+            Error::SaslAborted => 906,
+            Error::SaslAlready => 907,
+            Error::InvalidCommand(_cmd) => 600,
+            Error::InvalidHost(_host) => 601,
+            Error::InvalidUser(_user) => 602,
+            Error::NoCommandGiven => 603,
+            Error::EmptyMessage => 604,
+            Error::EmptyName => 605,
+            Error::EmptyNick => 606,
+            Error::EmptyUser => 607,
+            Error::EmptyHost => 608,
+        }
+    }
+
+    /* whatever goes between the recipient and the text */
+    pub fn params(&self) -> Vec<String> {
+        match self {
+            Error::NoSuchNick(nick) => vec![nick.clone()],
+            Error::NoSuchServer(server) => vec![server.clone()],
+            Error::NoSuchChannel(chan) => vec![chan.clone()],
+            Error::CannotSendToChan(chan) => vec![chan.clone()],
+            Error::TooManyChannels(chan) => vec![chan.clone()],
+            Error::TooManyTargets(cmd, _max) => vec![cmd.clone()],
+            Error::NoTopLevel(mask) => vec![mask.clone()],
+            Error::WildTopLevel(mask) => vec![mask.clone()],
+            Error::UnknownCommand(cmd) => vec![cmd.clone()],
+            Error::NoAdminInfo(server) => vec![server.clone()],
+            Error::ErroneusNickname(nick) => vec![nick.clone()],
+            Error::NicknameInUse(nick) => vec![nick.clone()],
+            Error::UnavailResource(name, _reason) => vec![name.clone()],
+            Error::NickTooFast(nick, _secs) => vec![nick.clone()],
+            Error::UserNotInChannel(nick, chan) => vec![nick.clone(), chan.clone()],
+            Error::NotOnChannel(chan) => vec![chan.clone()],
+            Error::NeedMoreParams(cmd) => vec![cmd.clone()],
+            Error::UnknownMode(mode) => vec![mode.to_string()],
+            Error::BannedFromChan(chan) => vec![chan.clone()],
+            Error::SecureOnlyChan(chan) => vec![chan.clone()],
+            Error::ChanOPrivsNeeded(chan) => vec![chan.clone()],
+            Error::NoNonReg(nick) => vec![nick.clone()],
+            Error::CantSendToUser(nick, _reason) => vec![nick.clone()],
+            Error::InvalidCapCmd(cmd) => vec![cmd.clone()],
+            Error::InvalidCommand(cmd) => vec![cmd.clone()],
+            Error::InvalidHost(host) => vec![host.clone()],
+            Error::InvalidUser(user) => vec![user.clone()],
+            _ => Vec::new(),
+        }
+    }
+
+    /* the human readable part at the end, with any [replies] template */
+    pub fn text(&self, templates: &Templates) -> String {
+        let (default, values) = self.template();
+        templates.text(self.numeric(), default, &values)
+    }
+
+    /* the built in text, and the values for its {1}, {2}.. */
+    fn template(&self) -> (&'static str, Vec<&dyn fmt::Display>) {
+        match self {
+            Error::NoSuchNick(_nick) => ("No such nick/channel", vec![]),
+            Error::NoSuchServer(_server) => ("No such server", vec![]),
+            Error::NoSuchChannel(_chan) => ("No such channel", vec![]),
+            Error::CannotSendToChan(_chan) => ("Cannot send to channel", vec![]),
+            Error::TooManyChannels(_chan) => ("You have joined too many channels", vec![]),
+            Error::TooManyTargets(cmd, max) => ("Too many targets, {1} takes at most {2}", vec![cmd, max]),
+            Error::NoRecipient(cmd) => ("No recipient given ({1})", vec![cmd]),
+            Error::NoTextToSend => ("No text to send", vec![]),
+            Error::NoTopLevel(_mask) => ("No toplevel domain specified", vec![]),
+            Error::WildTopLevel(_mask) => ("Wildcard in toplevel domain", vec![]),
+            Error::InputTooLong => ("Input line was too long", vec![]),
+            Error::UnknownCommand(_cmd) => ("Unknown command", vec![]),
+            Error::NoAdminInfo(_server) => ("No administrative info available", vec![]),
+            Error::NoNicknameGiven => ("No nickname given", vec![]),
+            Error::ErroneusNickname(_nick) => ("Erroneous nickname", vec![]),
+            Error::NicknameInUse(_nick) => ("Nickname is already in use", vec![]),
+            Error::UnavailResource(_name, reason) => ("Nick/channel is temporarily unavailable ({1})", vec![reason]),
+            Error::NickTooFast(_nick, secs) => ("Nick change too fast. Please wait {1} seconds", vec![secs]),
+            Error::UserNotInChannel(_nick, _chan) => ("They aren't on that channel", vec![]),
+            Error::NotOnChannel(_chan) => ("You're not on that channel", vec![]),
+            Error::NotRegistered => ("You have not registered", vec![]),
+            Error::NeedMoreParams(_cmd) => ("Not enough parameters", vec![]),
+            Error::AlreadyRegistred => ("You may not reregister", vec![]),
+            Error::InvalidUsername => ("Your username is invalid", vec![]),
+            Error::NoPermForHost => ("Your host isn't among the privileged", vec![]),
+            Error::PasswdMismatch => ("Password incorrect", vec![]),
+            Error::UnknownMode(_mode) => ("is unknown mode char to me", vec![]),
+            Error::BannedFromChan(_chan) => ("Cannot join channel (+b)", vec![]),
+            Error::SecureOnlyChan(_chan) => ("Cannot join channel (SSL is required)", vec![]),
+            Error::NoPrivileges => ("Permission Denied- You're not an IRC operator", vec![]),
+            Error::ChanOPrivsNeeded(_chan) => ("You're not channel operator", vec![]),
+            Error::NoNonReg(_nick) => ("You must be logged in to an account to message this user (+R)", vec![]),
+            Error::NoOperHost => ("No O-lines for your host", vec![]),
+            Error::UModeUnknownFlag => ("Unknown MODE flag", vec![]),
+            Error::CantSendToUser(_nick, reason) => ("{1}", vec![reason]),
+            Error::UsersDontMatch => ("Cannot change mode for other users", vec![]),
+            Error::InvalidCapCmd(_cmd) => ("Invalid CAP command", vec![]),
+            Error::SaslFail => ("SASL authentication failed", vec![]),
+            Error::SaslTooLong => ("SASL message too long", vec![]),
+            Error::SaslAborted => ("SASL authentication aborted", vec![]),
+            Error::SaslAlready => ("You have already authenticated using SASL", vec![]),
+            Error::InvalidCommand(_cmd) => ("Parser: invalid command", vec![]),
+            Error::InvalidHost(_host) => ("Parser: invalid host", vec![]),
+            Error::InvalidUser(_user) => ("Parser: invalid user", vec![]),
+            Error::NoCommandGiven => ("Parser: no command given", vec![]),
+            Error::EmptyMessage => ("Parser: empty message", vec![]),
+            Error::EmptyName => ("Parser: empty message", vec![]),
+            Error::EmptyNick => ("Parser: empty message", vec![]),
+            Error::EmptyUser => ("Parser: empty message", vec![]),
+            Error::EmptyHost => ("Parser: empty message", vec![]),
+        }
+    }
+
+    /* `:<server> <numeric> <recipient> [<params>] :<text>`, ready to send */
+    pub fn format(&self, server: &str, recipient: &str, templates: &Templates) -> String {
+        let mut line = format!(":{} {:03} {}", server, self.numeric(), recipient);
+        for param in self.params() {
+            line.push(' ');
+            line.push_str(&param);
+        }
+        line.push_str(" :");
+        line.push_str(&self.text(templates));
+        line
+    }
+}
+
 impl error::Error for Error {}
+
+/* `<numeric> [<params>] :<text>`, without the recipient. for logs, replies want format() */
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Error::NoSuchNick(nick) => write!(f, "401 {} :No such nick/channel", nick),
-            Error::NoSuchChannel(chan) => write!(f, "403 {} :No such channel", chan),
-            Error::CannotSendToChan(chan) => write!(f, "404 {} :Cannot send to channel", chan),
-            Error::NoRecipient(cmd) => write!(f, "411 :No recipient given ({})", cmd),
-            Error::NoTextToSend => write!(f, "412 :No text to send"),
-            Error::UnknownCommand(cmd) => write!(f, "421 {} :Unknown command", cmd),
-            Error::ErroneusNickname(nick) => write!(f, "432 {} :Erroneous nickname", nick),
-            Error::NicknameInUse(nick) => write!(f, "433 {} :Nickname is already in use", nick),
-            Error::NotOnChannel(chan) => write!(f, "442 {} :You're not on that channel", chan),
-            Error::NotRegistered => write!(f, "451 :You have not registered"),
-            Error::NeedMoreParams(cmd) => write!(f, "461 {} :Not enough parameters", cmd),
-            Error::AlreadyRegistred => write!(f, "462 :You may not reregister"),
-            Error::ChanOPrivsNeeded(chan) => write!(f, "482 {} :You're not channel operator", chan),
-            Error::InvalidCommand(cmd) => write!(f, "600 {} :Parser: invalid command", cmd),
-            Error::InvalidHost(host) => write!(f, "601 {} :Parser: invalid host", host),
-            Error::InvalidUser(user) => write!(f, "602 {} :Parser: invalid user", user),
-            Error::NoCommandGiven => write!(f, "603 :Parser: no command given"),
-            Error::EmptyMessage => write!(f, "604 :Parser: empty message"),
-            Error::EmptyName => write!(f, "605 :Parser: empty message"),
-            Error::EmptyNick => write!(f, "606 :Parser: empty message"),
-            Error::EmptyUser => write!(f, "607 :Parser: empty message"),
-            Error::EmptyHost => write!(f, "608 :Parser: empty message"),
+        write!(f, "{:03}", self.numeric())?;
+        for param in self.params() {
+            write!(f, " {}", param)?;
         }
+        write!(f, " :{}", self.text(&Templates::default()))
     }
 }
 
 #[derive(Debug)]
 pub enum Error {
     NoSuchNick(String),
-    //    NoSuchServer(        NumReply, &'static str),
+    NoSuchServer(String),
     NoSuchChannel(String),
     CannotSendToChan(String),
-    //    TooManyChannels(     NumReply, &'static str),
+    TooManyChannels(String),
     //    WasNoSuchNick(       NumReply, &'static str),
-    //    TooManyTargets(      NumReply, &'static str),
+    TooManyTargets(String, usize),
     //    NoOrigin(            NumReply, &'static str),
     NoRecipient(String),
     NoTextToSend,
-    //    NoTopLevel(          NumReply, &'static str),
-    //    WildTopLevel(        NumReply, &'static str),
+    NoTopLevel(String),
+    WildTopLevel(String),
+    /* ERR_INPUTTOOLONG, the line was dropped unread */
+    InputTooLong,
     UnknownCommand(String),
     //    NoMotd(              NumReply, &'static str),
-    //    NoAdminInfo(         NumReply, &'static str),
+    NoAdminInfo(String),
     //    FileError(           NumReply, &'static str),
-    //    NoNickNameGiven(     NumReply, &'static str),
+    NoNicknameGiven,
     ErroneusNickname(String),
     NicknameInUse(String),
     //    NickCollision(       NumReply, &'static str),
-    //    UserNotInChannel(    NumReply, &'static str),
+    UnavailResource(String, String),
+    NickTooFast(String, u64),
+    UserNotInChannel(String, String),
     NotOnChannel(String),
     //    UserOnChannel(       NumReply, &'static str),
     //    NoLogin(             NumReply, &'static str),
@@ -95,22 +249,34 @@ pub enum Error {
     NotRegistered,
     NeedMoreParams(String),
     AlreadyRegistred,
-    //    NoPermForHost(       NumReply, &'static str),
-    //    PasswdmisMatch(      NumReply, &'static str),
+    /* ERR_INVALIDUSERNAME, not in the RFCs but what ircu and others send */
+    InvalidUsername,
+    NoPermForHost,
+    PasswdMismatch,
     //    YoureBannedCreep(    NumReply, &'static str),
     //    KeySet(              NumReply, &'static str),
     //    ChannelIsFull(       NumReply, &'static str),
-    //    UnknownMode(         NumReply, &'static str),
+    UnknownMode(char),
     //    InviteOnlyChan(      NumReply, &'static str),
-    //    BannedFromChan(      NumReply, &'static str),
+    BannedFromChan(String),
     //    BadChannelKey(       NumReply, &'static str),
-    //    NoPrivileges(        NumReply, &'static str),
+    SecureOnlyChan(String),
+    NoPrivileges,
     ChanOPrivsNeeded(String),
+    /* ERR_NONONREG, the target is +R and we're not logged in */
+    NoNonReg(String),
     //    CantKillServer(      NumReply, &'static str),
-    //    NoOperHost(          NumReply, &'static str),
-    //    UModeUnknownFlag(    NumReply, &'static str),
-    //    UsersDontMatch(      NumReply, &'static str),
+    NoOperHost,
+    UModeUnknownFlag,
+    /* ERR_CANTSENDTOUSER, refused for some reason of the target's */
+    CantSendToUser(String, String),
+    UsersDontMatch,
     //BadChanMask(String)
+    InvalidCapCmd(String),
+    SaslFail,
+    SaslTooLong,
+    SaslAborted,
+    SaslAlready,
     InvalidCommand(String),
     InvalidHost(String),
     InvalidUser(String),
@@ -122,49 +288,16 @@ pub enum Error {
     EmptyHost,
 }
 
-//pub const ERR_NOSUCHNICK: Error = Error::NoSuchNick(401, "<nickname> :No such nick/channel");
-//pub const ERR_: Error = NoSuchServer(        402, "<server name> :No such server"),
-//pub const ERR_: Error = NoSuchChannel(       403, "<channel name> :No such channel"),
-//pub const ERR_: Error = CannotSendToChan(    404, "<channel name> :Cannot send to channel"),
-//pub const ERR_: Error = TooManyChannels(     405, "<channel name> :You have joined too many channels"),
-//pub const ERR_: Error = WasNoSuchNick(       406, "<nickname> :There was no such nickname"),
-//pub const ERR_: Error = TooManyTargets(      407, "<target> :Duplicate recipients. No message delivered"),
-//pub const ERR_: Error = NoOrigin(            409, ":no origin specified"),
-//pub const ERR_NORECIPIENT: Error = Error::NoRecipient(411, ":No recipient given (<command>)");
-//pub const ERR_NOTEXTTOSEND: Error = Error::NoTextToSend(412, ":No text to send");
-//pub const ERR_: Error = NoTopLevel(          413, "<mask> :No toplevel domain specified"),
-//pub const ERR_: Error = WildTopLevel(        414, "<mask> :Wildcard in toplevel domain"),
-//pub const ERR_UNKNOWNCOMMAND: Error = Error::UnknownCommand(421, "<command> :Unknown command");
-//pub const ERR_: Error = NoMotd(              422, ":MOTD File is missing"),
-//pub const ERR_: Error = NoAdminInfo(         423, "<server> :No administrative info available"),
-//pub const ERR_: Error = FileError(           424, ":File error doing <file op> on <file>"),
-//pub const ERR_: Error = NoNickNameGiven(     431, ":No nickname given"),
-//pub const ERR_: Error = ErroneusNickname(    432, "<nick> :Erroneus nickname"),
-//pub const ERR_NICKNAMEINUSE: Error =
-//    Error::NicknameInUse(433, "<nick> :Nickname is already in use");
-//pub const ERR_: Error = NickCollision(       436, "<nick> :Nickname collision KILL"),
-//pub const ERR_: Error = UserNotInChannel(    441, "<nick> <channel> :They aren't on that channel"),
-//pub const ERR_: Error = NotOnChannel(        442, "<channel> :You're not on that channel"),
-//pub const ERR_: Error = UserOnChannel(       443, "<user> <channel> :is already on channel"),
-//pub const ERR_: Error = NoLogin(             444, "<user> :User not logged in"),
-//pub const ERR_: Error = SummonDisabled(      445, ":SUMMON has been disabled"),
-//pub const ERR_: Error = UsersDisabled(       446, ":USERS has been disabled"),
-//pub const ERR_NOTREGISTERED: Error = Error::NotRegistered(451, ":You have not registered");
-//pub const ERR_NEEDMOREPARAMS: Error =
-//    Error::NeedMoreParams(461, "<command> :Not enough parameters");
-//pub const ERR_ALREADYREGISTRED: Error = Error::AlreadyRegistred(462, ":You may not reregister");
-//pub const ERR_: Error = NoPermForHost(       463, ":Your host isn't among the privileged"),
-//pub const ERR_: Error = PasswdmisMatch(      464, ":Password incorrect"),
-//pub const ERR_: Error = YoureBannedCreep(    465, ":You are banned from this server"),
-//pub const ERR_: Error = KeySet(              467, "<channel> :Channel key already set"),
-//pub const ERR_: Error = ChannelIsFull(       471, "<channel> :Cannot join channel (+l)"),
-//pub const ERR_: Error = UnknownMode(         472, "<char> :is unknown mode char to me"),
-//pub const ERR_: Error = InviteOnlyChan(      473, "<channel> :Cannot join channel (+i)"),
-//pub const ERR_: Error = BannedFromChan(      474, "<channel> :Cannot join channel (+b)"),
-//pub const ERR_: Error = BadChannelKey(       475, "<channel> :Cannot join channel (+k)"),
-//pub const ERR_: Error = NoPrivileges(        481, ":Permission Denied- You're not an IRC operator"),
-//pub const ERR_: Error = ChanOPrivsNeeded(    482, "<channel> :You're not channel operator"),
-//pub const ERR_: Error = CantKillServer(      483, ":You cant kill a server!"),
-//pub const ERR_: Error = NoOperHost(          491, ":No O-lines for your host"),
-//pub const ERR_: Error = UModeUnknownFlag(    501, ":Unknown MODE flag"),
-//pub const ERR_: Error = UsersDontMatch(      502, ":Cant change mode for other users")
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formatting() {
+        let err = Error::UserNotInChannel("bob".to_string(), "#chan".to_string());
+        let templates = Templates::default();
+        assert_eq!(err.format("irc.test", "alice", &templates), ":irc.test 441 alice bob #chan :They aren't on that channel");
+        assert_eq!(Error::NotRegistered.format("irc.test", "*", &templates), ":irc.test 451 * :You have not registered");
+        assert_eq!(Error::NickTooFast("bob".to_string(), 5).to_string(), "438 bob :Nick change too fast. Please wait 5 seconds");
+    }
+}
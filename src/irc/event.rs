@@ -0,0 +1,146 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* something that happened, as opposed to the line it goes out as. each
+ * connection gets the line for the caps it negotiated: client tags only
+ * with message-tags, the account and real name on a JOIN with
+ * extended-join. Shared holds on to every form it's been asked for, so
+ * fanning one out to a big channel renders each form just the once */
+use crate::client::{shared_line, Client};
+use crate::irc::cap;
+use crate::irc::rfc_defs as rfc;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    Join { prefix: String, chan: String, account: Option<String>, realname: String },
+    /* PRIVMSG, NOTICE or TAGMSG along with whatever client tags go with it,
+     * formatted by tags::relay() */
+    Message { tags: String, prefix: String, command: String, target: String, text: String },
+    Nick { prefix: String, nick: String },
+}
+
+/* what a connection negotiated that changes the lines it gets */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Wire {
+    pub tags: bool,
+    pub extended_join: bool,
+}
+
+impl Wire {
+    pub fn of(client: &Client) -> Self {
+        Wire {
+            tags: client.has_cap(cap::MESSAGE_TAGS),
+            extended_join: client.has_cap(cap::EXTENDED_JOIN),
+        }
+    }
+}
+
+impl Event {
+    /* None when there's nothing this connection should see, a TAGMSG to
+     * someone without message-tags */
+    pub fn render(&self, wire: Wire) -> Option<String> {
+        match self {
+            Event::Join { prefix, chan, account, realname } if wire.extended_join => {
+                Some(format!(":{} JOIN {} {} :{}", prefix, chan, account.as_deref().unwrap_or("*"), realname))
+            },
+            Event::Join { prefix, chan, .. } => Some(format!(":{} JOIN {}", prefix, chan)),
+            Event::Message { command, .. } if command == "TAGMSG" && !wire.tags => None,
+            Event::Message { tags, prefix, command, target, text } => {
+                let tags = if wire.tags { tags.as_str() } else { "" };
+                if command == "TAGMSG" {
+                    Some(format!("{}:{} {} {}", tags, prefix, command, target))
+                } else {
+                    Some(format!("{}:{} {} {} :{}", tags, prefix, command, target, text))
+                }
+            },
+            Event::Nick { prefix, nick } => Some(format!(":{} NICK :{}", prefix, nick)),
+        }
+    }
+
+    /* a message to a channel, what a slow client goes without first */
+    pub fn is_chatter(&self) -> bool {
+        matches!(self, Event::Message { target, .. } if rfc::valid_channel(target))
+    }
+}
+
+#[derive(Debug)]
+pub struct Shared {
+    event: Event,
+    rendered: Mutex<Vec<(Wire, Option<Arc<str>>)>>,
+}
+
+impl Shared {
+    pub fn new(event: Event) -> Arc<Self> {
+        Arc::new(Shared { event, rendered: Mutex::new(Vec::new()) })
+    }
+
+    pub fn line(&self, wire: Wire) -> Option<Arc<str>> {
+        let mut rendered = self.rendered.lock().unwrap();
+        if let Some((_wire, line)) = rendered.iter().find(|(done, _line)| *done == wire) {
+            return line.clone();
+        }
+        let line = self.event.render(wire).map(|line| shared_line(&line));
+        rendered.push((wire, line.clone()));
+        line
+    }
+
+    pub fn is_chatter(&self) -> bool {
+        self.event.is_chatter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_wire() {
+        let plain = Wire::default();
+        let modern = Wire { tags: true, extended_join: true };
+        let join = Event::Join {
+            prefix: "nick!user@host".to_string(),
+            chan: "#chan".to_string(),
+            account: None,
+            realname: "Real Name".to_string(),
+        };
+        assert_eq!(join.render(plain).unwrap(), ":nick!user@host JOIN #chan");
+        assert_eq!(join.render(modern).unwrap(), ":nick!user@host JOIN #chan * :Real Name");
+
+        let tagmsg = Event::Message {
+            tags: "@+typing=active ".to_string(),
+            prefix: "nick!user@host".to_string(),
+            command: "TAGMSG".to_string(),
+            target: "#chan".to_string(),
+            text: String::new(),
+        };
+        assert!(tagmsg.render(plain).is_none());
+        assert!(tagmsg.is_chatter());
+        assert!(!join.is_chatter());
+        assert_eq!(tagmsg.render(modern).unwrap(), "@+typing=active :nick!user@host TAGMSG #chan");
+
+        let shared = Shared::new(Event::Message {
+            tags: "@+draft/reply=1 ".to_string(),
+            prefix: "nick!user@host".to_string(),
+            command: "PRIVMSG".to_string(),
+            target: "#chan".to_string(),
+            text: "hi".to_string(),
+        });
+        assert_eq!(&*shared.line(plain).unwrap(), ":nick!user@host PRIVMSG #chan :hi\r\n");
+        assert_eq!(&*shared.line(modern).unwrap(), "@+draft/reply=1 :nick!user@host PRIVMSG #chan :hi\r\n");
+        assert!(Arc::ptr_eq(&shared.line(plain).unwrap(), &shared.line(plain).unwrap()));
+    }
+}
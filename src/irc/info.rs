@@ -0,0 +1,96 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* the informational commands, ADMIN, INFO, VERSION and TIME.
+ * they all take an optional server to ask, but there's only us so far */
+use crate::client::{ClientReplies, GenError};
+use crate::irc::error::Error as ircError;
+use crate::irc::isupport;
+use crate::irc::reply::Reply as ircReply;
+use crate::irc::Core;
+use crate::parser::ParsedMsg;
+use chrono::Local;
+
+/* Err if they asked about some server that isn't this one */
+fn check_target(irc: &Core, params: &ParsedMsg) -> Result<(), ircError> {
+    match params.opt_params.first() {
+        Some(server) if !server.eq_ignore_ascii_case(&irc.get_host()) => Err(ircError::NoSuchServer(server.to_string())),
+        _ => Ok(()),
+    }
+}
+
+pub async fn admin(irc: &Core, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if let Err(err) = check_target(irc, &params) {
+        replies.push(Err(err));
+        return Ok(replies);
+    }
+    let admin = &irc.get_config().admin;
+    if admin.is_empty() {
+        replies.push(Err(ircError::NoAdminInfo(irc.get_host())));
+        return Ok(replies);
+    }
+    replies.push(Ok(ircReply::AdminMe(irc.get_host())));
+    replies.push(Ok(ircReply::AdminLoc1(admin.location.clone().unwrap_or_default())));
+    replies.push(Ok(ircReply::AdminLoc2(admin.description.clone().unwrap_or_default())));
+    replies.push(Ok(ircReply::AdminEmail(admin.email.clone().unwrap_or_default())));
+    Ok(replies)
+}
+
+pub async fn info(irc: &Core, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if let Err(err) = check_target(irc, &params) {
+        replies.push(Err(err));
+        return Ok(replies);
+    }
+    let features = if cfg!(feature = "sqlite") { "sqlite" } else { "none" };
+    let lines = vec![
+        irc.get_version(),
+        "an IRC daemon written in Rust".to_string(),
+        "".to_string(),
+        "Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>".to_string(),
+        "released under the GNU Lesser General Public License,".to_string(),
+        "version 3 or (at your option) any later version".to_string(),
+        "".to_string(),
+        format!("built with features: {}", features),
+        format!("running since {}", irc.get_date()),
+    ];
+    replies.extend(lines.into_iter().map(|line| Ok(ircReply::Info(line))));
+    replies.push(Ok(ircReply::EndofInfo));
+    Ok(replies)
+}
+
+pub async fn version(irc: &Core, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if let Err(err) = check_target(irc, &params) {
+        replies.push(Err(err));
+        return Ok(replies);
+    }
+    replies.push(Ok(ircReply::Version(env!("CARGO_PKG_VERSION").to_string(), irc.get_host(), irc.get_version())));
+    replies.extend(isupport::replies(irc).into_iter().map(Ok));
+    Ok(replies)
+}
+
+pub async fn time(irc: &Core, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if let Err(err) = check_target(irc, &params) {
+        replies.push(Err(err));
+        return Ok(replies);
+    }
+    let now = Local::now().format("%A %B %-d %Y -- %H:%M:%S %:z").to_string();
+    replies.push(Ok(ircReply::Time(irc.get_host(), now)));
+    Ok(replies)
+}
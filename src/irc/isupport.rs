@@ -0,0 +1,65 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* RPL_ISUPPORT (005) tokens sent after registration,
+ * see https://modern.ircdocs.horse/#rplisupport-005 */
+use crate::irc::chan::{FLAG_MODES, LIST_MODES, STATUS_MODES};
+use crate::irc::reply::Reply as ircReply;
+use crate::irc::rfc_defs as rfc;
+use crate::irc::Core;
+
+pub const CHANTYPES: &str = "#&+!";
+
+/* clients are supposed to cope with any number of 005 lines,
+ * but more than this many tokens in one line is asking for trouble */
+const TOKENS_PER_LINE: usize = 12;
+
+pub fn tokens(irc: &Core) -> Vec<String> {
+    let limits = &irc.get_config().limits;
+    let chanlimit = if limits.max_channels > 0 {
+        format!("CHANLIMIT={}:{}", CHANTYPES, limits.max_channels)
+    } else {
+        format!("CHANLIMIT={}:", CHANTYPES)
+    };
+    let targmax: Vec<String> = limits.targmax.iter()
+        .map(|(cmd, max)| if *max > 0 { format!("{}:{}", cmd, max) } else { format!("{}:", cmd) })
+        .collect();
+    let mut tokens = vec![
+        /* https://ircv3.net/specs/extensions/bot-mode */
+        "BOT=B".to_string(),
+        chanlimit,
+        format!("CHANMODES={},,,{}", LIST_MODES, FLAG_MODES),
+        format!("CHANNELLEN={}", rfc::MAX_CHANNAME_SIZE),
+        format!("CHANTYPES={}", CHANTYPES),
+        format!("NICKLEN={}", rfc::MAX_NICKNAME_SIZE),
+        format!("PREFIX=({})@+", STATUS_MODES),
+        "SAFELIST".to_string(),
+        "STATUSMSG=@+".to_string(),
+        format!("TARGMAX={}", targmax.join(",")),
+    ];
+    /* the older way of saying the same for PRIVMSG */
+    if let Some(max) = limits.targmax.get("PRIVMSG").filter(|max| **max > 0) {
+        tokens.push(format!("MAXTARGETS={}", max));
+    }
+    tokens
+}
+
+pub fn replies(irc: &Core) -> Vec<ircReply> {
+    tokens(irc)
+        .chunks(TOKENS_PER_LINE)
+        .map(|chunk| ircReply::ISupport(chunk.to_vec()))
+        .collect()
+}
@@ -0,0 +1,65 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* K-lines, user@host masks (* and ? wildcards) kept off the server.
+ * checked against both the hostname and the address at registration.
+ * only the spamfilter hands them out for now, and they only last until
+ * they expire or we restart (a hot upgrade keeps them, see upgrade.rs) */
+use crate::irc::mask;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct Kline {
+    pub mask: String,
+    pub reason: String,
+    pub expires: Instant,
+}
+
+impl Kline {
+    pub fn new(mask: &str, reason: &str, duration: Duration) -> Kline {
+        Kline {
+            mask: mask.to_string(),
+            reason: reason.to_string(),
+            expires: Instant::now() + duration,
+        }
+    }
+
+    pub fn matches(&self, username: &str, host: &str, ip: &IpAddr) -> bool {
+        mask::matches_userhost(&self.mask, username, host, ip)
+    }
+
+    pub fn expired(&self) -> bool {
+        Instant::now() >= self.expires
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_or_address() {
+        let ip: IpAddr = "192.0.2.7".parse().unwrap();
+        let by_host = Kline::new("*@*.example.com", "", Duration::from_secs(60));
+        assert!(by_host.matches("bob", "spam.example.com", &ip));
+        assert!(!by_host.matches("bob", "example.org", &ip));
+        let by_ip = Kline::new("*@192.0.2.*", "", Duration::from_secs(60));
+        assert!(by_ip.matches("bob", "example.org", &ip));
+        assert!(!by_ip.expired());
+        assert!(Kline::new("*@*", "", Duration::from_secs(0)).expired());
+    }
+}
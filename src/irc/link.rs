@@ -0,0 +1,704 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* TS6 server link for an external services package (Atheme and the like),
+ * as an alternative to the built-in NickServ and HostServ. services connect
+ * in to us, or with [link] connect set we dial them, and there's only ever
+ * the one link, so we never have to pass anything on to a third server. just enough of TS6 for services is here,
+ * see https://github.com/grawity/irc-docs/blob/master/server/ts6.txt */
+use crate::client::{shared_line, Client, ClientReplies, ClientType, GenError, Host};
+use crate::config::{self, LinkBlock};
+use crate::irc::chan::{self, ChanFlags, Channel};
+use crate::irc::collision::{self, NickClaim, Resolution};
+use crate::irc::connlog::{self, Event};
+use crate::irc::error::Error as ircError;
+use crate::irc::isupport::CHANTYPES;
+use crate::irc::mode::{self, ModeChange};
+use crate::irc::rfc_defs as rfc;
+use crate::irc::{recipient_result, Core, NamedEntity, User};
+use crate::parser::{parse_message, ParsedMsg};
+use crate::password;
+use chrono::Utc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::task;
+use tracing::{debug, trace, warn};
+
+const UID_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+
+/* the channel modes both ends know about, anything else stays local */
+const LINK_CHAN_MODES: &str = "bmnotv";
+
+#[derive(Debug)]
+pub struct Link {
+    name: String,
+    sid: String,
+    our_sid: String,
+    client: Weak<Client>,
+    /* services' users by UID, the link holds the only strong
+     * refs so they drop out of the namespace along with it */
+    users: Mutex<HashMap<String, Arc<User>>>,
+}
+
+/* a TS6 UID is the SID plus six characters, the first a letter.
+ * ids past 26 * 36^5 wrap round, which is a lot of connections */
+pub fn make_uid(sid: &str, id: u64) -> String {
+    let mut uid = [b'A'; 6];
+    let mut rest = id;
+    for (i, c) in uid.iter_mut().enumerate().rev() {
+        let base = if i == 0 { 26 } else { 36 };
+        *c = UID_CHARS[(rest % base) as usize];
+        rest /= base;
+    }
+    format!("{}{}", sid, String::from_utf8_lossy(&uid))
+}
+
+fn uid_to_id(uid: &str) -> Option<u64> {
+    uid.bytes().try_fold(0u64, |id, c| {
+        let pos = UID_CHARS.iter().position(|&u| u == c)?;
+        Some(id * 36 + pos as u64)
+    })
+}
+
+impl Link {
+    pub fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    /* straight out to services, errors just mean the link is on its way out */
+    fn send(&self, line: &str) {
+        trace!("link {} <- {}", self.name, line);
+        if let Some(client) = Weak::upgrade(&self.client) {
+            if let Err(err) = client.send_raw(&shared_line(line)) {
+                debug!("couldn't send to link {}: {}", self.name, err);
+            }
+        }
+    }
+
+    pub fn uid(&self, user: &User) -> String {
+        user.get_remote_uid().unwrap_or_else(|| make_uid(&self.our_sid, user.get_id()))
+    }
+
+    /* services mostly name users by UID, but a nick is fine too */
+    fn find_user(&self, irc: &Core, target: &str) -> Option<Arc<User>> {
+        if let Some(user) = self.users.lock().unwrap().get(target) {
+            return Some(Arc::clone(user));
+        }
+        if let Some(local) = target.strip_prefix(self.our_sid.as_str()).filter(|local| local.len() == 6) {
+            let client = uid_to_id(local)
+                .and_then(|id| irc.get_client(&id))
+                .and_then(|weak| Weak::upgrade(&weak));
+            if let Some(ClientType::User(user)) = client.map(|client| client.get_client_type()) {
+                return Some(user);
+            }
+        }
+        irc.get_nick(target).and_then(|weak| Weak::upgrade(&weak))
+    }
+
+    fn remove_user(&self, uid: &str) {
+        let user = self.users.lock().unwrap().remove(uid);
+        drop(user);
+    }
+
+    /* one of ours as services should see them */
+    fn uid_line(&self, user: &User) -> String {
+        /* a v6 address starting with : would look like a trailing param */
+        let ip = match user.first_client() {
+            Some(client) => client.get_ip().to_string(),
+            None => String::from("0"),
+        };
+        let ip = if ip.starts_with(':') { format!("0{}", ip) } else { ip };
+        format!(":{} UID {} 1 {} {} {} {} {} {} :{}", self.our_sid, user.get_nick(), user.get_nick_ts(),
+                user.get_modes(), user.get_username(), user.get_visible_host(), ip, self.uid(user), user.get_realname())
+    }
+
+    /* the hooks below tell services what our users are up to, anything
+     * done by services' own users came from the link to begin with */
+    pub fn introduce(&self, user: &User) {
+        if !user.is_remote() {
+            self.send(&self.uid_line(user));
+        }
+    }
+
+    pub fn nick_change(&self, user: &User) {
+        if !user.is_remote() {
+            self.send(&format!(":{} NICK {} :{}", self.uid(user), user.get_nick(), user.get_nick_ts()));
+        }
+    }
+
+    pub fn chghost(&self, user: &User) {
+        if !user.is_remote() {
+            self.send(&format!(":{} ENCAP * CHGHOST {} {}", self.our_sid, self.uid(user), user.get_visible_host()));
+        }
+    }
+
+    pub fn quit(&self, user: &User, reason: &str) {
+        if !user.is_remote() {
+            self.send(&format!(":{} QUIT :{}", self.uid(user), reason));
+        }
+    }
+
+    /* a channel we've only just made needs its TS and modes sent with it */
+    pub fn join(&self, user: &User, chan: &Channel, created: bool) {
+        if user.is_remote() {
+            return;
+        }
+        if created {
+            self.send(&format!(":{} SJOIN {} {} +nt :@{}", self.our_sid, chan.get_ts(), chan.get_name(), self.uid(user)));
+        } else {
+            self.send(&format!(":{} JOIN {} {} +", self.uid(user), chan.get_ts(), chan.get_name()));
+        }
+    }
+
+    pub fn part(&self, user: &User, chan: &str, msg: &str) {
+        if !user.is_remote() {
+            self.send(&format!(":{} PART {} :{}", self.uid(user), chan, msg));
+        }
+    }
+
+    /* no source means the server did it */
+    pub fn kick(&self, by: Option<&User>, chan: &str, target: &User, reason: &str) {
+        if by.is_some_and(|by| by.is_remote()) {
+            return;
+        }
+        let source = by.map_or_else(|| self.our_sid.clone(), |by| self.uid(by));
+        self.send(&format!(":{} KICK {} {} :{}", source, chan, self.uid(target), reason));
+    }
+
+    pub fn chan_mode(&self, irc: &Core, setter: Option<&User>, chan: &Channel, changes: &[ModeChange]) {
+        if setter.is_some_and(|setter| setter.is_remote()) {
+            return;
+        }
+        let changes: Vec<ModeChange> = changes.iter()
+            .filter(|change| LINK_CHAN_MODES.contains(change.mode))
+            .map(|change| match (change.mode, &change.arg) {
+                ('o', Some(nick)) | ('v', Some(nick)) => {
+                    let target = irc.get_nick(nick).and_then(|weak| Weak::upgrade(&weak));
+                    ModeChange { arg: target.map(|target| self.uid(&target)), ..change.clone() }
+                },
+                _ => change.clone(),
+            })
+            .collect();
+        if changes.is_empty() {
+            return;
+        }
+        let source = setter.map_or_else(|| self.our_sid.clone(), |setter| self.uid(setter));
+        self.send(&format!(":{} TMODE {} {} {}", source, chan.get_ts(), chan.get_name(), mode::format_changes(&changes)));
+    }
+
+    pub fn user_mode(&self, user: &User, modes: &str) {
+        if !user.is_remote() {
+            let uid = self.uid(user);
+            self.send(&format!(":{} MODE {} :{}", uid, uid, modes));
+        }
+    }
+
+    pub fn topic(&self, user: &User, chan: &str, text: &str) {
+        if !user.is_remote() {
+            self.send(&format!(":{} TOPIC {} :{}", self.uid(user), chan, text));
+        }
+    }
+
+    /* target is a channel name or one of services' UIDs */
+    pub fn message(&self, src: &User, cmd: &str, target: &str, text: &str) {
+        if !src.is_remote() {
+            self.send(&format!(":{} {} {} :{}", self.uid(src), cmd, target, text));
+        }
+    }
+
+    pub fn operwall(&self, src: &User, text: &str) {
+        if !src.is_remote() {
+            self.send(&format!(":{} OPERWALL :{}", self.uid(src), text));
+        }
+    }
+
+    /* everything services need to know about us once the handshake is done */
+    async fn burst(&self, irc: &Core) {
+        for user in irc.get_users().iter().filter(|user| !user.is_remote()) {
+            self.send(&self.uid_line(user));
+        }
+        for chan in irc.list_chans_ptr() {
+            let name = chan.get_name();
+            let members: Vec<String> = chan.get_members()
+                .await
+                .iter()
+                .filter(|(member, _flags)| !member.is_remote())
+                .map(|(member, flags)| format!("{}{}", flags.prefix(), self.uid(member)))
+                .collect();
+            if members.is_empty() {
+                continue;
+            }
+            let chan_modes = chan.get_modes().await;
+            let modes: String = "mnt".chars().filter(|&mode| chan_modes.is_set(mode)).collect();
+            self.send(&format!(":{} SJOIN {} {} +{} :{}", self.our_sid, chan.get_ts(), name, modes, members.join(" ")));
+            let bans: Vec<String> = chan.get_bans().await.into_iter().map(|ban| ban.mask).collect();
+            if !bans.is_empty() {
+                self.send(&format!(":{} BMASK {} {} b :{}", self.our_sid, chan.get_ts(), name, bans.join(" ")));
+            }
+            if let Some(topic) = chan.get_topic().await {
+                self.send(&format!(":{} TB {} {} {} :{}", self.our_sid, name, topic.timestamp, topic.usermask, topic.text));
+            }
+        }
+        self.send(&format!(":{} PING :{}", self.our_sid, self.our_sid));
+    }
+}
+
+/* PASS <password> TS 6 :<sid>, kept until the SERVER that follows.
+ * a client sending PASS for anything else just gets ignored */
+pub fn pass(client: &Client, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    if let [password, ts, version, sid] = &params.opt_params[..] {
+        if ts == "TS" && version == "6" {
+            client.set_link_pass(password.clone(), sid.clone());
+        }
+    }
+    Ok(Vec::new())
+}
+
+/* SERVER <name> <hopcount> :<description>, the services end of a link
+ * introducing itself. anything wrong and they get dropped, there's no
+ * point carrying on a conversation with a misconfigured server */
+pub async fn accept(irc: &Arc<Core>, client: &Arc<Client>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let block = match &irc.get_config().link {
+        Some(block) => block.clone(),
+        None => return Err(ircError::UnknownCommand("SERVER".to_string()).into()),
+    };
+    let name = match params.opt_params.first() {
+        Some(name) => name.clone(),
+        None => return Err(ircError::NeedMoreParams("SERVER".to_string()).into()),
+    };
+    let refuse = |reason: &str| {
+        warn!("refused link from {} ({}): {}", name, client.get_host_string(), reason);
+        Err(GenError::Disconnect(format!("Closing Link: {}", reason)))
+    };
+    if !name.eq_ignore_ascii_case(&block.name) {
+        return refuse("No link block for that server");
+    }
+    /* on a connection we dialed, we picked the address ourselves */
+    let dialed = irc.is_link_dialed(client.get_id());
+    if !dialed && !config::in_ranges(&block.hosts, client.get_ip()) {
+        return refuse("Not connecting from an allowed host");
+    }
+    let (password, sid) = match client.get_link_pass() {
+        Some(pass) => pass,
+        None => return refuse("No TS6 PASS"),
+    };
+    let hash = block.password_hash.clone();
+    if !task::spawn_blocking(move || password::verify(&password, &hash)).await? {
+        return refuse("Bad password");
+    }
+    if !rfc::valid_sid(&sid) || sid == block.sid {
+        return refuse("Bad SID");
+    }
+    if irc.get_link().is_some() {
+        return refuse("Already linked");
+    }
+
+    let link = Arc::new(Link {
+        name: block.name.clone(),
+        sid,
+        our_sid: block.sid.clone(),
+        client: Arc::downgrade(client),
+        users: Mutex::new(HashMap::new()),
+    });
+    client.set_client_type(ClientType::Server(Arc::clone(&link)));
+    client.release_pending_slot();
+    irc.set_link(Some(Arc::clone(&link)));
+    if !dialed {
+        for line in hello(irc, &block) {
+            link.send(&line);
+        }
+    }
+    link.send(&format!("SVINFO 6 6 0 :{}", Utc::now().timestamp()));
+    link.burst(irc).await;
+    irc.notice_opers(&format!("Link with {} ({}) established", link.name, link.sid)).await;
+    Ok(Vec::new())
+}
+
+/* PASS, CAPAB and SERVER, what the end starting a link sends first */
+pub fn hello(irc: &Core, block: &LinkBlock) -> [String; 3] {
+    [
+        format!("PASS {} TS 6 :{}", block.send_password, block.sid),
+        String::from("CAPAB :QS ENCAP TB"),
+        format!("SERVER {} 1 :{}", irc.get_host(), irc.get_version()),
+    ]
+}
+
+/* how long to wait before dialing again after `failures` attempts in a
+ * row came to nothing: reconnect_min doubling each time up to
+ * reconnect_max, plus up to a quarter again picked by `jitter` (any
+ * number, only its low bits count) so a restarted services package
+ * isn't hit by every server at once */
+pub fn backoff(block: &LinkBlock, failures: u32, jitter: u32) -> Duration {
+    let min = block.reconnect_min.max(1);
+    let secs = min.saturating_mul(1 << failures.saturating_sub(1).min(16)).min(block.reconnect_max.max(min));
+    let spread = secs * 1000 / 4;
+    Duration::from_millis(secs * 1000 + u64::from(jitter) % (spread + 1))
+}
+
+/* the link's gone, and services' users with it */
+pub async fn closed(irc: &Core, link: &Arc<Link>, reason: &str) {
+    if irc.get_link().is_some_and(|current| Arc::ptr_eq(&current, link)) {
+        irc.set_link(None);
+    }
+    let users: Vec<Arc<User>> = link.users.lock().unwrap().drain().map(|(_uid, user)| user).collect();
+    drop(users);
+    irc.notice_opers(&format!("Lost link with {}: {}", link.name, reason)).await;
+}
+
+/* everything services send us once linked. lines usually start with a
+ * SID or UID, which the client parser won't take as a prefix, so that
+ * gets split off here */
+pub async fn command(irc: &Arc<Core>, link: &Arc<Link>, line: &str) -> Result<ClientReplies, GenError> {
+    trace!("link {} -> {}", link.name, line);
+    let (source, rest) = match line.strip_prefix(':').and_then(|rest| rest.split_once(' ')) {
+        Some((source, rest)) => (Some(source), rest),
+        None => (None, line),
+    };
+    let params = parse_message(rest)?;
+    let from = source.and_then(|source| link.users.lock().unwrap().get(source).cloned());
+    /* for KICK, MODE and the like when the server did it itself */
+    let by = from.as_ref().map_or_else(|| link.name.clone(), |user| user.get_prefix());
+    let args = params.opt_params;
+    match (params.command.as_str(), &args[..]) {
+        ("PING", [.., token]) => link.send(&format!(":{} PONG {} :{}", link.our_sid, irc.get_host(), token)),
+        ("PONG", _) | ("SVINFO", _) | ("CAPAB", _) | ("EOB", _) => (),
+        ("UID", [nick, _hops, ts, umodes, username, host, _ip, uid, realname]) => {
+            introduce(irc, link, uid, nick, ts.parse().unwrap_or(0), umodes, username, host, realname).await;
+        },
+        ("NICK", [nick, rest @ ..]) => {
+            if let Some(user) = from {
+                let ts = rest.first().and_then(|ts| ts.parse().ok()).unwrap_or_else(|| Utc::now().timestamp());
+                if !claim_nick(irc, &user, nick, ts).await {
+                    kill_remote(irc, link, &user, "Nick collision");
+                }
+            }
+        },
+        ("QUIT", _) => {
+            if let Some(uid) = from.and_then(|user| user.get_remote_uid()) {
+                link.remove_user(&uid);
+            }
+        },
+        ("KILL", [target, rest @ ..]) => {
+            let reason = rest.first().map_or("", String::as_str);
+            match link.find_user(irc, target) {
+                Some(user) if user.is_remote() => {
+                    if let Some(uid) = user.get_remote_uid() {
+                        link.remove_user(&uid);
+                    }
+                },
+                Some(user) => match user.fetch_client() {
+                    Ok(client) => {
+                        connlog::log(&client, Event::Kill(reason));
+                        client.disconnect(&format!("Killed ({})", reason));
+                    },
+                    Err(_) => user.end_session(),
+                },
+                None => (),
+            }
+        },
+        /* SJOIN <ts> <chan> <modes> [args...] :<members> */
+        ("SJOIN", [_ts, chanmask, .., members]) => {
+            for member in members.split_whitespace() {
+                let uid = member.trim_start_matches(['@', '+']);
+                if let Some(user) = link.find_user(irc, uid).filter(|user| user.is_remote()) {
+                    let flags = if member.starts_with('@') {
+                        ChanFlags::Op
+                    } else if member.starts_with('+') {
+                        ChanFlags::Voice
+                    } else {
+                        ChanFlags::None
+                    };
+                    join(irc, link, &user, chanmask, flags).await?;
+                }
+            }
+        },
+        ("JOIN", [_ts, chanmask, ..]) => {
+            if let Some(user) = from {
+                join(irc, link, &user, chanmask, ChanFlags::None).await?;
+            }
+        },
+        ("PART", [chanmasks, rest @ ..]) => {
+            if let Some(user) = from {
+                let msg = rest.first().map_or("", String::as_str);
+                for chanmask in chanmasks.split(',') {
+                    let _res = irc.part_chan(chanmask, &user, msg).await;
+                }
+            }
+        },
+        ("KICK", [chanmask, target, rest @ ..]) => {
+            let reason = rest.first().map_or("", String::as_str);
+            if let (Ok(chan), Some(target)) = (irc.get_chan(chanmask), link.find_user(irc, target)) {
+                if let Err(err) = chan.kick(&by, &target, reason).await {
+                    debug!("kick from link failed: {}", err);
+                }
+            }
+        },
+        ("PRIVMSG", [target, text]) | ("NOTICE", [target, text]) => {
+            if let Some(user) = from {
+                message(irc, link, &user, &params.command, target, text).await?;
+            }
+        },
+        ("OPERWALL", [text]) => {
+            let from = from.map_or_else(|| link.name.clone(), |user| user.get_nick());
+            irc.notice_globops(&from, text).await;
+        },
+        /* TMODE <ts> <chan> <modes> [args...] */
+        ("TMODE", [_ts, chanmask, modes @ ..]) => chan_mode(irc, link, &by, chanmask, modes).await,
+        ("MODE", [target, modes @ ..]) if target.starts_with(|c| CHANTYPES.contains(c)) => {
+            chan_mode(irc, link, &by, target, modes).await;
+        },
+        ("MODE", [target, modes]) => match link.find_user(irc, target) {
+            Some(user) if user.is_remote() => user_mode(&user, modes),
+            Some(user) => {
+                mode::force_user(&user, &by, modes).await?;
+                irc.uncloak_oper(&user).await?;
+            },
+            None => (),
+        },
+        /* SVSMODE <uid> [ts] <modes> */
+        ("SVSMODE", [target, .., modes]) => {
+            if let Some(user) = link.find_user(irc, target).filter(|user| !user.is_remote()) {
+                mode::force_user(&user, &by, modes).await?;
+                irc.uncloak_oper(&user).await?;
+            }
+        },
+        /* SVSNICK <uid> <newnick> [ts] */
+        ("SVSNICK", [target, nick, ..]) => {
+            if let Some(user) = link.find_user(irc, target) {
+                force_nick(&user, nick).await?;
+            }
+        },
+        ("TOPIC", [chanmask, text]) => topic(irc, &by, chanmask, text).await,
+        /* TB <chan> <ts> [setter] :<topic> */
+        ("TB", [chanmask, _ts, setter, text]) => topic(irc, setter, chanmask, text).await,
+        ("TB", [chanmask, _ts, text]) => topic(irc, &by, chanmask, text).await,
+        ("ENCAP", [_target, subcmd, rest @ ..]) => encap(irc, link, subcmd, rest).await?,
+        ("SQUIT", _) | ("ERROR", _) => {
+            let reason = args.last().cloned().unwrap_or_default();
+            return Err(GenError::Disconnect(format!("Link closed: {}", reason)));
+        },
+        (cmd, _) => debug!("ignoring {} from link {}", cmd, link.name),
+    }
+    Ok(Vec::new())
+}
+
+/* UID <nick> <hops> <ts> <umodes> <username> <host> <ip> <uid> :<realname>
+ * the new user goes in under their UID, which can't clash with anything,
+ * and then takes their nick like anyone changing nick would */
+#[allow(clippy::too_many_arguments)]
+async fn introduce(irc: &Arc<Core>, link: &Arc<Link>, uid: &str, nick: &str, ts: i64, umodes: &str,
+                   username: &str, host: &str, realname: &str) {
+    let client = match Weak::upgrade(&link.client) {
+        Some(client) => client,
+        None => return,
+    };
+    if !uid.starts_with(link.sid.as_str()) || irc.get_name(uid).is_some() {
+        warn!("link {} sent a bad or duplicate UID {}", link.name, uid);
+        return;
+    }
+    let user = User::new(irc.assign_id(), irc, uid.to_string(), username.to_string(), realname.to_string(),
+                         Host::Hostname(host.to_string()), link.name.clone(), &client, Some(uid.to_string()));
+    for mode in umodes.chars() {
+        match mode {
+            'o' => user.set_oper(true),
+            'i' => user.set_invisible(true),
+            _ => (),
+        }
+    }
+    if let Err(err) = irc.insert_name(uid, NamedEntity::User(Arc::downgrade(&user))) {
+        warn!("couldn't add UID {} from link {}: {}", uid, link.name, err);
+        return;
+    }
+    link.users.lock().unwrap().insert(uid.to_string(), Arc::clone(&user));
+    if !claim_nick(irc, &user, nick, ts).await {
+        kill_remote(irc, link, &user, "Nick collision");
+    }
+}
+
+/* settle any collision with one of our users and take the nick */
+async fn claim_nick(irc: &Core, user: &Arc<User>, nick: &str, ts: i64) -> bool {
+    if !rfc::valid_nick(nick) {
+        return false;
+    }
+    let holder = irc.get_nick(nick).and_then(|weak| Weak::upgrade(&weak));
+    if let Some(local) = holder.filter(|holder| !holder.is_remote()) {
+        let (username, host) = (user.get_username(), user.get_host_string());
+        let claim = NickClaim { ts, username: &username, host: &host };
+        if collision::handle(irc, &local, &claim).await != Resolution::KeepRemote {
+            return false;
+        }
+    }
+    if user.change_nick(nick).is_err() {
+        return false;
+    }
+    user.set_nick_ts(ts);
+    true
+}
+
+fn kill_remote(irc: &Core, link: &Link, user: &User, reason: &str) {
+    if let Some(uid) = user.get_remote_uid() {
+        link.send(&format!(":{} KILL {} :{} ({})", link.our_sid, uid, irc.get_host(), reason));
+        link.remove_user(&uid);
+    }
+}
+
+async fn join(irc: &Arc<Core>, link: &Link, user: &Arc<User>, chanmask: &str, flags: ChanFlags) -> Result<(), GenError> {
+    if !rfc::valid_channel(chanmask) {
+        debug!("link {} tried to join {} to {}", link.name, user.get_nick(), chanmask);
+        return Ok(());
+    }
+    irc.force_join(chanmask, user).await?;
+    if flags != ChanFlags::None {
+        if let Ok(chan) = irc.get_chan(chanmask) {
+            let nick = user.get_nick();
+            let mode = if flags == ChanFlags::Op { 'o' } else { 'v' };
+            if chan.set_flags(&nick, flags).await {
+                chan.send_line(&format!(":{} MODE {} +{} {}", link.name, chanmask, mode, nick)).await;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn message(irc: &Core, link: &Link, src: &User, cmd: &str, target: &str, text: &str) -> Result<(), GenError> {
+    let (_status, name) = chan::split_status(target);
+    if name.starts_with(|c| CHANTYPES.contains(c)) {
+        if let Ok(chan) = irc.get_chan(name) {
+            if let Err(err) = chan.send_msg(src, cmd, target, text, "").await? {
+                debug!("message from link to {} refused: {}", target, err);
+            }
+        }
+    } else if let Some(recv) = link.find_user(irc, target) {
+        recipient_result(irc, recv.send_msg(src, cmd, &recv.get_nick(), text, "").await).await?;
+    }
+    Ok(())
+}
+
+/* channel modes from services, o and v args come as UIDs */
+async fn chan_mode(irc: &Core, link: &Link, by: &str, chanmask: &str, modes: &[String]) {
+    let chan = match irc.get_chan(chanmask) {
+        Ok(chan) => chan,
+        Err(_) => return,
+    };
+    let params = modes.iter()
+        .map(|arg| match link.find_user(irc, arg) {
+            Some(user) if arg.len() == 9 => user.get_nick(),
+            _ => arg.clone(),
+        })
+        .collect();
+    mode::force_as(&chan, by, params, &mut Vec::new()).await;
+}
+
+fn user_mode(user: &User, modes: &str) {
+    let mut on = true;
+    for mode in modes.chars() {
+        match mode {
+            '+' => on = true,
+            '-' => on = false,
+            'o' => user.set_oper(on),
+            'i' => user.set_invisible(on),
+            _ => (),
+        }
+    }
+}
+
+async fn topic(irc: &Core, by: &str, chanmask: &str, text: &str) {
+    if let Ok(chan) = irc.get_chan(chanmask) {
+        chan.set_topic_as(text, by).await;
+        chan.send_line(&format!(":{} TOPIC {} :{}", by, chanmask, text)).await;
+    }
+}
+
+/* services renaming one of our users, nick enforcement and the like. the
+ * change goes through the same try_nick_change() as a NICK would, which
+ * tells services about it in turn */
+async fn force_nick(user: &Arc<User>, nick: &str) -> Result<(), GenError> {
+    if user.is_remote() || !rfc::valid_nick(nick) {
+        return Ok(());
+    }
+    let old_prefix = user.get_prefix();
+    match user.change_nick(nick) {
+        Ok(_) => {
+            user.send_line(&format!(":{} NICK :{}", old_prefix, nick)).await?;
+        },
+        Err(err) => debug!("services couldn't rename {} to {}: {}", user.get_nick(), nick, err),
+    }
+    Ok(())
+}
+
+/* ENCAP * SU <uid> [account] logs a user in or out, RSFNC renames
+ * them and CHGHOST sets a vhost */
+async fn encap(irc: &Core, link: &Link, subcmd: &str, args: &[String]) -> Result<(), GenError> {
+    match (subcmd, args) {
+        ("SU", [uid, rest @ ..]) => {
+            if let Some(user) = link.find_user(irc, uid) {
+                match rest.first().filter(|account| !account.is_empty()) {
+                    Some(account) => irc.login(&user, account).await?,
+                    None => irc.logout(&user).await?,
+                }
+            }
+        },
+        /* RSFNC <uid> <newnick> <newts> <oldts>, dropped if the user's
+         * changed nick since services decided on it */
+        ("RSFNC", [uid, nick, _new_ts, old_ts]) => {
+            if let Some(user) = link.find_user(irc, uid) {
+                if old_ts.parse() == Ok(user.get_nick_ts()) {
+                    force_nick(&user, nick).await?;
+                }
+            }
+        },
+        ("CHGHOST", [uid, host]) => {
+            if let Some(user) = link.find_user(irc, uid) {
+                if rfc::valid_hostname(host) {
+                    user.set_vhost(Some(host.clone())).await?;
+                }
+            }
+        },
+        (subcmd, _) => debug!("ignoring ENCAP {} from link {}", subcmd, link.name),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uids() {
+        assert_eq!(make_uid("0RS", 0), "0RSAAAAAA");
+        assert_eq!(make_uid("0RS", 37), "0RSAAAABB");
+        let uid = make_uid("0RS", 123_456_789);
+        assert_eq!(uid.len(), 9);
+        assert!(uid.as_bytes()[3].is_ascii_uppercase());
+        assert_eq!(uid_to_id(&uid[3..]), Some(123_456_789));
+        assert_eq!(uid_to_id("aaaaaa"), None);
+    }
+
+    #[test]
+    fn reconnect_backoff() {
+        let block: LinkBlock = toml::from_str(r#"
+            name = "services.example.org"
+            password_hash = ""
+            send_password = ""
+        "#).unwrap();
+        assert_eq!(backoff(&block, 1, 0), Duration::from_secs(5));
+        assert_eq!(backoff(&block, 3, 0), Duration::from_secs(20));
+        assert_eq!(backoff(&block, 40, 0), Duration::from_secs(300));
+        assert!(backoff(&block, 1, u32::MAX) <= Duration::from_millis(6250));
+        assert!(backoff(&block, 1, 999) > Duration::from_secs(5));
+    }
+}
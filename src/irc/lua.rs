@@ -0,0 +1,269 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* Lua scripting, built with `--features lua`. every script in [lua]
+ * gets its own Lua state with only the harmless parts of the standard
+ * library, and is hooked in as a Module. a script can define
+ *   on_register(nick, username, host)
+ *   on_privmsg(nick, target, text, notice)
+ *   on_join(nick, channel)
+ * where returning false from on_privmsg or on_join stops it. the `irc`
+ * table has notice(target, text), kick(channel, nick, reason),
+ * mode(channel, modes, args...) and log(text). those are queued while
+ * the script runs and carried out in the background once it returns,
+ * so nothing a script does can hold up the client that set it off */
+use crate::client::GenError;
+use crate::irc::error::Error as ircError;
+use crate::irc::mode;
+use crate::irc::module::Module;
+use crate::irc::{Core, User};
+use mlua::{Function, HookTriggers, IntoLuaMulti, Lua, LuaOptions, StdLib, Value, Variadic};
+use std::fmt;
+use std::fs;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use tracing::{debug, info, warn};
+
+/* the count hook fires every 1000 instructions, so a hook call gets
+ * about ten million before it's cut off */
+const HOOK_INTERVAL: u32 = 1000;
+const HOOK_BUDGET: u32 = 10_000;
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    Notice(String, String),
+    Kick(String, String, String),
+    Mode(String, Vec<String>),
+}
+
+struct Script {
+    path: String,
+    lua: Mutex<Lua>,
+    actions: Arc<Mutex<Vec<Action>>>,
+    budget: Arc<AtomicU32>,
+}
+
+impl Script {
+    fn load(path: &str, source: &str) -> mlua::Result<Script> {
+        let libs = StdLib::COROUTINE | StdLib::TABLE | StdLib::STRING | StdLib::UTF8 | StdLib::MATH;
+        let lua = Lua::new_with(libs, LuaOptions::default())?;
+        let actions = Arc::new(Mutex::new(Vec::new()));
+        let budget = Arc::new(AtomicU32::new(0));
+
+        /* base gets loaded regardless, take out the bits that touch files */
+        let globals = lua.globals();
+        for name in ["dofile", "loadfile", "require", "collectgarbage"].iter() {
+            globals.set(*name, Value::Nil)?;
+        }
+
+        let api = lua.create_table()?;
+        let queue = Arc::clone(&actions);
+        api.set("notice", lua.create_function(move |_, (target, text): (String, String)| {
+            queue.lock().unwrap().push(Action::Notice(target, text));
+            Ok(())
+        })?)?;
+        let queue = Arc::clone(&actions);
+        api.set("kick", lua.create_function(move |_, (chan, nick, reason): (String, String, Option<String>)| {
+            queue.lock().unwrap().push(Action::Kick(chan, nick, reason.unwrap_or_default()));
+            Ok(())
+        })?)?;
+        let queue = Arc::clone(&actions);
+        api.set("mode", lua.create_function(move |_, (chan, params): (String, Variadic<String>)| {
+            queue.lock().unwrap().push(Action::Mode(chan, params.to_vec()));
+            Ok(())
+        })?)?;
+        let name = path.to_string();
+        let log = lua.create_function(move |_, text: String| {
+            info!("lua {}: {}", name, text);
+            Ok(())
+        })?;
+        api.set("log", log.clone())?;
+        globals.set("print", log)?;
+        globals.set("irc", api)?;
+
+        let count = Arc::clone(&budget);
+        lua.set_hook(HookTriggers::new().every_nth_instruction(HOOK_INTERVAL), move |_, _| {
+            if count.fetch_add(1, Ordering::Relaxed) >= HOOK_BUDGET {
+                return Err(mlua::Error::RuntimeError("script ran for too long".to_string()));
+            }
+            Ok(())
+        });
+
+        budget.store(0, Ordering::Relaxed);
+        lua.load(source).set_name(path).exec()?;
+        drop(globals);
+        /* anything queued while loading goes nowhere */
+        actions.lock().unwrap().clear();
+        Ok(Script { path: path.to_string(), lua: Mutex::new(lua), actions, budget })
+    }
+
+    /* runs the script's global `hook` if it has one and hands back what
+     * it queued. false only if it actually returned false, a script that
+     * falls over is logged and lets things through */
+    fn call<A>(&self, hook: &str, args: A) -> (bool, Vec<Action>)
+    where
+        A: for<'lua> IntoLuaMulti<'lua>,
+    {
+        let lua = self.lua.lock().unwrap();
+        self.budget.store(0, Ordering::Relaxed);
+        let verdict = match lua.globals().get::<_, Option<Function>>(hook) {
+            Ok(Some(func)) => match func.call::<_, Value>(args) {
+                Ok(Value::Boolean(false)) => false,
+                Ok(_) => true,
+                Err(err) => {
+                    warn!("lua {}: {} failed: {}", self.path, hook, err);
+                    true
+                },
+            },
+            Ok(None) => true,
+            Err(err) => {
+                warn!("lua {}: {} isn't a function: {}", self.path, hook, err);
+                true
+            },
+        };
+        drop(lua);
+        (verdict, std::mem::take(&mut *self.actions.lock().unwrap()))
+    }
+}
+
+pub struct LuaModule {
+    scripts: Vec<Script>,
+}
+
+impl fmt::Debug for LuaModule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let paths: Vec<&str> = self.scripts.iter().map(|script| script.path.as_str()).collect();
+        f.debug_struct("LuaModule").field("scripts", &paths).finish()
+    }
+}
+
+impl LuaModule {
+    pub fn load(paths: &[String]) -> Result<LuaModule, GenError> {
+        let mut scripts = Vec::new();
+        for path in paths.iter() {
+            let source = fs::read_to_string(path)?;
+            scripts.push(Script::load(path, &source)?);
+            debug!("loaded lua script {}", path);
+        }
+        Ok(LuaModule { scripts })
+    }
+
+    /* every script gets a go until one says no */
+    fn dispatch<A>(&self, irc: &Arc<Core>, hook: &str, args: A) -> bool
+    where
+        A: for<'lua> IntoLuaMulti<'lua> + Clone,
+    {
+        for script in self.scripts.iter() {
+            let (verdict, actions) = script.call(hook, args.clone());
+            if !actions.is_empty() {
+                tokio::spawn(run_actions(Arc::clone(irc), actions));
+            }
+            if !verdict {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Module for LuaModule {
+    fn name(&self) -> &str {
+        "lua"
+    }
+
+    fn on_register(&self, irc: &Arc<Core>, user: &Arc<User>) {
+        self.dispatch(irc, "on_register", (user.get_nick(), user.get_username(), user.get_host_string()));
+    }
+
+    fn on_privmsg(&self, irc: &Arc<Core>, user: &Arc<User>, target: &str, msg: &str, notice: bool) -> Result<(), ircError> {
+        if self.dispatch(irc, "on_privmsg", (user.get_nick(), target.to_string(), msg.to_string(), notice)) {
+            Ok(())
+        } else {
+            Err(ircError::CannotSendToChan(target.to_string()))
+        }
+    }
+
+    fn on_join(&self, irc: &Arc<Core>, user: &Arc<User>, chan: &str) -> Result<(), ircError> {
+        if self.dispatch(irc, "on_join", (user.get_nick(), chan.to_string())) {
+            Ok(())
+        } else {
+            Err(ircError::BannedFromChan(chan.to_string()))
+        }
+    }
+}
+
+/* everything comes from the server, targets that have gone away
+ * in the meantime are skipped */
+async fn run_actions(irc: Arc<Core>, actions: Vec<Action>) {
+    for action in actions {
+        match action {
+            Action::Notice(target, text) => {
+                if let Ok(chan) = irc.get_chan(&target) {
+                    chan.send_line(&format!(":{} NOTICE {} :{}", irc.get_host(), chan.get_name(), text)).await;
+                } else if let Some(user) = irc.get_nick(&target).and_then(|weak| Weak::upgrade(&weak)) {
+                    irc.server_notice(&user, &text).await;
+                }
+            },
+            Action::Kick(chan, nick, reason) => {
+                let user = irc.get_nick(&nick).and_then(|weak| Weak::upgrade(&weak));
+                if let (Ok(chan), Some(user)) = (irc.get_chan(&chan), user) {
+                    match chan.kick(&irc.get_host(), &user, &reason).await {
+                        Ok(()) => if let Some(link) = irc.get_link() {
+                            link.kick(None, &chan.get_name(), &user, &reason);
+                        },
+                        Err(err) => debug!("script kick failed: {}", err),
+                    }
+                }
+            },
+            Action::Mode(chan, params) => {
+                if let Ok(chan) = irc.get_chan(&chan) {
+                    mode::force(&irc, &chan, params, &mut Vec::new()).await;
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hooks_and_actions() {
+        let script = Script::load("test.lua", r#"
+            function on_privmsg(nick, target, text, notice)
+                if text:find("spam") then return false end
+                irc.notice(nick, "seen " .. target)
+            end
+        "#).unwrap();
+        let (verdict, actions) = script.call("on_privmsg", ("bob".to_string(), "#rust".to_string(), "buy spam".to_string(), false));
+        assert!(!verdict);
+        assert!(actions.is_empty());
+        let (verdict, actions) = script.call("on_privmsg", ("bob".to_string(), "#rust".to_string(), "hi".to_string(), false));
+        assert!(verdict);
+        assert_eq!(actions, vec![Action::Notice("bob".to_string(), "seen #rust".to_string())]);
+        /* no on_join, so nothing to stop it */
+        assert!(script.call("on_join", ("bob".to_string(), "#rust".to_string())).0);
+    }
+
+    #[test]
+    fn sandboxed() {
+        assert!(Script::load("libs.lua", "assert(os == nil and io == nil and dofile == nil and require == nil)").is_ok());
+        assert!(Script::load("loop.lua", "while true do end").is_err());
+        let script = Script::load("later.lua", "function on_join() while true do end end").unwrap();
+        assert!(script.call("on_join", ("bob".to_string(), "#rust".to_string())).0);
+    }
+}
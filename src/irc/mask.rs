@@ -0,0 +1,181 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* IRC-style masks: * for any run of characters (none included), ? for
+ * exactly one, everything else compared under a casemapping. one place
+ * for bans, WHO, K-lines, RESVs and oper hosts to all agree on what
+ * matches. works on chars rather than bytes so ? stands for a whole
+ * UTF-8 character in nicks, realnames and the like */
+use std::net::IpAddr;
+
+/* how letters compare, see CASEMAPPING in
+ * https://modern.ircdocs.horse/#casemapping-parameter */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Casemapping {
+    /* only A-Z and a-z */
+    Ascii,
+    /* ascii plus []\~ being the upper case of {}|^ */
+    Rfc1459,
+    /* rfc1459 without ~ and ^ */
+    StrictRfc1459,
+}
+
+/* what the rest of the server uses (names are compared with
+ * eq_ignore_ascii_case everywhere), so masks agree with it */
+pub const CASEMAPPING: Casemapping = Casemapping::Ascii;
+
+impl Casemapping {
+    pub fn fold(self, c: char) -> char {
+        match (self, c) {
+            (Casemapping::Rfc1459, '~') => '^',
+            (Casemapping::Rfc1459, '[') | (Casemapping::StrictRfc1459, '[') => '{',
+            (Casemapping::Rfc1459, ']') | (Casemapping::StrictRfc1459, ']') => '}',
+            (Casemapping::Rfc1459, '\\') | (Casemapping::StrictRfc1459, '\\') => '|',
+            _ => c.to_ascii_lowercase(),
+        }
+    }
+
+    pub fn eq(self, a: &str, b: &str) -> bool {
+        a.chars().count() == b.chars().count() && a.chars().zip(b.chars()).all(|(a, b)| self.fold(a) == self.fold(b))
+    }
+}
+
+/* mask against name under the server's casemapping */
+pub fn matches(mask: &str, name: &str) -> bool {
+    matches_with(CASEMAPPING, mask, name)
+}
+
+/* backtracks to the last * seen, which is all a glob with no
+ * character classes ever needs, so it's linear-ish and can't blow up
+ * on masks like *a*a*a*a*b */
+pub fn matches_with(casemapping: Casemapping, mask: &str, name: &str) -> bool {
+    let mask: Vec<char> = mask.chars().map(|c| casemapping.fold(c)).collect();
+    let name: Vec<char> = name.chars().map(|c| casemapping.fold(c)).collect();
+    let (mut m, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        /* a * in the mask is always a wildcard, even against a * in the name */
+        if m < mask.len() && mask[m] == '*' {
+            star = Some((m, n));
+            m += 1;
+        } else if m < mask.len() && (mask[m] == '?' || mask[m] == name[n]) {
+            m += 1;
+            n += 1;
+        } else if let Some((star_m, star_n)) = star {
+            m = star_m + 1;
+            n = star_n + 1;
+            star = Some((star_m, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    mask[m..].iter().all(|c| *c == '*')
+}
+
+/* a user@host mask against both the hostname and the address, so
+ * K-lines and oper hosts can be written either way */
+pub fn matches_userhost(mask: &str, username: &str, host: &str, ip: &IpAddr) -> bool {
+    matches(mask, &format!("{}@{}", username, host)) || matches(mask, &format!("{}@{}", username, ip))
+}
+
+/* anything * or ? in it, as opposed to a plain name */
+pub fn is_wild(mask: &str) -> bool {
+    mask.contains(['*', '?'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal() {
+        assert!(matches("nick", "nick"));
+        assert!(matches("NiCk", "nick"));
+        assert!(!matches("nick", "nicks"));
+        assert!(!matches("nicks", "nick"));
+        assert!(matches("", ""));
+        assert!(!matches("", "a"));
+    }
+
+    #[test]
+    fn star() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("**", "anything"));
+        assert!(matches("a*", "a"));
+        assert!(matches("*a", "bba"));
+        assert!(!matches("*a", "ab"));
+        assert!(matches("*!*@*.example.com", "nick!~user@host.EXAMPLE.com"));
+        assert!(!matches("*!*@*.example.com", "nick!~user@example.com"));
+        assert!(matches("a*b*c", "aXXbYYbZZc"));
+        assert!(!matches("a*b*c", "aXXbYYcZZ"));
+        /* has to backtrack past the first b */
+        assert!(matches("*ab*cd", "xabyabzcd"));
+        /* a * in the name doesn't stop the mask's from backtracking */
+        assert!(matches("*a", "*ba"));
+        assert!(matches("*!*@*", "*!*@*"));
+        assert!(!matches("a*", "*a"));
+    }
+
+    #[test]
+    fn question_mark() {
+        assert!(matches("?", "a"));
+        assert!(!matches("?", ""));
+        assert!(!matches("?", "ab"));
+        assert!(matches("n?ck", "nick"));
+        assert!(matches("*?", "a"));
+        assert!(!matches("?*?", "a"));
+        /* one character, not one byte */
+        assert!(matches("f?o", "f🤔o"));
+        assert!(matches("??", "éé"));
+    }
+
+    #[test]
+    fn pathological() {
+        let name = "a".repeat(200);
+        assert!(!matches(&format!("{}b", "*a".repeat(30)), &name));
+        assert!(matches(&"*a".repeat(30), &name));
+    }
+
+    #[test]
+    fn casemappings() {
+        assert!(matches_with(Casemapping::Rfc1459, "[nick]~", "{NICK}^"));
+        assert!(matches_with(Casemapping::Rfc1459, "a\\b", "A|B"));
+        assert!(matches_with(Casemapping::StrictRfc1459, "[x]", "{X}"));
+        assert!(!matches_with(Casemapping::StrictRfc1459, "~", "^"));
+        assert!(!matches_with(Casemapping::Ascii, "[x]", "{x}"));
+        assert!(Casemapping::Rfc1459.eq("Nick[away]", "nick{AWAY}"));
+        assert!(!Casemapping::Ascii.eq("Nick[away]", "nick{AWAY}"));
+        assert!(!Casemapping::Ascii.eq("nick", "nic"));
+    }
+
+    #[test]
+    fn userhost() {
+        let ip: IpAddr = "192.0.2.7".parse().unwrap();
+        assert!(matches_userhost("*@*.example.com", "~bob", "spam.example.com", &ip));
+        assert!(matches_userhost("~*@192.0.2.*", "~bob", "spam.example.com", &ip));
+        assert!(!matches_userhost("bob@*", "~bob", "spam.example.com", &ip));
+        let ip6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert!(matches_userhost("*@2001:db8:*", "bob", "host", &ip6));
+    }
+
+    #[test]
+    fn wild() {
+        assert!(is_wild("*.example.com"));
+        assert!(is_wild("n?ck"));
+        assert!(!is_wild("#chan"));
+    }
+}
@@ -0,0 +1,77 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* what we're holding on to on behalf of clients, in bytes, for STATS z and
+ * the [limits] total_sendq and total_backlog ceilings. queued is written
+ * to a send queue and not yet out on the socket, backlog is kept for
+ * detached always_on sessions. whatever adds to either gives it back: a
+ * send queue as lines go out and the rest when the connection's gone, a
+ * session when it's resumed or the user goes away */
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Debug, Default)]
+pub struct Memory {
+    queued: AtomicUsize,
+    backlog: AtomicUsize,
+}
+
+impl Memory {
+    pub fn queue(&self, bytes: usize) {
+        self.queued.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    pub fn unqueue(&self, bytes: usize) {
+        self.queued.fetch_sub(bytes, Ordering::SeqCst);
+    }
+
+    pub fn keep(&self, bytes: usize) {
+        self.backlog.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    pub fn release(&self, bytes: usize) {
+        self.backlog.fetch_sub(bytes, Ordering::SeqCst);
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    pub fn backlog(&self) -> usize {
+        self.backlog.load(Ordering::SeqCst)
+    }
+
+    /* whether bytes more would go over ceiling, 0 for no ceiling */
+    pub fn over(total: usize, bytes: usize, ceiling: usize) -> bool {
+        ceiling > 0 && total + bytes > ceiling
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ceilings() {
+        let memory = Memory::default();
+        memory.queue(100);
+        memory.queue(50);
+        memory.unqueue(100);
+        assert_eq!(memory.queued(), 50);
+        assert!(!Memory::over(memory.queued(), 50, 100));
+        assert!(Memory::over(memory.queued(), 51, 100));
+        assert!(!Memory::over(memory.queued(), 1 << 20, 0));
+    }
+}
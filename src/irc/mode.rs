@@ -0,0 +1,319 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* MODE, see https://modern.ircdocs.horse/#mode-message
+ * channel modes are b (ban masks), o and v (member status) and the
+ * flags in chan::FLAG_MODES: C (no CTCP), G (censored), m, n, s
+ * (secret), t, z (TLS only) and P (permanent). user modes are B (bot),
+ * R (private messages only from logged in users), S (private messages
+ * only over TLS), i and o, plus Z for a TLS connection, which comes from
+ * the connection and can't be changed. o is only handed out by OPER,
+ * MODE can just take it away. SAMODE lets opers change channel modes
+ * without being an op or even on the channel. +P is for opers alone,
+ * ops or not, since a channel that outlives its users is the server's
+ * business */
+use crate::client::{ClientReplies, GenError};
+use crate::irc::chan::{ChanFlags, Channel};
+use crate::irc::error::Error as ircError;
+use crate::irc::isupport::CHANTYPES;
+use crate::irc::reply::Reply as ircReply;
+use crate::irc::{Core, User};
+use crate::parser::ParsedMsg;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModeChange {
+    pub on: bool,
+    pub mode: char,
+    pub arg: Option<String>,
+}
+
+/* "+ov-b" plus the args, the way it goes out in a MODE line */
+pub fn format_changes(changes: &[ModeChange]) -> String {
+    let mut modes = String::new();
+    let mut args = Vec::new();
+    let mut sign = None;
+    for change in changes.iter() {
+        if sign != Some(change.on) {
+            modes.push(if change.on { '+' } else { '-' });
+            sign = Some(change.on);
+        }
+        modes.push(change.mode);
+        if let Some(arg) = &change.arg {
+            args.push(arg.as_str());
+        }
+    }
+    if args.is_empty() {
+        modes
+    } else {
+        format!("{} {}", modes, args.join(" "))
+    }
+}
+
+pub async fn command(irc: &Arc<Core>, user: &Arc<User>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let target = params.opt_params.remove(0);
+    if !target.starts_with(|c| CHANTYPES.contains(c)) {
+        return user_mode(irc, user, &target, params.opt_params).await;
+    }
+    let chan = match irc.get_chan(&target) {
+        Ok(chan) => chan,
+        Err(err) => {
+            replies.push(Err(err));
+            return Ok(replies);
+        }
+    };
+    if params.opt_params.is_empty() {
+        replies.push(Ok(ircReply::ChannelModeIs(chan.get_name(), chan.get_modes().await.to_string())));
+        return Ok(replies);
+    }
+    let privileged = chan.is_op(user).await;
+    let changes = apply(&chan, &user.get_prefix(), privileged, user.is_oper(), params.opt_params, &mut replies).await;
+    if !changes.is_empty() {
+        let formatted = format_changes(&changes);
+        chan.audit(&user.get_prefix(), format!("MODE {}", formatted));
+        chan.send_line(&format!(":{} MODE {} {}", user.get_prefix(), chan.get_name(), formatted)).await;
+        if let Some(link) = irc.get_link() {
+            link.chan_mode(irc, Some(user), &chan, &changes);
+        }
+    }
+    Ok(replies)
+}
+
+pub async fn samode(irc: &Arc<Core>, user: &Arc<User>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let target = params.opt_params.remove(0);
+    let chan = match irc.get_chan(&target) {
+        Ok(chan) => chan,
+        Err(err) => {
+            replies.push(Err(err));
+            return Ok(replies);
+        }
+    };
+    if let Some(formatted) = force(irc, &chan, params.opt_params, &mut replies).await {
+        irc.notice_opers(&format!("{} used SAMODE: {} {}", user.get_nick(), chan.get_name(), formatted)).await;
+    }
+    Ok(replies)
+}
+
+/* modes set by the server itself, no ops needed. SAMODE and scripts both
+ * end up here, gives back what changed if anything did */
+pub async fn force(irc: &Core, chan: &Channel, params: Vec<String>, replies: &mut ClientReplies) -> Option<String> {
+    let changes = force_as(chan, &irc.get_host(), params, replies).await;
+    if changes.is_empty() {
+        return None;
+    }
+    if let Some(link) = irc.get_link() {
+        link.chan_mode(irc, None, chan, &changes);
+    }
+    Some(format_changes(&changes))
+}
+
+/* as force(), but the MODE line comes from `setter`, which is how
+ * modes from the services link go out */
+pub async fn force_as(chan: &Channel, setter: &str, params: Vec<String>, replies: &mut ClientReplies) -> Vec<ModeChange> {
+    let changes = apply(chan, setter, true, true, params, replies).await;
+    if !changes.is_empty() {
+        let formatted = format_changes(&changes);
+        chan.audit(setter, format!("MODE {}", formatted));
+        chan.send_line(&format!(":{} MODE {} {}", setter, chan.get_name(), formatted)).await;
+    }
+    changes
+}
+
+/* works through the mode string, handing back the changes that actually
+ * did something. anything needing ops gets one ChanOPrivsNeeded between
+ * the lot of them if `privileged` is false, +P wants `oper` instead */
+async fn apply(chan: &Channel, setter: &str, privileged: bool, oper: bool, params: Vec<String>, replies: &mut ClientReplies) -> Vec<ModeChange> {
+    let chan_name = chan.get_name();
+    let mut params = params.into_iter();
+    let modestr = params.next().unwrap_or_default();
+    let mut changes = Vec::new();
+    let mut on = true;
+    let mut denied = false;
+    let mut deny = |replies: &mut ClientReplies| {
+        if !denied {
+            replies.push(Err(ircError::ChanOPrivsNeeded(chan_name.clone())));
+            denied = true;
+        }
+    };
+    for mode in modestr.chars() {
+        match mode {
+            '+' => on = true,
+            '-' => on = false,
+            'b' => {
+                let mask = match params.next() {
+                    Some(mask) => mask,
+                    None => {
+                        /* no mask means list them, anyone can do that */
+                        for ban in chan.get_bans().await {
+                            replies.push(Ok(ircReply::BanList(chan.get_name(), ban.mask, ban.setter, ban.timestamp)));
+                        }
+                        replies.push(Ok(ircReply::EndofBanList(chan.get_name())));
+                        continue;
+                    }
+                };
+                if !privileged {
+                    deny(replies);
+                } else if (on && chan.add_ban(&mask, setter).await) || (!on && chan.remove_ban(&mask).await) {
+                    changes.push(ModeChange { on, mode, arg: Some(mask) });
+                }
+            },
+            'o' | 'v' => {
+                let nick = match params.next() {
+                    Some(nick) => nick,
+                    None => continue,
+                };
+                if !privileged {
+                    deny(replies);
+                    continue;
+                }
+                let current = match chan.get_flags(&nick).await {
+                    Some(flags) => flags,
+                    None => {
+                        replies.push(Err(ircError::UserNotInChannel(nick, chan.get_name())));
+                        continue;
+                    }
+                };
+                /* only the one status per member, so +v on an op does
+                 * nothing and -v doesn't take ops away */
+                let status = if mode == 'o' { ChanFlags::Op } else { ChanFlags::Voice };
+                let new_flags = match (on, current == status) {
+                    (true, false) if current != ChanFlags::Op => status,
+                    (false, true) => ChanFlags::None,
+                    _ => continue,
+                };
+                chan.set_flags(&nick, new_flags).await;
+                changes.push(ModeChange { on, mode, arg: Some(nick) });
+            },
+            'P' => {
+                if !oper {
+                    replies.push(Err(ircError::NoPrivileges));
+                } else if chan.set_mode(mode, on).await {
+                    changes.push(ModeChange { on, mode, arg: None });
+                }
+            },
+            'C' | 'G' | 'm' | 'n' | 's' | 't' | 'z' => {
+                if !privileged {
+                    deny(replies);
+                } else if chan.set_mode(mode, on).await {
+                    changes.push(ModeChange { on, mode, arg: None });
+                }
+            },
+            _ => replies.push(Err(ircError::UnknownMode(mode))),
+        }
+    }
+    changes
+}
+
+async fn user_mode(irc: &Core, user: &Arc<User>, target: &str, params: Vec<String>) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let nick = user.get_nick();
+    if !target.eq_ignore_ascii_case(&nick) {
+        replies.push(Err(ircError::UsersDontMatch));
+        return Ok(replies);
+    }
+    let modestr = match params.first() {
+        Some(modestr) => modestr,
+        None => {
+            replies.push(Ok(ircReply::UModeIs(user.get_modes())));
+            return Ok(replies);
+        }
+    };
+    let (changes, unknown) = apply_user(user, modestr);
+    if unknown {
+        replies.push(Err(ircError::UModeUnknownFlag));
+    }
+    if !changes.is_empty() {
+        let formatted = format_changes(&changes);
+        user.send_line(&format!(":{} MODE {} :{}", user.get_prefix(), nick, formatted)).await?;
+        if let Some(link) = irc.get_link() {
+            link.user_mode(user, &formatted);
+        }
+        irc.uncloak_oper(user).await?;
+    }
+    Ok(replies)
+}
+
+/* flip the user modes in modestr, gives back what changed and whether
+ * there were any letters we don't know */
+fn apply_user(user: &User, modestr: &str) -> (Vec<ModeChange>, bool) {
+    let mut on = true;
+    let mut unknown = false;
+    let mut changes = Vec::new();
+    for mode in modestr.chars() {
+        match mode {
+            '+' => on = true,
+            '-' => on = false,
+            'B' if on != user.is_bot() => {
+                user.set_bot(on);
+                changes.push(ModeChange { on, mode, arg: None });
+            },
+            'B' => (),
+            'R' if on != user.is_registered_only() => {
+                user.set_registered_only(on);
+                changes.push(ModeChange { on, mode, arg: None });
+            },
+            'R' => (),
+            'S' if on != user.is_secure_only() => {
+                user.set_secure_only(on);
+                changes.push(ModeChange { on, mode, arg: None });
+            },
+            'S' => (),
+            /* +Z comes from the connection, nobody gets to change it */
+            'Z' => (),
+            'i' if on != user.is_invisible() => {
+                user.set_invisible(on);
+                changes.push(ModeChange { on, mode, arg: None });
+            },
+            'i' => (),
+            /* +o has to go through OPER */
+            'o' if !on && user.is_oper() => {
+                user.set_oper(false);
+                changes.push(ModeChange { on, mode, arg: None });
+            },
+            'o' => (),
+            _ => unknown = true,
+        }
+    }
+    (changes, unknown)
+}
+
+/* user modes set from outside, SVSMODE from services. the user sees
+ * the MODE come from `setter` */
+pub async fn force_user(user: &Arc<User>, setter: &str, modestr: &str) -> Result<Vec<ModeChange>, GenError> {
+    let (changes, _unknown) = apply_user(user, modestr);
+    if !changes.is_empty() {
+        user.send_line(&format!(":{} MODE {} :{}", setter, user.get_nick(), format_changes(&changes))).await?;
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mode_lines() {
+        let change = |on, mode, arg: Option<&str>| ModeChange { on, mode, arg: arg.map(str::to_string) };
+        assert_eq!(format_changes(&[change(true, 'n', None), change(true, 't', None)]), "+nt");
+        assert_eq!(
+            format_changes(&[change(true, 'o', Some("bob")), change(true, 'v', Some("eve")), change(false, 'b', Some("*!*@*"))]),
+            "+ov-b bob eve *!*@*"
+        );
+        assert_eq!(format_changes(&[change(false, 'm', None), change(true, 'm', None)]), "-m+m");
+    }
+}
@@ -0,0 +1,76 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* compiled-in modules. anything implementing Module can be handed to
+ * ServerBuilder::module() (or Core::add_module() later on) and gets a look
+ * at commands and events before the built-in handling does, so filters,
+ * extra commands and logging don't need irc::command() editing. every hook
+ * has a do-nothing default, so a module only writes the ones it cares about.
+ * modules run in the order they were added */
+use crate::client::{Client, ClientReplies, GenError};
+use crate::irc::error::Error as ircError;
+use crate::irc::{Core, User};
+use crate::parser::ParsedMsg;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+/* what handle_command() hands back when it takes a command */
+pub type CommandFuture<'a> = Pin<Box<dyn Future<Output = Result<ClientReplies, GenError>> + Send + 'a>>;
+
+/* something that's been and happened on a channel, local or from the
+ * link. prefixes are nick!user@host as everyone on the channel saw them */
+#[derive(Debug, Clone)]
+pub enum ChanEvent {
+    Join { prefix: String },
+    Part { prefix: String, reason: String },
+    Kick { by: String, nick: String, reason: String },
+    Quit { prefix: String, reason: String },
+    Topic { by: String, text: String },
+    /* PRIVMSG or NOTICE, TAGMSGs have nothing to tell */
+    Message { prefix: String, command: String, text: String },
+}
+
+pub trait Module: fmt::Debug + Send + Sync {
+    fn name(&self) -> &str;
+
+    /* a user just got their 001, nobody else can see them yet */
+    fn on_register(&self, _irc: &Arc<Core>, _user: &Arc<User>) {}
+
+    /* a PRIVMSG or NOTICE about to go to `target`, which is a nick or a
+     * channel. Err stops it there, and goes back to the sender unless it
+     * was a NOTICE */
+    fn on_privmsg(&self, _irc: &Arc<Core>, _user: &Arc<User>, _target: &str, _msg: &str, _notice: bool) -> Result<(), ircError> {
+        Ok(())
+    }
+
+    /* a JOIN that's passed the usual checks, Err turns it away. SAJOIN
+     * doesn't come through here */
+    fn on_join(&self, _irc: &Arc<Core>, _user: &Arc<User>, _chan: &str) -> Result<(), ircError> {
+        Ok(())
+    }
+
+    /* told about everything that happens on every channel, after the fact.
+     * this runs on the channel's own task, so it mustn't hang about */
+    fn on_chan_event(&self, _irc: &Arc<Core>, _chan: &str, _event: &ChanEvent) {}
+
+    /* every command is offered here first, registered or not. Some takes
+     * it and the built-in handler never sees it, None passes it on */
+    fn handle_command<'a>(&'a self, _irc: &'a Arc<Core>, _client: &'a Arc<Client>, _params: &'a ParsedMsg) -> Option<CommandFuture<'a>> {
+        None
+    }
+}
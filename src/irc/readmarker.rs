@@ -0,0 +1,131 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* draft/read-marker, see https://ircv3.net/specs/extensions/read-marker
+ * MARKREAD <target> [timestamp=<ts>] sets or asks for the time the user
+ * last read a channel or query. markers are kept per account in the
+ * account store, so every client logged in to the account sees the same
+ * one, and they only ever move forward. without an account there's
+ * nowhere to keep them and the answer is always `*` */
+use crate::client::{shared_line, ClientReplies, GenError};
+use crate::irc::cap;
+use crate::irc::rfc_defs as rfc;
+use crate::irc::{Core, User};
+use crate::parser::ParsedMsg;
+use chrono::{DateTime, TimeZone, Utc};
+use std::sync::Arc;
+use tracing::debug;
+
+fn format_ts(millis: i64) -> String {
+    Utc.timestamp_millis(millis).format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+/* timestamp=YYYY-MM-DDThh:mm:ss.sssZ into milliseconds */
+fn parse_ts(param: &str) -> Option<i64> {
+    let ts = param.strip_prefix("timestamp=")?;
+    DateTime::parse_from_rfc3339(ts).ok().map(|ts| ts.timestamp_millis())
+}
+
+fn markread_line(irc: &Core, target: &str, marker: Option<i64>) -> String {
+    match marker {
+        Some(millis) => format!(":{} MARKREAD {} timestamp={}", irc.get_host(), target, format_ts(millis)),
+        None => format!(":{} MARKREAD {} *", irc.get_host(), target),
+    }
+}
+
+async fn fail(irc: &Core, user: &Arc<User>, code: &str, context: &str, text: &str) -> Result<(), GenError> {
+    user.send_line(&format!(":{} FAIL MARKREAD {} {} :{}", irc.get_host(), code, context, text)).await?;
+    Ok(())
+}
+
+pub async fn command(irc: &Arc<Core>, user: &Arc<User>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let replies = Vec::new();
+    let target = &params.opt_params[0];
+    if !rfc::valid_channel(target) && !rfc::valid_nick(target) {
+        fail(irc, user, "INVALID_PARAMS", target, "Invalid target").await?;
+        return Ok(replies);
+    }
+    let timestamp = match params.opt_params.get(1) {
+        Some(param) => match parse_ts(param) {
+            Some(millis) => Some(millis),
+            None => {
+                fail(irc, user, "INVALID_PARAMS", param, "Invalid timestamp").await?;
+                return Ok(replies);
+            }
+        },
+        None => None,
+    };
+    let account = match user.get_account() {
+        Some(account) => account,
+        None => {
+            user.send_line(&markread_line(irc, target, None)).await?;
+            return Ok(replies);
+        }
+    };
+
+    let old = irc.accounts.get_read_marker(&account, target).await?;
+    let millis = match timestamp {
+        Some(millis) => millis,
+        None => {
+            user.send_line(&markread_line(irc, target, old)).await?;
+            return Ok(replies);
+        }
+    };
+    let marker = irc.accounts.set_read_marker(&account, target, millis).await?;
+    if old == Some(marker) {
+        /* didn't move, just tell them where it is */
+        user.send_line(&markread_line(irc, target, Some(marker))).await?;
+    } else {
+        notify(irc, &account, target, marker).await;
+    }
+    Ok(replies)
+}
+
+/* everyone logged in to account who asked for read markers */
+async fn notify(irc: &Core, account: &str, target: &str, marker: i64) {
+    let line = shared_line(&markread_line(irc, target, Some(marker)));
+    for user in irc.find_users_by_account(account) {
+        if let Err(err) = user.send_cap_line(cap::READ_MARKER, &line).await {
+            debug!("couldn't send MARKREAD to {}: {}", user.get_nick(), err);
+        }
+    }
+}
+
+/* after a JOIN the client gets told where it left off in the channel */
+pub async fn on_join(irc: &Core, user: &Arc<User>, chan: &str) -> Result<(), GenError> {
+    if !user.has_cap(cap::READ_MARKER) {
+        return Ok(());
+    }
+    let marker = match user.get_account() {
+        Some(account) => irc.accounts.get_read_marker(&account, chan).await?,
+        None => None,
+    };
+    user.send_cap_line(cap::READ_MARKER, &shared_line(&markread_line(irc, chan, marker))).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps() {
+        let millis = parse_ts("timestamp=2023-01-02T03:04:05.678Z").unwrap();
+        assert_eq!(format_ts(millis), "2023-01-02T03:04:05.678Z");
+        assert!(parse_ts("2023-01-02T03:04:05.678Z").is_none());
+        assert!(parse_ts("timestamp=yesterday").is_none());
+    }
+}
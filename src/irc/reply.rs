@@ -43,9 +43,9 @@
 */
 
 use std::fmt;
-use std::str;
 use crate::irc::rfc_defs as rfc;
 use crate::irc::chan::ChanTopic;
+use crate::irc::templates::Templates;
 
 pub enum Reply {
     None,
@@ -53,14 +53,57 @@ pub enum Reply {
     YourHost(String, String),
     Created(String),
     MyInfo(String, String, String, String),
+    ISupport(Vec<String>),
+    UModeIs(String),
+    AdminMe(String),
+    AdminLoc1(String),
+    AdminLoc2(String),
+    AdminEmail(String),
+    ChannelModeIs(String, String),
     NoTopic(String),
     Topic(String, String),
     TopicSetBy(String, String, i64),
     NameReply(String, Vec<String>),
     EndofNames(String),
+    BanList(String, String, String, i64),
+    EndofBanList(String),
+    TraceUnknown(String, String),
+    TraceOperator(String, String),
+    TraceUser(String, String),
+    TraceServer(String, String),
+    TraceEnd(String, String),
+    StatsKLine(String, String, String),
+    EndofStats(char),
+    StatsSpamfilter(String, String, u64, String),
+    StatsCommands(String, u64, u128),
+    StatsMemory(String, usize, usize),
+    StatsDns(u64, u64, usize),
+    Version(String, String, String),
+    Info(String),
+    EndofInfo,
+    Time(String, String),
     ListStart,
     ListReply(String, usize, Option<ChanTopic>),
     EndofList,
+    YoureOper,
+    Rehashing(String),
+    WhoisUser(String, String, String, String),
+    WhoisServer(String, String, String),
+    WhoisOperator(String),
+    WhoisSpecial(String, String),
+    WhoisIdle(String, i64, i64),
+    WhoisChannels(String, String),
+    WhoisBot(String),
+    WhoisSecure(String),
+    WhoisCertfp(String, String),
+    EndofWhois(String),
+    WhoReply(String, String, String, String, String, String, String),
+    EndofWho(String),
+    HostHidden(String),
+    LoggedIn(String, String),
+    LoggedOut(String),
+    SaslSuccess,
+    SaslMechs(String),
 }
 
 type Code = u16;
@@ -70,19 +113,62 @@ impl Reply {
     /* map enums to numberic reply codes */
     fn numeric(&self) -> Code {
         match self {
-            Reply::Welcome(_n, _u, _h) => 001,
-            Reply::YourHost(_s,_v) => 002,
-            Reply::Created(_t) => 003,
-            Reply::MyInfo(_s, _v, _um, _cm) => 004,
+            Reply::Welcome(_n, _u, _h) => 1,
+            Reply::YourHost(_s,_v) => 2,
+            Reply::Created(_t) => 3,
+            Reply::MyInfo(_s, _v, _um, _cm) => 4,
+            Reply::ISupport(_tokens) => 5,
+            Reply::TraceUnknown(_class, _ip) => 203,
+            Reply::TraceOperator(_class, _nick) => 204,
+            Reply::TraceUser(_class, _nick) => 205,
+            Reply::TraceServer(_class, _serv) => 206,
+            Reply::StatsKLine(_host, _user, _reason) => 216,
+            Reply::EndofStats(_letter) => 219,
+            Reply::UModeIs(_modes) => 221,
+            Reply::StatsSpamfilter(_targets, _action, _hits, _regex) => 229,
+            Reply::StatsCommands(_cmd, _count, _micros) => 212,
+            Reply::StatsMemory(_what, _bytes, _limit) => 249,
+            Reply::StatsDns(_hits, _misses, _entries) => 249,
+            Reply::TraceEnd(_serv, _ver) => 262,
+            Reply::AdminMe(_serv) => 256,
+            Reply::AdminLoc1(_loc) => 257,
+            Reply::AdminLoc2(_loc) => 258,
+            Reply::AdminEmail(_email) => 259,
             Reply::None => 300,
+            Reply::WhoisUser(_nick, _user, _host, _real) => 311,
+            Reply::WhoisServer(_nick, _serv, _info) => 312,
+            Reply::WhoisOperator(_nick) => 313,
+            Reply::WhoisSpecial(_nick, _text) => 320,
+            Reply::EndofWho(_mask) => 315,
+            Reply::WhoisIdle(_nick, _idle, _signon) => 317,
+            Reply::WhoisChannels(_nick, _chans) => 319,
+            Reply::EndofWhois(_nick) => 318,
             Reply::ListStart => 321,
             Reply::ListReply(_ch, _nu, _top) => 322,
             Reply::EndofList => 323,
+            Reply::ChannelModeIs(_ch, _modes) => 324,
             Reply::NoTopic(_ch) => 331,
             Reply::Topic(_ch, _top) => 332,
             Reply::TopicSetBy(_ch, _umask, _stamp) => 333,
+            Reply::WhoisBot(_nick) => 335,
+            Reply::Version(_ver, _serv, _comments) => 351,
+            Reply::WhoReply(_ch, _user, _host, _serv, _nick, _flags, _real) => 352,
             Reply::NameReply(_ch, _ns) => 353,
-            Reply::EndofNames(_ch) => 366
+            Reply::EndofNames(_ch) => 366,
+            Reply::BanList(_ch, _mask, _setter, _stamp) => 367,
+            Reply::EndofBanList(_ch) => 368,
+            Reply::Info(_text) => 371,
+            Reply::EndofInfo => 374,
+            Reply::YoureOper => 381,
+            Reply::Rehashing(_file) => 382,
+            Reply::Time(_serv, _time) => 391,
+            Reply::HostHidden(_host) => 396,
+            Reply::WhoisSecure(_nick) => 671,
+            Reply::WhoisCertfp(_nick, _certfp) => 276,
+            Reply::LoggedIn(_mask, _acc) => 900,
+            Reply::LoggedOut(_mask) => 901,
+            Reply::SaslSuccess => 903,
+            Reply::SaslMechs(_mechs) => 908,
         }
     }
 
@@ -91,15 +177,49 @@ impl Reply {
         format!("{:03}", self.numeric())
     }
 
-    /* the body is everything in the reply after :<server> <Code> <recipient> */
-    fn body(&self) -> Option<String> {
+    /* the body is everything in the reply after :<server> <Code> <recipient>.
+     * the human readable text at the end goes through the templates, with
+     * its values in the order given here */
+    fn body(&self, templates: &Templates) -> Option<String> {
+        let text = |default: &str, values: &[&dyn fmt::Display]| templates.text(self.numeric(), default, values);
         match self {
             Reply::None => None,
-            Reply::Welcome(nick, user, host) => Some(format!(":Welcome to Rusty IRC Network {}!{}@{}", nick, user, host)),
-            Reply::YourHost(serv, ver) => Some(format!(":Your host is {}, running version {}", serv, ver)),
-            Reply::Created(time) => Some(format!(":This server was created {}", time)),
+            Reply::Welcome(nick, user, host) => Some(format!(":{}", text("Welcome to Rusty IRC Network {1}!{2}@{3}", &[nick, user, host]))),
+            Reply::YourHost(serv, ver) => Some(format!(":{}", text("Your host is {1}, running version {2}", &[serv, ver]))),
+            Reply::Created(time) => Some(format!(":{}", text("This server was created {1}", &[time]))),
             Reply::MyInfo(serv, ver, umodes, chanmodes) => Some(format!(":{} {} {} {}", serv, ver, umodes, chanmodes)),
-            Reply::ListStart => Some(format!("Channel Users :Topic")),
+            Reply::ISupport(tokens) => Some(format!("{} :{}", tokens.join(" "), text("are supported by this server", &[]))),
+            Reply::TraceUnknown(class, ip) => Some(format!("???? {} [{}]", class, ip)),
+            Reply::TraceOperator(class, nick) => Some(format!("Oper {} {}", class, nick)),
+            Reply::TraceUser(class, nick) => Some(format!("User {} {}", class, nick)),
+            Reply::TraceServer(class, serv) => Some(format!("Serv {} 1S 0C {} *!*@{}", class, serv, serv)),
+            Reply::TraceEnd(serv, ver) => Some(format!("{} {} :{}", serv, ver, text("End of TRACE", &[]))),
+            Reply::StatsKLine(host, user, reason) => Some(format!("K {} * {} :{}", host, user, reason)),
+            Reply::EndofStats(letter) => Some(format!("{} :{}", letter, text("End of /STATS report", &[]))),
+            Reply::StatsSpamfilter(targets, action, hits, regex) => Some(format!("F {} {} {} :{}", targets, action, hits, regex)),
+            Reply::StatsCommands(cmd, count, micros) => Some(format!("{} {} :{} us total", cmd, count, micros)),
+            Reply::StatsMemory(what, bytes, limit) => Some(format!("z :{} {} bytes (limit {})", what, bytes, limit)),
+            Reply::StatsDns(hits, misses, entries) => Some(format!("d :reverse DNS cache {} hits {} misses {} entries", hits, misses, entries)),
+            Reply::UModeIs(modes) => Some(modes.to_string()),
+            Reply::AdminMe(serv) => Some(format!("{} :{}", serv, text("Administrative info", &[]))),
+            Reply::AdminLoc1(loc) => Some(format!(":{}", loc)),
+            Reply::AdminLoc2(loc) => Some(format!(":{}", loc)),
+            Reply::AdminEmail(email) => Some(format!(":{}", email)),
+            Reply::ChannelModeIs(chan, modes) => Some(format!("{} {}", chan, modes)),
+            Reply::WhoisUser(nick, user, host, real) => Some(format!("{} {} {} * :{}", nick, user, host, real)),
+            Reply::WhoisServer(nick, serv, info) => Some(format!("{} {} :{}", nick, serv, info)),
+            Reply::WhoisOperator(nick) => Some(format!("{} :{}", nick, text("is an IRC operator", &[]))),
+            Reply::WhoisSpecial(nick, text) => Some(format!("{} :{}", nick, text)),
+            Reply::WhoisSecure(nick) => Some(format!("{} :{}", nick, text("is using a secure connection", &[]))),
+            Reply::WhoisCertfp(nick, certfp) => Some(format!("{} :{}", nick, text("has client certificate fingerprint {1}", &[certfp]))),
+            Reply::WhoisBot(nick) => Some(format!("{} :{}", nick, text("is a bot", &[]))),
+            Reply::WhoisIdle(nick, idle, signon) => Some(format!("{} {} {} :{}", nick, idle, signon, text("seconds idle, signon time", &[]))),
+            Reply::WhoisChannels(nick, chans) => Some(format!("{} :{}", nick, chans)),
+            Reply::EndofWhois(nick) => Some(format!("{} :{}", nick, text("End of /WHOIS list", &[]))),
+            Reply::WhoReply(chan, user, host, serv, nick, flags, real)
+                => Some(format!("{} {} {} {} {} {} :0 {}", chan, user, host, serv, nick, flags, real)),
+            Reply::EndofWho(mask) => Some(format!("{} :{}", mask, text("End of /WHO list", &[]))),
+            Reply::ListStart => Some("Channel Users :Topic".to_string()),
             Reply::ListReply(chan, n_users, topic_opt) => {
                 if let Some(topic) = topic_opt {
                     Some(format!("{} {} :{}", chan, n_users, topic.text))
@@ -107,24 +227,56 @@ impl Reply {
                     Some(format!("{} {}", chan, n_users))
                 }
             },
-            Reply::EndofList => Some(format!(":End of /LIST")),
-            Reply::NoTopic(chan) => Some(format!("{} :No topic is set.", chan)),
+            Reply::EndofList => Some(format!(":{}", text("End of /LIST", &[]))),
+            Reply::NoTopic(chan) => Some(format!("{} :{}", chan, text("No topic is set.", &[]))),
             Reply::Topic(chan, topic_msg) => Some(format!("{} :{}", chan, topic_msg)),
             Reply::TopicSetBy(chan, usermask, timestamp) => Some(format!("{} {} {}", chan, usermask, timestamp)),
             Reply::NameReply(chan, nicks) => Some(format!("{} :{}", chan, nicks.join(" "))),
-            Reply::EndofNames(chan) => Some(format!("{} :End of /NAMES list", chan)),
+            Reply::EndofNames(chan) => Some(format!("{} :{}", chan, text("End of /NAMES list", &[]))),
+            Reply::BanList(chan, mask, setter, timestamp) => Some(format!("{} {} {} {}", chan, mask, setter, timestamp)),
+            Reply::EndofBanList(chan) => Some(format!("{} :{}", chan, text("End of channel ban list", &[]))),
+            Reply::Version(ver, serv, comments) => Some(format!("{} {} :{}", ver, serv, comments)),
+            Reply::Info(text) => Some(format!(":{}", text)),
+            Reply::EndofInfo => Some(format!(":{}", text("End of /INFO list", &[]))),
+            Reply::Time(serv, time) => Some(format!("{} :{}", serv, time)),
+            Reply::YoureOper => Some(format!(":{}", text("You are now an IRC operator", &[]))),
+            Reply::Rehashing(file) => Some(format!("{} :{}", file, text("Rehashing", &[]))),
+            Reply::HostHidden(host) => Some(format!("{} :{}", host, text("is now your displayed host", &[]))),
+            Reply::LoggedIn(mask, account) => Some(format!("{} {} :{}", mask, account, text("You are now logged in as {1}", &[account]))),
+            Reply::LoggedOut(mask) => Some(format!("{} :{}", mask, text("You are now logged out", &[]))),
+            Reply::SaslSuccess => Some(format!(":{}", text("SASL authentication successful", &[]))),
+            Reply::SaslMechs(mechs) => Some(format!("{} :{}", mechs, text("are available SASL mechanisms", &[]))),
         }
     }
 
     /* format a full IRC string for sending to the client
-       - NB this isn't currently checked for exceeding RFC message length */
-    pub fn format(&self, server: &str, recipient: &str) -> String {
-        if let Some(reply_body) = self.body() {
+       - NB this isn't checked for exceeding RFC message length, lines() is */
+    pub fn format(&self, server: &str, recipient: &str, templates: &Templates) -> String {
+        if let Some(reply_body) = self.body(templates) {
             format!(":{} {} {} {}", server, self.reply_code(), recipient, reply_body)
         } else {
             format!(":{} {} {}", server, self.reply_code(), recipient)
         }
     }
+
+    /* the reply as it goes out, broken up by split() where it's too long.
+     * Reply::None is nothing to send */
+    pub fn lines(&self, server: &str, recipient: &str, templates: &Templates) -> Vec<String> {
+        let mut lines = Vec::new();
+        if let Reply::None = self {
+            return lines;
+        }
+        let mut line = self.format(server, recipient, templates);
+        loop {
+            let (trim, rest_opt) = split(&line);
+            lines.push(trim);
+            match rest_opt {
+                Some(rest) => line = rest,
+                None => break,
+            }
+        }
+        lines
+    }
 }
 
 /* `:asdf.cool.net 001 luser :Welcome my lovely!` */
@@ -137,34 +289,35 @@ pub fn split(message: &str) -> (String, Option<String>) {
     
     let message_trimmed = &message[1..];
     let substrings: Vec<&str> = message_trimmed.splitn(2, " :").collect();
+    /* nothing trailing to break up, it'll have to go as it is */
     if substrings.len() != 2 {
-        panic!("message {} contains no ` :` token!", message);
+        return (message.to_string(), None);
     }
-    let prefix = substrings[0];
-    let prefix_bytes = substrings[0].as_bytes();
-    let reply_bulk = substrings[1].as_bytes();
-    let overhead = prefix_bytes.len() + 5;
-    let room = rfc::MAX_MSG_SIZE - overhead;
+    let (prefix, reply_bulk) = (substrings[0], substrings[1]);
+    /* room for a char of the body at least, or we'd never get anywhere */
+    let room = match rfc::MAX_MSG_SIZE.checked_sub(prefix.len() + 5) {
+        Some(room) if room >= 4 => room,
+        _ => return (message.to_string(), None),
+    };
     if reply_bulk.len() <= room {
-        panic!("body {} is already short enough, algorithm is broken", str::from_utf8(reply_bulk).unwrap());
+        return (message.to_string(), None);
     }
 
-    if let Some(space_index) = rfind_space_index(reply_bulk, room) {
-        let chunk = str::from_utf8(&reply_bulk[..space_index]).unwrap();
-        let remainder = str::from_utf8(&reply_bulk[space_index+1..]).unwrap();
-        (
-            format!(":{} :{}", prefix, chunk),
-            Some(format!(":{} :{}", prefix, remainder))
-        )
+    let (chunk, remainder) = if let Some(space_index) = rfind_space_index(reply_bulk.as_bytes(), room) {
+        (&reply_bulk[..space_index], &reply_bulk[space_index+1..])
     } else {
-        /* if there was no space we could use to split at, just cut arbitrarily at the max */
-        let chunk = str::from_utf8(&reply_bulk[..room]).unwrap();
-        let remainder = str::from_utf8(&reply_bulk[room..]).unwrap();
-        (
-            format!(":{} :{}", prefix, chunk),
-            Some(format!(":{} :{}", prefix, remainder))
-        )
-    }
+        /* if there was no space we could use to split at, just cut arbitrarily
+         * at the max, or as near as we can without cutting a char in half */
+        let mut cut = room;
+        while !reply_bulk.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        (&reply_bulk[..cut], &reply_bulk[cut..])
+    };
+    (
+        format!(":{} :{}", prefix, chunk),
+        Some(format!(":{} :{}", prefix, remainder))
+    )
 }
 
 fn rfind_space_index (bytes: &[u8], mut index: usize) -> Option<usize> {
@@ -177,28 +330,48 @@ fn rfind_space_index (bytes: &[u8], mut index: usize) -> Option<usize> {
     None
 }
 
+/* `<numeric> <body>`, without the server or recipient. for logs,
+ * anything going to a client wants lines() */
 impl fmt::Display for Reply {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Reply::None => write!(f, "300"),
-            Reply::Welcome(nick, user, host) => write!(f, "001 :Welcome to Rusty IRC Network {}!{}@{}", nick, user, host),
-            Reply::YourHost(serv, ver) => write!(f, "002 :Your host is {}, running version {}", serv, ver),
-            Reply::Created(time) => write!(f, "003 :This server was created {}", time),
-            Reply::MyInfo(serv, ver, umodes, chanmodes) => write!(f, "004 :{} {} {} {}", serv, ver, umodes, chanmodes),
-            Reply::ListStart => write!(f, "321 Chan Users :Topic"),
-            Reply::ListReply(chan, n_users, topic_opt) => {
-                if let Some(topic) = topic_opt {
-                    write!(f, "322 {} {} :{}", chan, n_users, topic.text)
-                } else {
-                    write!(f, "322 {} {}", chan, n_users)
-                }
-            },
-            Reply::EndofList => write!(f, "323 :End of /LIST"),
-            Reply::NoTopic(chan) => write!(f, "331 {} :No topic is set", chan),
-            Reply::Topic(chan, topic_msg) => write!(f, "332 {} :{}", chan, topic_msg),
-            Reply::TopicSetBy(chan, usermask, timestamp) => write!(f, "333 {} {} {}", chan, usermask, timestamp),
-            Reply::NameReply(chan, nicks) => write!(f, "353 {} :{}", chan, nicks.join(" ")),
-            Reply::EndofNames(chan) => write!(f, "366 {} :End of /NAMES list", chan),
+        match self.body(&Templates::default()) {
+            Some(body) => write!(f, "{} {}", self.reply_code(), body),
+            None => write!(f, "{}", self.reply_code()),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_replies() {
+        let templates = Templates::default();
+        assert!(Reply::None.lines("irc.test", "nick", &templates).is_empty());
+        let topic = Reply::Topic("#chan".to_string(), "short".to_string());
+        assert_eq!(topic.lines("irc.test", "nick", &templates), vec![":irc.test 332 nick #chan :short"]);
+
+        let nicks: Vec<String> = (0..100).map(|n| format!("nick{}", n)).collect();
+        let lines = Reply::NameReply("#chan".to_string(), nicks.clone()).lines("irc.test", "nick", &templates);
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| line.len() <= rfc::MAX_MSG_SIZE - 2));
+        let joined: Vec<String> = lines.iter().map(|line| line.split_once(" :").unwrap().1.to_string()).collect();
+        assert_eq!(joined.join(" "), nicks.join(" "));
+
+        /* no spaces to break at, and never half a char */
+        let lines = Reply::Info("é".repeat(400)).lines("irc.test", "nick", &templates);
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|line| line.len() <= rfc::MAX_MSG_SIZE - 2));
+    }
+
+    #[test]
+    fn templated() {
+        let mut replies = std::collections::BTreeMap::new();
+        replies.insert("900".to_string(), "Angemeldet als {1}".to_string());
+        let templates = Templates::new(&replies);
+        let reply = Reply::LoggedIn("nick!user@host".to_string(), "acc".to_string());
+        assert_eq!(reply.format("irc.test", "nick", &templates), ":irc.test 900 nick nick!user@host acc :Angemeldet als acc");
+        assert_eq!(reply.format("irc.test", "nick", &Templates::default()), ":irc.test 900 nick nick!user@host acc :You are now logged in as acc");
+    }
+}
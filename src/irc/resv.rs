@@ -0,0 +1,100 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* RESV: nick and channel masks ordinary users can't take or join.
+ * RESV <mask> [:<reason>]   reserve a mask (no args lists them)
+ * UNRESV <mask>             lift it again
+ * opers only, and opers are exempt. [[resv]] blocks in the config are
+ * loaded at startup, ones added on IRC only last until restart (or
+ * through a hot upgrade, see upgrade.rs) */
+use crate::client::{ClientReplies, GenError};
+use crate::config::ResvBlock;
+use crate::irc::mask;
+use crate::irc::{Core, User};
+use crate::parser::ParsedMsg;
+use std::sync::Arc;
+
+const DEFAULT_REASON: &str = "Reserved";
+
+#[derive(Debug, Clone)]
+pub struct Resv {
+    pub mask: String,
+    pub reason: String,
+}
+
+impl From<&ResvBlock> for Resv {
+    fn from(block: &ResvBlock) -> Resv {
+        Resv {
+            mask: block.mask.clone(),
+            reason: block.reason.clone().unwrap_or_else(|| DEFAULT_REASON.to_string()),
+        }
+    }
+}
+
+impl Resv {
+    pub fn matches(&self, name: &str) -> bool {
+        mask::matches(&self.mask, name)
+    }
+}
+
+pub async fn resv(irc: &Arc<Core>, user: &Arc<User>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let replies = Vec::new();
+    if params.opt_params.is_empty() {
+        for resv in irc.get_resvs().iter() {
+            irc.server_notice(user, &format!("RESV {} :{}", resv.mask, resv.reason)).await;
+        }
+        irc.server_notice(user, "End of RESV list").await;
+        return Ok(replies);
+    }
+    let mask = params.opt_params.remove(0);
+    let reason = params.opt_params.first().cloned().unwrap_or_else(|| DEFAULT_REASON.to_string());
+    if irc.add_resv(Resv { mask: mask.clone(), reason: reason.clone() }) {
+        irc.notice_opers(&format!("{} added RESV for {} ({})", user.get_nick(), mask, reason)).await;
+    } else {
+        irc.server_notice(user, &format!("{} is already reserved", mask)).await;
+    }
+    Ok(replies)
+}
+
+pub async fn unresv(irc: &Arc<Core>, user: &Arc<User>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let replies = Vec::new();
+    let mask = &params.opt_params[0];
+    if irc.remove_resv(mask) {
+        irc.notice_opers(&format!("{} removed RESV for {}", user.get_nick(), mask)).await;
+    } else {
+        irc.server_notice(user, &format!("No RESV for {}", mask)).await;
+    }
+    Ok(replies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks() {
+        let serv = Resv { mask: "*serv".to_string(), reason: String::new() };
+        assert!(serv.matches("NickServ"));
+        assert!(serv.matches("serv"));
+        assert!(!serv.matches("servant"));
+        let opers = Resv { mask: "#opers*".to_string(), reason: String::new() };
+        assert!(opers.matches("#OPERS-help"));
+        assert!(!opers.matches("#help-opers"));
+        let single = Resv { mask: "guest?".to_string(), reason: String::new() };
+        assert!(single.matches("Guest1"));
+        assert!(!single.matches("Guest12"));
+    }
+}
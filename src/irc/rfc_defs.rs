@@ -14,9 +14,7 @@
 *  You should have received a copy of the GNU Lesser General Public License
 *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
-use std::fmt;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-use std::str::CharIndices;
+use std::net::{Ipv4Addr, Ipv6Addr};
 pub const MAX_HOSTNAME_SIZE: usize = 253;
 pub const MAX_SHORTNAME_SIZE: usize = 63;
 pub const MAX_CHANNAME_SIZE: usize = 50;
@@ -33,9 +31,21 @@ pub const HEXDIGIT: &str = "0123456789ABCDEF";
 
 // user can have any character which is not in the set CONTROL, or an '@'
 pub const CONTROL: &str = "\0\r\n :";
-pub const NOT_USER: &str = "\0\r\n @";
+pub const NOT_USER: &str = "\0\r\n !@";
 pub const NOT_CHANSTRING: &str = "\0\r\n\x07, :";
 
+/* the control characters clients use for formatting and CTCP, any other
+ * one (and DEL) gets stripped from what's passed on to other clients */
+pub const FORMATTING: &str = "\x01\x02\x03\x04\x0f\x11\x16\x1d\x1e\x1f";
+
+pub fn forbidden_control(c: char) -> bool {
+    (c.is_ascii_control() && !FORMATTING.contains(c)) || c == '\x7f'
+}
+
+pub fn strip_controls(text: &str) -> String {
+    text.chars().filter(|c| !forbidden_control(*c)).collect()
+}
+
 // this can probably be generalised a bit
 // this is really asking "is msg a subset of allowed"
 fn matches_allowed(msg: &str, allowed: &str) -> bool {
@@ -64,10 +74,7 @@ fn matches_disallowed(msg: &str, disallowed: &str) -> bool {
  * YES! there is, for the IRC proto! will work on that soon
  */
 pub fn valid_ipv4_addr(host_addr: &str) -> bool {
-    match host_addr.parse::<Ipv4Addr>() {
-        Ok(_) => true,
-        Err(_) => false,
-    }
+    host_addr.parse::<Ipv4Addr>().is_ok()
 }
 
 // again, might be a library function for this?
@@ -76,10 +83,7 @@ pub fn valid_ipv4_addr(host_addr: &str) -> bool {
 // so for example the ipv4 parts can be 352.437.999.325,
 // and we won't complain
 pub fn valid_ipv6_addr(host_addr: &str) -> bool {
-    match host_addr.parse::<Ipv6Addr>() {
-        Ok(_) => true,
-        Err(_) => false,
-    }
+    host_addr.parse::<Ipv6Addr>().is_ok()
 }
 
 // valid hostname/shortname
@@ -111,8 +115,8 @@ pub fn valid_shortname(shortname: &str) -> bool {
     // exception if first or last letter is "-"
     if shortname.is_empty()
         || shortname.len() > MAX_SHORTNAME_SIZE
-        || shortname.chars().nth(0).unwrap() == '-'
-        || shortname.chars().last().unwrap() == '-'
+        || shortname.starts_with('-')
+        || shortname.ends_with('-')
     {
         return false;
     }
@@ -120,7 +124,7 @@ pub fn valid_shortname(shortname: &str) -> bool {
     let mut allowed = String::new();
     allowed.push_str(LOWER);
     allowed.push_str(DIGIT);
-    allowed.push_str("-");
+    allowed.push('-');
     matches_allowed(shortname, &allowed)
 }
 
@@ -135,7 +139,8 @@ pub fn valid_command(cmd_string: &str) -> bool {
 }
 
 // this one is very permissive, according to the rfc
-// user can contain any character except NUL, CR, LF, ' ', or @
+// user can contain any character except NUL, CR, LF, ' ', or @.
+// ! is kept out too, it'd make the nick!user@host prefix ambiguous
 pub fn valid_user(username: &str) -> bool {
     // just in case...
     if !username.is_empty() {
@@ -222,10 +227,18 @@ pub fn valid_nick(nick: &str) -> bool {
         return true;
     }
     allowed.push_str(DIGIT);
-    allowed.push_str("-");
+    allowed.push('-');
     matches_allowed(&rest, &allowed)
 }
 
+/* TS6 server IDs, e.g. 0RS */
+pub fn valid_sid(sid: &str) -> bool {
+    let bytes = sid.as_bytes();
+    bytes.len() == 3
+        && bytes[0].is_ascii_digit()
+        && bytes[1..].iter().all(|c| c.is_ascii_digit() || c.is_ascii_uppercase())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -242,10 +255,10 @@ mod tests {
 
     #[test]
     fn match_allowed_or_disallowed_sets() {
-        assert_eq!(matches_allowed("abc", "abcdef"), true);
-        assert_eq!(matches_allowed("abc", "abdef"), false);
-        assert_eq!(matches_disallowed("abc", "adef"), true);
-        assert_eq!(matches_disallowed("abc", "def"), false);
+        assert!(matches_allowed("abc", "abcdef"));
+        assert!(!matches_allowed("abc", "abdef"));
+        assert!(matches_disallowed("abc", "adef"));
+        assert!(!matches_disallowed("abc", "def"));
     }
 
     #[test]
@@ -0,0 +1,188 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* SASL authentication over the AUTHENTICATE command,
+ * see https://ircv3.net/specs/extensions/sasl-3.1
+ * PLAIN checks a password, EXTERNAL logs in whoever owns the
+ * fingerprint of the client's TLS certificate */
+use crate::client::{Client, ClientReplies, ClientType, GenError};
+use crate::io::{Acceptor, TlsAccept};
+use crate::irc::error::Error as ircError;
+use crate::irc::reply::Reply as ircReply;
+use crate::irc::Core;
+use crate::parser::ParsedMsg;
+use tracing::debug;
+use std::sync::Arc;
+
+/* EXTERNAL is only offered when some listener asks for client
+ * certificates and the TLS backend can actually do that, anywhere else
+ * there's never a certfp to log in with */
+pub fn mechanisms(irc: &Core) -> &'static str {
+    let certs = Acceptor::CLIENT_CERTS && irc.get_config().listeners().iter().any(|block| block.tls && block.client_certs);
+    if certs { "PLAIN,EXTERNAL" } else { "PLAIN" }
+}
+
+/* AUTHENTICATE payloads are split into 400 byte chunks,
+ * a chunk of exactly 400 bytes means more is coming */
+const CHUNK_SIZE: usize = 400;
+const MAX_PAYLOAD: usize = 4 * CHUNK_SIZE;
+
+#[derive(Debug, Clone, Default)]
+pub struct SaslState {
+    mechanism: Option<String>,
+    buffer: String,
+    /* account authenticated before registration completed,
+     * picked up by irc::register_if_ready() */
+    account: Option<String>,
+}
+
+impl SaslState {
+    pub fn get_account(&self) -> Option<String> {
+        self.account.clone()
+    }
+}
+
+/* "authzid\0authcid\0passwd", we don't support authorizing as someone else */
+fn decode_plain(payload: &str) -> Option<(String, String)> {
+    let bytes = if payload == "+" { Vec::new() } else { base64::decode(payload).ok()? };
+    let text = String::from_utf8(bytes).ok()?;
+    let fields: Vec<&str> = text.split('\0').collect();
+    if fields.len() != 3 || fields[1].is_empty() {
+        return None;
+    }
+    if !fields[0].is_empty() && fields[0] != fields[1] {
+        return None;
+    }
+    Some((fields[1].to_string(), fields[2].to_string()))
+}
+
+/* EXTERNAL's payload is just the authzid, empty for "whoever the cert says" */
+fn decode_external(payload: &str) -> Option<String> {
+    let bytes = if payload == "+" { Vec::new() } else { base64::decode(payload).ok()? };
+    String::from_utf8(bytes).ok()
+}
+
+pub async fn authenticate(irc: &Arc<Core>, client: &Arc<Client>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let arg = params.opt_params.remove(0);
+    if !irc.sasl_enabled() {
+        client.update_sasl(|sasl| *sasl = SaslState::default());
+        replies.push(Err(ircError::SaslFail));
+        return Ok(replies);
+    }
+
+    let logged_in = match client.get_client_type() {
+        ClientType::User(user) => user.get_account().is_some(),
+        _ => client.get_sasl().account.is_some(),
+    };
+    if logged_in {
+        replies.push(Err(ircError::SaslAlready));
+        return Ok(replies);
+    }
+
+    if arg == "*" {
+        client.update_sasl(|sasl| *sasl = SaslState::default());
+        replies.push(Err(ircError::SaslAborted));
+        return Ok(replies);
+    }
+
+    let mechanism = client.get_sasl().mechanism;
+    match mechanism {
+        None => {
+            let external = arg.eq_ignore_ascii_case("EXTERNAL") && client.get_certfp().is_some();
+            if arg.eq_ignore_ascii_case("PLAIN") || external {
+                client.update_sasl(|sasl| sasl.mechanism = Some(arg.to_ascii_uppercase()));
+                client.send_line("AUTHENTICATE +").await?;
+            } else {
+                replies.push(Ok(ircReply::SaslMechs(mechanisms(irc).to_string())));
+                replies.push(Err(ircError::SaslFail));
+            }
+        },
+        Some(mechanism) => {
+            if arg.len() > CHUNK_SIZE {
+                client.update_sasl(|sasl| *sasl = SaslState::default());
+                replies.push(Err(ircError::SaslTooLong));
+                return Ok(replies);
+            }
+            let mut too_long = false;
+            client.update_sasl(|sasl| {
+                if arg != "+" {
+                    sasl.buffer.push_str(&arg);
+                }
+                too_long = sasl.buffer.len() > MAX_PAYLOAD;
+            });
+            if too_long {
+                client.update_sasl(|sasl| *sasl = SaslState::default());
+                replies.push(Err(ircError::SaslTooLong));
+                return Ok(replies);
+            }
+            if arg.len() == CHUNK_SIZE {
+                /* wait for the rest of the payload */
+                return Ok(replies);
+            }
+
+            let buffer = client.get_sasl().buffer;
+            let payload = if buffer.is_empty() { "+".to_string() } else { buffer };
+            client.update_sasl(|sasl| *sasl = SaslState::default());
+            let account = if mechanism == "EXTERNAL" {
+                match (decode_external(&payload), client.get_certfp()) {
+                    (Some(authzid), Some(certfp)) => irc.accounts.find_certfp(&certfp).await?
+                        .filter(|account| authzid.is_empty() || authzid.eq_ignore_ascii_case(&account.name)),
+                    _ => None,
+                }
+            } else {
+                match decode_plain(&payload) {
+                    Some((authcid, password)) => irc.accounts.authenticate(&authcid, &password).await?,
+                    None => None,
+                }
+            };
+            match account {
+                Some(account) => {
+                    debug!("client {} authenticated as {} via SASL {}", client.get_id(), account.name, mechanism);
+                    match client.get_client_type() {
+                        ClientType::User(user) => irc.login(&user, &account.name).await?,
+                        _ => {
+                            client.update_sasl(|sasl| sasl.account = Some(account.name.clone()));
+                            let mask = format!("{}!*@{}", client.get_nick_or_star(), client.get_host_string());
+                            replies.push(Ok(ircReply::LoggedIn(mask, account.name)));
+                        }
+                    }
+                    replies.push(Ok(ircReply::SaslSuccess));
+                },
+                None => replies.push(Err(ircError::SaslFail)),
+            }
+        }
+    }
+    Ok(replies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_payloads() {
+        /* base64 of "\0alice\0hunter2" and "alice\0alice\0hunter2" */
+        assert_eq!(decode_plain("AGFsaWNlAGh1bnRlcjI="), Some(("alice".to_string(), "hunter2".to_string())));
+        assert_eq!(decode_plain("YWxpY2UAYWxpY2UAaHVudGVyMg=="), Some(("alice".to_string(), "hunter2".to_string())));
+        /* "bob\0alice\0hunter2", authorizing as somebody else */
+        assert_eq!(decode_plain("Ym9iAGFsaWNlAGh1bnRlcjI="), None);
+        assert_eq!(decode_plain("not base64!"), None);
+        assert_eq!(decode_plain("+"), None);
+        assert_eq!(decode_external("+"), Some(String::new()));
+        assert_eq!(decode_external("YWxpY2U="), Some("alice".to_string()));
+    }
+}
@@ -0,0 +1,405 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* built-in pseudo-services: these nicks never belong to a real client,
+ * PRIVMSGs addressed to them are handled here and answered with NOTICEs */
+use crate::client::{ClientReplies, GenError};
+use crate::irc::accounts::AccountError;
+use crate::irc::collision;
+use crate::irc::connlog::{self, Event};
+use crate::irc::rfc_defs as rfc;
+use crate::irc::webhook;
+use crate::irc::{Core, User};
+use chrono::{TimeZone, Utc};
+use std::sync::{Arc, Weak};
+
+pub const NICKSERV: &str = "NickServ";
+pub const HOSTSERV: &str = "HostServ";
+pub const CHANSERV: &str = "ChanServ";
+
+pub const SERVICES: &[&str] = &[NICKSERV, HOSTSERV, CHANSERV];
+
+/* returns the canonical spelling of the service nick, if any */
+pub fn lookup(nick: &str) -> Option<&'static str> {
+    SERVICES.iter().find(|serv| serv.eq_ignore_ascii_case(nick)).copied()
+}
+
+pub fn is_service(nick: &str) -> bool {
+    lookup(nick).is_some()
+}
+
+async fn notice(irc: &Core, user: &Arc<User>, service: &str, text: &str) -> Result<(), GenError> {
+    let line = format!(":{}!{}@{} NOTICE {} :{}", service, service, irc.get_host(), user.get_nick(), text);
+    user.send_line(&line).await?;
+    Ok(())
+}
+
+pub async fn privmsg(irc: &Arc<Core>, user: &Arc<User>, service: &str, text: &str) -> Result<ClientReplies, GenError> {
+    match service {
+        NICKSERV => nickserv(irc, user, text).await,
+        HOSTSERV => hostserv(irc, user, text).await,
+        CHANSERV => chanserv(irc, user, text).await,
+        _ => Ok(Vec::new()),
+    }
+}
+
+async fn nickserv(irc: &Arc<Core>, user: &Arc<User>, text: &str) -> Result<ClientReplies, GenError> {
+    let args: Vec<&str> = text.split_whitespace().collect();
+    let subcmd = args.first().unwrap_or(&"HELP").to_ascii_uppercase();
+    let account = user.get_account();
+    match (&subcmd[..], &args[1..]) {
+        ("REGISTER", [password, rest @ ..]) if rest.len() <= 1 => {
+            if let Some(account) = account {
+                notice(irc, user, NICKSERV, &format!("You are already logged in as {}", account)).await?;
+                return Ok(Vec::new());
+            }
+            let name = user.get_nick();
+            let email = rest.first().map(|email| email.to_string());
+            match irc.accounts.register(&name, password, email).await {
+                Ok(()) => {
+                    notice(irc, user, NICKSERV, &format!("Account {} registered", name)).await?;
+                    irc.login(user, &name).await?;
+                },
+                Err(AccountError::Exists(_)) => {
+                    notice(irc, user, NICKSERV, &format!("{} is already registered", name)).await?;
+                },
+                Err(err) => return Err(err.into()),
+            }
+        },
+        ("IDENTIFY", [password]) | ("IDENTIFY", [_, password]) => {
+            let name = if args.len() == 3 { args[1].to_string() } else { user.get_nick() };
+            if account.is_some() {
+                notice(irc, user, NICKSERV, "You are already logged in").await?;
+            } else if let Some(found) = irc.accounts.authenticate(&name, password).await? {
+                irc.login(user, &found.name).await?;
+            } else {
+                notice(irc, user, NICKSERV, &format!("Invalid password for {}", name)).await?;
+            }
+        },
+        ("LOGOUT", []) => {
+            if account.is_some() {
+                irc.logout(user).await?;
+            } else {
+                notice(irc, user, NICKSERV, "You are not logged in").await?;
+            }
+        },
+        ("INFO", rest) if rest.len() <= 1 => {
+            let name = match (rest.first(), &account) {
+                (Some(name), _) => name.to_string(),
+                (None, Some(account)) => account.clone(),
+                (None, None) => user.get_nick(),
+            };
+            match irc.accounts.get(&name).await? {
+                Some(found) => {
+                    let registered = Utc.timestamp(found.registered_at, 0).to_rfc2822();
+                    notice(irc, user, NICKSERV, &format!("{} registered {}", found.name, registered)).await?;
+                    /* only the owner and opers get to see the private bits */
+                    let owner = account.is_some_and(|acc| acc.eq_ignore_ascii_case(&found.name));
+                    if owner || user.is_oper() {
+                        if let Some(email) = found.email {
+                            notice(irc, user, NICKSERV, &format!("email: {}", email)).await?;
+                        }
+                        if let Some(vhost) = found.vhost {
+                            notice(irc, user, NICKSERV, &format!("vhost: {}", vhost)).await?;
+                        }
+                    }
+                },
+                None => notice(irc, user, NICKSERV, &format!("{} is not registered", name)).await?,
+            }
+        },
+        /* only ever the certificate they're connected with, so nobody can
+         * claim a fingerprint without holding its key. one given has to
+         * match it */
+        ("CERT", [action, rest @ ..]) if action.eq_ignore_ascii_case("ADD") && rest.len() <= 1 => {
+            let certfp = match user.get_certfp() {
+                Some(certfp) => certfp,
+                None => {
+                    notice(irc, user, NICKSERV, "You aren't using a client certificate").await?;
+                    return Ok(Vec::new());
+                }
+            };
+            if rest.first().is_some_and(|given| !given.eq_ignore_ascii_case(&certfp)) {
+                notice(irc, user, NICKSERV, "That isn't the certificate you're connected with").await?;
+                return Ok(Vec::new());
+            }
+            match account {
+                Some(account) => match irc.accounts.add_certfp(&account, &certfp).await {
+                    Ok(()) => notice(irc, user, NICKSERV, &format!("Added certificate fingerprint {} to {}", certfp, account)).await?,
+                    Err(AccountError::CertfpTaken(_certfp)) => {
+                        notice(irc, user, NICKSERV, "That certificate is already on another account").await?
+                    },
+                    Err(err) => return Err(err.into()),
+                },
+                None => notice(irc, user, NICKSERV, "You are not logged in").await?,
+            }
+        },
+        /* WEBHOOK <url> sets it, OFF clears it, nothing says what it is */
+        ("WEBHOOK", rest) if rest.len() <= 1 => {
+            let account = match account {
+                Some(account) => account,
+                None => {
+                    notice(irc, user, NICKSERV, "You are not logged in").await?;
+                    return Ok(Vec::new());
+                }
+            };
+            if irc.webhooks.is_none() {
+                notice(irc, user, NICKSERV, "Webhooks aren't enabled on this server").await?;
+                return Ok(Vec::new());
+            }
+            match rest.first() {
+                None => match irc.accounts.get_webhook(&account).await? {
+                    Some(url) => notice(irc, user, NICKSERV, &format!("Webhook for {}: {}", account, url)).await?,
+                    None => notice(irc, user, NICKSERV, &format!("{} has no webhook", account)).await?,
+                },
+                Some(off) if off.eq_ignore_ascii_case("OFF") => {
+                    irc.accounts.set_webhook(&account, None).await?;
+                    notice(irc, user, NICKSERV, &format!("Removed the webhook for {}", account)).await?;
+                },
+                Some(url) => match webhook::refuse(&irc.get_config().webhooks, url) {
+                    None => {
+                        irc.accounts.set_webhook(&account, Some(url.to_string())).await?;
+                        notice(irc, user, NICKSERV, &format!("Webhook for {} set to {}", account, url)).await?;
+                    },
+                    Some(why) => notice(irc, user, NICKSERV, &format!("{} {}", url, why)).await?,
+                },
+            }
+        },
+        ("GHOST", [nick, rest @ ..]) if rest.len() <= 1 => {
+            ghost(irc, user, nick, rest.first().copied()).await?;
+        },
+        /* GHOST and then take the nick for themselves */
+        ("REGAIN", [nick, rest @ ..]) if rest.len() <= 1 => {
+            if !ghost(irc, user, nick, rest.first().copied()).await? {
+                return Ok(Vec::new());
+            }
+            let old_prefix = user.get_prefix();
+            match user.change_nick(nick) {
+                Ok(_) => irc.notify_nick(user, &old_prefix).await,
+                Err(err) => notice(irc, user, NICKSERV, &format!("Couldn't change your nick to {}: {}", nick, err)).await?,
+            }
+        },
+        _ => {
+            notice(irc, user, NICKSERV, "NickServ commands: REGISTER <password> [email], IDENTIFY [account] <password>,").await?;
+            notice(irc, user, NICKSERV, "LOGOUT, INFO [account], CERT ADD [fingerprint], WEBHOOK [url|OFF],").await?;
+            notice(irc, user, NICKSERV, "GHOST <nick> [password], REGAIN <nick> [password]").await?;
+        },
+    }
+    Ok(Vec::new())
+}
+
+/* disconnect whoever's on nick, if it's the caller's account nick or
+ * they're logged in to the caller's account. a password for the account
+ * named nick will do instead of being logged in. the ghost gets moved to
+ * its UID nick first like the loser of a collision, so the nick's free as
+ * soon as this returns and not whenever its connection gets round to
+ * closing. true if it's gone */
+async fn ghost(irc: &Arc<Core>, user: &Arc<User>, nick: &str, password: Option<&str>) -> Result<bool, GenError> {
+    let target = match irc.get_nick(nick).and_then(|weak| Weak::upgrade(&weak)) {
+        Some(target) if !target.is_remote() => target,
+        _ => {
+            notice(irc, user, NICKSERV, &format!("{} isn't online", nick)).await?;
+            return Ok(false);
+        }
+    };
+    if target.get_id() == user.get_id() {
+        notice(irc, user, NICKSERV, "You can't ghost yourself").await?;
+        return Ok(false);
+    }
+    let account = match password {
+        Some(password) => irc.accounts.authenticate(nick, password).await?.map(|found| found.name),
+        None => user.get_account(),
+    };
+    let owner = account.is_some_and(|account| {
+        account.eq_ignore_ascii_case(nick) || target.get_account().is_some_and(|acc| acc.eq_ignore_ascii_case(&account))
+    });
+    if !owner {
+        notice(irc, user, NICKSERV, &format!("You don't have access to {}", nick)).await?;
+        return Ok(false);
+    }
+
+    let old_prefix = target.get_prefix();
+    if target.change_nick(&collision::uid_nick(target.get_id())).is_ok() {
+        irc.notify_nick(&target, &old_prefix).await;
+    }
+    let reason = format!("GHOST command used by {}", user.get_nick());
+    let clients = target.get_clients();
+    if clients.is_empty() {
+        target.end_session();
+    }
+    for client in clients {
+        connlog::log(&client, Event::Kill(&reason));
+        client.disconnect(&reason);
+    }
+    notice(irc, user, NICKSERV, &format!("{} has been ghosted", nick)).await?;
+    Ok(true)
+}
+
+async fn hostserv(irc: &Arc<Core>, user: &Arc<User>, text: &str) -> Result<ClientReplies, GenError> {
+    let mut args = text.split_whitespace();
+    let subcmd = args.next().unwrap_or("HELP").to_ascii_uppercase();
+    let oper = user.is_oper();
+    match (&subcmd[..], args.next(), args.next()) {
+        ("SET", Some(account), Some(vhost)) if oper => {
+            let vhost = vhost.to_ascii_lowercase();
+            if !rfc::valid_hostname(&vhost) {
+                notice(irc, user, HOSTSERV, &format!("{} is not a valid hostname", vhost)).await?;
+                return Ok(Vec::new());
+            }
+            match irc.accounts.set_vhost(account, Some(vhost.clone())).await {
+                Ok(()) => {
+                    notice(irc, user, HOSTSERV, &format!("vhost for {} set to {}", account, vhost)).await?;
+                    for target in irc.find_users_by_account(account) {
+                        irc.apply_vhost(&target).await?;
+                    }
+                },
+                Err(AccountError::NoSuchAccount(_)) => {
+                    notice(irc, user, HOSTSERV, &format!("{} is not a registered account", account)).await?;
+                },
+                Err(err) => return Err(err.into()),
+            }
+        },
+        ("DEL", Some(account), None) if oper => {
+            match irc.accounts.set_vhost(account, None).await {
+                Ok(()) => {
+                    notice(irc, user, HOSTSERV, &format!("vhost for {} deleted", account)).await?;
+                    for target in irc.find_users_by_account(account) {
+                        target.set_vhost(None).await?;
+                    }
+                },
+                Err(AccountError::NoSuchAccount(_)) => {
+                    notice(irc, user, HOSTSERV, &format!("{} is not a registered account", account)).await?;
+                },
+                Err(err) => return Err(err.into()),
+            }
+        },
+        ("LIST", None, None) if oper => {
+            for (account, vhost) in irc.accounts.list_vhosts().await? {
+                notice(irc, user, HOSTSERV, &format!("{}: {}", account, vhost)).await?;
+            }
+            notice(irc, user, HOSTSERV, "End of vhost list").await?;
+        },
+        ("SET", _, _) | ("DEL", _, _) | ("LIST", _, _) if !oper => {
+            notice(irc, user, HOSTSERV, "Permission denied, you're not an IRC operator").await?;
+        },
+        ("ON", None, None) => {
+            if user.get_account().is_none() {
+                notice(irc, user, HOSTSERV, "You are not logged in to an account").await?;
+            } else if !irc.apply_vhost(user).await? {
+                notice(irc, user, HOSTSERV, "Your account has no vhost assigned").await?;
+            }
+        },
+        ("OFF", None, None) => user.set_vhost(None).await?,
+        _ => {
+            notice(irc, user, HOSTSERV, "HostServ commands: ON, OFF").await?;
+            if oper {
+                notice(irc, user, HOSTSERV, "Operator commands: SET <account> <vhost>, DEL <account>, LIST").await?;
+            }
+        },
+    }
+    Ok(Vec::new())
+}
+
+/* registered channels keep their modes, topic, bans and access list in
+ * the account store, and are recreated from it at startup, see chan.rs */
+async fn chanserv(irc: &Arc<Core>, user: &Arc<User>, text: &str) -> Result<ClientReplies, GenError> {
+    let args: Vec<&str> = text.split_whitespace().collect();
+    let subcmd = args.first().unwrap_or(&"HELP").to_ascii_uppercase();
+    let account = user.get_account();
+    let chan = match args.get(1).map(|chanmask| irc.get_chan(chanmask)) {
+        Some(Ok(chan)) => Some(chan),
+        Some(Err(_)) if subcmd != "HELP" => {
+            notice(irc, user, CHANSERV, &format!("{} doesn't exist", args[1])).await?;
+            return Ok(Vec::new());
+        },
+        _ => None,
+    };
+    let reg = match &chan {
+        Some(chan) => chan.get_reg().await,
+        None => None,
+    };
+    let founder = match (&reg, &account) {
+        (Some(reg), Some(account)) => reg.founder.eq_ignore_ascii_case(account),
+        _ => false,
+    };
+    match (&subcmd[..], chan, reg, &args[1..]) {
+        ("REGISTER", Some(chan), None, [_]) => {
+            let account = match account {
+                Some(account) => account,
+                None => {
+                    notice(irc, user, CHANSERV, "You need to be logged in to register a channel").await?;
+                    return Ok(Vec::new());
+                }
+            };
+            if !chan.is_op(user).await {
+                notice(irc, user, CHANSERV, &format!("You need to be a channel operator on {}", chan.get_name())).await?;
+            } else if chan.register(&account).await {
+                notice(irc, user, CHANSERV, &format!("{} registered to {}", chan.get_name(), account)).await?;
+            } else {
+                notice(irc, user, CHANSERV, &format!("{} is already registered", chan.get_name())).await?;
+            }
+        },
+        ("REGISTER", Some(chan), Some(_), [_]) => {
+            notice(irc, user, CHANSERV, &format!("{} is already registered", chan.get_name())).await?;
+        },
+        ("DROP", Some(chan), Some(_), [_]) if founder || user.is_oper() => {
+            if chan.unregister().await {
+                irc.accounts.drop_channel(&chan.get_name()).await?;
+            }
+            notice(irc, user, CHANSERV, &format!("{} has been dropped", chan.get_name())).await?;
+        },
+        ("ACCESS", Some(chan), Some(reg), [_, action]) if action.eq_ignore_ascii_case("LIST") => {
+            notice(irc, user, CHANSERV, &format!("{} founder: {}", chan.get_name(), reg.founder)).await?;
+            for entry in reg.access.iter() {
+                notice(irc, user, CHANSERV, &format!("{} op: {}", chan.get_name(), entry)).await?;
+            }
+            notice(irc, user, CHANSERV, &format!("End of access list for {}", chan.get_name())).await?;
+        },
+        ("ACCESS", Some(chan), Some(_), [_, action, target]) if founder || user.is_oper() => {
+            let add = match action.to_ascii_uppercase().as_str() {
+                "ADD" => true,
+                "DEL" => false,
+                _ => {
+                    notice(irc, user, CHANSERV, "ACCESS <channel> ADD|DEL <account>, or ACCESS <channel> LIST").await?;
+                    return Ok(Vec::new());
+                }
+            };
+            if add && irc.accounts.get(target).await?.is_none() {
+                notice(irc, user, CHANSERV, &format!("{} is not a registered account", target)).await?;
+            } else if chan.set_access(target, add).await {
+                let done = if add { "added to" } else { "removed from" };
+                notice(irc, user, CHANSERV, &format!("{} {} the {} access list", target, done, chan.get_name())).await?;
+            } else {
+                let state = if add { "already on" } else { "not on" };
+                notice(irc, user, CHANSERV, &format!("{} is {} the {} access list", target, state, chan.get_name())).await?;
+            }
+        },
+        ("INFO", Some(chan), Some(reg), [_]) => {
+            let registered = Utc.timestamp(reg.registered_at, 0).to_rfc2822();
+            notice(irc, user, CHANSERV, &format!("{} registered {} by {}", chan.get_name(), registered, reg.founder)).await?;
+        },
+        ("DROP", _, Some(_), [_]) | ("ACCESS", _, Some(_), [_, _, _]) => {
+            notice(irc, user, CHANSERV, "Only the channel founder can do that").await?;
+        },
+        ("DROP", Some(chan), None, _) | ("ACCESS", Some(chan), None, _) | ("INFO", Some(chan), None, _) => {
+            notice(irc, user, CHANSERV, &format!("{} is not registered", chan.get_name())).await?;
+        },
+        _ => {
+            notice(irc, user, CHANSERV, "ChanServ commands: REGISTER <channel>, DROP <channel>, INFO <channel>,").await?;
+            notice(irc, user, CHANSERV, "ACCESS <channel> ADD|DEL <account>, ACCESS <channel> LIST").await?;
+        },
+    }
+    Ok(Vec::new())
+}
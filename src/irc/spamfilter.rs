@@ -0,0 +1,172 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* the [[spamfilter]] rules, compiled once at startup. anything a user
+ * sends that a rule covers goes through enforce() first, the first rule
+ * that matches decides what happens. each rule counts its hits for STATS F */
+use crate::client::Client;
+use crate::config::{SpamAction, SpamTarget, SpamfilterBlock};
+use crate::irc::connlog::{self, Event};
+use crate::irc::kline::Kline;
+use crate::irc::Core;
+use regex::Regex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+const DEFAULT_REASON: &str = "Spam";
+
+#[derive(Debug)]
+pub struct Rule {
+    pub regex: Regex,
+    pub targets: Vec<SpamTarget>,
+    pub action: SpamAction,
+    pub reason: String,
+    pub duration: Duration,
+    hits: AtomicU64,
+}
+
+impl Rule {
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Spamfilter {
+    rules: Vec<Rule>,
+}
+
+impl Spamfilter {
+    /* Config::parse() has already checked the regexes, this only
+     * skips bad ones for configs built some other way */
+    pub fn new(blocks: &[SpamfilterBlock]) -> Spamfilter {
+        let rules = blocks.iter().filter_map(|block| match Regex::new(&block.regex) {
+            Ok(regex) => Some(Rule {
+                regex,
+                targets: block.targets.clone(),
+                action: block.action,
+                reason: block.reason.clone().unwrap_or_else(|| DEFAULT_REASON.to_string()),
+                duration: Duration::from_secs(block.duration),
+                hits: AtomicU64::new(0),
+            }),
+            Err(err) => {
+                warn!("skipping spamfilter {}: {}", block.regex, err);
+                None
+            },
+        }).collect();
+        Spamfilter { rules }
+    }
+
+    /* the first rule for `target` that matches, which counts as a hit */
+    pub fn check(&self, target: SpamTarget, text: &str) -> Option<&Rule> {
+        let rule = self.rules.iter().find(|rule| rule.targets.contains(&target) && rule.regex.is_match(text))?;
+        rule.hits.fetch_add(1, Ordering::Relaxed);
+        Some(rule)
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+}
+
+pub fn target_name(target: SpamTarget) -> &'static str {
+    match target {
+        SpamTarget::Privmsg => "privmsg",
+        SpamTarget::Notice => "notice",
+        SpamTarget::Part => "part",
+        SpamTarget::Quit => "quit",
+        SpamTarget::Nick => "nick",
+    }
+}
+
+pub fn action_name(action: SpamAction) -> &'static str {
+    match action {
+        SpamAction::Block => "block",
+        SpamAction::Kill => "kill",
+        SpamAction::Kline => "kline",
+    }
+}
+
+/* true if `text` tripped a rule, in which case the client has been dealt
+ * with and the caller should drop the text (for PRIVMSG/NOTICE/NICK the
+ * whole command, PART and QUIT still go ahead without their message).
+ * `dest` is just for the oper notice, where it was headed */
+pub async fn enforce(irc: &Arc<Core>, client: &Arc<Client>, target: SpamTarget, dest: &str, text: &str) -> bool {
+    if client.is_oper() {
+        return false;
+    }
+    let rule = match irc.get_spamfilter().check(target, text) {
+        Some(rule) => rule,
+        None => return false,
+    };
+    let host = client.get_host_string();
+    irc.notice_opers(&format!(
+        "Spamfilter {} ({}): {} ({}) {} {}: {}",
+        rule.regex, action_name(rule.action), client.get_nick_or_star(), host, target_name(target), dest, text
+    )).await;
+    match rule.action {
+        SpamAction::Block => {
+            let line = format!(":{} NOTICE {} :*** Not sent to {}: {}", irc.get_host(), client.get_nick_or_star(), dest, rule.reason);
+            if let Err(err) = client.send_line(&line).await {
+                warn!("couldn't tell client {} about a spamfilter hit: {}", client.get_id(), err);
+            }
+        },
+        SpamAction::Kill => {
+            connlog::log(client, Event::Kill(&rule.reason));
+            client.disconnect(&format!("Killed ({})", rule.reason));
+        },
+        /* on a privacy listener the host is the cloak everyone there
+         * shares, K-lining it would shut all of them out */
+        SpamAction::Kline if client.is_privacy() => {
+            connlog::log(client, Event::Kill(&rule.reason));
+            client.disconnect(&format!("Killed ({})", rule.reason));
+        },
+        SpamAction::Kline => {
+            let mask = format!("*@{}", host);
+            irc.add_kline(Kline::new(&mask, &rule.reason, rule.duration));
+            connlog::log(client, Event::Kline(&mask));
+            client.disconnect(&format!("K-lined ({})", rule.reason));
+        },
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(regex: &str, targets: Vec<SpamTarget>) -> SpamfilterBlock {
+        SpamfilterBlock { regex: regex.to_string(), targets, action: SpamAction::Block, reason: None, duration: 0 }
+    }
+
+    #[test]
+    fn targets_and_hits() {
+        let filter = Spamfilter::new(&[
+            block("(?i)free bitcoin", vec![SpamTarget::Privmsg]),
+            block("^spam", vec![SpamTarget::Nick, SpamTarget::Privmsg]),
+            block("(unclosed", vec![SpamTarget::Privmsg]),
+        ]);
+        assert_eq!(filter.rules().len(), 2);
+        assert!(filter.check(SpamTarget::Privmsg, "get FREE Bitcoin now").is_some());
+        assert!(filter.check(SpamTarget::Notice, "get free bitcoin now").is_none());
+        assert!(filter.check(SpamTarget::Nick, "spammer").is_some());
+        assert!(filter.check(SpamTarget::Nick, "notspam").is_none());
+        assert_eq!(filter.rules()[0].hits(), 1);
+        assert_eq!(filter.rules()[1].hits(), 1);
+    }
+}
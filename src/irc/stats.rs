@@ -0,0 +1,83 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* STATS <letter>, opers only.
+ *   d  reverse DNS cache hits, misses and how many answers it holds
+ *   F  spamfilter rules and how many times each has matched
+ *   k  K-lines still in force
+ *   m  how often each command has been used and the time spent in it
+ *   z  bytes held in send queues and always_on backlogs, and their limits
+ * anything else just gets the end of the (empty) report */
+use crate::client::{ClientReplies, GenError};
+use crate::irc::error::Error as ircError;
+use crate::irc::reply::Reply as ircReply;
+use crate::irc::spamfilter::{action_name, target_name};
+use crate::irc::{Core, User};
+use crate::parser::ParsedMsg;
+use std::sync::Arc;
+use std::time::Duration;
+
+/* one command's running totals for STATS m */
+#[derive(Debug, Clone, Default)]
+pub struct CommandStats {
+    pub count: u64,
+    pub time: Duration,
+}
+
+pub async fn command(irc: &Arc<Core>, _user: &Arc<User>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let letter = match params.opt_params.first().and_then(|param| param.chars().next()) {
+        Some(letter) => letter,
+        None => {
+            replies.push(Err(ircError::NeedMoreParams("STATS".to_string())));
+            return Ok(replies);
+        }
+    };
+    match letter {
+        'D' | 'd' => {
+            let cache = irc.get_resolver().cache_stats();
+            replies.push(Ok(ircReply::StatsDns(cache.hits, cache.misses, cache.entries)));
+        },
+        'F' | 'f' => {
+            for rule in irc.get_spamfilter().rules() {
+                let targets: Vec<&str> = rule.targets.iter().map(|target| target_name(*target)).collect();
+                replies.push(Ok(ircReply::StatsSpamfilter(
+                    targets.join(","), action_name(rule.action).to_string(), rule.hits(), rule.regex.to_string()
+                )));
+            }
+        },
+        'K' | 'k' => {
+            for kline in irc.get_klines() {
+                let (username, host) = kline.mask.split_once('@').unwrap_or(("*", &kline.mask));
+                replies.push(Ok(ircReply::StatsKLine(host.to_string(), username.to_string(), kline.reason.clone())));
+            }
+        },
+        'M' | 'm' => {
+            for (cmd, stats) in irc.get_command_stats() {
+                replies.push(Ok(ircReply::StatsCommands(cmd, stats.count, stats.time.as_micros())));
+            }
+        },
+        'Z' | 'z' => {
+            let memory = irc.get_memory();
+            let limits = &irc.get_config().limits;
+            replies.push(Ok(ircReply::StatsMemory("sendq".to_string(), memory.queued(), limits.total_sendq)));
+            replies.push(Ok(ircReply::StatsMemory("backlog".to_string(), memory.backlog(), limits.total_backlog)));
+        },
+        _ => (),
+    }
+    replies.push(Ok(ircReply::EndofStats(letter)));
+    Ok(replies)
+}
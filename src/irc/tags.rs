@@ -0,0 +1,144 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* client-only tags (the ones starting with +) and TAGMSG. only the tags
+ * in RELAYED get passed on, anything else a client sends is dropped, and
+ * only to recipients who've negotiated message-tags. everyone else gets
+ * a PRIVMSG or NOTICE without them, and never sees a TAGMSG at all.
+ * +typing is rate limited on its own by [limits] typing_max, past that
+ * the tag is dropped and the message (if any) still goes through.
+ * the relayed tags are kept within the 4094 bytes a client is allowed,
+ * leaving the other half of the 8191 byte budget for tags of our own.
+ * tags don't go over server links */
+use crate::client::{ClientReplies, GenError};
+use crate::irc::chan;
+use crate::irc::error::Error as ircError;
+use crate::irc::{recipient_result, Core, NamedEntity, User};
+use crate::parser::{escape_tag, ParsedMsg};
+use std::sync::Arc;
+
+pub const TYPING: &str = "+typing";
+pub const REACT: &str = "+draft/react";
+pub const REPLY: &str = "+draft/reply";
+
+pub const RELAYED: &[&str] = &[TYPING, REACT, REPLY];
+
+const TYPING_STATES: &[&str] = &["active", "paused", "done"];
+
+/* bytes of client tag data, not counting the @ and the space after */
+pub const MAX_CLIENT_TAGS: usize = 4094;
+
+/* the tags from tags we'll pass on, as `@key=value;key ` ready to go
+ * in front of the line, or an empty string if there aren't any */
+pub fn relay(user: &User, tags: &[(String, String)]) -> String {
+    let mut kept = Vec::new();
+    for (key, value) in tags {
+        if !RELAYED.contains(&key.as_str()) || kept.iter().any(|(kept_key, _value)| kept_key == key) {
+            continue;
+        }
+        if key == TYPING && (!TYPING_STATES.contains(&value.as_str()) || user.throttle_typing().is_err()) {
+            continue;
+        }
+        kept.push((key.clone(), value.clone()));
+    }
+    format_tags(&kept)
+}
+
+/* any tag that would take it over MAX_CLIENT_TAGS is left out */
+fn format_tags(tags: &[(String, String)]) -> String {
+    let mut data = String::new();
+    for (key, value) in tags {
+        let tag = if value.is_empty() { key.clone() } else { format!("{}={}", key, escape_tag(value)) };
+        let sep = usize::from(!data.is_empty());
+        if data.len() + sep + tag.len() > MAX_CLIENT_TAGS {
+            continue;
+        }
+        if sep > 0 {
+            data.push(';');
+        }
+        data.push_str(&tag);
+    }
+    if data.is_empty() {
+        return String::new();
+    }
+    format!("@{} ", data)
+}
+
+/* TAGMSG <target>{,<target>}, a message that's nothing but tags. like a
+ * NOTICE nothing comes back for targets that aren't there or won't take it */
+pub async fn tagmsg(irc: &Arc<Core>, user: &Arc<User>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let targets = match params.opt_params.first() {
+        Some(targets) => targets,
+        None => {
+            replies.push(Err(ircError::NoRecipient("TAGMSG".to_string())));
+            return Ok(replies);
+        }
+    };
+    if let Err(err) = irc.check_targets("TAGMSG", targets) {
+        replies.push(Err(err));
+        return Ok(replies);
+    }
+    let tags = relay(user, &params.tags);
+    if tags.is_empty() {
+        return Ok(replies);
+    }
+    for target in targets.split(',') {
+        match irc.get_name(chan::split_status(target).1) {
+            Some(NamedEntity::User(user_weak)) => {
+                if let Ok(recv_u) = User::upgrade(&user_weak, target) {
+                    if recv_u.refuses_msg_from(user).is_none() {
+                        recipient_result(irc, recv_u.send_msg(user, "TAGMSG", target, "", &tags).await).await?;
+                    }
+                }
+            },
+            Some(NamedEntity::Chan(chan)) => {
+                let _ = chan.send_msg(user, "TAGMSG", target, "", &tags).await?;
+            },
+            None => (),
+        }
+    }
+    Ok(replies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formatting() {
+        assert_eq!(format_tags(&[]), "");
+        let tags = vec![
+            (REACT.to_string(), "👍".to_string()),
+            (REPLY.to_string(), "a;b c".to_string()),
+            ("+flag".to_string(), String::new()),
+        ];
+        assert_eq!(format_tags(&tags), "@+draft/react=👍;+draft/reply=a\\:b\\sc;+flag ");
+    }
+
+    #[test]
+    fn budget() {
+        let big = vec![
+            (REPLY.to_string(), "x".repeat(MAX_CLIENT_TAGS)),
+            (REACT.to_string(), "y".repeat(MAX_CLIENT_TAGS - REACT.len() - 1)),
+            ("+flag".to_string(), String::new()),
+        ];
+        let formatted = format_tags(&big);
+        assert_eq!(formatted.len(), MAX_CLIENT_TAGS + 2);
+        assert!(formatted.starts_with(&format!("@{}=", REACT)));
+        assert!(!formatted.contains(REPLY));
+    }
+}
@@ -0,0 +1,106 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* the human readable text at the end of numerics, "No such nick/channel"
+ * and the like, can be replaced by [replies] in the config, keyed by the
+ * numeric. {1}, {2}.. in a template are the values the built in text
+ * has in it, in the same order (see reply.rs and error.rs for which),
+ * so a translation can move them about. only the text is up for grabs,
+ * the numeric and the parameters before it stay as they are */
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Write};
+
+#[derive(Debug, Default)]
+pub struct Templates {
+    text: HashMap<u16, String>,
+}
+
+impl Templates {
+    /* keys that aren't numerics are left out, Config::parse() won't let
+     * them through anyway */
+    pub fn new(replies: &BTreeMap<String, String>) -> Self {
+        let text = replies
+            .iter()
+            .filter_map(|(key, text)| numeric(key).map(|numeric| (numeric, text.clone())))
+            .collect();
+        Templates { text }
+    }
+
+    /* default with the values filled in, unless there's a template for it */
+    pub fn text(&self, numeric: u16, default: &str, values: &[&dyn fmt::Display]) -> String {
+        fill(self.text.get(&numeric).map_or(default, String::as_str), values)
+    }
+}
+
+/* "001" to 1, anything not from 001 to 999 is None */
+pub fn numeric(key: &str) -> Option<u16> {
+    match key.parse::<u16>() {
+        Ok(numeric) if key.len() == 3 && numeric > 0 => Some(numeric),
+        _ => None,
+    }
+}
+
+/* whether text is fit to go at the end of a line */
+pub fn valid(text: &str) -> bool {
+    !text.contains(['\r', '\n', '\0'])
+}
+
+/* in the one pass, so a value that looks like {1} stays as it is */
+fn fill(template: &str, values: &[&dyn fmt::Display]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest
+            .find('}')
+            .and_then(|end| rest[1..end].parse::<usize>().ok().map(|n| (n, end)))
+            .and_then(|(n, end)| values.get(n.wrapping_sub(1)).map(|value| (value, end)));
+        match value {
+            Some((value, end)) => {
+                let _res = write!(out, "{}", value);
+                rest = &rest[end + 1..];
+            },
+            None => {
+                out.push('{');
+                rest = &rest[1..];
+            },
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn templates() {
+        let mut replies = BTreeMap::new();
+        replies.insert("401".to_string(), "Kein solcher Nick: {1}".to_string());
+        replies.insert("nope".to_string(), "ignored".to_string());
+        let templates = Templates::new(&replies);
+        assert_eq!(templates.text(401, "No such nick/channel", &[&"bob"]), "Kein solcher Nick: bob");
+        assert_eq!(templates.text(438, "Please wait {1} seconds", &[&5]), "Please wait 5 seconds");
+        /* values aren't filled in again, and unknown placeholders stay */
+        assert_eq!(fill("{2} {1} {3} {x", &[&"{2}", &"a"]), "a {2} {3} {x");
+        assert_eq!(numeric("001"), Some(1));
+        assert_eq!(numeric("1"), None);
+        assert_eq!(numeric("000"), None);
+        assert!(!valid("two\r\nlines"));
+    }
+}
@@ -0,0 +1,64 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* TRACE [<server>|<nick>], opers only. lists every connection we have,
+ * users and opers by nick and anything still registering by address.
+ * there's no server linking yet, so no RPL_TRACELINK/RPL_TRACESERVER
+ * lines either, those will want adding once there are links to show */
+use crate::client::{Client, ClientReplies, ClientType, GenError};
+use crate::irc::error::Error as ircError;
+use crate::irc::reply::Reply as ircReply;
+use crate::irc::{Core, User};
+use crate::parser::ParsedMsg;
+use std::sync::Arc;
+
+/* no connection classes yet, everyone is in the same one */
+const CLASS: &str = "users";
+
+fn trace_client(client: &Client) -> Option<ircReply> {
+    match client.get_client_type() {
+        ClientType::User(user) if user.is_oper() => Some(ircReply::TraceOperator(CLASS.to_string(), user.get_nick())),
+        ClientType::User(user) => Some(ircReply::TraceUser(CLASS.to_string(), user.get_nick())),
+        ClientType::Server(link) => Some(ircReply::TraceServer(CLASS.to_string(), link.get_name())),
+        ClientType::ProtoUser(_) | ClientType::Unregistered => {
+            Some(ircReply::TraceUnknown(CLASS.to_string(), client.get_ip().to_string()))
+        },
+        ClientType::Dead => None,
+    }
+}
+
+pub async fn command(irc: &Arc<Core>, _user: &Arc<User>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let clients = match params.opt_params.first() {
+        Some(target) if !target.eq_ignore_ascii_case(&irc.get_host()) => {
+            let found = irc.get_clients().into_iter().find(|client| match client.get_client_type() {
+                ClientType::User(user) => user.get_nick().eq_ignore_ascii_case(target),
+                _ => false,
+            });
+            match found {
+                Some(client) => vec![client],
+                None => {
+                    replies.push(Err(ircError::NoSuchServer(target.to_string())));
+                    return Ok(replies);
+                }
+            }
+        },
+        _ => irc.get_clients(),
+    };
+    replies.extend(clients.iter().filter_map(|client| trace_client(client)).map(Ok));
+    replies.push(Ok(ircReply::TraceEnd(irc.get_host(), env!("CARGO_PKG_VERSION").to_string())));
+    Ok(replies)
+}
@@ -0,0 +1,422 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* push notifications for detached always_on sessions. a PM, or a channel
+ * message with the user's nick in it, turns into a JSON POST to the URL
+ * the account set with NickServ WEBHOOK. it all happens on a task of its
+ * own: pushes are queued up for it, it looks up the URL, rate limits per
+ * account by [webhooks] max/window and hands each one to a task of its
+ * own to deliver. ones that fail come back to go in the retry queue.
+ * since any account can pick the URL, it has to be https:// (unless
+ * [webhooks] allow_http) and mustn't lead to loopback, private or
+ * link-local addresses, checked on the addresses it resolves to right
+ * before connecting to one of them, unless it's one of [webhooks] hosts */
+use crate::config::WebhookConfig;
+use crate::io::Connector;
+use crate::irc::accounts::AccountStore;
+use crate::irc::chan::Channel;
+use crate::irc::mask;
+use crate::irc::User;
+use crate::throttle::RateWindow;
+use chrono::Utc;
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::{Error as ioError, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{self, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time;
+use tracing::{debug, warn};
+
+/* past this many waiting to be retried the oldest get dropped */
+const MAX_RETRIES_QUEUED: usize = 1000;
+
+/* longer than any status line a webhook endpoint has reason to send */
+const MAX_STATUS_LINE: usize = 512;
+
+#[derive(Debug, Clone)]
+struct Push {
+    account: String,
+    url: String,
+    body: String,
+    attempts: u32,
+}
+
+#[derive(Debug)]
+enum Job {
+    New(String, String),
+    Failed(Push),
+}
+
+#[derive(Debug)]
+pub struct Webhooks {
+    jobs: mpsc::UnboundedSender<Job>,
+}
+
+impl Webhooks {
+    /* None with [webhooks] turned off */
+    pub fn start(config: &WebhookConfig, accounts: &AccountStore) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+        let connector = match Connector::new(&config.ca_file) {
+            Ok(connector) => Arc::new(connector),
+            Err(err) => {
+                warn!("webhooks turned off, no TLS for them: {}", err);
+                return None;
+            }
+        };
+        let (jobs, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run(config.clone(), connector, accounts.clone(), jobs.clone(), rx));
+        Some(Webhooks { jobs })
+    }
+
+    fn push(&self, account: &str, from: &User, target: &str, message: &str) {
+        let body = json!({
+            "account": account,
+            "from": from.get_nick(),
+            "target": target,
+            "message": message,
+            "time": Utc::now().to_rfc3339(),
+        }).to_string();
+        let _res = self.jobs.send(Job::New(account.to_string(), body));
+    }
+
+    /* a PRIVMSG to recv_u */
+    pub fn private(&self, recv_u: &User, from: &User, message: &str) {
+        if let Some(account) = recv_u.get_account().filter(|_| recv_u.is_detached()) {
+            self.push(&account, from, &recv_u.get_nick(), message);
+        }
+    }
+
+    /* a PRIVMSG to a channel, for anyone detached whose nick is in it */
+    pub async fn channel(&self, chan: &Channel, from: &User, message: &str) {
+        for (member, _flags) in chan.get_members().await {
+            if !member.is_detached() || member.get_id() == from.get_id() || !mentions(message, &member.get_nick()) {
+                continue;
+            }
+            if let Some(account) = member.get_account() {
+                self.push(&account, from, &chan.get_name(), message);
+            }
+        }
+    }
+}
+
+fn is_nick_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "[]\\`_^{|}-".contains(c)
+}
+
+/* nick as a word of its own in message, any case */
+pub fn mentions(message: &str, nick: &str) -> bool {
+    message.split(|c| !is_nick_char(c)).any(|word| word.eq_ignore_ascii_case(nick))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Url {
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+/* http[s]://host[:port][/path] into its parts, nothing else will do */
+pub fn parse_url(url: &str) -> Option<Url> {
+    let (tls, rest) = match url.strip_prefix("https://") {
+        Some(rest) => (true, rest),
+        None => (false, url.strip_prefix("http://")?),
+    };
+    let default_port = if tls { 443 } else { 80 };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(v6) => match v6.split_once(']')? {
+            (host, "") => (host, default_port),
+            (host, port) => (host, port.strip_prefix(':')?.parse().ok()?),
+        },
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, port.parse().ok()?),
+            None => (authority, default_port),
+        },
+    };
+    if host.is_empty() || path.contains(char::is_whitespace) {
+        return None;
+    }
+    Some(Url { tls, host: host.to_string(), port, path: path.to_string() })
+}
+
+/* somewhere on the internet at large */
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            !(v4.is_loopback() || v4.is_private() || v4.is_link_local() || v4.is_unspecified() || v4.is_broadcast()
+                || v4.is_multicast() || v4.is_documentation() || a == 0 || (a == 100 && (64..128).contains(&b)))
+        },
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_public(IpAddr::V4(v4)),
+            None => {
+                let first = v6.segments()[0];
+                /* fc00::/7 unique local, fe80::/10 link-local */
+                !(v6.is_loopback() || v6.is_unspecified() || v6.is_multicast() || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80)
+            },
+        },
+    }
+}
+
+/* listed in [webhooks] hosts, so trusted wherever it resolves to */
+fn trusted(config: &WebhookConfig, host: &str) -> bool {
+    config.hosts.iter().any(|allowed| mask::matches(allowed, host))
+}
+
+/* why NickServ WEBHOOK won't take url, if it won't */
+pub fn refuse(config: &WebhookConfig, url: &str) -> Option<&'static str> {
+    let url = match parse_url(url) {
+        Some(url) if url.tls || config.allow_http => url,
+        _ if config.allow_http => return Some("isn't an http:// or https:// URL"),
+        _ => return Some("isn't an https:// URL"),
+    };
+    if trusted(config, &url.host) {
+        None
+    } else if !config.hosts.is_empty() {
+        Some("isn't on a host webhooks can go to")
+    } else if url.host.parse::<IpAddr>().is_ok_and(|ip| !is_public(ip)) {
+        Some("isn't a public address")
+    } else {
+        None
+    }
+}
+
+/* the address to connect to. everything the host resolves to is
+ * checked, so a name with a private address among its public ones
+ * can't slip through on whichever one comes first */
+async fn resolve(config: &WebhookConfig, url: &Url) -> Result<SocketAddr, ioError> {
+    if !config.hosts.is_empty() && !trusted(config, &url.host) {
+        return Err(ioError::new(ErrorKind::PermissionDenied, "host isn't allowed"));
+    }
+    let addrs: Vec<SocketAddr> = net::lookup_host((url.host.as_str(), url.port)).await?.collect();
+    if !trusted(config, &url.host) && addrs.iter().any(|addr| !is_public(addr.ip())) {
+        return Err(ioError::new(ErrorKind::PermissionDenied, "resolves to a private address"));
+    }
+    addrs.first().copied().ok_or_else(|| ioError::new(ErrorKind::NotFound, "no addresses"))
+}
+
+async fn post(config: &WebhookConfig, connector: &Connector, url: &str, body: &str) -> Result<(), ioError> {
+    let url = match parse_url(url) {
+        Some(url) if url.tls || config.allow_http => url,
+        _ => return Err(ioError::new(ErrorKind::PermissionDenied, "not an allowed URL")),
+    };
+    let sock = TcpStream::connect(resolve(config, &url).await?).await?;
+    if url.tls {
+        let stream = connector.connect(&url.host, sock).await.map_err(|err| ioError::other(err.to_string()))?;
+        exchange(stream, &url, body).await
+    } else {
+        exchange(sock, &url, body).await
+    }
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, url: &Url, body: &str) -> Result<(), ioError> {
+    let (host, path) = (&url.host, &url.path);
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path, host, body.len(), body
+    );
+    stream.write_all(request.as_bytes()).await?;
+    /* only the status line matters */
+    let status = read_status(&mut stream).await?;
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        Some(code) => Err(ioError::other(format!("HTTP {}", code))),
+        None => Err(ioError::new(ErrorKind::InvalidData, "no HTTP status")),
+    }
+}
+
+/* the reply can arrive in as many pieces as it likes, so this reads until
+ * the status line's LF rather than taking whatever the first read gets.
+ * a line that doesn't end within MAX_STATUS_LINE bytes isn't HTTP */
+async fn read_status<S: AsyncRead + Unpin>(stream: &mut S) -> Result<String, ioError> {
+    let mut line = Vec::new();
+    let mut buf = [0; 64];
+    loop {
+        if let Some(end) = line.iter().position(|&byte| byte == b'\n') {
+            line.truncate(end);
+            return Ok(String::from_utf8_lossy(&line).trim_end_matches('\r').to_string());
+        }
+        if line.len() >= MAX_STATUS_LINE {
+            return Err(ioError::new(ErrorKind::InvalidData, "status line too long"));
+        }
+        match stream.read(&mut buf).await? {
+            0 => return Err(ioError::new(ErrorKind::UnexpectedEof, "no HTTP status")),
+            n => line.extend_from_slice(&buf[..n]),
+        }
+    }
+}
+
+fn deliver(mut push: Push, config: Arc<WebhookConfig>, connector: Arc<Connector>, jobs: mpsc::UnboundedSender<Job>) {
+    tokio::spawn(async move {
+        push.attempts += 1;
+        let timeout = Duration::from_secs(config.timeout);
+        let res = match time::timeout(timeout, post(&config, &connector, &push.url, &push.body)).await {
+            Ok(res) => res,
+            Err(_) => Err(ioError::new(ErrorKind::TimedOut, "timed out")),
+        };
+        match res {
+            /* no point trying again somewhere it's not allowed to go */
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                warn!("webhook for {} refused: {}", push.account, err);
+            },
+            Err(err) => {
+                debug!("webhook for {} failed (attempt {}): {}", push.account, push.attempts, err);
+                let _res = jobs.send(Job::Failed(push));
+            },
+            Ok(()) => (),
+        }
+    });
+}
+
+async fn run(
+    config: WebhookConfig,
+    connector: Arc<Connector>,
+    accounts: AccountStore,
+    jobs: mpsc::UnboundedSender<Job>,
+    mut rx: mpsc::UnboundedReceiver<Job>,
+) {
+    let config = Arc::new(config);
+    let window = Duration::from_secs(config.window);
+    let mut limits: HashMap<String, RateWindow> = HashMap::new();
+    let mut retries: Vec<(Instant, Push)> = Vec::new();
+    loop {
+        /* wake up for the next retry that's due, if there is one */
+        let next = retries.iter().map(|(due, _push)| *due).min();
+        let wait = next.map_or(Duration::from_secs(3600), |due| due.saturating_duration_since(Instant::now()));
+        let job = tokio::select! {
+            job = rx.recv() => match job {
+                Some(job) => Some(job),
+                None => return,
+            },
+            _ = time::sleep(wait) => None,
+        };
+
+        match job {
+            Some(Job::New(account, body)) => {
+                let limit = limits.entry(account.to_ascii_lowercase()).or_default();
+                if limit.check(config.max, window).is_err() {
+                    debug!("webhook for {} is over the limit, dropped", account);
+                    continue;
+                }
+                match accounts.get_webhook(&account).await {
+                    Ok(Some(url)) => deliver(Push { account, url, body, attempts: 0 }, Arc::clone(&config), Arc::clone(&connector), jobs.clone()),
+                    Ok(None) => (),
+                    Err(err) => warn!("couldn't look up webhook for {}: {}", account, err),
+                }
+            },
+            Some(Job::Failed(push)) => {
+                if push.attempts > config.retries {
+                    warn!("giving up on webhook for {} after {} attempts", push.account, push.attempts);
+                    continue;
+                }
+                if retries.len() >= MAX_RETRIES_QUEUED {
+                    let oldest = retries.iter().enumerate().min_by_key(|(_i, (due, _push))| *due).map(|(i, _)| i);
+                    if let Some(i) = oldest {
+                        retries.swap_remove(i);
+                    }
+                }
+                let delay = Duration::from_secs(config.retry_delay * u64::from(push.attempts));
+                retries.push((Instant::now() + delay, push));
+            },
+            None => {
+                let now = Instant::now();
+                let (due, waiting): (Vec<_>, Vec<_>) = retries.drain(..).partition(|(due, _push)| *due <= now);
+                retries = waiting;
+                for (_due, push) in due {
+                    deliver(push, Arc::clone(&config), Arc::clone(&connector), jobs.clone());
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nick_mentions() {
+        assert!(mentions("hey Alice, you there?", "alice"));
+        assert!(mentions("[m]bob: ping", "[m]bob"));
+        assert!(!mentions("malice aforethought", "alice"));
+        assert!(!mentions("alice_ said hi", "alice"));
+    }
+
+    fn url(tls: bool, host: &str, port: u16, path: &str) -> Option<Url> {
+        Some(Url { tls, host: host.to_string(), port, path: path.to_string() })
+    }
+
+    #[test]
+    fn urls() {
+        assert_eq!(parse_url("https://push.example/hook?t=1"), url(true, "push.example", 443, "/hook?t=1"));
+        assert_eq!(parse_url("http://push.example:8080"), url(false, "push.example", 8080, "/"));
+        assert_eq!(parse_url("https://[2001:db8::1]:8443/x"), url(true, "2001:db8::1", 8443, "/x"));
+        assert_eq!(parse_url("http://[2001:db8::1]"), url(false, "2001:db8::1", 80, "/"));
+        assert!(parse_url("ftp://push.example/").is_none());
+        assert!(parse_url("https://:443/").is_none());
+    }
+
+    #[test]
+    fn refused() {
+        let mut config = WebhookConfig::default();
+        assert_eq!(refuse(&config, "https://push.example/hook"), None);
+        assert!(refuse(&config, "http://push.example/hook").is_some());
+        assert!(refuse(&config, "https://127.0.0.1:8080/").is_some());
+        assert!(refuse(&config, "https://169.254.169.254/latest/meta-data").is_some());
+        assert!(refuse(&config, "https://[::ffff:10.0.0.1]/").is_some());
+        assert!(refuse(&config, "https://[fd00::1]/").is_some());
+        config.allow_http = true;
+        assert_eq!(refuse(&config, "http://push.example/hook"), None);
+        config.hosts = vec!["relay.internal".to_string()];
+        assert_eq!(refuse(&config, "http://relay.internal:8080/"), None);
+        assert!(refuse(&config, "https://push.example/hook").is_some());
+    }
+
+    #[tokio::test]
+    async fn status_in_pieces() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let reply = tokio::spawn(async move {
+            for piece in ["HTTP/1.", "1 20", "4 No Content\r", "\nServer: x\r\n\r\n"].iter() {
+                server.write_all(piece.as_bytes()).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+        assert_eq!(read_status(&mut client).await.unwrap(), "HTTP/1.1 204 No Content");
+        reply.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn status_cut_short() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        server.write_all(b"HTTP/1.1 2").await.unwrap();
+        drop(server);
+        assert_eq!(read_status(&mut client).await.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        server.write_all(&[b'x'; MAX_STATUS_LINE + 1]).await.unwrap();
+        assert_eq!(read_status(&mut client).await.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
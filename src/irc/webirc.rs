@@ -0,0 +1,85 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* WEBIRC, see https://ircv3.net/specs/extensions/webirc
+ * WEBIRC <password> <gateway> <hostname> <ip> [:<options>]
+ * a trusted gateway sends this before registering so the user gets their
+ * own host and IP instead of the gateway's. options are ignored for now */
+use crate::client::{Client, ClientReplies, GenError, Host};
+use crate::irc::dnsbl;
+use crate::irc::error::Error as ircError;
+use crate::irc::rfc_defs as rfc;
+use crate::irc::Core;
+use crate::parser::ParsedMsg;
+use crate::password;
+use tracing::{debug, warn};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::task;
+
+pub async fn command(irc: &Arc<Core>, client: &Arc<Client>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if client.get_gateway().is_some() {
+        replies.push(Err(ircError::AlreadyRegistred));
+        return Ok(replies);
+    }
+    let (password, gateway, hostname, ip_str) = (
+        params.opt_params[0].clone(),
+        &params.opt_params[1],
+        &params.opt_params[2],
+        &params.opt_params[3],
+    );
+    let ip: IpAddr = match ip_str.parse() {
+        Ok(ip) => ip,
+        Err(_) => {
+            replies.push(Err(ircError::InvalidHost(ip_str.to_string())));
+            return Ok(replies);
+        }
+    };
+
+    /* the gateway has to be connecting from one of its configured addresses */
+    let hashes: Vec<String> = irc.get_config()
+        .find_webirc(client.get_ip())
+        .iter()
+        .map(|block| block.password_hash.clone())
+        .collect();
+    if hashes.is_empty() {
+        warn!("WEBIRC from untrusted host {} (gateway {})", client.get_host_string(), gateway);
+        replies.push(Err(ircError::NoPermForHost));
+        return Ok(replies);
+    }
+    let accepted = task::spawn_blocking(move || {
+        hashes.iter().any(|hash| password::verify(&password, hash))
+    }).await?;
+    if !accepted {
+        warn!("WEBIRC from {} (gateway {}) with bad password", client.get_host_string(), gateway);
+        replies.push(Err(ircError::PasswdMismatch));
+        return Ok(replies);
+    }
+
+    /* take the gateway's word for the hostname if it looks sane,
+     * otherwise fall back to the bare address */
+    let host = if *hostname != ip.to_string() && rfc::valid_hostname(hostname) {
+        Host::Hostname(hostname.to_string())
+    } else {
+        Host::HostAddr(ip)
+    };
+    debug!("client {} via gateway {} is really {} ({})", client.get_id(), gateway, hostname, ip);
+    client.set_gateway(gateway, host, ip);
+    /* redo the DNSBL lookup now we know who it really is */
+    dnsbl::start_check(client);
+    Ok(replies)
+}
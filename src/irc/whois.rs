@@ -0,0 +1,180 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* WHOIS [<server>] <nick>{,<nick>} and WHO <mask>. both only know about
+ * local users, there being no other servers to ask */
+use crate::client::{ClientReplies, ClientType, GenError};
+use crate::irc::chan::ChanFlags;
+use crate::irc::error::Error as ircError;
+use crate::irc::isupport::CHANTYPES;
+use crate::irc::reply::Reply as ircReply;
+use crate::irc::mask;
+use crate::irc::{Core, User};
+use crate::parser::ParsedMsg;
+use std::sync::{Arc, Weak};
+
+const SERVER_INFO: &str = "Rusty IRC Network";
+/* room left for ":server 319 me nick :" with long names on all sides */
+const WHOIS_CHANNELS_LEN: usize = 400;
+
+pub async fn whois(irc: &Core, source: &Arc<User>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    /* with two params the first is a server, which can only be us */
+    let nicks = match params.opt_params.last() {
+        Some(nicks) if !nicks.is_empty() => nicks,
+        _ => {
+            replies.push(Err(ircError::NoNicknameGiven));
+            return Ok(replies);
+        }
+    };
+    if let Err(err) = irc.check_targets("WHOIS", nicks) {
+        replies.push(Err(err));
+        return Ok(replies);
+    }
+    for nick in nicks.split(',') {
+        match irc.get_nick(nick).and_then(|user| Weak::upgrade(&user)) {
+            Some(user) => replies.extend(whois_user(source, &user).await.into_iter().map(Ok)),
+            None => replies.push(Err(ircError::NoSuchNick(nick.to_string()))),
+        }
+        replies.push(Ok(ircReply::EndofWhois(nick.to_string())));
+    }
+    Ok(replies)
+}
+
+async fn whois_user(source: &User, user: &User) -> Vec<ircReply> {
+    let nick = user.get_nick();
+    let mut lines = vec![
+        ircReply::WhoisUser(nick.clone(), user.get_username(), user.get_visible_host(), user.get_realname()),
+    ];
+    for chans in chunk_channels(whois_channels(source, user).await) {
+        lines.push(ircReply::WhoisChannels(nick.clone(), chans));
+    }
+    lines.push(ircReply::WhoisServer(nick.clone(), user.get_server(), SERVER_INFO.to_string()));
+    if user.is_oper() {
+        lines.push(ircReply::WhoisOperator(nick.clone()));
+    }
+    if user.is_bot() {
+        lines.push(ircReply::WhoisBot(nick.clone()));
+    }
+    if user.is_secure() {
+        lines.push(ircReply::WhoisSecure(nick.clone()));
+    }
+    /* the certfp is as good as a login, so only they and opers see it */
+    if let Some(certfp) = user.get_certfp().filter(|_| source.get_id() == user.get_id() || source.is_oper()) {
+        lines.push(ircReply::WhoisCertfp(nick.clone(), certfp));
+    }
+    lines.push(ircReply::WhoisIdle(nick.clone(), user.get_idle(), user.get_signon()));
+    /* opers get told if the user got past a DNSBL listing */
+    if let Some(zones) = user.get_dnsbl_mark().filter(|_| source.is_oper()) {
+        lines.push(ircReply::WhoisSpecial(nick, format!("is listed in DNSBL zones {}", zones)));
+    }
+    lines
+}
+
+/* user's channels with their @ or + in front. +s ones only
+ * show up for people on them, and opers */
+async fn whois_channels(source: &User, user: &User) -> Vec<String> {
+    let nick = user.get_nick();
+    let mut chans = Vec::new();
+    for chan in user.get_channel_list().iter().filter_map(Weak::upgrade) {
+        let name = chan.get_name();
+        if !source.is_oper() && !source.is_on(&name) && chan.get_modes().await.secret {
+            continue;
+        }
+        let prefix = chan.get_flags(&nick).await.map_or("", |flags| flags.prefix());
+        chans.push(format!("{}{}", prefix, name));
+    }
+    chans.sort();
+    chans
+}
+
+/* as many channels to a 319 as fit comfortably in a line */
+fn chunk_channels(chans: Vec<String>) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for chan in chans {
+        match lines.last_mut() {
+            Some(line) if line.len() + 1 + chan.len() <= WHOIS_CHANNELS_LEN => {
+                line.push(' ');
+                line.push_str(&chan);
+            },
+            _ => lines.push(chan),
+        }
+    }
+    lines
+}
+
+/* H for here (there's no AWAY yet), * for opers, the channel prefix if
+ * there is one and B for bots */
+fn who_flags(user: &User, chan_flags: Option<&ChanFlags>) -> String {
+    let mut flags = String::from("H");
+    if user.is_oper() {
+        flags.push('*');
+    }
+    if let Some(chan_flags) = chan_flags {
+        flags.push_str(chan_flags.prefix());
+    }
+    if user.is_bot() {
+        flags.push('B');
+    }
+    flags
+}
+
+fn who_reply(irc: &Core, chan: &str, user: &User, chan_flags: Option<&ChanFlags>) -> ircReply {
+    ircReply::WhoReply(
+        chan.to_string(),
+        user.get_username(),
+        user.get_visible_host(),
+        irc.get_host(),
+        user.get_nick(),
+        who_flags(user, chan_flags),
+        user.get_realname(),
+    )
+}
+
+/* WHO on a channel lists its members, anything else is matched as a
+ * nick mask against everyone. "0" is the old way of asking for "*".
+ * invisible users only show up for people who share a channel with
+ * them, or when asked for by their exact nick */
+pub async fn who(irc: &Core, source: &Arc<User>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let mask = match params.opt_params.first().map(String::as_str) {
+        None | Some("0") => "*",
+        Some(mask) => mask,
+    };
+    if mask.starts_with(|c| CHANTYPES.contains(c)) {
+        if let Ok(chan) = irc.get_chan(mask) {
+            let name = chan.get_name();
+            for (user, flags) in chan.get_members().await {
+                if !source.can_see(&user) {
+                    continue;
+                }
+                replies.push(Ok(who_reply(irc, &name, &user, Some(&flags))));
+            }
+        }
+    } else {
+        let wild = mask::is_wild(mask);
+        for client in irc.get_clients() {
+            if let ClientType::User(user) = client.get_client_type() {
+                if mask::matches(mask, &user.get_nick()) && (!wild || source.can_see(&user)) {
+                    replies.push(Ok(who_reply(irc, "*", &user, None)));
+                }
+            }
+        }
+    }
+    replies.push(Ok(ircReply::EndofWho(mask.to_string())));
+    Ok(replies)
+}
+
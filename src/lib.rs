@@ -0,0 +1,36 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* see the lock order above Core in irc.rs */
+#![deny(clippy::await_holding_lock)]
+extern crate dns_lookup;
+extern crate tokio;
+pub mod irc;
+pub mod client;
+pub mod config;
+pub mod daemon;
+pub mod dns;
+pub mod io;
+pub mod linereader;
+pub mod logfile;
+pub mod parser;
+pub mod password;
+pub mod server;
+pub mod throttle;
+pub mod upgrade;
+pub use crate::server::{Server, ServerBuilder, ServerHandle};
+
+pub const USER_MODES: &str = "BRSZio";
@@ -0,0 +1,180 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* the framing for everything a client sends. reads lines off its socket
+ * like BufReader::lines(), except that it never holds on to more of one
+ * line than it's allowed. anything over the limit is thrown away up to the
+ * next newline and the caller is told so it can send ERR_INPUTTOOLONG */
+use crate::irc::rfc_defs as rfc;
+use std::io::Error as ioError;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+
+/* a client that's negotiated message-tags gets this much for the tags,
+ * including the @ and the space after them, on top of MAX_MSG_SIZE */
+pub const MAX_TAGS_SIZE: usize = 8191;
+
+#[derive(Debug, PartialEq)]
+pub enum Line {
+    Line(String),
+    TooLong,
+    /* had a NUL in it, which nothing we'd pass it on to could cope with */
+    Forbidden,
+}
+
+#[derive(Debug)]
+pub struct LineReader<R> {
+    inner: BufReader<R>,
+    buf: Vec<u8>,
+    /* past the limit, dropping everything until the next newline */
+    overlong: bool,
+    /* the last line ended in \r, if a \n comes next it's the same ending */
+    after_cr: bool,
+}
+
+/* the most a line can be, CRLF included */
+pub fn max_line_len(tags: bool) -> usize {
+    if tags {
+        MAX_TAGS_SIZE + rfc::MAX_MSG_SIZE
+    } else {
+        rfc::MAX_MSG_SIZE
+    }
+}
+
+/* with tags the budgets are separate, a short message can't borrow
+ * what the tags didn't use or the other way round */
+fn fits(line: &[u8], tags: bool) -> bool {
+    match line.iter().position(|byte| *byte == b' ') {
+        Some(space) if tags && line[0] == b'@' => space < MAX_TAGS_SIZE && line.len() - space - 1 <= rfc::MAX_MSG_SIZE,
+        _ => line.len() <= rfc::MAX_MSG_SIZE,
+    }
+}
+
+impl<R: AsyncRead + Unpin> LineReader<R> {
+    pub fn new(sock: R) -> Self {
+        LineReader {
+            inner: BufReader::new(sock),
+            buf: Vec::new(),
+            overlong: false,
+            after_cr: false,
+        }
+    }
+
+    /* the next line with its line ending taken off, None at EOF. `tags`
+     * is whether the client has message-tags and gets the bigger budget.
+     * \r\n, a bare \n or a stray \r all end a line */
+    pub async fn next_line(&mut self, tags: bool) -> Result<Option<Line>, ioError> {
+        let max = max_line_len(tags);
+        loop {
+            let available = self.inner.fill_buf().await?;
+            if available.is_empty() {
+                /* whatever came without a line ending isn't a whole line */
+                self.buf.clear();
+                self.overlong = false;
+                return Ok(None);
+            }
+            if std::mem::replace(&mut self.after_cr, false) && available[0] == b'\n' {
+                self.inner.consume(1);
+                continue;
+            }
+            let (used, ending) = match available.iter().position(|byte| *byte == b'\n' || *byte == b'\r') {
+                Some(end) => (end + 1, Some(available[end])),
+                None => (available.len(), None),
+            };
+            if !self.overlong {
+                if self.buf.len() + used > max {
+                    self.overlong = true;
+                    self.buf.clear();
+                } else {
+                    self.buf.extend_from_slice(&available[..used]);
+                }
+            }
+            self.inner.consume(used);
+            if let Some(ending) = ending {
+                self.after_cr = ending == b'\r';
+                break;
+            }
+        }
+
+        let mut line = std::mem::take(&mut self.buf);
+        if std::mem::replace(&mut self.overlong, false) || !fits(&line, tags) {
+            return Ok(Some(Line::TooLong));
+        }
+        line.pop();
+        if line.contains(&0) {
+            return Ok(Some(Line::Forbidden));
+        }
+        /* IRC is bytes on the wire, one line of latin-1 or whatever else
+         * isn't worth dropping the connection over */
+        match String::from_utf8(line) {
+            Ok(line) => Ok(Some(Line::Line(line))),
+            Err(err) => Ok(Some(Line::Line(String::from_utf8_lossy(err.as_bytes()).into_owned()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn overlong_lines_are_dropped() {
+        let long = "x".repeat(rfc::MAX_MSG_SIZE);
+        let input = format!("PING a\r\nPRIVMSG #chan :{}\r\nPING b\n", long);
+        let mut reader = LineReader::new(input.as_bytes());
+        assert_eq!(reader.next_line(false).await.unwrap(), Some(Line::Line("PING a".to_string())));
+        assert_eq!(reader.next_line(false).await.unwrap(), Some(Line::TooLong));
+        assert_eq!(reader.next_line(false).await.unwrap(), Some(Line::Line("PING b".to_string())));
+        assert_eq!(reader.next_line(false).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn tags_get_their_own_budget() {
+        let tags = format!("@+draft/x={}", "y".repeat(1000));
+        let input = format!("{} PING a\r\n", tags);
+        let mut reader = LineReader::new(input.as_bytes());
+        assert_eq!(reader.next_line(true).await.unwrap(), Some(Line::Line(format!("{} PING a", tags))));
+        let mut reader = LineReader::new(input.as_bytes());
+        assert_eq!(reader.next_line(false).await.unwrap(), Some(Line::TooLong));
+    }
+
+    #[tokio::test]
+    async fn line_endings() {
+        let input = "PING a\nPING b\r\nPING c\rPING d";
+        let mut reader = LineReader::new(input.as_bytes());
+        let mut lines = Vec::new();
+        while let Some(line) = reader.next_line(false).await.unwrap() {
+            lines.push(line);
+        }
+        let expected: Vec<Line> = ["PING a", "PING b", "PING c"].iter().map(|line| Line::Line(line.to_string())).collect();
+        assert_eq!(lines, expected);
+    }
+
+    #[tokio::test]
+    async fn nul_drops_the_line() {
+        let input = "PRIVMSG #chan :a\0b\r\nPING a\r\n";
+        let mut reader = LineReader::new(input.as_bytes());
+        assert_eq!(reader.next_line(false).await.unwrap(), Some(Line::Forbidden));
+        assert_eq!(reader.next_line(false).await.unwrap(), Some(Line::Line("PING a".to_string())));
+    }
+
+    #[tokio::test]
+    async fn bad_utf8_keeps_the_connection() {
+        let input: &[u8] = b"PRIVMSG #chan :caf\xe9\r\nPING a\r\n";
+        let mut reader = LineReader::new(input);
+        assert_eq!(reader.next_line(false).await.unwrap(), Some(Line::Line("PRIVMSG #chan :caf\u{fffd}".to_string())));
+        assert_eq!(reader.next_line(false).await.unwrap(), Some(Line::Line("PING a".to_string())));
+    }
+}
@@ -0,0 +1,210 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* the [logging] file, handed to tracing as a writer. every event is
+ * formatted into one buffer and written in one go, so checking whether
+ * it's time to rotate before each write never splits a line */
+use crate::config::{LogRotate, LoggingConfig};
+use chrono::{NaiveDate, Utc};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error as ioError, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::fmt;
+use tracing_subscriber::field::RecordFields;
+use tracing_subscriber::fmt::format::{DefaultFields, Writer};
+use tracing_subscriber::fmt::{FormatFields, MakeWriter};
+
+pub struct LogFile {
+    inner: Mutex<Inner>,
+}
+
+pub struct LogWriter<'a>(MutexGuard<'a, Inner>);
+
+struct Inner {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /* the day the open file's lines were written on */
+    date: NaiveDate,
+    rotate: LogRotate,
+    max_size: u64,
+    keep: usize,
+}
+
+impl LogFile {
+    pub fn open(path: &str, config: &LoggingConfig) -> Result<Self, ioError> {
+        let path = PathBuf::from(path);
+        let file = open_append(&path)?;
+        let meta = file.metadata()?;
+        /* a file left over from yesterday gets rotated on the first write */
+        let date = match meta.modified() {
+            Ok(time) => chrono::DateTime::<Utc>::from(time).naive_utc().date(),
+            Err(_) => Utc::today().naive_utc(),
+        };
+        Ok(LogFile {
+            inner: Mutex::new(Inner {
+                path,
+                file,
+                size: meta.len(),
+                date,
+                rotate: config.rotate,
+                max_size: config.max_size_kb.saturating_mul(1024),
+                keep: config.keep,
+            }),
+        })
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogFile {
+    type Writer = LogWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        /* a panic mid-write leaves nothing worse than a short line */
+        LogWriter(self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+}
+
+impl Write for LogWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, ioError> {
+        let inner = &mut *self.0;
+        if inner.due(buf.len()) {
+            /* nowhere to log a failed rotation, carry on with the old file */
+            let _res = inner.rotate();
+        }
+        let len = inner.file.write(buf)?;
+        inner.size += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<(), ioError> {
+        self.0.file.flush()
+    }
+}
+
+impl Inner {
+    fn due(&self, len: usize) -> bool {
+        match self.rotate {
+            LogRotate::Never => false,
+            LogRotate::Size => self.size > 0 && self.size + len as u64 > self.max_size,
+            LogRotate::Daily => Utc::today().naive_utc() != self.date,
+        }
+    }
+
+    fn rotate(&mut self) -> Result<(), ioError> {
+        match self.rotate {
+            LogRotate::Never => return Ok(()),
+            /* .1 is always the newest, everything shuffles up one and
+             * whatever would become .keep+1 falls off the end */
+            LogRotate::Size => {
+                for n in (1..=self.keep).rev() {
+                    let from = if n == 1 { self.path.clone() } else { numbered(&self.path, n - 1) };
+                    if from.exists() {
+                        fs::rename(from, numbered(&self.path, n))?;
+                    }
+                }
+            },
+            LogRotate::Daily => {
+                fs::rename(&self.path, suffixed(&self.path, &self.date.to_string()))?;
+                prune_dated(&self.path, self.keep)?;
+            },
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        self.date = Utc::today().naive_utc();
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<File, ioError> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    suffixed(path, &n.to_string())
+}
+
+/* dates sort the same as strings, so the oldest files come first */
+fn prune_dated(path: &Path, keep: usize) -> Result<(), ioError> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => format!("{}.", name),
+        None => return Ok(()),
+    };
+    let mut old: Vec<String> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| match name.strip_prefix(&prefix) {
+            Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok(),
+            None => false,
+        })
+        .collect();
+    old.sort();
+    let excess = old.len().saturating_sub(keep);
+    for name in old.iter().take(excess) {
+        fs::remove_file(dir.join(name))?;
+    }
+    Ok(())
+}
+
+/* span fields are formatted once per field formatter type and shared by
+ * every layer using it, so the file layer needs a type of its own or it
+ * ends up with the stderr layer's colours (and each field twice) */
+#[derive(Default)]
+pub struct PlainFields(DefaultFields);
+
+impl<'w> FormatFields<'w> for PlainFields {
+    fn format_fields<R: RecordFields>(&self, writer: Writer<'w>, fields: R) -> fmt::Result {
+        self.0.format_fields(writer, fields)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size_rotation() {
+        let dir = std::env::temp_dir().join(format!("rusty-ircd-logfile-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.log");
+        let config = LoggingConfig {
+            rotate: LogRotate::Size,
+            max_size_kb: 1,
+            keep: 2,
+            ..LoggingConfig::default()
+        };
+        let log = LogFile::open(path.to_str().unwrap(), &config).unwrap();
+        let line = [b'x'; 600];
+        for _ in 0..4 {
+            log.make_writer().write_all(&line).unwrap();
+        }
+        assert_eq!(fs::metadata(&path).unwrap().len(), 600);
+        assert_eq!(fs::metadata(numbered(&path, 1)).unwrap().len(), 600);
+        assert_eq!(fs::metadata(numbered(&path, 2)).unwrap().len(), 600);
+        assert!(!numbered(&path, 3).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
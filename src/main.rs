@@ -14,126 +14,220 @@
 *  You should have received a copy of the GNU Lesser General Public License
 *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
-extern crate dns_lookup;
-extern crate log;
-extern crate tokio;
-extern crate tokio_native_tls;
-pub mod irc;
-pub mod client;
-pub mod io;
-pub mod parser;
-use crate::client::{run_client_handler, run_write_task, Host, GenError};
-use crate::io::{ReadHalfWrap, WriteHalfWrap};
-use crate::irc::Core;
-use dns_lookup::lookup_addr;
-use std::fs::File;
+use rusty_ircd::config::{Config, ConfigError, LogFormat, LoggingConfig, RuntimeConfig, DEFAULT_CONFIG_PATH};
+use rusty_ircd::daemon;
+use rusty_ircd::io::{Acceptor, TlsAccept};
+use rusty_ircd::logfile::{LogFile, PlainFields};
+use rusty_ircd::password;
+use rusty_ircd::upgrade::UpgradeState;
+use rusty_ircd::Server;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::process;
 use std::io::Error as ioError;
-use std::io::Read;
-use std::net::IpAddr;
-use std::sync::Arc;
-use tokio::io::split;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc;
-use tokio::task;
-use tokio_native_tls::TlsAcceptor;
-use tokio_native_tls::native_tls::Identity;
-use tokio_native_tls::native_tls::TlsAcceptor as NativeTlsAcc;
-
-pub const USER_MODES: &str = "";
-pub const CHAN_MODES: &str = "+o";
-
-fn get_host(ip_addr: IpAddr) -> Result<Host, ioError> {
-    match lookup_addr(&ip_addr) {
-        Ok(h) => Ok(Host::Hostname(h)),
-        Err(_) => Ok(Host::HostAddr(ip_addr)),
+use std::io::ErrorKind;
+use std::io::BufRead;
+use std::thread;
+use tokio::runtime::{Builder, Runtime};
+use tokio::signal;
+use tracing::{info, warn};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, EnvFilter};
+
+/* stderr always, plus the log file if there is one */
+fn init_logging(config: &LoggingConfig, file: Option<LogFile>) {
+    let registry = tracing_subscriber::registry().with(EnvFilter::from_default_env());
+    match config.format {
+        LogFormat::Text => registry
+            .with(fmt::layer().with_writer(std::io::stderr))
+            .with(file.map(|file| fmt::layer().fmt_fields(PlainFields::default()).with_ansi(false).with_writer(file)))
+            .init(),
+        LogFormat::Json => registry
+            .with(fmt::layer().json().with_writer(std::io::stderr))
+            .with(file.map(|file| fmt::layer().json().with_writer(file)))
+            .init(),
     }
 }
 
-async fn plaintext_socket(sock: TcpStream, irc: Arc<Core>) -> Result<(), GenError> {
-    let id = irc.assign_id();
-    /* Two ? required, one expects a potential JoinError, the second ?
-     * decomposes to give Host or an ioError - may need some additional error
-     * composition to deal with the possible JoinError... */
-    let ip_address = sock.peer_addr()?.ip();
-    let host = task::spawn_blocking(move || get_host(ip_address)).await??;
-    let (tx, rx) = mpsc::channel(32);
-    let (read, write) = split(sock);
-    tokio::spawn(run_write_task(WriteHalfWrap::ClearText(write), rx));
-    tokio::spawn(run_client_handler(
-        id,
-        host,
-        irc,
-        tx,
-        ReadHalfWrap::ClearText(read),
-    ));
-    Ok(())
+fn build_runtime(config: &RuntimeConfig) -> Result<Runtime, ioError> {
+    let mut builder = Builder::new_multi_thread();
+    builder.enable_all();
+    let workers = match config.worker_threads {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    builder.worker_threads(workers);
+    if config.max_blocking_threads > 0 {
+        builder.max_blocking_threads(config.max_blocking_threads);
+    }
+    builder.build()
 }
 
-async fn plain_listen(server: TcpListener, irc_core: Arc<Core>) -> Result<(), GenError> {
-    loop {
-        let (socket, _) = server.accept().await?;
-        tokio::spawn(plaintext_socket(socket, Arc::clone(&irc_core)));
+/* no #[tokio::main], the runtime can't start until we've
+ * forked into the background if we're going to */
+pub fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /* `rusty-ircd --mkpasswd [password]` prints an argon2id hash for use in
+     * an [[oper]] block, reading the password from stdin if not given */
+    if env::args().nth(1).as_deref() == Some("--mkpasswd") {
+        let password = match env::args().nth(2) {
+            Some(password) => password,
+            None => {
+                let mut line = String::new();
+                std::io::stdin().lock().read_line(&mut line)?;
+                line.trim_end_matches(&['\r', '\n'][..]).to_string()
+            },
+        };
+        println!("{}", password::hash(&password)?);
+        return Ok(());
+    }
+
+    /* `rusty-ircd --check-config [path]` loads everything the server would
+     * at startup without listening, for checking a config before deploying it */
+    if env::args().nth(1).as_deref() == Some("--check-config") {
+        let path = env::args().nth(2).unwrap_or_else(|| DEFAULT_CONFIG_PATH.to_string());
+        process::exit(if check_config(&path) { 0 } else { 1 });
+    }
+
+    /* logging can't start until we know which format the config wants */
+    let config_path = env::args().nth(1).unwrap_or_else(|| DEFAULT_CONFIG_PATH.to_string());
+    let (config, found) = match Config::load(&config_path) {
+        Ok(config) => (config, true),
+        Err(ConfigError::Io(err)) if err.kind() == ErrorKind::NotFound => (Config::default(), false),
+        Err(err) => return Err(err.into()),
+    };
+    /* opened before detaching so a bad path is reported on the terminal */
+    let log_file = match &config.logging.file {
+        Some(path) => Some(LogFile::open(path, &config.logging)?),
+        None => None,
+    };
+    /* after a hot upgrade we're already wherever we were before */
+    let upgrade = UpgradeState::from_env();
+    if config.daemon.background && upgrade.is_none() {
+        daemon::detach(&config.daemon)?;
     }
+    init_logging(&config.logging, log_file);
+    if !found {
+        warn!("no config file at {}, using defaults", config_path);
+    }
+    let pidfile = config.daemon.pidfile.clone();
+    if let Some(path) = &pidfile {
+        daemon::write_pidfile(path)?;
+    }
+    let res = build_runtime(&config.runtime)?.block_on(run(config, upgrade));
+    if let Some(path) = &pidfile {
+        daemon::remove_pidfile(path);
+    }
+    res
 }
 
-async fn process_socket(sock: TcpStream, irc: Arc<Core>, acceptor: Arc<TlsAcceptor>) -> Result<(), GenError> {
-    let id = irc.assign_id();
-    /* Two ? required, one expects a potential JoinError, the second ?
-     * decomposes to give Host or an ioError - may need some additional error
-     * composition to deal with the possible JoinError... */
-    let ip_address = sock.peer_addr()?.ip();
-    let host = task::spawn_blocking(move || get_host(ip_address)).await??;
-    let (tx, rx) = mpsc::channel(32);
-    let tls_stream = acceptor.accept(sock).await?;
-    let (read, write) = split(tls_stream);
-    tokio::spawn(run_write_task(WriteHalfWrap::Encrypted(write), rx));
-    tokio::spawn(run_client_handler(
-        id,
-        host,
-        irc,
-        tx,
-        ReadHalfWrap::Encrypted(read),
-    ));
-    Ok(())
+async fn run(config: Config, upgrade: Option<UpgradeState>) -> Result<(), Box<dyn std::error::Error>> {
+    /* looked up now, once the binary has been replaced on disk this
+     * would give the old one's path with " (deleted)" tacked on */
+    #[cfg(unix)]
+    let exe = env::current_exe();
+    let mut builder = Server::builder().config(config);
+    if let Some(state) = upgrade {
+        info!("carrying on from before the upgrade with {} listener(s)", state.listeners.len());
+        builder = builder.upgrade(state);
+    }
+    let handle = builder.start().await?;
+
+    loop {
+        match next_signal().await? {
+            Signal::Stop(reason) => {
+                info!("received {}, shutting down", reason);
+                handle.shutdown(&format!("Received {}", reason)).await;
+                return Ok(());
+            },
+            /* the exec only comes back if it failed, by which time
+             * everyone's gone so there's nothing for it but to exit */
+            #[cfg(unix)]
+            Signal::Upgrade => {
+                let exe = match &exe {
+                    Ok(exe) => exe.clone(),
+                    Err(err) => {
+                        warn!("received SIGUSR2 but can't upgrade, no path to our binary: {}", err);
+                        continue;
+                    }
+                };
+                let state = handle.save_for_upgrade()?;
+                info!("received SIGUSR2, upgrading to {}", exe.display());
+                handle.shutdown("Server upgrading").await;
+                return Err(state.exec(&exe).into());
+            },
+        }
+    }
 }
 
-#[tokio::main]
-pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let version = env!("CARGO_PKG_NAME").to_string() + ", version: " + env!("CARGO_PKG_VERSION");
-    env_logger::init();
-
-    // is this even necessary?
-    let server_host = if let Ok(ip) = "127.0.1.1".parse::<IpAddr>() {
-        if let Host::Hostname(h) = task::spawn_blocking(move ||get_host(ip)).await?? {
-            h
-        } else {
-            "localhost".to_string()
+/* prints a line per check, true if there was nothing wrong */
+fn check_config(path: &str) -> bool {
+    let config = match Config::load(path) {
+        Ok(config) => config,
+        Err(err) => {
+            println!("error: {}: {}", path, err);
+            return false;
         }
-    } else {
-        "localhost".to_string()
     };
-    let irc_core = Core::new(server_host, version);
-
-    // encryption key stuff
-    let mut file = File::open("identity.pfx").unwrap();
-    let mut identity = vec![];
-    file.read_to_end(&mut identity).unwrap();
-    let identity = Identity::from_pkcs12(&identity, "password").expect("failed to get identity, check password?");
-
-    // start raw socket listeners
-    let plain_listener = TcpListener::bind("127.0.1.1:6667").await?;
-    let listener = TcpListener::bind("127.0.1.1:6697").await?;
-    
-    // spawn routine to deal with plaintext clients
-    tokio::spawn(plain_listen(plain_listener, Arc::clone(&irc_core)));
-
-    // first create the non-async TlsAcceptor
-    let acceptor = NativeTlsAcc::new(identity).unwrap();
-
-    // this creates the tokio wrapper
-    let acceptor = Arc::new(TlsAcceptor::from(acceptor));
-    loop {
-        let (socket, _) = listener.accept().await?;
-        tokio::spawn(process_socket(socket, Arc::clone(&irc_core), Arc::clone(&acceptor)));
+    println!("ok: {} parsed", path);
+    let mut good = true;
+    let mut problem = |text: String| {
+        println!("error: {}", text);
+        good = false;
+    };
+
+    let listeners = config.listeners();
+    let mut bound = HashSet::new();
+    for block in listeners.iter() {
+        if !bound.insert((block.address, block.port)) {
+            problem(format!("more than one listener on {} port {}", block.address, block.port));
+        }
+    }
+    println!("ok: {} listener(s)", listeners.len());
+    if listeners.iter().any(|block| block.tls) {
+        let client_certs = listeners.iter().any(|block| block.tls && block.client_certs);
+        match Acceptor::from_config(&config.tls, client_certs) {
+            Ok(_acceptor) => println!("ok: TLS certificate loaded"),
+            Err(err) => problem(format!("TLS certificate: {}", err)),
+        }
+    }
+
+    let mut opers = HashSet::new();
+    for block in config.oper.iter() {
+        if !opers.insert(&block.name) {
+            problem(format!("more than one oper block named {}", block.name));
+        }
+    }
+    println!("ok: {} oper block(s)", config.oper.len());
+
+    for script in config.lua.scripts.iter() {
+        if let Err(err) = fs::metadata(script) {
+            problem(format!("lua script {}: {}", script, err));
+        }
     }
+    good
+}
+
+enum Signal {
+    /* the name of whichever signal asked us to stop */
+    Stop(&'static str),
+    /* SIGUSR2, see upgrade.rs */
+    #[cfg(unix)]
+    Upgrade,
+}
+
+#[cfg(unix)]
+async fn next_signal() -> Result<Signal, ioError> {
+    let mut term = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+    let mut usr2 = signal::unix::signal(signal::unix::SignalKind::user_defined2())?;
+    tokio::select! {
+        res = signal::ctrl_c() => res.map(|()| Signal::Stop("SIGINT")),
+        _ = term.recv() => Ok(Signal::Stop("SIGTERM")),
+        _ = usr2.recv() => Ok(Signal::Upgrade),
+    }
+}
+
+#[cfg(not(unix))]
+async fn next_signal() -> Result<Signal, ioError> {
+    signal::ctrl_c().await.map(|()| Signal::Stop("SIGINT"))
 }
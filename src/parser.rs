@@ -64,7 +64,14 @@ pub enum MsgPrefix {
     Host(HostType),
 }
 
+/* the strings are owned, not borrowed from the line buffer: handlers take
+ * params apart with remove(0), aliases build new ones and modules get the
+ * message handed to them, all of which want Strings. a zero-copy
+ * ParsedMsg<'a> would mean reworking every one of those, so it waits */
 pub struct ParsedMsg {
+    /* IRCv3 message tags, unescaped, in the order they came in.
+     * a tag with no value gets an empty one */
+    pub tags: Vec<(String, String)>,
     pub opt_prefix: Option<MsgPrefix>,
     pub command: String,
     // NB: our parser first makes a Vec<&str>, where things will still point to stuff
@@ -84,54 +91,127 @@ pub fn parse_message(message: &str) -> Result<ParsedMsg, ParseError> {
     if line.is_empty() {
         return Err(ParseError::EmptyMessage);
     }
-    let opt_prefix = if &message[..1] == ":" {
+    let tags = match line.strip_prefix('@') {
+        Some(rest) => {
+            let vec: Vec<&str> = rest.splitn(2, ' ').collect();
+            if vec.len() < 2 {
+                return Err(ParseError::NoCommand);
+            }
+            line = vec[1].trim_start_matches(' ');
+            parse_tags(vec[0])
+        },
+        None => Vec::new(),
+    };
+    if line.is_empty() {
+        return Err(ParseError::NoCommand);
+    }
+    let opt_prefix = if &line[..1] == ":" {
         // try for prefix
         let vec: Vec<&str> = line.splitn(2, ' ').collect();
         if vec.len() < 2 {
             return Err(ParseError::NoCommand);
         }
         line = vec[1];
-        Some(parse_prefix(&vec[0])?)
+        Some(parse_prefix(vec[0])?)
     } else {
         None
     };
 
+    /* params are split on runs of spaces. one starting with ':' is the
+     * trailing param and takes the rest of the line as it is, and so does
+     * the 15th, whether it has a ':' or not */
     let mut params: Vec<String> = Vec::new();
-    let mut n_args = 0;
-    loop {
-        let vec: Vec<&str> = line.splitn(2, ' ').collect();
-        n_args += 1;
-        params.push(vec[0].to_string());
-        if vec.len() < 2 {
-            break;
+    let mut rest = line.trim_start_matches(' ');
+    while !rest.is_empty() {
+        if !params.is_empty() {
+            if let Some(trailing) = rest.strip_prefix(':') {
+                params.push(trailing.to_string());
+                break;
+            }
+            /* params still has the command in it */
+            if params.len() == rfc::MAX_MSG_PARAMS {
+                params.push(rest.to_string());
+                break;
+            }
         }
-
-        line = vec[1];
-        // " :" means squash/collect all remaining args,
-        // which is also supposed to happen if rfc::MaxParams
-        // is reached
-        if line.is_empty() {
-            break;
-        } else if &line[..1] == ":" {
-            line = &line[1..line.len()];
-            params.push(line.to_string());
-            break;
-        } else if n_args >= 16 {
-            params.push(line.to_string());
-            break;
+        match rest.split_once(' ') {
+            Some((word, remainder)) => {
+                params.push(word.to_string());
+                rest = remainder.trim_start_matches(' ');
+            },
+            None => {
+                params.push(rest.to_string());
+                break;
+            },
         }
     }
-    /* should be safe - above code ensure non-zero length of params */
+    if params.is_empty() {
+        return Err(ParseError::NoCommand);
+    }
     let command = params.remove(0);
+    /* params and tag values can end up in front of other clients, so
+     * nothing in them gets to mess with their terminals or our framing */
+    let params = params.iter().map(|param| rfc::strip_controls(param)).collect();
+    let tags = tags.into_iter().map(|(key, value)| (key, rfc::strip_controls(&value))).collect();
 
     // return the stuff
     Ok(ParsedMsg {
+        tags,
         opt_prefix,
         command,
         opt_params: params,
     })
 }
 
+// <key>[=<value>] separated by semicolons, see
+// https://ircv3.net/specs/extensions/message-tags
+fn parse_tags(tags: &str) -> Vec<(String, String)> {
+    tags.split(';')
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| match tag.split_once('=') {
+            Some((key, value)) => (key.to_string(), unescape_tag(value)),
+            None => (tag.to_string(), String::new()),
+        })
+        .collect()
+}
+
+// \: is a semicolon, \s a space, \r and \n CR and LF, any other
+// escaped character is just itself and a lone trailing \ goes
+pub fn unescape_tag(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => out.push(';'),
+            Some('s') => out.push(' '),
+            Some('r') => out.push('\r'),
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => (),
+        }
+    }
+    out
+}
+
+pub fn escape_tag(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ';' => out.push_str("\\:"),
+            ' ' => out.push_str("\\s"),
+            '\\' => out.push_str("\\\\"),
+            '\r' => out.push_str("\\r"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
 // parse the prefix part of an IRC message
 // with preceding colon and delimiting space stripped off
 fn parse_prefix(msg: &str) -> Result<MsgPrefix, ParseError> {
@@ -198,3 +278,70 @@ fn parse_host(host_string: &str) -> Result<HostType, ParseError> {
         Err(ParseError::InvalidHost(host))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trailing_and_folded_params() {
+        let msg = parse_message("PRIVMSG #chan :hello  there :)").ok().unwrap();
+        assert_eq!(msg.command, "PRIVMSG");
+        assert_eq!(msg.opt_params, vec!["#chan", "hello  there :)"]);
+
+        let msg = parse_message("TOPIC #chan :").ok().unwrap();
+        assert_eq!(msg.opt_params, vec!["#chan", ""]);
+
+        /* past 15 params the rest gets folded into the last one */
+        let words = (1..=20).map(|n| n.to_string()).collect::<Vec<String>>();
+        let msg = parse_message(&format!("CMD {}", words.join(" "))).ok().unwrap();
+        assert_eq!(msg.opt_params.len(), rfc::MAX_MSG_PARAMS);
+        assert_eq!(msg.opt_params[rfc::MAX_MSG_PARAMS - 1], "15 16 17 18 19 20");
+    }
+
+    #[test]
+    fn param_edge_cases() {
+        let msg = parse_message("PRIVMSG   #chan   :  spaced  out ").ok().unwrap();
+        assert_eq!(msg.opt_params, vec!["#chan", "  spaced  out "]);
+
+        let msg = parse_message("MODE #chan +o nick   ").ok().unwrap();
+        assert_eq!(msg.opt_params, vec!["#chan", "+o", "nick"]);
+
+        let msg = parse_message("PING").ok().unwrap();
+        assert!(msg.opt_params.is_empty());
+
+        let msg = parse_message("PRIVMSG #chan ::)").ok().unwrap();
+        assert_eq!(msg.opt_params, vec!["#chan", ":)"]);
+
+        /* the 15th takes the rest, colon or not */
+        let words = (1..=14).map(|n| n.to_string()).collect::<Vec<String>>().join(" ");
+        let msg = parse_message(&format!("CMD {} :last one", words)).ok().unwrap();
+        assert_eq!(msg.opt_params[rfc::MAX_MSG_PARAMS - 1], "last one");
+        let msg = parse_message(&format!("CMD {} last  :one", words)).ok().unwrap();
+        assert_eq!(msg.opt_params[rfc::MAX_MSG_PARAMS - 1], "last  :one");
+
+        assert!(parse_message(":nick!user@host").is_err());
+        assert!(parse_message(":nick!user@host   ").is_err());
+    }
+
+    #[test]
+    fn message_tags() {
+        let msg = parse_message("@+typing=active;+draft/reply=a\\sb\\:c;flag TAGMSG #chan").ok().unwrap();
+        assert_eq!(msg.command, "TAGMSG");
+        assert_eq!(msg.opt_params, vec!["#chan"]);
+        assert_eq!(msg.tags, vec![
+            ("+typing".to_string(), "active".to_string()),
+            ("+draft/reply".to_string(), "a b;c".to_string()),
+            ("flag".to_string(), String::new()),
+        ]);
+        assert_eq!(escape_tag("a b;c\\"), "a\\sb\\:c\\\\");
+        assert!(parse_message("@+typing=active").is_err());
+    }
+
+    #[test]
+    fn control_characters() {
+        let msg = parse_message("@+draft/reply=a\\rb PRIVMSG #chan :\x02bold\x02 \x1b[2Jbell\x07 \x01ACTION waves\x01").ok().unwrap();
+        assert_eq!(msg.opt_params[1], "\x02bold\x02 [2Jbell \x01ACTION waves\x01");
+        assert_eq!(msg.tags[0].1, "ab");
+    }
+}
@@ -0,0 +1,66 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* argon2id password hashing for accounts and oper blocks.
+ * hashing and verifying are deliberately slow, so callers on the
+ * async side should wrap these in spawn_blocking */
+extern crate argon2;
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::password_hash::rand_core::OsRng;
+
+pub use argon2::password_hash::Error as HashError;
+
+const ALGORITHM: &str = "argon2id";
+
+/* hash with a fresh random salt, giving a PHC string like $argon2id$v=19$... */
+pub fn hash(password: &str) -> Result<String, HashError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string())
+}
+
+/* anything that isn't a parseable argon2id hash never matches */
+pub fn verify(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) if parsed.algorithm.as_str() == ALGORITHM => {
+            Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok()
+        },
+        _ => false,
+    }
+}
+
+pub fn is_hash(hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|parsed| parsed.algorithm.as_str() == ALGORITHM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_and_verify() {
+        let hashed = hash("hunter2").unwrap();
+        assert!(is_hash(&hashed));
+        assert!(verify("hunter2", &hashed));
+        assert!(!verify("hunter3", &hashed));
+    }
+
+    #[test]
+    fn plaintext_is_not_a_hash() {
+        assert!(!is_hash("changeme"));
+        assert!(!verify("changeme", "changeme"));
+    }
+}
@@ -0,0 +1,466 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* the daemon as a library: Server::builder() takes the config,
+ * start() binds the listeners and hands back a ServerHandle for
+ * finding out where we ended up listening and for shutting down.
+ * the binary in main.rs is just this plus logging and signals */
+use crate::client::{run_client_handler, run_write_task, send_queue, Host, GenError};
+use crate::config::{Config, LinkBlock, ListenBlock};
+use crate::io::{self, accept_websocket, Acceptor, ReadHalfWrap, TlsAccept, WriteHalfWrap};
+use crate::irc::Core;
+use crate::irc::accounts::AccountStore;
+use crate::irc::chanlog::ChanLog;
+use crate::irc::link;
+use crate::irc::module::Module;
+use crate::throttle::{ConnLimit, ConnSlot, PendingSlot};
+use crate::upgrade::UpgradeState;
+use std::collections::hash_map::{Entry, HashMap};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{split, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{debug, field, info_span, warn, Instrument, Span};
+
+pub struct Server;
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ServerBuilder {
+    config: Config,
+    modules: Vec<Arc<dyn Module>>,
+    upgrade: Option<UpgradeState>,
+}
+
+impl ServerBuilder {
+    /* replaces everything set so far */
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.config.server.name = Some(name.to_string());
+        self
+    }
+
+    /* adds to the [[listen]] blocks, port 0 picks any free port which
+     * local_addr() will tell you about afterwards */
+    pub fn listen(mut self, block: ListenBlock) -> Self {
+        self.config.listen.push(block);
+        self
+    }
+
+    /* modules get their hooks called in the order they're added */
+    pub fn module<M: Module + 'static>(mut self, module: M) -> Self {
+        self.modules.push(Arc::new(module));
+        self
+    }
+
+    /* we're the new process of a hot upgrade, see upgrade.rs */
+    pub fn upgrade(mut self, state: UpgradeState) -> Self {
+        self.upgrade = Some(state);
+        self
+    }
+
+    /* binds every listener, then leaves them accepting in the background */
+    pub async fn start(self) -> Result<ServerHandle, GenError> {
+        let config = self.config;
+        let version = env!("CARGO_PKG_NAME").to_string() + ", version: " + env!("CARGO_PKG_VERSION");
+        let accounts = AccountStore::open(&config.services.database)?;
+        let server_host = match config.server.name.clone() {
+            Some(name) => name,
+            None => dns_lookup::get_hostname().unwrap_or_else(|_| "localhost".to_string()),
+        };
+        let listen_blocks = config.listeners();
+        let irc_core = Core::new(server_host, version, config, accounts);
+        irc_core.restore_channels().await?;
+        let mut upgrade = self.upgrade;
+        if let Some(state) = &upgrade {
+            state.restore(&irc_core);
+        }
+        for module in self.modules {
+            irc_core.add_module(module);
+        }
+        if let Some(chanlog) = ChanLog::start(&irc_core.get_config().chanlog) {
+            irc_core.add_module(Arc::new(chanlog));
+        }
+        #[cfg(feature = "lua")]
+        if !irc_core.get_config().lua.scripts.is_empty() {
+            let scripts = crate::irc::lua::LuaModule::load(&irc_core.get_config().lua.scripts)?;
+            irc_core.add_module(Arc::new(scripts));
+        }
+
+        // encryption key stuff, which files depends on the TLS backend.
+        // listeners asking for client certificates need an acceptor of their own
+        let mut acceptors = HashMap::new();
+        for block in listen_blocks.iter().filter(|block| block.tls) {
+            if let Entry::Vacant(entry) = acceptors.entry(block.client_certs) {
+                let acceptor = Acceptor::from_config(&irc_core.get_config().tls, block.client_certs)?;
+                entry.insert(Arc::new(acceptor));
+            }
+        }
+
+        /* every listener and connection holds a drain sender, once they've
+         * all been dropped recv() gives None and we know it's safe to exit */
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (drain_tx, drain_rx) = mpsc::channel::<()>(1);
+
+        // bind everything first so a bad address stops us before anyone connects
+        let mut servers = Vec::new();
+        let mut local_addrs = Vec::new();
+        for block in listen_blocks {
+            let inherited = upgrade.as_mut().and_then(|state| state.take_listener(block.address, block.port));
+            let server = match inherited {
+                Some(server) => {
+                    server.set_nonblocking(true)?;
+                    TcpListener::from_std(server)?
+                },
+                None => TcpListener::bind((block.address, block.port)).await?,
+            };
+            let addr = server.local_addr()?;
+            debug!("listening on {}{}{}", addr,
+                   if block.tls { " (tls)" } else { "" }, if block.websocket { " (websocket)" } else { "" });
+            local_addrs.push(addr);
+            servers.push((Arc::new(server), block));
+        }
+        if let Some(state) = &mut upgrade {
+            state.close_unused();
+        }
+        for (server, block) in servers.iter() {
+            let block_acceptor = if block.tls { acceptors.get(&block.client_certs).cloned() } else { None };
+            tokio::spawn(listen(Arc::clone(server), Arc::clone(&irc_core), block.clone(), block_acceptor, shutdown_rx.clone(), drain_tx.clone()));
+        }
+        if let Some(block) = irc_core.get_config().link.clone().filter(|block| block.connect.is_some()) {
+            tokio::spawn(supervise_link(Arc::clone(&irc_core), block, shutdown_rx.clone(), drain_tx.clone()));
+        }
+        let listeners = servers.into_iter().map(|(server, block)| (block.address, block.port, server)).collect();
+
+        Ok(ServerHandle { irc: irc_core, local_addrs, listeners, shutdown_tx, drain_rx })
+    }
+}
+
+pub struct ServerHandle {
+    irc: Arc<Core>,
+    local_addrs: Vec<SocketAddr>,
+    /* kept here as well as in the listen tasks for handing on in an upgrade */
+    listeners: Vec<(IpAddr, u16, Arc<TcpListener>)>,
+    shutdown_tx: watch::Sender<bool>,
+    drain_rx: mpsc::Receiver<()>,
+}
+
+impl ServerHandle {
+    /* the first listener's address */
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addrs[0]
+    }
+
+    /* one per listener, in config order */
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    pub fn core(&self) -> &Arc<Core> {
+        &self.irc
+    }
+
+    /* everything the next process needs for a hot upgrade, call it before
+     * shutdown() so the listening sockets are still open */
+    #[cfg(unix)]
+    pub fn save_for_upgrade(&self) -> Result<UpgradeState, std::io::Error> {
+        use std::os::unix::io::AsRawFd;
+        let mut state = UpgradeState::save(&self.irc);
+        for (address, port, server) in self.listeners.iter() {
+            state.add_listener(*address, *port, server.as_raw_fd())?;
+        }
+        Ok(state)
+    }
+
+    /* stops accepting, sends everyone off with `reason` and waits up to
+     * shutdown_grace seconds for that to reach them */
+    pub async fn shutdown(mut self, reason: &str) {
+        let _ = self.shutdown_tx.send(true);
+        self.irc.shutdown(reason).await;
+        let grace = Duration::from_secs(self.irc.get_config().limits.shutdown_grace);
+        if time::timeout(grace, self.drain_rx.recv()).await.is_err() {
+            warn!("gave up waiting for connections to close after {}s", grace.as_secs());
+        }
+    }
+}
+
+/* every log line from a connection's tasks carries this span, so one
+ * client's activity can be picked out of the noise. nick gets filled
+ * in on registration and updated on nick changes */
+fn connection_span(id: u64, ip_address: IpAddr) -> Span {
+    info_span!("client", id, ip = %ip_address, host = field::Empty, nick = field::Empty)
+}
+
+/* IPv4 clients on a dual-stack :: listener show up as ::ffff:a.b.c.d,
+ * turn those back into plain IPv4 so throttling, DNSBL and hostnames
+ * all see the same address they would on an IPv4 listener */
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/* how long each of the TLS and WebSocket handshakes (and the reverse
+ * lookup, though that gives up sooner by itself) gets before we hang up */
+const HANDSHAKE_TIMEOUT: u64 = 10;
+
+/* one step of getting a new connection going, HANDSHAKE_TIMEOUT at most */
+async fn handshake<T, E>(step: impl Future<Output = Result<T, E>>) -> Result<T, GenError>
+where
+    GenError: From<E>,
+{
+    match time::timeout(Duration::from_secs(HANDSHAKE_TIMEOUT), step).await {
+        Ok(res) => Ok(res?),
+        Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "handshake timed out").into()),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_socket(
+    sock: TcpStream,
+    ip_address: IpAddr,
+    irc: Arc<Core>,
+    listen: ListenBlock,
+    acceptor: Option<Arc<Acceptor>>,
+    drain: mpsc::Sender<()>,
+    slot: ConnSlot,
+    pending: Option<PendingSlot>,
+    accepted: time::Instant,
+) -> Result<(), GenError> {
+    let id = irc.assign_id();
+    let span = connection_span(id, ip_address);
+    /* never longer than [dns] timeout_ms, after that they just get the IP */
+    let host = if listen.privacy {
+        Host::Hostname(listen.cloak.clone())
+    } else if !listen.rdns || !irc.get_config().dns.rdns {
+        Host::HostAddr(ip_address)
+    } else {
+        let lookup = irc.get_resolver().reverse(ip_address);
+        match time::timeout(Duration::from_secs(HANDSHAKE_TIMEOUT), lookup).await {
+            Ok(Some(name)) => Host::Hostname(name),
+            _ => Host::HostAddr(ip_address),
+        }
+    };
+    span.record("host", field::display(&host));
+    let (tx, rx) = send_queue(irc.get_memory());
+    let mut certfp = None;
+    let (read, write) = match (acceptor, listen.websocket) {
+        (None, false) => {
+            let (read, write) = split(sock);
+            (ReadHalfWrap::ClearText(read), WriteHalfWrap::ClearText(write))
+        },
+        (Some(acceptor), false) => {
+            let stream = handshake(acceptor.accept(sock)).await?;
+            certfp = Acceptor::peer_cert(&stream).map(|der| io::fingerprint(&der));
+            let (read, write) = split(stream);
+            (ReadHalfWrap::Encrypted(read), WriteHalfWrap::Encrypted(write))
+        },
+        (None, true) => {
+            let (read, write) = handshake(accept_websocket(sock, &listen.origins)).await?;
+            (ReadHalfWrap::WebSocket(read), WriteHalfWrap::WebSocket(write))
+        },
+        (Some(acceptor), true) => {
+            let stream = handshake(acceptor.accept(sock)).await?;
+            certfp = Acceptor::peer_cert(&stream).map(|der| io::fingerprint(&der));
+            let (read, write) = handshake(accept_websocket(stream, &listen.origins)).await?;
+            (ReadHalfWrap::SecureWebSocket(read), WriteHalfWrap::SecureWebSocket(write))
+        },
+    };
+    /* the write task holds on to `drain` so shutdown can tell when
+     * everything has been flushed out, and to the listener's slot since
+     * it's the last thing to go */
+    tokio::spawn(async move {
+        let res = run_write_task(write, rx).await;
+        drop(slot);
+        drop(drain);
+        res
+    }.instrument(span.clone()));
+    tokio::spawn(run_client_handler(id, host, ip_address, listen.privacy, certfp, irc, tx, read, pending, accepted).instrument(span));
+    Ok(())
+}
+
+/* how long a dial out to services gets to connect */
+const LINK_CONNECT_TIMEOUT: u64 = 30;
+
+/* dials [link] connect, plaintext only, and hands the socket over to a
+ * client handler like any other connection once it's had our half of
+ * the handshake. services answer with their PASS and SERVER and
+ * link::accept() takes it from there */
+async fn dial_link(irc: &Arc<Core>, block: &LinkBlock, addr: &str, drain: mpsc::Sender<()>) -> Result<JoinHandle<()>, GenError> {
+    let connect = time::timeout(Duration::from_secs(LINK_CONNECT_TIMEOUT), TcpStream::connect(addr));
+    let mut sock = match connect.await {
+        Ok(sock) => sock?,
+        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out").into()),
+    };
+    let ip_address = canonical_ip(sock.peer_addr()?.ip());
+    let id = irc.assign_id();
+    irc.set_link_dialed(id);
+    for line in link::hello(irc, block) {
+        sock.write_all(format!("{}\r\n", line).as_bytes()).await?;
+    }
+    let span = connection_span(id, ip_address);
+    let (tx, rx) = send_queue(irc.get_memory());
+    let (read, write) = split(sock);
+    tokio::spawn(async move {
+        let res = run_write_task(WriteHalfWrap::ClearText(write), rx).await;
+        drop(drain);
+        res
+    }.instrument(span.clone()));
+    let handler = run_client_handler(id, Host::HostAddr(ip_address), ip_address, false, None, Arc::clone(irc), tx, ReadHalfWrap::ClearText(read), None, time::Instant::now());
+    Ok(tokio::spawn(handler.instrument(span)))
+}
+
+/* keeps the services link up when [link] connect is set. nothing gets
+ * dialed while there's a link, whichever end started it, and each
+ * attempt that fails or doesn't get as far as a link waits longer
+ * before the next, see link::backoff(). opers hear about every attempt */
+async fn supervise_link(irc: Arc<Core>, block: LinkBlock, mut shutdown: watch::Receiver<bool>, drain: mpsc::Sender<()>) {
+    let addr = block.connect.clone().unwrap_or_default();
+    let check = Duration::from_secs(block.reconnect_min.max(1));
+    let mut failures = 0;
+    loop {
+        let wait = if irc.get_link().is_some() {
+            failures = 0;
+            check
+        } else {
+            failures += 1;
+            irc.notice_opers(&format!("Connecting to {} at {} (attempt {})", block.name, addr, failures)).await;
+            match dial_link(&irc, &block, &addr, drain.clone()).await {
+                /* once it's up it's link::accept() that says so */
+                Ok(mut handler) => loop {
+                    tokio::select! {
+                        _ = &mut handler => break,
+                        _ = time::sleep(check) => {
+                            if irc.get_link().is_some() {
+                                failures = 0;
+                            }
+                        },
+                        _ = shutdown.changed() => return,
+                    }
+                },
+                Err(err) => {
+                    warn!("couldn't connect to {} at {}: {}", block.name, addr, err);
+                    irc.notice_opers(&format!("Couldn't connect to {}: {}", block.name, err)).await;
+                },
+            }
+            let jitter = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.subsec_nanos());
+            let wait = link::backoff(&block, failures, jitter);
+            irc.notice_opers(&format!("No link with {}, trying again in {} seconds", block.name, wait.as_secs())).await;
+            wait
+        };
+        tokio::select! {
+            _ = time::sleep(wait) => (),
+            _ = shutdown.changed() => return,
+        }
+    }
+}
+
+/* how long a listener backs off after accept() fails */
+const ACCEPT_RETRY_MS: u64 = 100;
+
+/* the acceptor is only passed in for tls listeners. runs until
+ * `shutdown` flips to true */
+/* turns a connection away, with an ERROR saying why if it's plaintext */
+fn refuse(mut socket: TcpStream, plaintext: bool, reason: &'static str) {
+    if plaintext {
+        tokio::spawn(async move {
+            socket.write_all(format!("ERROR :{}\r\n", reason).as_bytes()).await
+        });
+    }
+}
+
+async fn listen(
+    server: Arc<TcpListener>,
+    irc_core: Arc<Core>,
+    listen: ListenBlock,
+    acceptor: Option<Arc<Acceptor>>,
+    mut shutdown: watch::Receiver<bool>,
+    drain: mpsc::Sender<()>,
+) {
+    let limit = ConnLimit::new(listen.max_connections);
+    loop {
+        let accepted = tokio::select! {
+            accepted = server.accept() => accepted,
+            _ = shutdown.changed() => {
+                debug!("no longer listening on {}:{}", listen.address, listen.port);
+                return;
+            },
+        };
+        /* EMFILE, ECONNABORTED and the like come and go, so take a breather
+         * and carry on rather than stop listening for good */
+        let (socket, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("couldn't accept on {}:{}: {}", listen.address, listen.port, err);
+                time::sleep(Duration::from_millis(ACCEPT_RETRY_MS)).await;
+                continue;
+            }
+        };
+        let connected = time::Instant::now();
+        let ip_address = canonical_ip(addr.ip());
+        /* no handshake to get through on plaintext, so may as well say why */
+        let plaintext = acceptor.is_none() && !listen.websocket;
+        if !listen.allows(ip_address) {
+            debug!("refused connection from {} on port {}, not in its allow list", ip_address, listen.port);
+            refuse(socket, plaintext, "Not allowed to connect on this port");
+            continue;
+        }
+        /* a privacy listener's clients all come from the one address */
+        if !listen.privacy && !irc_core.allow_connection(ip_address) {
+            debug!("throttled connection from {} on port {}", ip_address, listen.port);
+            refuse(socket, plaintext, "Trying to reconnect too fast");
+            continue;
+        }
+        let slot = match limit.reserve() {
+            Some(slot) => slot,
+            None => {
+                debug!("refused connection from {}, port {} is full", ip_address, listen.port);
+                refuse(socket, plaintext, "Too many connections on this port");
+                continue;
+            }
+        };
+        /* taken now rather than after the handshakes, or connections that
+         * never finish one would never count */
+        let pending = if listen.privacy {
+            None
+        } else {
+            match irc_core.reserve_unregistered(ip_address) {
+                Some(slot) => Some(slot),
+                None => {
+                    debug!("too many unregistered connections from {}", ip_address);
+                    refuse(socket, plaintext, "Too many unregistered connections from your host");
+                    continue;
+                }
+            }
+        };
+        tokio::spawn(process_socket(socket, ip_address, Arc::clone(&irc_core), listen.clone(), acceptor.clone(), drain.clone(), slot, pending, connected));
+    }
+}
@@ -0,0 +1,308 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* rate limiting bits and pieces */
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/* the last few times something happened, for "at most max per window"
+ * style limits. on a miss it says how long until the oldest one expires */
+#[derive(Debug, Clone, Default)]
+pub struct RateWindow {
+    times: VecDeque<Instant>,
+}
+
+impl RateWindow {
+    /* a max of 0 means unlimited */
+    pub fn check(&mut self, max: usize, window: Duration) -> Result<(), Duration> {
+        self.check_at(max, window, Instant::now())
+    }
+
+    fn check_at(&mut self, max: usize, window: Duration, now: Instant) -> Result<(), Duration> {
+        if max == 0 {
+            return Ok(());
+        }
+        while self.times.front().is_some_and(|first| now.duration_since(*first) >= window) {
+            self.times.pop_front();
+        }
+        if self.times.len() >= max {
+            let oldest = self.times[0];
+            return Err(window - now.duration_since(oldest));
+        }
+        self.times.push_back(now);
+        Ok(())
+    }
+
+    fn is_stale(&self, window: Duration, now: Instant) -> bool {
+        self.times.back().is_none_or(|last| now.duration_since(*last) >= window)
+    }
+}
+
+/* sliding window of recent connection attempts per source address,
+ * checked in the accept loops before any DNS or TLS work happens.
+ * IPv6 addresses are grouped by /64 since anyone with one has the lot */
+#[derive(Debug)]
+pub struct ConnThrottle {
+    max: usize,
+    window: Duration,
+    attempts: HashMap<IpAddr, RateWindow>,
+    last_prune: Instant,
+}
+
+fn throttle_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => {
+            let mut segments = v6.segments();
+            for segment in segments[4..].iter_mut() {
+                *segment = 0;
+            }
+            IpAddr::from(segments)
+        }
+    }
+}
+
+impl ConnThrottle {
+    pub fn new(max: usize, window: Duration) -> Self {
+        ConnThrottle {
+            max,
+            window,
+            attempts: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+
+    /* records the attempt and says whether to let it through,
+     * a max of 0 turns throttling off */
+    pub fn check(&mut self, ip: IpAddr) -> bool {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if self.max == 0 {
+            return true;
+        }
+        let window = self.window;
+        if now.duration_since(self.last_prune) > window {
+            self.attempts.retain(|_ip, times| !times.is_stale(window, now));
+            self.last_prune = now;
+        }
+        self.attempts
+            .entry(throttle_key(ip))
+            .or_default()
+            .check_at(self.max, window, now)
+            .is_ok()
+    }
+}
+
+/* connections from one IP (or /64) that haven't registered yet. each
+ * one holds a PendingSlot which gives its place back when dropped, so
+ * there's no forgetting to decrement on one of the many ways out */
+#[derive(Debug, Clone)]
+pub struct PendingLimit {
+    max: usize,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+#[derive(Debug)]
+pub struct PendingSlot {
+    key: IpAddr,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl PendingLimit {
+    /* a max of 0 means unlimited */
+    pub fn new(max: usize) -> Self {
+        PendingLimit { max, counts: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub fn reserve(&self, ip: IpAddr) -> Option<PendingSlot> {
+        let key = throttle_key(ip);
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(key).or_insert(0);
+        if self.max > 0 && *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(PendingSlot { key, counts: Arc::clone(&self.counts) })
+    }
+}
+
+impl Drop for PendingSlot {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.key);
+            }
+        }
+    }
+}
+
+/* every connection open on one listener, whichever address it's from.
+ * the only limit there is on a privacy listener, where they're all
+ * from the one address anyway */
+#[derive(Debug, Clone)]
+pub struct ConnLimit {
+    max: usize,
+    count: Arc<AtomicUsize>,
+}
+
+#[derive(Debug)]
+pub struct ConnSlot {
+    count: Arc<AtomicUsize>,
+}
+
+impl ConnLimit {
+    /* a max of 0 means unlimited */
+    pub fn new(max: usize) -> Self {
+        ConnLimit { max, count: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    pub fn reserve(&self) -> Option<ConnSlot> {
+        let max = self.max;
+        self.count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                if max > 0 && count >= max { None } else { Some(count + 1) }
+            })
+            .ok()?;
+        Some(ConnSlot { count: Arc::clone(&self.count) })
+    }
+}
+
+impl Drop for ConnSlot {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/* per-client command rate, `capacity` lines can be sent in a burst
+ * and after that they trickle in at `rate` lines per second */
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: f64, rate: f64) -> Self {
+        TokenBucket {
+            capacity,
+            rate,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    /* takes a token if there is one, otherwise says how long until there
+     * will be. a rate of 0 means no limit */
+    pub fn try_take(&mut self) -> Result<(), Duration> {
+        self.try_take_at(Instant::now())
+    }
+
+    fn try_take_at(&mut self, now: Instant) -> Result<(), Duration> {
+        if self.rate <= 0.0 {
+            return Ok(());
+        }
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pending_slots() {
+        let limit = PendingLimit::new(2);
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        let neighbour: IpAddr = "2001:db8::2".parse().unwrap();
+        let first = limit.reserve(ip).unwrap();
+        let _second = limit.reserve(neighbour).unwrap();
+        assert!(limit.reserve(ip).is_none());
+        drop(first);
+        assert!(limit.reserve(ip).is_some());
+    }
+
+    #[test]
+    fn listener_slots() {
+        let limit = ConnLimit::new(2);
+        let first = limit.reserve().unwrap();
+        let _second = limit.reserve().unwrap();
+        assert!(limit.reserve().is_none());
+        drop(first);
+        assert!(limit.reserve().is_some());
+        assert!(ConnLimit::new(0).reserve().is_some());
+    }
+
+    #[test]
+    fn connection_window() {
+        let mut throttle = ConnThrottle::new(2, Duration::from_secs(10));
+        let start = Instant::now();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(throttle.check_at(ip, start));
+        assert!(throttle.check_at(ip, start + Duration::from_secs(1)));
+        assert!(!throttle.check_at(ip, start + Duration::from_secs(2)));
+        assert!(throttle.check_at("192.0.2.2".parse().unwrap(), start + Duration::from_secs(2)));
+        assert!(throttle.check_at(ip, start + Duration::from_secs(11)));
+    }
+
+    #[test]
+    fn ipv6_grouped_by_64() {
+        let mut throttle = ConnThrottle::new(1, Duration::from_secs(10));
+        let start = Instant::now();
+        assert!(throttle.check_at("2001:db8::1".parse().unwrap(), start));
+        assert!(!throttle.check_at("2001:db8::2".parse().unwrap(), start));
+        assert!(throttle.check_at("2001:db8:0:1::1".parse().unwrap(), start));
+    }
+
+    #[test]
+    fn window_wait_time() {
+        let start = Instant::now();
+        let mut joins = RateWindow::default();
+        let window = Duration::from_secs(30);
+        assert!(joins.check_at(2, window, start).is_ok());
+        assert!(joins.check_at(2, window, start + Duration::from_secs(10)).is_ok());
+        assert_eq!(joins.check_at(2, window, start + Duration::from_secs(12)), Err(Duration::from_secs(18)));
+        assert!(joins.check_at(2, window, start + Duration::from_secs(30)).is_ok());
+    }
+
+    #[test]
+    fn bucket_burst_then_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 1.0);
+        bucket.last = start;
+        assert!(bucket.try_take_at(start).is_ok());
+        assert!(bucket.try_take_at(start).is_ok());
+        assert_eq!(bucket.try_take_at(start), Err(Duration::from_secs(1)));
+        assert!(bucket.try_take_at(start + Duration::from_millis(1500)).is_ok());
+        assert!(bucket.try_take_at(start + Duration::from_millis(1500)).is_err());
+    }
+}
@@ -0,0 +1,196 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* hot upgrades. on SIGUSR2 the server says goodbye to everyone connected
+ * and execs whatever binary is now at the path it was started from, with
+ * the same arguments. the listening sockets are passed across as open
+ * file descriptors so anyone connecting in the meantime just waits in the
+ * backlog instead of being refused, and the K-lines and RESVs that were
+ * added on IRC come along too, in UPGRADE_ENV as JSON. the config is read
+ * afresh, so listeners it no longer has are closed and new ones bound */
+use crate::irc::kline::Kline;
+use crate::irc::resv::Resv;
+use crate::irc::Core;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::net::{IpAddr, TcpListener};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+#[cfg(unix)]
+use std::convert::TryFrom;
+#[cfg(unix)]
+use std::io::Error as ioError;
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, RawFd};
+#[cfg(unix)]
+use std::path::Path;
+
+pub const UPGRADE_ENV: &str = "RUSTY_IRCD_UPGRADE";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedListener {
+    pub address: IpAddr,
+    pub port: u16,
+    pub fd: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedKline {
+    pub mask: String,
+    pub reason: String,
+    /* seconds left, an Instant means nothing to another process */
+    pub remaining: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedResv {
+    pub mask: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpgradeState {
+    pub listeners: Vec<SavedListener>,
+    pub klines: Vec<SavedKline>,
+    pub resvs: Vec<SavedResv>,
+}
+
+impl UpgradeState {
+    /* what the old process left us, if we're the new one. the variable is
+     * cleared so it doesn't leak into anything we start ourselves */
+    pub fn from_env() -> Option<Self> {
+        let json = env::var(UPGRADE_ENV).ok()?;
+        env::remove_var(UPGRADE_ENV);
+        match serde_json::from_str(&json) {
+            Ok(state) => Some(state),
+            Err(err) => {
+                warn!("couldn't read what the old process passed on, closing its listeners: {}", err);
+                close_fds(&json);
+                None
+            }
+        }
+    }
+
+    pub fn save(irc: &Core) -> Self {
+        let now = Instant::now();
+        UpgradeState {
+            listeners: Vec::new(),
+            klines: irc.get_klines().into_iter().map(|kline| SavedKline {
+                remaining: kline.expires.saturating_duration_since(now).as_secs(),
+                mask: kline.mask,
+                reason: kline.reason,
+            }).collect(),
+            resvs: irc.get_resvs().into_iter().map(|resv| SavedResv {
+                mask: resv.mask,
+                reason: resv.reason,
+            }).collect(),
+        }
+    }
+
+    /* config resvs are already there, so this only brings back the ones
+     * added on IRC */
+    pub fn restore(&self, irc: &Core) {
+        for kline in self.klines.iter() {
+            irc.add_kline(Kline::new(&kline.mask, &kline.reason, Duration::from_secs(kline.remaining)));
+        }
+        for resv in self.resvs.iter() {
+            irc.add_resv(Resv { mask: resv.mask.clone(), reason: resv.reason.clone() });
+        }
+    }
+
+    /* the socket the old process had bound to address:port, if it had one */
+    #[cfg(unix)]
+    pub fn take_listener(&mut self, address: IpAddr, port: u16) -> Option<TcpListener> {
+        let index = self.listeners.iter().position(|saved| saved.address == address && saved.port == port)?;
+        let saved = self.listeners.remove(index);
+        /* inherited without close-on-exec, so set it again or the next
+         * upgrade would leave this one open in the process after that */
+        if let Err(err) = set_cloexec(saved.fd) {
+            warn!("couldn't set close-on-exec on the listener for {}:{}: {}", address, port, err);
+        }
+        Some(unsafe { TcpListener::from_raw_fd(saved.fd) })
+    }
+
+    #[cfg(not(unix))]
+    pub fn take_listener(&mut self, _address: IpAddr, _port: u16) -> Option<TcpListener> {
+        None
+    }
+
+    /* anything not taken by now isn't in the config any more */
+    #[cfg(unix)]
+    pub fn close_unused(&mut self) {
+        for saved in self.listeners.drain(..) {
+            drop(unsafe { TcpListener::from_raw_fd(saved.fd) });
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn close_unused(&mut self) {}
+
+    /* std marks every socket close-on-exec, a dup() of it isn't */
+    #[cfg(unix)]
+    pub fn add_listener(&mut self, address: IpAddr, port: u16, fd: RawFd) -> Result<(), ioError> {
+        let fd = unsafe { libc::dup(fd) };
+        if fd == -1 {
+            return Err(ioError::last_os_error());
+        }
+        self.listeners.push(SavedListener { address, port, fd });
+        Ok(())
+    }
+
+    /* only comes back if the exec failed */
+    #[cfg(unix)]
+    pub fn exec(&self, exe: &Path) -> ioError {
+        use std::os::unix::process::CommandExt;
+        let json = match serde_json::to_string(self) {
+            Ok(json) => json,
+            Err(err) => return err.into(),
+        };
+        std::process::Command::new(exe)
+            .args(env::args_os().skip(1))
+            .env(UPGRADE_ENV, json)
+            .exec()
+    }
+}
+
+#[cfg(unix)]
+fn set_cloexec(fd: RawFd) -> Result<(), ioError> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } == -1 {
+        return Err(ioError::last_os_error());
+    }
+    Ok(())
+}
+
+/* the listener fds in state that wouldn't parse as a whole, if they can
+ * be picked out of it, so they don't hold on to their ports for good */
+#[cfg(unix)]
+fn close_fds(json: &str) {
+    let value: serde_json::Value = match serde_json::from_str(json) {
+        Ok(value) => value,
+        Err(_) => return,
+    };
+    let listeners = value.get("listeners").and_then(|listeners| listeners.as_array());
+    for fd in listeners.into_iter().flatten().filter_map(|saved| saved.get("fd")?.as_i64()) {
+        if let Ok(fd) = RawFd::try_from(fd) {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn close_fds(_json: &str) {}
@@ -0,0 +1,238 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rusty_ircd::client::Client;
+use rusty_ircd::config::{Config, ListenBlock};
+use rusty_ircd::irc::error::Error as ircError;
+use rusty_ircd::irc::module::{CommandFuture, Module};
+use rusty_ircd::irc::{Core, User};
+use rusty_ircd::parser::ParsedMsg;
+use rusty_ircd::Server;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::TcpStream;
+use tokio::time::{self, Duration};
+
+#[tokio::test]
+async fn register_and_shut_down() {
+    let mut config = Config::default();
+    config.services.database = ":memory:".to_string();
+    let handle = Server::builder()
+        .config(config)
+        .name("irc.test")
+        .listen(ListenBlock { address: IpAddr::V4(Ipv4Addr::LOCALHOST), port: 0, ..ListenBlock::default() })
+        .start()
+        .await
+        .expect("server should start");
+
+    let (read, mut write) = TcpStream::connect(handle.local_addr()).await.unwrap().into_split();
+    let mut lines = BufReader::new(read).lines();
+    write.write_all(b"NICK tester\r\nUSER tester 0 * :Test User\r\n").await.unwrap();
+    let welcome = lines.next_line().await.unwrap().unwrap();
+    assert!(welcome.starts_with(":irc.test 001 tester "), "got {}", welcome);
+
+    handle.shutdown("test over").await;
+    let mut last = String::new();
+    while let Ok(Some(line)) = lines.next_line().await {
+        last = line;
+    }
+    assert_eq!(last, "ERROR :Closing link: test over");
+}
+
+#[derive(Debug)]
+struct Hello;
+
+impl Module for Hello {
+    fn name(&self) -> &str {
+        "hello"
+    }
+
+    fn on_privmsg(&self, _irc: &Arc<Core>, _user: &Arc<User>, target: &str, msg: &str, _notice: bool) -> Result<(), ircError> {
+        if msg.contains("spam") {
+            return Err(ircError::CannotSendToChan(target.to_string()));
+        }
+        Ok(())
+    }
+
+    fn handle_command<'a>(&'a self, _irc: &'a Arc<Core>, client: &'a Arc<Client>, params: &'a ParsedMsg) -> Option<CommandFuture<'a>> {
+        if !params.command.eq_ignore_ascii_case("HELLO") {
+            return None;
+        }
+        Some(Box::pin(async move {
+            client.send_line("HELLO :from a module").await?;
+            Ok(Vec::new())
+        }))
+    }
+}
+
+#[tokio::test]
+async fn module_hooks() {
+    let mut config = Config::default();
+    config.services.database = ":memory:".to_string();
+    let handle = Server::builder()
+        .config(config)
+        .name("irc.test")
+        .listen(ListenBlock { address: IpAddr::V4(Ipv4Addr::LOCALHOST), port: 0, ..ListenBlock::default() })
+        .module(Hello)
+        .start()
+        .await
+        .expect("server should start");
+
+    let (read, mut write) = TcpStream::connect(handle.local_addr()).await.unwrap().into_split();
+    let mut lines = BufReader::new(read).lines();
+    write.write_all(b"NICK tester\r\nUSER tester 0 * :Test User\r\nHELLO\r\nPRIVMSG tester :spam\r\n").await.unwrap();
+    let mut got = Vec::new();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.starts_with(":irc.test 404 ") {
+            break;
+        }
+        got.push(line);
+    }
+    assert!(got.iter().any(|line| line == "HELLO :from a module"), "got {:?}", got);
+    assert!(!got.iter().any(|line| line.contains(" PRIVMSG ")), "got {:?}", got);
+    handle.shutdown("test over").await;
+}
+
+/* the next line matching want, failing the test if it takes too long */
+async fn wait_for(lines: &mut Lines<BufReader<OwnedReadHalf>>, want: impl Fn(&str) -> bool) -> String {
+    loop {
+        match time::timeout(Duration::from_secs(10), lines.next_line()).await {
+            Ok(Ok(Some(line))) if want(&line) => return line,
+            Ok(Ok(Some(_line))) => (),
+            other => panic!("connection ended or timed out: {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn enforced_nick_is_seen_by_channel() {
+    let mut config = Config::default();
+    config.services.database = ":memory:".to_string();
+    config.services.enforce_nick = true;
+    let handle = Server::builder()
+        .config(config)
+        .name("irc.test")
+        .listen(ListenBlock { address: IpAddr::V4(Ipv4Addr::LOCALHOST), port: 0, ..ListenBlock::default() })
+        .start()
+        .await
+        .expect("server should start");
+
+    let (read, mut write) = TcpStream::connect(handle.local_addr()).await.unwrap().into_split();
+    let mut owner = BufReader::new(read).lines();
+    write.write_all(b"NICK acct\r\nUSER acct 0 * :Owner\r\nPRIVMSG NickServ :REGISTER hunter22\r\n").await.unwrap();
+    wait_for(&mut owner, |line| line.contains("Account acct registered")).await;
+    write.write_all(b"QUIT\r\n").await.unwrap();
+    while let Ok(Some(_line)) = owner.next_line().await {}
+
+    let (read, mut guest_write) = TcpStream::connect(handle.local_addr()).await.unwrap().into_split();
+    let mut guest = BufReader::new(read).lines();
+    guest_write.write_all(b"NICK guest\r\nUSER guest 0 * :Guest\r\nJOIN #test\r\n").await.unwrap();
+    wait_for(&mut guest, |line| line.starts_with(":irc.test 366 ")).await;
+    let (read, mut write) = TcpStream::connect(handle.local_addr()).await.unwrap().into_split();
+    let mut watcher = BufReader::new(read).lines();
+    write.write_all(b"NICK watcher\r\nUSER watcher 0 * :Watcher\r\nJOIN #test\r\n").await.unwrap();
+    wait_for(&mut watcher, |line| line.starts_with(":irc.test 366 ")).await;
+
+    guest_write.write_all(b"PRIVMSG NickServ :IDENTIFY acct hunter22\r\n").await.unwrap();
+    let seen = wait_for(&mut watcher, |line| line.contains(" NICK ")).await;
+    assert!(seen.starts_with(":guest!~guest@") && seen.ends_with(" NICK :acct"), "got {}", seen);
+    let own = wait_for(&mut guest, |line| line.contains(" NICK ")).await;
+    assert_eq!(own, seen);
+    handle.shutdown("test over").await;
+}
+
+#[tokio::test]
+async fn username_cant_fake_a_prefix() {
+    let mut config = Config::default();
+    config.services.database = ":memory:".to_string();
+    let handle = Server::builder()
+        .config(config)
+        .name("irc.test")
+        .listen(ListenBlock { address: IpAddr::V4(Ipv4Addr::LOCALHOST), port: 0, ..ListenBlock::default() })
+        .start()
+        .await
+        .expect("server should start");
+
+    let (read, mut write) = TcpStream::connect(handle.local_addr()).await.unwrap().into_split();
+    let mut lines = BufReader::new(read).lines();
+    write.write_all(b"NICK tester\r\nUSER a@evil.host 0 * :Test User\r\n").await.unwrap();
+    let refused = wait_for(&mut lines, |_line| true).await;
+    assert_eq!(refused, ":irc.test 468 tester :Your username is invalid");
+    write.write_all(b"USER a!b 0 * :Test User\r\n").await.unwrap();
+    let refused = wait_for(&mut lines, |_line| true).await;
+    assert_eq!(refused, ":irc.test 468 tester :Your username is invalid");
+    write.write_all(b"USER ~tester 0 * :Test User\r\nWHOIS tester\r\n").await.unwrap();
+    let whois = wait_for(&mut lines, |line| line.starts_with(":irc.test 311 ")).await;
+    assert!(whois.starts_with(":irc.test 311 tester tester ~tester "), "got {}", whois);
+    handle.shutdown("test over").await;
+}
+
+/* a connection that never gets through the WebSocket handshake still
+ * holds its unregistered slot, so it can't be used to dodge the limit */
+#[tokio::test]
+async fn stalled_handshake_counts_as_unregistered() {
+    let mut config = Config::default();
+    config.services.database = ":memory:".to_string();
+    config.limits.unregistered_max = 1;
+    let handle = Server::builder()
+        .config(config)
+        .name("irc.test")
+        .listen(ListenBlock { address: IpAddr::V4(Ipv4Addr::LOCALHOST), port: 0, websocket: true, ..ListenBlock::default() })
+        .start()
+        .await
+        .expect("server should start");
+
+    let _stalled = TcpStream::connect(handle.local_addr()).await.unwrap();
+    let mut refused = TcpStream::connect(handle.local_addr()).await.unwrap();
+    let mut buf = [0u8; 64];
+    let read = time::timeout(Duration::from_secs(5), refused.read(&mut buf)).await;
+    assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))), "second connection wasn't turned away: {:?}", read);
+    handle.shutdown("test over").await;
+}
+
+/* nothing per-IP applies on a privacy listener, but it's still full
+ * once max_connections are open */
+#[tokio::test]
+async fn privacy_listener_fills_up() {
+    let mut config = Config::default();
+    config.services.database = ":memory:".to_string();
+    let handle = Server::builder()
+        .config(config)
+        .name("irc.test")
+        .listen(ListenBlock {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 0,
+            privacy: true,
+            max_connections: 1,
+            ..ListenBlock::default()
+        })
+        .start()
+        .await
+        .expect("server should start");
+
+    let (read, mut write) = TcpStream::connect(handle.local_addr()).await.unwrap().into_split();
+    let mut lines = BufReader::new(read).lines();
+    write.write_all(b"NICK first\r\nUSER first 0 * :First\r\n").await.unwrap();
+    wait_for(&mut lines, |line| line.starts_with(":irc.test 001 ")).await;
+
+    let (read, _write) = TcpStream::connect(handle.local_addr()).await.unwrap().into_split();
+    let mut full = BufReader::new(read).lines();
+    let refused = wait_for(&mut full, |line| line.starts_with("ERROR ")).await;
+    assert_eq!(refused, "ERROR :Too many connections on this port");
+    handle.shutdown("test over").await;
+}